@@ -0,0 +1,66 @@
+// Minimal helpers for walking raw bencoded data. The info hash has to be the
+// SHA-1 of the exact bytes found in the .torrent file, so we need to locate
+// the info dictionary without decoding and re-encoding it.
+
+fn integer_end(bytes: &[u8], start: usize, terminator: u8) -> Option<usize> {
+    let mut end = start;
+    while end < bytes.len() && bytes[end] != terminator {
+        end += 1;
+    }
+    if end >= bytes.len() {
+        return None;
+    }
+    return Some(end);
+}
+
+fn parse_usize(bytes: &[u8]) -> Option<usize> {
+    return std::str::from_utf8(bytes).ok()?.parse::<usize>().ok();
+}
+
+// Returns the index one past the end of the bencoded value starting at `start`.
+pub fn value_end(bytes: &[u8], start: usize) -> Option<usize> {
+    match *bytes.get(start)? {
+        b'i' => {
+            let end = integer_end(bytes, start + 1, b'e')?;
+            return Some(end + 1);
+        }
+        b'l' | b'd' => {
+            let mut cursor = start + 1;
+            while *bytes.get(cursor)? != b'e' {
+                cursor = value_end(bytes, cursor)?;
+            }
+            return Some(cursor + 1);
+        }
+        b'0'..=b'9' => {
+            let colon = integer_end(bytes, start, b':')?;
+            let length = parse_usize(&bytes[start..colon])?;
+            let end = colon + 1 + length;
+            if end > bytes.len() {
+                return None;
+            }
+            return Some(end);
+        }
+        _ => None,
+    }
+}
+
+// Finds the raw bytes stored under `key` in the top level dictionary.
+pub fn dict_value<'a>(bytes: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    if *bytes.first()? != b'd' {
+        return None;
+    }
+
+    let mut cursor = 1;
+    while *bytes.get(cursor)? != b'e' {
+        let key_end = value_end(bytes, cursor)?;
+        let colon = integer_end(bytes, cursor, b':')?;
+        let current_key = &bytes[colon + 1..key_end];
+        let value_end = value_end(bytes, key_end)?;
+
+        if current_key == key {
+            return Some(&bytes[key_end..value_end]);
+        }
+        cursor = value_end;
+    }
+    return None;
+}
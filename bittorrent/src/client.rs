@@ -20,7 +20,9 @@ impl fmt::Display for dyn ConnectablePeer {
     }
 }
 
-pub async fn connect_peer(peer: &dyn ConnectablePeer) -> Result<TcpStream, Box<dyn Error>> {
+pub async fn connect_peer(
+    peer: &(dyn ConnectablePeer + Sync),
+) -> Result<TcpStream, Box<dyn Error>> {
     let stream = TcpStream::connect(format!("{}:{}", peer.ip(), peer.port())).await?;
     return Ok(stream);
 }
@@ -44,7 +46,7 @@ pub fn gen_peer_id_bytes() -> [u8; 20] {
     return bytes;
 }
 
-pub async fn try_connect(peer: &dyn ConnectablePeer) -> Result<TcpStream, ()> {
+pub async fn try_connect(peer: &(dyn ConnectablePeer + Sync)) -> Result<TcpStream, ()> {
     let maybe_connection = connect_peer(peer).await;
 
     match maybe_connection {
@@ -64,7 +66,10 @@ pub async fn try_connect(peer: &dyn ConnectablePeer) -> Result<TcpStream, ()> {
     }
 }
 
-pub async fn try_handshake(conn: &mut TcpStream, handshake: &PeerHandshake) -> Result<(), ()> {
+pub async fn try_handshake(
+    conn: &mut TcpStream,
+    handshake: &PeerHandshake,
+) -> Result<PeerHandshake, ()> {
     let peer_addr = conn.peer_addr().unwrap();
     let handshake_bytes = serialize_peer_handshake(handshake);
 
@@ -103,7 +108,7 @@ pub async fn try_handshake(conn: &mut TcpStream, handshake: &PeerHandshake) -> R
         }
 
         debug!("Metainfo matches!");
-        return Ok(());
+        return Ok(received_handshake);
     }
 
     warn!(
@@ -115,11 +120,19 @@ pub async fn try_handshake(conn: &mut TcpStream, handshake: &PeerHandshake) -> R
 }
 
 pub async fn try_establish(
-    peer: &dyn ConnectablePeer,
+    peer: &(dyn ConnectablePeer + Sync),
     metainfo: &TorrentMetainfo,
 ) -> Result<TcpStream, ()> {
-    let mut conn = try_connect(peer).await?;
-    let our_handshake = gen_peer_handshake(gen_peer_id_bytes(), metainfo.gen_info_hash_bytes());
-    try_handshake(&mut conn, &our_handshake).await?;
+    let (conn, _) = try_establish_with_hash(peer, metainfo.gen_info_hash_bytes()).await?;
     return Ok(conn);
 }
+
+pub async fn try_establish_with_hash(
+    peer: &(dyn ConnectablePeer + Sync),
+    info_hash: [u8; 20],
+) -> Result<(TcpStream, PeerHandshake), ()> {
+    let mut conn = try_connect(peer).await?;
+    let our_handshake = gen_peer_handshake(gen_peer_id_bytes(), info_hash);
+    let their_handshake = try_handshake(&mut conn, &our_handshake).await?;
+    return Ok((conn, their_handshake));
+}
@@ -3,6 +3,13 @@ extern crate log;
 extern crate serde;
 extern crate serde_bencode;
 
+mod bencode;
 pub mod client;
+pub mod magnet;
+mod metadata;
 pub mod p2p;
+pub mod session;
+pub mod storage;
 pub mod torrent;
+pub mod tracker;
+pub mod wire;
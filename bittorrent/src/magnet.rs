@@ -0,0 +1,126 @@
+use std::convert::TryInto;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct MagnetError(String);
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "Invalid magnet link: {}", self.0);
+    }
+}
+
+impl std::error::Error for MagnetError {}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+        i += 1;
+    }
+
+    return String::from_utf8_lossy(&decoded).into_owned();
+}
+
+fn decode_hex_hash(hash: &str) -> Option<[u8; 20]> {
+    let bytes = hash.as_bytes();
+    let mut decoded = vec![];
+    for pair in bytes.chunks(2) {
+        decoded.push(hex_value(pair[0])? << 4 | hex_value(pair[1])?);
+    }
+    return decoded[..].try_into().ok();
+}
+
+// Older magnet links carry the info hash as 32 characters of base32.
+fn decode_base32_hash(hash: &str) -> Option<[u8; 20]> {
+    let mut bits: u64 = 0;
+    let mut bit_count = 0;
+    let mut decoded = vec![];
+
+    for byte in hash.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a',
+            b'2'..=b'7' => byte - b'2' + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | value as u64;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+        }
+    }
+    return decoded[..].try_into().ok();
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<MagnetLink, MagnetError> {
+        let query = match uri.strip_prefix("magnet:?") {
+            Some(query) => query,
+            None => return Err(MagnetError(String::from("missing `magnet:?` prefix"))),
+        };
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+
+        for pair in query.split('&') {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = percent_decode(parts.next().unwrap_or(""));
+
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = match hash.len() {
+                            40 => decode_hex_hash(hash),
+                            32 => decode_base32_hash(hash),
+                            _ => None,
+                        };
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                _ => (),
+            }
+        }
+
+        match info_hash {
+            Some(info_hash) => Ok(MagnetLink {
+                info_hash: info_hash,
+                display_name: display_name,
+                trackers: trackers,
+            }),
+            None => Err(MagnetError(String::from(
+                "missing or malformed `xt=urn:btih:` info hash",
+            ))),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::bencode;
+use crate::torrent::sha1_bytes;
+use crate::wire::{read_message, write_message, PeerMessage};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use tokio::net::TcpStream;
+
+/*
+ * Fetches the info dictionary of a torrent from a peer using the extension
+ * protocol (https://www.bittorrent.org/beps/bep_0010.html) and the metadata
+ * extension (https://www.bittorrent.org/beps/bep_0009.html). This is how a
+ * magnet link, which only carries the info hash, becomes a full torrent.
+ */
+
+// The ID we ask peers to use when sending us `ut_metadata` messages.
+const UT_METADATA_LOCAL_ID: u8 = 1;
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+// Info dictionaries for even very large torrents are a few megabytes.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const MSG_TYPE_REQUEST: i64 = 0;
+const MSG_TYPE_DATA: i64 = 1;
+const MSG_TYPE_REJECT: i64 = 2;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ExtendedHandshake {
+    m: HashMap<String, i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_size: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MetadataMessage {
+    msg_type: i64,
+    piece: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+pub async fn fetch_metadata(
+    conn: &mut TcpStream,
+    info_hash: [u8; 20],
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut supported = HashMap::new();
+    supported.insert(String::from("ut_metadata"), UT_METADATA_LOCAL_ID as i64);
+    let our_handshake = ExtendedHandshake {
+        m: supported,
+        metadata_size: None,
+    };
+    write_message(
+        conn,
+        &PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: serde_bencode::to_bytes(&our_handshake)?,
+        },
+    )
+    .await?;
+
+    // Peers usually send their bitfield first, so skip ahead to their
+    // extended handshake.
+    let their_handshake: ExtendedHandshake = loop {
+        if let PeerMessage::Extended { id, payload } = read_message(conn).await? {
+            if id == EXTENDED_HANDSHAKE_ID {
+                break serde_bencode::from_bytes(&payload)?;
+            }
+        }
+    };
+
+    let their_id = match their_handshake.m.get("ut_metadata") {
+        Some(id) if *id > 0 && *id < 256 => *id as u8,
+        _ => return Err("Peer does not support the metadata extension.".into()),
+    };
+    let metadata_size = match their_handshake.metadata_size {
+        Some(size) if size > 0 && (size as usize) <= MAX_METADATA_SIZE => size as usize,
+        _ => return Err("Peer did not advertise a usable metadata size.".into()),
+    };
+
+    let piece_count = (metadata_size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE;
+    for piece in 0..piece_count {
+        let request = MetadataMessage {
+            msg_type: MSG_TYPE_REQUEST,
+            piece: piece as i64,
+            total_size: None,
+        };
+        write_message(
+            conn,
+            &PeerMessage::Extended {
+                id: their_id,
+                payload: serde_bencode::to_bytes(&request)?,
+            },
+        )
+        .await?;
+    }
+
+    let mut pieces: Vec<Option<Vec<u8>>> = vec![None; piece_count];
+    while pieces.iter().any(|piece| piece.is_none()) {
+        let payload = match read_message(conn).await? {
+            PeerMessage::Extended { id, payload } if id == UT_METADATA_LOCAL_ID => payload,
+            _ => continue,
+        };
+
+        // Data messages are a bencoded header immediately followed by the
+        // raw piece, so the header has to be measured rather than decoded.
+        let header_end = match bencode::value_end(&payload, 0) {
+            Some(end) => end,
+            None => return Err("Peer sent a malformed metadata message.".into()),
+        };
+        let header: MetadataMessage = serde_bencode::from_bytes(&payload[..header_end])?;
+
+        match header.msg_type {
+            MSG_TYPE_DATA => {
+                let index = header.piece as usize;
+                if index >= piece_count {
+                    return Err("Peer sent an out of range metadata piece.".into());
+                }
+                pieces[index] = Some(payload[header_end..].to_vec());
+            }
+            MSG_TYPE_REJECT => return Err("Peer rejected our metadata request.".into()),
+            _ => (),
+        }
+    }
+
+    let metadata: Vec<u8> = pieces.into_iter().flatten().flatten().collect();
+    if metadata.len() != metadata_size || sha1_bytes(&metadata) != info_hash {
+        return Err("Metadata from peer does not match the info hash.".into());
+    }
+    return Ok(metadata);
+}
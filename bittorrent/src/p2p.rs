@@ -14,11 +14,22 @@ pub struct PeerHandshake {
 
 pub const HANDSHAKE_BYTE_SIZE: usize = size_of::<PeerHandshake>();
 
+// Bit 20 from the right of the reserved bytes advertises support for the
+// extension protocol, see https://www.bittorrent.org/beps/bep_0010.html.
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+impl PeerHandshake {
+    pub fn supports_extensions(&self) -> bool {
+        return self.reserved_bytes[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0;
+    }
+}
+
 pub fn gen_peer_handshake(peer_id_bytes: [u8; 20], metainfo_hash_bytes: [u8; 20]) -> PeerHandshake {
     return PeerHandshake {
         magic: 0x13,
         more_magic: *b"BitTorrent protocol",
-        reserved_bytes: [0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0],
+        reserved_bytes: [0x0, 0x0, 0x0, 0x0, 0x0, EXTENSION_PROTOCOL_BIT, 0x0, 0x0],
         metainfo_hash_bytes: metainfo_hash_bytes,
         peer_id_bytes: peer_id_bytes,
     };
@@ -0,0 +1,1208 @@
+use crate::client::{gen_peer_id_bytes, try_establish_with_hash};
+use crate::magnet::{MagnetError, MagnetLink};
+use crate::metadata::fetch_metadata;
+use crate::storage::Storage;
+use crate::torrent::{
+    hex_encode_bytes, sha1_bytes, CompactTorrentPeer, TorrentInfo, TorrentMetainfo,
+};
+use crate::tracker::{announce, AnnounceEvent, AnnounceRequest};
+use crate::wire::{read_message, write_message, PeerMessage};
+
+use futures::future;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::{oneshot, watch};
+use tokio::task;
+use tokio::time::{delay_for, timeout};
+
+type PeerResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const BLOCK_SIZE: u32 = 16 * 1024;
+// Outstanding block requests per peer. Keeping a few in flight hides the
+// round trip latency between requesting a block and receiving it.
+const PIPELINE_DEPTH: usize = 5;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+// Peers are expected to send at least a keep-alive every two minutes.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const TICK: Duration = Duration::from_secs(1);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+// When to announce again, as the trackers asked. Running out of peers
+// allows announcing before `interval`, but never before `min_interval`.
+#[derive(Debug, Clone, Copy)]
+struct AnnounceSchedule {
+    interval: Duration,
+    min_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    // The port reported to trackers. Incoming connections are not accepted
+    // yet, so this only matters to trackers that validate it.
+    pub listen_port: u16,
+    pub max_peers_per_torrent: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        return SessionConfig {
+            listen_port: 6881,
+            max_peers_per_torrent: 30,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TorrentId(pub [u8; 20]);
+
+impl TorrentId {
+    pub fn from_hex(hex: &str) -> Option<TorrentId> {
+        let uri = format!("magnet:?xt=urn:btih:{}", hex);
+        return MagnetLink::parse(&uri)
+            .ok()
+            .map(|magnet| TorrentId(magnet.info_hash));
+    }
+}
+
+impl fmt::Display for TorrentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}", hex_encode_bytes(&self.0));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TorrentStatus {
+    FetchingMetadata,
+    Checking,
+    Downloading,
+    Paused,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentProgress {
+    pub id: TorrentId,
+    // Unknown until the metadata of a magnet link has been fetched.
+    pub name: Option<String>,
+    pub status: TorrentStatus,
+    pub downloaded_bytes: u64,
+    pub total_bytes: u64,
+    pub peers: usize,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Added(TorrentId),
+    MetadataReceived { id: TorrentId, name: String },
+    Progress(TorrentProgress),
+    Completed { id: TorrentId, path: PathBuf },
+    Failed { id: TorrentId, reason: String },
+    Removed(TorrentId),
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    InvalidTorrent(String),
+    AlreadyAdded(TorrentId),
+    UnknownTorrent(TorrentId),
+    Io(io::Error),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::InvalidTorrent(reason) => write!(f, "Invalid torrent: {}", reason),
+            SessionError::AlreadyAdded(id) => write!(f, "Torrent {} was already added.", id),
+            SessionError::UnknownTorrent(id) => write!(f, "Unknown torrent {}.", id),
+            SessionError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> SessionError {
+        return SessionError::Io(e);
+    }
+}
+
+impl From<MagnetError> for SessionError {
+    fn from(e: MagnetError) -> SessionError {
+        return SessionError::InvalidTorrent(e.to_string());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Control {
+    Run,
+    Pause,
+    Remove { delete_files: bool },
+    Shutdown,
+}
+
+enum TorrentSource {
+    Metainfo(TorrentMetainfo),
+    Magnet(MagnetLink),
+}
+
+struct TorrentHandle {
+    control: watch::Sender<Control>,
+    progress: Arc<Mutex<TorrentProgress>>,
+}
+
+struct Shared {
+    config: SessionConfig,
+    peer_id: [u8; 20],
+    torrents: Mutex<HashMap<TorrentId, TorrentHandle>>,
+    subscribers: Mutex<Vec<mpsc::Sender<SessionEvent>>>,
+    running_tasks: AtomicUsize,
+}
+
+impl Shared {
+    fn emit(&self, event: SessionEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+/*
+ * Owns a tokio runtime on a background thread and downloads any number of
+ * torrents on it. Every method is synchronous so that callers do not need
+ * to be async themselves; progress is reported through `subscribe`.
+ */
+pub struct Session {
+    runtime: Handle,
+    shared: Arc<Shared>,
+    stop_runtime: Mutex<Option<oneshot::Sender<()>>>,
+    runtime_thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Session {
+    pub fn new(config: SessionConfig) -> Result<Session, SessionError> {
+        let (handle_tx, handle_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+        let runtime_thread = thread::Builder::new()
+            .name(String::from("bittorrent-session"))
+            .spawn(move || {
+                let mut runtime = match Runtime::new() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        handle_tx.send(Err(e)).unwrap();
+                        return;
+                    }
+                };
+                handle_tx.send(Ok(runtime.handle().clone())).unwrap();
+                let _ = runtime.block_on(stop_rx);
+            })?;
+        let runtime = match handle_rx.recv() {
+            Ok(result) => result?,
+            Err(_) => {
+                return Err(SessionError::Io(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Session runtime thread exited during startup.",
+                )))
+            }
+        };
+
+        return Ok(Session {
+            runtime: runtime,
+            shared: Arc::new(Shared {
+                config: config,
+                peer_id: gen_peer_id_bytes(),
+                torrents: Mutex::new(HashMap::new()),
+                subscribers: Mutex::new(vec![]),
+                running_tasks: AtomicUsize::new(0),
+            }),
+            stop_runtime: Mutex::new(Some(stop_tx)),
+            runtime_thread: Mutex::new(Some(runtime_thread)),
+        });
+    }
+
+    pub fn subscribe(&self) -> mpsc::Receiver<SessionEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.shared.subscribers.lock().unwrap().push(tx);
+        return rx;
+    }
+
+    fn add(
+        &self,
+        id: TorrentId,
+        name: Option<String>,
+        source: TorrentSource,
+        download_dir: &Path,
+    ) -> Result<TorrentId, SessionError> {
+        let mut torrents = self.shared.torrents.lock().unwrap();
+        if torrents.contains_key(&id) {
+            return Err(SessionError::AlreadyAdded(id));
+        }
+
+        let status = match source {
+            TorrentSource::Metainfo(_) => TorrentStatus::Checking,
+            TorrentSource::Magnet(_) => TorrentStatus::FetchingMetadata,
+        };
+        let progress = Arc::new(Mutex::new(TorrentProgress {
+            id: id,
+            name: name,
+            status: status,
+            downloaded_bytes: 0,
+            total_bytes: 0,
+            peers: 0,
+            error: None,
+        }));
+        let (control_tx, control_rx) = watch::channel(Control::Run);
+        torrents.insert(
+            id,
+            TorrentHandle {
+                control: control_tx,
+                progress: Arc::clone(&progress),
+            },
+        );
+        drop(torrents);
+
+        self.shared.emit(SessionEvent::Added(id));
+
+        let task = TorrentTask {
+            shared: Arc::clone(&self.shared),
+            id: id,
+            download_dir: download_dir.to_path_buf(),
+            control: control_rx,
+            progress: progress,
+        };
+        self.shared.running_tasks.fetch_add(1, Ordering::SeqCst);
+        self.runtime.spawn(async move {
+            let shared = Arc::clone(&task.shared);
+            task.run(source).await;
+            shared.running_tasks.fetch_sub(1, Ordering::SeqCst);
+        });
+
+        return Ok(id);
+    }
+
+    pub fn add_torrent_bytes(
+        &self,
+        bytes: &[u8],
+        download_dir: &Path,
+    ) -> Result<TorrentId, SessionError> {
+        let (metainfo, info_hash) = match TorrentMetainfo::from_bytes(bytes) {
+            Ok(parsed) => parsed,
+            Err(e) => return Err(SessionError::InvalidTorrent(e.to_string())),
+        };
+        if let Err(reason) = metainfo.info.check_layout() {
+            return Err(SessionError::InvalidTorrent(reason));
+        }
+
+        let name = Some(metainfo.info.name.clone());
+        return self.add(
+            TorrentId(info_hash),
+            name,
+            TorrentSource::Metainfo(metainfo),
+            download_dir,
+        );
+    }
+
+    pub fn add_torrent_file(
+        &self,
+        path: &Path,
+        download_dir: &Path,
+    ) -> Result<TorrentId, SessionError> {
+        let bytes = fs::read(path)?;
+        return self.add_torrent_bytes(&bytes, download_dir);
+    }
+
+    pub fn add_magnet(&self, uri: &str, download_dir: &Path) -> Result<TorrentId, SessionError> {
+        let magnet = MagnetLink::parse(uri)?;
+        let name = magnet.display_name.clone();
+        return self.add(
+            TorrentId(magnet.info_hash),
+            name,
+            TorrentSource::Magnet(magnet),
+            download_dir,
+        );
+    }
+
+    fn send_control(&self, id: TorrentId, control: Control) -> Result<(), SessionError> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        match torrents.get(&id) {
+            Some(handle) => {
+                // The task may already have finished, in which case there is
+                // nobody left to tell.
+                let _ = handle.control.broadcast(control);
+                return Ok(());
+            }
+            None => return Err(SessionError::UnknownTorrent(id)),
+        }
+    }
+
+    pub fn pause(&self, id: TorrentId) -> Result<(), SessionError> {
+        return self.send_control(id, Control::Pause);
+    }
+
+    pub fn resume(&self, id: TorrentId) -> Result<(), SessionError> {
+        return self.send_control(id, Control::Run);
+    }
+
+    pub fn remove(&self, id: TorrentId, delete_files: bool) -> Result<(), SessionError> {
+        self.send_control(
+            id,
+            Control::Remove {
+                delete_files: delete_files,
+            },
+        )?;
+        self.shared.torrents.lock().unwrap().remove(&id);
+        return Ok(());
+    }
+
+    pub fn progress(&self, id: TorrentId) -> Option<TorrentProgress> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        return torrents
+            .get(&id)
+            .map(|handle| handle.progress.lock().unwrap().clone());
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentProgress> {
+        let torrents = self.shared.torrents.lock().unwrap();
+        return torrents
+            .values()
+            .map(|handle| handle.progress.lock().unwrap().clone())
+            .collect();
+    }
+
+    /*
+     * Stops every torrent, giving them up to `grace` to tell their trackers
+     * that we are leaving, then stops the runtime.
+     */
+    pub fn shutdown(&self, grace: Duration) {
+        for handle in self.shared.torrents.lock().unwrap().values() {
+            let _ = handle.control.broadcast(Control::Shutdown);
+        }
+
+        let deadline = Instant::now() + grace;
+        while self.shared.running_tasks.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        if let Some(stop) = self.stop_runtime.lock().unwrap().take() {
+            let _ = stop.send(());
+        }
+        if let Some(runtime_thread) = self.runtime_thread.lock().unwrap().take() {
+            let _ = runtime_thread.join();
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.shutdown(Duration::from_secs(0));
+    }
+}
+
+// Hands pieces out to peers so that no two peers download the same piece,
+// until only pieces that are already in flight remain. From then on pieces
+// are handed out again so that one slow peer cannot hold up completion.
+struct Picker {
+    have: Vec<bool>,
+    in_flight: Vec<u32>,
+}
+
+impl Picker {
+    fn pick(&mut self, peer_has: &[bool]) -> Option<u32> {
+        let wanted = |index: usize| !self.have[index] && peer_has[index];
+        let index = (0..self.have.len())
+            .find(|index| wanted(*index) && self.in_flight[*index] == 0)
+            .or_else(|| (0..self.have.len()).find(|index| wanted(*index)))?;
+        self.in_flight[index] += 1;
+        return Some(index as u32);
+    }
+
+    fn release(&mut self, index: u32) {
+        let in_flight = &mut self.in_flight[index as usize];
+        *in_flight = in_flight.saturating_sub(1);
+    }
+
+    fn is_complete(&self) -> bool {
+        return self.have.iter().all(|have| *have);
+    }
+}
+
+struct Download {
+    info_hash: [u8; 20],
+    info: TorrentInfo,
+    storage: Storage,
+    picker: Mutex<Picker>,
+    // Tells peer connections to wind down, e.g. when pausing. Peers race it
+    // against reading from their connection, see `halted`.
+    halt: watch::Sender<bool>,
+    halted: watch::Receiver<bool>,
+    active_peers: AtomicUsize,
+    connected: Mutex<HashSet<String>>,
+}
+
+impl Download {
+    fn downloaded_bytes(&self) -> u64 {
+        let picker = self.picker.lock().unwrap();
+        return (0..self.info.piece_count())
+            .filter(|index| picker.have[*index as usize])
+            .map(|index| self.info.piece_size(index) as u64)
+            .sum();
+    }
+}
+
+struct TorrentTask {
+    shared: Arc<Shared>,
+    id: TorrentId,
+    download_dir: PathBuf,
+    control: watch::Receiver<Control>,
+    progress: Arc<Mutex<TorrentProgress>>,
+}
+
+impl TorrentTask {
+    fn set_status(&self, status: TorrentStatus) {
+        self.progress.lock().unwrap().status = status;
+    }
+
+    fn fail(&self, reason: String) {
+        warn!("Torrent {} failed: {}", self.id, reason);
+        {
+            let mut progress = self.progress.lock().unwrap();
+            progress.status = TorrentStatus::Failed;
+            progress.error = Some(reason.clone());
+        }
+        self.shared.emit(SessionEvent::Failed {
+            id: self.id,
+            reason: reason,
+        });
+    }
+
+    fn current_control(&self) -> Control {
+        return *self.control.borrow();
+    }
+
+    // Blocks while paused. Returns the control that ended the pause.
+    async fn wait_while_paused(&mut self) -> Control {
+        let mut control = self.current_control();
+        while control == Control::Pause {
+            control = match self.control.recv().await {
+                Some(control) => control,
+                None => Control::Shutdown,
+            };
+        }
+        return control;
+    }
+
+    async fn announce_all(
+        &self,
+        trackers: &[String],
+        event: AnnounceEvent,
+        downloaded: u64,
+        left: u64,
+    ) -> (Vec<CompactTorrentPeer>, AnnounceSchedule) {
+        let request = AnnounceRequest {
+            info_hash: self.id.0,
+            peer_id: self.shared.peer_id,
+            port: self.shared.config.listen_port,
+            uploaded: 0,
+            downloaded: downloaded,
+            left: left,
+            event: event,
+        };
+        let responses =
+            future::join_all(trackers.iter().map(|tracker| announce(tracker, &request))).await;
+
+        let mut peers = vec![];
+        let mut interval = MAX_ANNOUNCE_INTERVAL;
+        let mut min_interval = MIN_ANNOUNCE_INTERVAL;
+        for (tracker, response) in trackers.iter().zip(responses) {
+            match response {
+                Ok(response) => {
+                    debug!(
+                        "Tracker {} returned {} peers for {}.",
+                        tracker,
+                        response.peers.len(),
+                        self.id
+                    );
+                    interval = interval.min(Duration::from_secs(response.interval as u64));
+                    if let Some(seconds) = response.min_interval {
+                        min_interval = min_interval.max(Duration::from_secs(seconds as u64));
+                    }
+                    peers.extend(response.peers);
+                }
+                Err(e) => debug!("Announce to {} failed: {}", tracker, e),
+            }
+        }
+        let min_interval = min_interval.min(MAX_ANNOUNCE_INTERVAL);
+        let schedule = AnnounceSchedule {
+            interval: interval.max(min_interval),
+            min_interval: min_interval,
+        };
+        return (peers, schedule);
+    }
+
+    async fn resolve_metadata(&mut self, magnet: &MagnetLink) -> Option<TorrentInfo> {
+        if magnet.trackers.is_empty() {
+            self.fail(String::from(
+                "Magnet link has no trackers and DHT is not supported.",
+            ));
+            return None;
+        }
+
+        loop {
+            match self.wait_while_paused().await {
+                Control::Run => self.set_status(TorrentStatus::FetchingMetadata),
+                _ => return None,
+            }
+
+            let (peers, schedule) = self
+                .announce_all(&magnet.trackers, AnnounceEvent::None, 0, 0)
+                .await;
+            for peer in peers {
+                if self.current_control() != Control::Run {
+                    break;
+                }
+                let fetch = async {
+                    let (mut conn, handshake) =
+                        match timeout(CONNECT_TIMEOUT, try_establish_with_hash(&peer, self.id.0))
+                            .await
+                        {
+                            Ok(Ok(established)) => established,
+                            _ => return None,
+                        };
+                    if !handshake.supports_extensions() {
+                        return None;
+                    }
+                    return fetch_metadata(&mut conn, self.id.0).await.ok();
+                };
+
+                if let Ok(Some(raw_info)) = timeout(METADATA_TIMEOUT, fetch).await {
+                    match serde_bencode::from_bytes::<TorrentInfo>(&raw_info) {
+                        Ok(info) => {
+                            // The metadata matches the info hash, so every
+                            // peer would send the same.
+                            if let Err(reason) = info.check_layout() {
+                                self.fail(format!("Invalid torrent: {}", reason));
+                                return None;
+                            }
+                            info!("Fetched metadata for {} ({}).", info.name, self.id);
+                            self.progress.lock().unwrap().name = Some(info.name.clone());
+                            self.shared.emit(SessionEvent::MetadataReceived {
+                                id: self.id,
+                                name: info.name.clone(),
+                            });
+                            return Some(info);
+                        }
+                        Err(e) => debug!("Peer sent metadata we could not parse: {}", e),
+                    }
+                }
+            }
+
+            if self.current_control() == Control::Run {
+                let _ = timeout(schedule.interval, self.control.recv()).await;
+            }
+        }
+    }
+
+    fn publish_progress(&self, download: &Download) {
+        let progress = {
+            let mut progress = self.progress.lock().unwrap();
+            progress.downloaded_bytes = download.downloaded_bytes();
+            progress.total_bytes = download.info.total_length();
+            progress.peers = download.active_peers.load(Ordering::SeqCst);
+            progress.clone()
+        };
+        self.shared.emit(SessionEvent::Progress(progress));
+    }
+
+    async fn wind_down_peers(&self, download: &Download) {
+        let _ = download.halt.broadcast(true);
+        while download.active_peers.load(Ordering::SeqCst) > 0 {
+            delay_for(Duration::from_millis(100)).await;
+        }
+        let _ = download.halt.broadcast(false);
+    }
+
+    // Tells trackers the torrent is going away while the peers wind down,
+    // so that a short shutdown grace period does not cut the announce off.
+    async fn stop(&self, trackers: &[String], download: &Download) {
+        future::join(
+            self.stop_announce(trackers, download),
+            self.wind_down_peers(download),
+        )
+        .await;
+    }
+
+    async fn run(mut self, source: TorrentSource) {
+        let (info, trackers) = match source {
+            TorrentSource::Metainfo(metainfo) => {
+                let trackers = metainfo.trackers();
+                (metainfo.info, trackers)
+            }
+            TorrentSource::Magnet(magnet) => match self.resolve_metadata(&magnet).await {
+                Some(info) => (info, magnet.trackers.clone()),
+                None => return self.finish_early(),
+            },
+        };
+
+        self.set_status(TorrentStatus::Checking);
+        let storage = match Storage::new(&info, &self.download_dir) {
+            Ok(storage) => storage,
+            Err(e) => return self.fail(format!("Failed to prepare files: {}", e)),
+        };
+        let piece_count = info.piece_count() as usize;
+        let (halt, halted) = watch::channel(false);
+        let download = Arc::new(Download {
+            info_hash: self.id.0,
+            info: info,
+            storage: storage,
+            picker: Mutex::new(Picker {
+                have: vec![false; piece_count],
+                in_flight: vec![0; piece_count],
+            }),
+            halt: halt,
+            halted: halted,
+            active_peers: AtomicUsize::new(0),
+            connected: Mutex::new(HashSet::new()),
+        });
+        // Hashing large torrents takes a while, which must not stall the
+        // other torrents on the runtime.
+        let checking = Arc::clone(&download);
+        match task::spawn_blocking(move || check_existing(&checking.info, &checking.storage)).await
+        {
+            Ok(have) => download.picker.lock().unwrap().have = have,
+            Err(e) => return self.fail(format!("Failed to check existing files: {}", e)),
+        }
+
+        let mut event = AnnounceEvent::Started;
+        let mut next_announce = Instant::now();
+        let mut last_announce = Instant::now();
+        let mut schedule = AnnounceSchedule {
+            interval: MIN_ANNOUNCE_INTERVAL,
+            min_interval: MIN_ANNOUNCE_INTERVAL,
+        };
+        // How long to wait after the last announce before announcing again
+        // for want of peers. Doubles every time that turns up nothing.
+        let mut retry_after = MIN_ANNOUNCE_INTERVAL;
+        let mut last_progress = Instant::now();
+        self.set_status(TorrentStatus::Downloading);
+
+        loop {
+            match self.current_control() {
+                Control::Run => (),
+                Control::Pause => {
+                    self.wind_down_peers(&download).await;
+                    self.set_status(TorrentStatus::Paused);
+                    self.publish_progress(&download);
+                    if self.wait_while_paused().await != Control::Run {
+                        continue;
+                    }
+                    self.set_status(TorrentStatus::Downloading);
+                    next_announce = last_announce + schedule.min_interval;
+                }
+                Control::Remove { delete_files } => {
+                    self.stop(&trackers, &download).await;
+                    if delete_files {
+                        if let Err(e) = download.storage.remove_files() {
+                            warn!("Failed to delete files of {}: {}", self.id, e);
+                        }
+                    }
+                    self.shared.emit(SessionEvent::Removed(self.id));
+                    return;
+                }
+                Control::Shutdown => {
+                    self.stop(&trackers, &download).await;
+                    return;
+                }
+            }
+
+            if download.picker.lock().unwrap().is_complete() {
+                break;
+            }
+
+            let out_of_peers = download.active_peers.load(Ordering::SeqCst) == 0;
+            let retry = out_of_peers
+                && event != AnnounceEvent::Started
+                && last_announce.elapsed() >= retry_after;
+            if Instant::now() >= next_announce || retry {
+                let downloaded = download.downloaded_bytes();
+                let left = download.info.total_length() - downloaded;
+                let (peers, announced) =
+                    self.announce_all(&trackers, event, downloaded, left).await;
+                event = AnnounceEvent::None;
+                schedule = announced;
+                last_announce = Instant::now();
+                next_announce = last_announce + schedule.interval;
+                retry_after = if retry {
+                    (retry_after * 2).clamp(schedule.min_interval, schedule.interval)
+                } else {
+                    schedule.min_interval
+                };
+
+                for peer in peers {
+                    if download.active_peers.load(Ordering::SeqCst)
+                        >= self.shared.config.max_peers_per_torrent
+                    {
+                        break;
+                    }
+                    let address = format!("{:?}:{}", peer.ip, peer.port);
+                    if !download.connected.lock().unwrap().insert(address.clone()) {
+                        continue;
+                    }
+
+                    let slot = PeerSlot::take(&download, address);
+                    tokio::spawn(async move {
+                        if let Err(e) = download_from_peer(&slot.download, &peer).await {
+                            debug!("Dropped peer {}: {}", slot.address, e);
+                        }
+                    });
+                }
+            }
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                self.publish_progress(&download);
+                last_progress = Instant::now();
+            }
+
+            let _ = timeout(TICK, self.control.recv()).await;
+        }
+
+        self.wind_down_peers(&download).await;
+        self.set_status(TorrentStatus::Completed);
+        self.publish_progress(&download);
+        info!("Finished downloading {} ({}).", download.info.name, self.id);
+
+        let total = download.info.total_length();
+        self.announce_all(&trackers, AnnounceEvent::Completed, total, 0)
+            .await;
+        self.shared.emit(SessionEvent::Completed {
+            id: self.id,
+            path: download.storage.root().to_path_buf(),
+        });
+    }
+
+    async fn stop_announce(&self, trackers: &[String], download: &Download) {
+        let downloaded = download.downloaded_bytes();
+        let left = download.info.total_length() - downloaded;
+        self.announce_all(trackers, AnnounceEvent::Stopped, downloaded, left)
+            .await;
+    }
+
+    fn finish_early(&self) {
+        if let Control::Remove { .. } = self.current_control() {
+            self.shared.emit(SessionEvent::Removed(self.id));
+        }
+    }
+}
+
+// Hashes whatever is already on disk so that restarting a download picks up
+// where it left off.
+fn check_existing(info: &TorrentInfo, storage: &Storage) -> Vec<bool> {
+    let mut have = vec![false; info.piece_count() as usize];
+    for index in 0..info.piece_count() {
+        if let Ok(data) = storage.read_piece(index, info.piece_size(index)) {
+            have[index as usize] = sha1_bytes(&data) == info.get_piece_sha1_bytes(index);
+        }
+    }
+    return have;
+}
+
+// A peer connection counted in `Download::active_peers`. The count is given
+// back when the slot is dropped, so that a peer task that panics cannot keep
+// `wind_down_peers` waiting forever.
+struct PeerSlot {
+    download: Arc<Download>,
+    address: String,
+}
+
+impl PeerSlot {
+    fn take(download: &Arc<Download>, address: String) -> PeerSlot {
+        download.active_peers.fetch_add(1, Ordering::SeqCst);
+        return PeerSlot {
+            download: Arc::clone(download),
+            address: address,
+        };
+    }
+}
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        self.download
+            .connected
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&self.address);
+        self.download.active_peers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct PieceInProgress {
+    index: u32,
+    size: u32,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    next_offset: u32,
+    outstanding: usize,
+}
+
+impl PieceInProgress {
+    fn new(index: u32, size: u32) -> PieceInProgress {
+        let block_count = (size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        return PieceInProgress {
+            index: index,
+            size: size,
+            data: vec![0x0; size as usize],
+            received: vec![false; block_count as usize],
+            next_offset: 0,
+            outstanding: 0,
+        };
+    }
+
+    fn is_complete(&self) -> bool {
+        return self.received.iter().all(|received| *received);
+    }
+
+    // Keeps `block` if it is one of the blocks the piece is requested in, see
+    // `download_from_peer`. Returns false for anything else, e.g. an empty
+    // block at the end of the piece.
+    fn receive(&mut self, begin: u32, block: &[u8]) -> bool {
+        let block_index = (begin / BLOCK_SIZE) as usize;
+        if begin != block_index as u32 * BLOCK_SIZE
+            || begin >= self.size
+            || block.len() != BLOCK_SIZE.min(self.size - begin) as usize
+        {
+            return false;
+        }
+        if !self.received[block_index] {
+            let start = begin as usize;
+            self.data[start..start + block.len()].copy_from_slice(block);
+            self.received[block_index] = true;
+        }
+        return true;
+    }
+}
+
+// Resolves once the peers of `download` are told to wind down.
+async fn halted(download: &Download) {
+    let mut halted = download.halted.clone();
+    while let Some(halt) = halted.recv().await {
+        if halt {
+            return;
+        }
+    }
+}
+
+async fn download_from_peer(download: &Download, peer: &CompactTorrentPeer) -> PeerResult<()> {
+    let connect = timeout(
+        CONNECT_TIMEOUT,
+        try_establish_with_hash(peer, download.info_hash),
+    );
+    let (mut conn, _) = tokio::select! {
+        connected = connect => match connected {
+            Ok(Ok(established)) => established,
+            Ok(Err(_)) => return Err("handshake failed".into()),
+            Err(_) => return Err("connection timed out".into()),
+        },
+        _ = halted(download) => return Ok(()),
+    };
+    write_message(&mut conn, &PeerMessage::Interested).await?;
+
+    let piece_count = download.info.piece_count() as usize;
+    let mut peer_has = vec![false; piece_count];
+    let mut choked = true;
+    let mut current: Option<PieceInProgress> = None;
+
+    let result: PeerResult<()> = async {
+        loop {
+            if *download.halted.borrow() {
+                return Ok(());
+            }
+
+            if !choked && current.is_none() {
+                let picked = download.picker.lock().unwrap().pick(&peer_has);
+                if let Some(index) = picked {
+                    current = Some(PieceInProgress::new(index, download.info.piece_size(index)));
+                } else if download.picker.lock().unwrap().is_complete() {
+                    return Ok(());
+                }
+            }
+
+            if let Some(piece) = current.as_mut() {
+                while !choked
+                    && piece.outstanding < PIPELINE_DEPTH
+                    && piece.next_offset < piece.size
+                {
+                    let length = BLOCK_SIZE.min(piece.size - piece.next_offset);
+                    let request = PeerMessage::Request {
+                        index: piece.index,
+                        begin: piece.next_offset,
+                        length: length,
+                    };
+                    write_message(&mut conn, &request).await?;
+                    piece.next_offset += length;
+                    piece.outstanding += 1;
+                }
+            }
+
+            // Peers may stay quiet for minutes, which must not hold up
+            // pausing or shutting down.
+            let message = tokio::select! {
+                read = timeout(PEER_IDLE_TIMEOUT, read_message(&mut conn)) => match read {
+                    Ok(message) => message?,
+                    Err(_) => return Err("peer went idle".into()),
+                },
+                _ = halted(download) => return Ok(()),
+            };
+
+            match message {
+                PeerMessage::Choke => {
+                    // Choking discards every request we had outstanding.
+                    choked = true;
+                    if let Some(piece) = current.take() {
+                        download.picker.lock().unwrap().release(piece.index);
+                    }
+                }
+                PeerMessage::Unchoke => choked = false,
+                PeerMessage::Have(index) => {
+                    if let Some(has) = peer_has.get_mut(index as usize) {
+                        *has = true;
+                    }
+                }
+                PeerMessage::Bitfield(bitfield) => {
+                    for index in 0..piece_count {
+                        let byte = bitfield.get(index / 8).copied().unwrap_or(0);
+                        peer_has[index] = byte & (0x80 >> (index % 8)) != 0;
+                    }
+                }
+                PeerMessage::Piece {
+                    index,
+                    begin,
+                    block,
+                } => {
+                    let piece = match current.as_mut() {
+                        Some(piece) if piece.index == index => piece,
+                        _ => continue,
+                    };
+                    if !piece.receive(begin, &block) {
+                        return Err("peer sent a block that was not requested".into());
+                    }
+                    piece.outstanding = piece.outstanding.saturating_sub(1);
+
+                    if piece.is_complete() {
+                        let piece = current.take().unwrap();
+                        finish_piece(download, piece)?;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    .await;
+
+    if let Some(piece) = current {
+        download.picker.lock().unwrap().release(piece.index);
+    }
+    return result;
+}
+
+fn finish_piece(download: &Download, piece: PieceInProgress) -> PeerResult<()> {
+    let mut picker = download.picker.lock().unwrap();
+    picker.release(piece.index);
+
+    if picker.have[piece.index as usize] {
+        // Another peer beat us to it during the endgame.
+        return Ok(());
+    }
+    if sha1_bytes(&piece.data) != download.info.get_piece_sha1_bytes(piece.index) {
+        return Err(format!("piece {} failed its hash check", piece.index).into());
+    }
+
+    download.storage.write_piece(piece.index, &piece.data)?;
+    picker.have[piece.index as usize] = true;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bytes::ByteBuf;
+    use std::process;
+
+    // Pieces of 4 bytes, the last one 2 bytes long.
+    const DATA: &[u8] = b"abcdefghij";
+    const PIECE_LENGTH: u32 = 4;
+
+    fn info(name: &str) -> TorrentInfo {
+        let mut pieces = vec![];
+        for piece in DATA.chunks(PIECE_LENGTH as usize) {
+            pieces.extend_from_slice(&sha1_bytes(piece));
+        }
+        return TorrentInfo {
+            name: String::from(name),
+            piece_length: PIECE_LENGTH,
+            pieces: ByteBuf::from(pieces),
+            length: Some(DATA.len() as u64),
+            files: None,
+        };
+    }
+
+    // A fresh download directory, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("bittorrent-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            return TempDir(path);
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn download(dir: &TempDir) -> Arc<Download> {
+        let info = info("download");
+        let storage = Storage::new(&info, &dir.0).unwrap();
+        let piece_count = info.piece_count() as usize;
+        let (halt, halted) = watch::channel(false);
+        return Arc::new(Download {
+            info_hash: [0; 20],
+            info: info,
+            storage: storage,
+            picker: Mutex::new(Picker {
+                have: vec![false; piece_count],
+                in_flight: vec![0; piece_count],
+            }),
+            halt: halt,
+            halted: halted,
+            active_peers: AtomicUsize::new(0),
+            connected: Mutex::new(HashSet::new()),
+        });
+    }
+
+    #[test]
+    fn checks_the_piece_layout() {
+        assert!(info("fits").check_layout().is_ok());
+
+        let mut no_length = info("no length");
+        no_length.piece_length = 0;
+        assert!(no_length.check_layout().is_err());
+
+        let mut too_short = info("too short");
+        too_short.length = Some(DATA.len() as u64 + 4);
+        assert!(too_short.check_layout().is_err());
+
+        let mut too_long = info("too long");
+        too_long.length = Some(DATA.len() as u64 - 4);
+        assert!(too_long.check_layout().is_err());
+
+        let mut torn_hash = info("torn hash");
+        torn_hash.pieces.pop();
+        assert!(torn_hash.check_layout().is_err());
+    }
+
+    #[test]
+    fn hands_out_every_piece_once_before_the_endgame() {
+        let mut picker = Picker {
+            have: vec![true, false, false],
+            in_flight: vec![0; 3],
+        };
+        let peer_has = [true, true, true];
+        assert_eq!(picker.pick(&peer_has), Some(1));
+        assert_eq!(picker.pick(&peer_has), Some(2));
+        // Only pieces in flight are left, so they are handed out again.
+        assert_eq!(picker.pick(&peer_has), Some(1));
+        assert_eq!(picker.in_flight, vec![0, 2, 1]);
+        // Nothing the peer has.
+        assert_eq!(picker.pick(&[true, false, false]), None);
+
+        picker.release(1);
+        picker.release(1);
+        picker.have[1] = true;
+        assert_eq!(picker.pick(&peer_has), Some(2));
+        picker.have[2] = true;
+        assert!(picker.is_complete());
+    }
+
+    #[test]
+    fn keeps_only_the_requested_blocks() {
+        let size = BLOCK_SIZE * 2 + 100;
+        let mut piece = PieceInProgress::new(0, size);
+        assert_eq!(piece.received.len(), 3);
+
+        let block = vec![1; BLOCK_SIZE as usize];
+        assert!(piece.receive(BLOCK_SIZE, &block));
+        assert!(!piece.is_complete());
+        // The last block is shorter.
+        assert!(piece.receive(BLOCK_SIZE * 2, &[2; 100]));
+
+        assert!(!piece.receive(1, &block), "misaligned");
+        assert!(!piece.receive(0, &block[1..]), "short");
+        assert!(!piece.receive(BLOCK_SIZE * 2, &[2; 101]), "past the end");
+        assert!(!piece.receive(size, &[]), "empty at the end");
+        assert!(!piece.receive(BLOCK_SIZE * 3, &[]), "beyond the end");
+
+        assert!(piece.receive(0, &block));
+        assert!(piece.is_complete());
+        // A block sent twice is only kept once.
+        assert!(piece.receive(0, &vec![3; BLOCK_SIZE as usize]));
+        assert_eq!(piece.data[0], 1);
+        assert_eq!(piece.data[size as usize - 1], 2);
+    }
+
+    #[test]
+    fn resumes_from_the_pieces_on_disk() {
+        let dir = TempDir::new("resume");
+        let info = info("resume");
+        // The second piece is damaged, and the last one missing.
+        let mut on_disk = DATA.to_vec();
+        on_disk[5] = b'X';
+        on_disk.truncate(8);
+        fs::write(dir.0.join("resume"), &on_disk).unwrap();
+
+        let storage = Storage::new(&info, &dir.0).unwrap();
+        assert_eq!(check_existing(&info, &storage), vec![true, false, false]);
+
+        storage.write_piece(1, b"efgh").unwrap();
+        storage.write_piece(2, b"ij").unwrap();
+        assert_eq!(check_existing(&info, &storage), vec![true, true, true]);
+    }
+
+    #[test]
+    fn gives_peer_slots_back_when_a_peer_task_panics() {
+        let dir = TempDir::new("slots");
+        let download = download(&dir);
+        download
+            .connected
+            .lock()
+            .unwrap()
+            .insert(String::from("peer"));
+        let slot = PeerSlot::take(&download, String::from("peer"));
+        assert_eq!(download.active_peers.load(Ordering::SeqCst), 1);
+
+        let mut runtime = Runtime::new().unwrap();
+        let task = runtime.spawn(async move {
+            let _slot = slot;
+            panic!("peer task panicked");
+        });
+        assert!(runtime.block_on(task).is_err());
+        assert_eq!(download.active_peers.load(Ordering::SeqCst), 0);
+        assert!(download.connected.lock().unwrap().is_empty());
+    }
+}
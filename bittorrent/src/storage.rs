@@ -0,0 +1,155 @@
+use crate::torrent::TorrentInfo;
+
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+struct StorageFile {
+    path: PathBuf,
+    // Offset of the first byte of this file within the torrent as a whole.
+    offset: u64,
+    length: u64,
+}
+
+/*
+ * Maps pieces onto the files of a torrent. Pieces are laid out across the
+ * concatenation of every file in order, so a single piece may span several
+ * files.
+ */
+pub struct Storage {
+    root: PathBuf,
+    files: Vec<StorageFile>,
+    piece_length: u64,
+}
+
+// Paths come from the torrent, which we do not trust. Refuse anything that
+// could escape the download directory.
+fn safe_component(component: &str) -> Result<&str, Error> {
+    let path = Path::new(component);
+    let mut components = path.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(component),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Torrent contains an unsafe path component `{}`.", component),
+        )),
+    }
+}
+
+impl Storage {
+    pub fn new(info: &TorrentInfo, download_dir: &Path) -> Result<Storage, Error> {
+        let root = download_dir.join(safe_component(&info.name)?);
+        let mut files = vec![];
+
+        match &info.files {
+            None => files.push(StorageFile {
+                path: root.clone(),
+                offset: 0,
+                length: info.total_length(),
+            }),
+            Some(torrent_files) => {
+                let mut offset = 0;
+                for file in torrent_files {
+                    if file.path.is_empty() {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "Torrent file has no path.",
+                        ));
+                    }
+                    let mut path = root.clone();
+                    for component in &file.path {
+                        path.push(safe_component(component)?);
+                    }
+                    files.push(StorageFile {
+                        path: path,
+                        offset: offset,
+                        length: file.length,
+                    });
+                    offset += file.length;
+                }
+            }
+        }
+
+        for file in &files {
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(&file.path)?;
+            if handle.metadata()?.len() != file.length {
+                handle.set_len(file.length)?;
+            }
+        }
+
+        return Ok(Storage {
+            root: root,
+            files: files,
+            piece_length: info.piece_length as u64,
+        });
+    }
+
+    pub fn root(&self) -> &Path {
+        return &self.root;
+    }
+
+    // Calls `f` with each file touched by the byte range and the offsets of
+    // the range within that file and within the buffer.
+    fn for_each_span<F>(&self, start: u64, length: u64, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(&StorageFile, u64, usize, usize) -> Result<(), Error>,
+    {
+        let end = start + length;
+        for file in &self.files {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+            let span_start = start.max(file.offset);
+            let span_end = end.min(file_end);
+            f(
+                file,
+                span_start - file.offset,
+                (span_start - start) as usize,
+                (span_end - start) as usize,
+            )?;
+        }
+        return Ok(());
+    }
+
+    pub fn write_piece(&self, index: u32, data: &[u8]) -> Result<(), Error> {
+        let start = index as u64 * self.piece_length;
+        return self.for_each_span(start, data.len() as u64, |file, file_offset, from, to| {
+            let mut handle = OpenOptions::new().write(true).open(&file.path)?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.write_all(&data[from..to])?;
+            return Ok(());
+        });
+    }
+
+    pub fn read_piece(&self, index: u32, length: u32) -> Result<Vec<u8>, Error> {
+        let start = index as u64 * self.piece_length;
+        let mut data = vec![0x0; length as usize];
+        self.for_each_span(start, length as u64, |file, file_offset, from, to| {
+            let mut handle = OpenOptions::new().read(true).open(&file.path)?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.read_exact(&mut data[from..to])?;
+            return Ok(());
+        })?;
+        return Ok(data);
+    }
+
+    pub fn remove_files(&self) -> Result<(), Error> {
+        if self.root.is_dir() {
+            return fs::remove_dir_all(&self.root);
+        }
+        for file in &self.files {
+            match fs::remove_file(&file.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        return Ok(());
+    }
+}
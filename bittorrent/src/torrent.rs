@@ -1,3 +1,4 @@
+use crate::bencode;
 use crate::client::ConnectablePeer;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
@@ -17,7 +18,23 @@ fn sha1_bytes_to_hex_string(bytes: &[u8; 20]) -> String {
     return s;
 }
 
-fn url_encode_bytes(bytes: &[u8]) -> String {
+pub fn sha1_bytes(bytes: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(bytes);
+    return hasher.finalize().to_vec()[..].try_into().unwrap();
+}
+
+pub fn hex_encode_bytes(bytes: &[u8]) -> String {
+    let mut s = String::new();
+
+    for byte in bytes {
+        write!(&mut s, "{:0>2x}", byte).unwrap();
+    }
+
+    return s;
+}
+
+pub fn url_encode_bytes(bytes: &[u8]) -> String {
     let mut s = String::new();
 
     for byte in bytes {
@@ -82,11 +99,60 @@ pub struct TorrentInfo {
      *
      * In the single file case, length maps to the length of the file in bytes.
      */
-    pub length: Option<u32>,
+    pub length: Option<u64>,
     pub files: Option<Vec<File>>,
 }
 
 impl TorrentInfo {
+    pub fn total_length(&self) -> u64 {
+        match (&self.length, &self.files) {
+            (Some(length), _) => *length,
+            (None, Some(files)) => files.iter().map(|file| file.length).sum(),
+            (None, None) => 0,
+        }
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        return (self.pieces.len() / 20) as u32;
+    }
+
+    // Every piece is `piece_length` long except possibly the last one.
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length as u64;
+        let remaining = self.total_length().saturating_sub(start);
+        return remaining.min(self.piece_length as u64) as u32;
+    }
+
+    // Whether there is exactly one piece hash for every piece of the files.
+    // Otherwise some data could never be verified, or some pieces never
+    // filled.
+    pub fn check_layout(&self) -> Result<(), String> {
+        if self.piece_length == 0 {
+            return Err(String::from("the piece length is 0"));
+        }
+        if self.pieces.len() != self.piece_count() as usize * 20 {
+            return Err(String::from("the piece hashes are not 20 bytes each"));
+        }
+        let piece_length = self.piece_length as u64;
+        let piece_count = self.piece_count() as u64;
+        let total_length = self.total_length();
+        if piece_count * piece_length < total_length
+            || (piece_count > 0 && (piece_count - 1) * piece_length >= total_length)
+        {
+            return Err(format!(
+                "{} pieces of {} bytes do not add up to {} bytes",
+                piece_count, piece_length, total_length
+            ));
+        }
+        return Ok(());
+    }
+
+    pub fn get_piece_sha1_bytes(&self, index: u32) -> [u8; 20] {
+        let start_index = 20 * index as usize;
+        let end_index = start_index + 20 as usize;
+        return self.pieces[start_index..end_index].try_into().unwrap();
+    }
+
     pub fn get_piece_sha1(&self, index: u32) -> String {
         let start_index = 20 * index as usize;
         let end_index = start_index + 20 as usize;
@@ -100,16 +166,50 @@ pub struct TorrentMetainfo {
     /*
      * The URL of the tracker.
      * */
+    #[serde(default)]
     pub announce: String,
+    /*
+     * Tiers of backup trackers, see https://www.bittorrent.org/beps/bep_0012.html.
+     */
+    #[serde(rename = "announce-list")]
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: TorrentInfo,
 }
 
 impl TorrentMetainfo {
+    /*
+     * Parses a .torrent file and hashes its info dictionary exactly as it
+     * appears in the file. Hashing a re-encoded `TorrentInfo` would silently
+     * drop any keys we do not model, such as `private`, and produce the
+     * wrong info hash.
+     */
+    pub fn from_bytes(bytes: &[u8]) -> Result<(TorrentMetainfo, [u8; 20]), serde_bencode::Error> {
+        let metainfo: TorrentMetainfo = serde_bencode::from_bytes(bytes)?;
+        let info_hash = match bencode::dict_value(bytes, b"info") {
+            Some(raw_info) => sha1_bytes(raw_info),
+            None => metainfo.gen_info_hash_bytes(),
+        };
+        return Ok((metainfo, info_hash));
+    }
+
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers = vec![];
+        if !self.announce.is_empty() {
+            trackers.push(self.announce.clone());
+        }
+        for tier in self.announce_list.iter().flatten() {
+            for tracker in tier {
+                if !trackers.contains(tracker) {
+                    trackers.push(tracker.clone());
+                }
+            }
+        }
+        return trackers;
+    }
+
     pub fn gen_info_hash_bytes(&self) -> [u8; 20] {
         let encoded_info = serde_bencode::to_bytes(&self.info).unwrap();
-        let mut hasher = Sha1::new();
-        hasher.update(&encoded_info);
-        return hasher.finalize().to_vec()[..].try_into().unwrap();
+        return sha1_bytes(&encoded_info);
     }
 
     pub fn gen_info_hash(&self) -> String {
@@ -179,7 +279,7 @@ pub struct TorrentPeer {
     pub port: u16,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CompactTorrentPeer {
     /*
      * 32-bit IPv4 address are 4 chunks of 1 byte each.
@@ -222,6 +322,7 @@ pub struct TrackerGetResponse {
     /*
      * A human readable string containing a failure reason.
      */
+    #[serde(rename = "failure reason")]
     pub failure_reason: Option<String>,
     /*
      * The number of seconds to wait before the next peer request.
@@ -229,6 +330,12 @@ pub struct TrackerGetResponse {
      * or they need more peers.
      */
     pub interval: Option<u32>,
+    /*
+     * The number of seconds downloaders must wait between any two peer
+     * requests, including nonscheduled ones.
+     */
+    #[serde(rename = "min interval")]
+    pub min_interval: Option<u32>,
     /*
      * A list of peers for a torrent.
      */
@@ -0,0 +1,205 @@
+use crate::torrent::{url_encode_bytes, CompactTorrentPeer, TrackerGetResponse};
+
+use hyper::Client;
+use std::convert::TryInto;
+use std::error::Error;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+type TrackerResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+// Used when a tracker does not tell us how long to wait between announces.
+const DEFAULT_INTERVAL: u32 = 30 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    None,
+    Started,
+    Stopped,
+    Completed,
+}
+
+#[derive(Debug)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+}
+
+#[derive(Debug)]
+pub struct AnnounceResponse {
+    // Seconds to wait before announcing again.
+    pub interval: u32,
+    // Seconds to wait before announcing again even when out of peers, if
+    // the tracker says.
+    pub min_interval: Option<u32>,
+    pub peers: Vec<CompactTorrentPeer>,
+}
+
+fn http_event_param(event: AnnounceEvent) -> &'static str {
+    match event {
+        AnnounceEvent::None => "",
+        AnnounceEvent::Started => "&event=started",
+        AnnounceEvent::Stopped => "&event=stopped",
+        AnnounceEvent::Completed => "&event=completed",
+    }
+}
+
+async fn announce_http(
+    tracker: &str,
+    request: &AnnounceRequest,
+) -> TrackerResult<AnnounceResponse> {
+    let separator = if tracker.contains('?') { '&' } else { '?' };
+    let uri = format!(
+        "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1{}",
+        tracker,
+        separator,
+        url_encode_bytes(&request.info_hash),
+        url_encode_bytes(&request.peer_id),
+        request.port,
+        request.uploaded,
+        request.downloaded,
+        request.left,
+        http_event_param(request.event)
+    );
+    debug!("Announcing to {}", uri);
+
+    let client = Client::new();
+    let response = client.get(uri.parse()?).await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    let tracker_response: TrackerGetResponse = serde_bencode::from_bytes(&body)?;
+
+    if let Some(reason) = tracker_response.failure_reason {
+        return Err(format!("Tracker {} refused announce: {}", tracker, reason).into());
+    }
+
+    return Ok(AnnounceResponse {
+        interval: tracker_response.interval.unwrap_or(DEFAULT_INTERVAL),
+        min_interval: tracker_response.min_interval,
+        peers: tracker_response.get_peers().unwrap_or_default(),
+    });
+}
+
+fn transaction_id() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos();
+    return nanos ^ std::process::id();
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    return u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+}
+
+async fn udp_round_trip(
+    socket: &mut UdpSocket,
+    address: &str,
+    packet: &[u8],
+    buffer: &mut [u8],
+) -> TrackerResult<usize> {
+    socket.send_to(packet, address).await?;
+    let (length, _) = timeout(TRACKER_TIMEOUT, socket.recv_from(buffer)).await??;
+    return Ok(length);
+}
+
+/*
+ * UDP tracker protocol, see https://www.bittorrent.org/beps/bep_0015.html.
+ * Every announce is preceded by a connect request which hands us a
+ * connection ID to prove that we are not spoofing our address.
+ */
+async fn announce_udp(tracker: &str, request: &AnnounceRequest) -> TrackerResult<AnnounceResponse> {
+    const PROTOCOL_ID: u64 = 0x41727101980;
+    const ACTION_CONNECT: u32 = 0;
+    const ACTION_ANNOUNCE: u32 = 1;
+    const ACTION_ERROR: u32 = 3;
+
+    let address = tracker
+        .trim_start_matches("udp://")
+        .split('/')
+        .next()
+        .unwrap_or("")
+        .to_string();
+    let mut socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let mut buffer = vec![0x0; 65536];
+
+    let connect_transaction = transaction_id();
+    let mut connect_packet = vec![];
+    connect_packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    connect_packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    connect_packet.extend_from_slice(&connect_transaction.to_be_bytes());
+
+    let length = udp_round_trip(&mut socket, &address, &connect_packet, &mut buffer).await?;
+    if length < 16
+        || read_u32(&buffer, 0) != ACTION_CONNECT
+        || read_u32(&buffer, 4) != connect_transaction
+    {
+        return Err(format!("Tracker {} sent a malformed connect response.", tracker).into());
+    }
+    let connection_id = &buffer[8..16].to_vec();
+
+    let event: u32 = match request.event {
+        AnnounceEvent::None => 0,
+        AnnounceEvent::Completed => 1,
+        AnnounceEvent::Started => 2,
+        AnnounceEvent::Stopped => 3,
+    };
+    let announce_transaction = transaction_id().wrapping_add(1);
+    let mut announce_packet = vec![];
+    announce_packet.extend_from_slice(connection_id);
+    announce_packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    announce_packet.extend_from_slice(&announce_transaction.to_be_bytes());
+    announce_packet.extend_from_slice(&request.info_hash);
+    announce_packet.extend_from_slice(&request.peer_id);
+    announce_packet.extend_from_slice(&request.downloaded.to_be_bytes());
+    announce_packet.extend_from_slice(&request.left.to_be_bytes());
+    announce_packet.extend_from_slice(&request.uploaded.to_be_bytes());
+    announce_packet.extend_from_slice(&event.to_be_bytes());
+    // Let the tracker use the address the packet came from.
+    announce_packet.extend_from_slice(&0u32.to_be_bytes());
+    announce_packet.extend_from_slice(&announce_transaction.to_be_bytes());
+    // Ask for as many peers as the tracker is willing to give us.
+    announce_packet.extend_from_slice(&(-1i32).to_be_bytes());
+    announce_packet.extend_from_slice(&request.port.to_be_bytes());
+
+    let length = udp_round_trip(&mut socket, &address, &announce_packet, &mut buffer).await?;
+    if length >= 8 && read_u32(&buffer, 0) == ACTION_ERROR {
+        let reason = String::from_utf8_lossy(&buffer[8..length]);
+        return Err(format!("Tracker {} refused announce: {}", tracker, reason).into());
+    }
+    if length < 20
+        || read_u32(&buffer, 0) != ACTION_ANNOUNCE
+        || read_u32(&buffer, 4) != announce_transaction
+    {
+        return Err(format!("Tracker {} sent a malformed announce response.", tracker).into());
+    }
+
+    let mut peers = vec![];
+    for chunk in buffer[20..length].chunks_exact(6) {
+        peers.push(CompactTorrentPeer {
+            ip: [chunk[0], chunk[1], chunk[2], chunk[3]],
+            port: u16::from_be_bytes([chunk[4], chunk[5]]),
+        });
+    }
+
+    return Ok(AnnounceResponse {
+        interval: read_u32(&buffer, 8),
+        min_interval: None,
+        peers: peers,
+    });
+}
+
+pub async fn announce(tracker: &str, request: &AnnounceRequest) -> TrackerResult<AnnounceResponse> {
+    if tracker.starts_with("udp://") {
+        return announce_udp(tracker, request).await;
+    } else if tracker.starts_with("http://") {
+        return timeout(TRACKER_TIMEOUT, announce_http(tracker, request)).await?;
+    }
+    return Err(format!("Unsupported tracker protocol: {}", tracker).into());
+}
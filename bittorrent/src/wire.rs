@@ -0,0 +1,178 @@
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+/*
+ * Messages exchanged after the handshake, see
+ * https://www.bittorrent.org/beps/bep_0003.html#peer-messages. Every message
+ * is a 4 byte big endian length prefix followed by a 1 byte message ID and
+ * its payload. A length of zero is a keep-alive.
+ */
+#[derive(Debug)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    Unknown(u8),
+}
+
+// Peers asking for more than this in one message are misbehaving. The
+// largest legitimate message is a piece block of 16 KiB plus its header, or a
+// bitfield for a torrent with a very large number of pieces.
+const MAX_MESSAGE_LENGTH: usize = 4 * 1024 * 1024;
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    match bytes.get(offset..offset + 4) {
+        Some(slice) => Ok(u32::from_be_bytes(slice.try_into().unwrap())),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Truncated peer message.",
+        )),
+    }
+}
+
+pub async fn read_message(conn: &mut TcpStream) -> Result<PeerMessage, Error> {
+    let mut length_bytes = [0x0; 4];
+    conn.read_exact(&mut length_bytes).await?;
+    let length = u32::from_be_bytes(length_bytes) as usize;
+
+    if length == 0 {
+        return Ok(PeerMessage::KeepAlive);
+    }
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Peer sent an oversized message of {} bytes.", length),
+        ));
+    }
+
+    let mut body = vec![0x0; length];
+    conn.read_exact(&mut body).await?;
+    let payload = &body[1..];
+
+    let message = match body[0] {
+        0 => PeerMessage::Choke,
+        1 => PeerMessage::Unchoke,
+        2 => PeerMessage::Interested,
+        3 => PeerMessage::NotInterested,
+        4 => PeerMessage::Have(read_u32(payload, 0)?),
+        5 => PeerMessage::Bitfield(payload.to_vec()),
+        6 => PeerMessage::Request {
+            index: read_u32(payload, 0)?,
+            begin: read_u32(payload, 4)?,
+            length: read_u32(payload, 8)?,
+        },
+        7 => PeerMessage::Piece {
+            index: read_u32(payload, 0)?,
+            begin: read_u32(payload, 4)?,
+            block: payload[8.min(payload.len())..].to_vec(),
+        },
+        8 => PeerMessage::Cancel {
+            index: read_u32(payload, 0)?,
+            begin: read_u32(payload, 4)?,
+            length: read_u32(payload, 8)?,
+        },
+        20 => match payload.first() {
+            Some(id) => PeerMessage::Extended {
+                id: *id,
+                payload: payload[1..].to_vec(),
+            },
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Empty extended message.",
+                ))
+            }
+        },
+        id => PeerMessage::Unknown(id),
+    };
+    return Ok(message);
+}
+
+fn frame(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(5 + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+    bytes.push(id);
+    bytes.extend_from_slice(payload);
+    return bytes;
+}
+
+fn serialize_message(message: &PeerMessage) -> Vec<u8> {
+    match message {
+        PeerMessage::KeepAlive => vec![0x0; 4],
+        PeerMessage::Choke => frame(0, &[]),
+        PeerMessage::Unchoke => frame(1, &[]),
+        PeerMessage::Interested => frame(2, &[]),
+        PeerMessage::NotInterested => frame(3, &[]),
+        PeerMessage::Have(index) => frame(4, &index.to_be_bytes()),
+        PeerMessage::Bitfield(bitfield) => frame(5, bitfield),
+        PeerMessage::Request {
+            index,
+            begin,
+            length,
+        } => {
+            let mut payload = vec![];
+            payload.extend_from_slice(&index.to_be_bytes());
+            payload.extend_from_slice(&begin.to_be_bytes());
+            payload.extend_from_slice(&length.to_be_bytes());
+            frame(6, &payload)
+        }
+        PeerMessage::Piece {
+            index,
+            begin,
+            block,
+        } => {
+            let mut payload = vec![];
+            payload.extend_from_slice(&index.to_be_bytes());
+            payload.extend_from_slice(&begin.to_be_bytes());
+            payload.extend_from_slice(block);
+            frame(7, &payload)
+        }
+        PeerMessage::Cancel {
+            index,
+            begin,
+            length,
+        } => {
+            let mut payload = vec![];
+            payload.extend_from_slice(&index.to_be_bytes());
+            payload.extend_from_slice(&begin.to_be_bytes());
+            payload.extend_from_slice(&length.to_be_bytes());
+            frame(8, &payload)
+        }
+        PeerMessage::Extended { id, payload } => {
+            let mut extended_payload = vec![*id];
+            extended_payload.extend_from_slice(payload);
+            frame(20, &extended_payload)
+        }
+        PeerMessage::Unknown(id) => frame(*id, &[]),
+    }
+}
+
+pub async fn write_message(conn: &mut TcpStream, message: &PeerMessage) -> Result<(), Error> {
+    conn.write_all(&serialize_message(message)).await?;
+    return Ok(());
+}
@@ -0,0 +1,78 @@
+use crate::daemon::format_duration;
+use crate::watchlist;
+use common::{
+    lock::{self, DaemonLock, LockError},
+    paths,
+    store::RocksStore,
+};
+use std::{fs, path::PathBuf, process};
+
+// The daemon does not share the working directory of the CLI.
+fn absolute(dir: &str) -> PathBuf {
+    let canonical = fs::create_dir_all(dir).and_then(|_| fs::canonicalize(dir));
+    match canonical {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Failed to use `{}` for backups.\nError: {}", dir, e);
+            process::exit(1);
+        }
+    }
+}
+
+pub fn backup(dir: &str, keep: Option<usize>) {
+    let dir = absolute(dir);
+    let backup = watchlist::backup(&dir, keep);
+    println!(
+        "Backed up the watch list to `{}` as backup {} ({} bytes).",
+        dir.display(),
+        backup.id,
+        backup.size_bytes
+    );
+}
+
+pub fn restore(dir: &str) {
+    // Held until the restore is done, so that no daemon starts with the
+    // database half replaced.
+    let config_path = paths::get().config_file.display().to_string();
+    let _lock = match DaemonLock::acquire(&config_path) {
+        Ok((lock, _)) => lock,
+        Err(LockError::AlreadyHeld(info)) => {
+            let pid = info.map_or(String::from("unknown"), |info| info.pid.to_string());
+            eprintln!(
+                "The animated daemon (PID {}) is running. Stop it with `animated kill` \
+                before restoring.",
+                pid
+            );
+            process::exit(1);
+        }
+        Err(LockError::Io(e)) => {
+            eprintln!(
+                "An error occurred while taking the daemon lock.\nError: {}",
+                e
+            );
+            process::exit(1);
+        }
+    };
+
+    let database = paths::get().rocksdb;
+    match RocksStore::restore(&PathBuf::from(dir), &database) {
+        Ok((backup, previous)) => {
+            println!(
+                "Restored backup {} taken {} ago to `{}`.",
+                backup.id,
+                format_duration(lock::now().saturating_sub(backup.created_at)),
+                database.display()
+            );
+            if previous.exists() {
+                println!(
+                    "The database it replaced was moved to `{}`.",
+                    previous.display()
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to restore from `{}`. {}.", dir, e);
+            process::exit(1);
+        }
+    }
+}
@@ -1,37 +1,49 @@
+mod backup;
+mod configure;
+mod daemon;
+mod diff;
+mod interchange;
+mod metadata;
+mod watchlist;
+
 extern crate bincode;
 extern crate common;
 #[macro_use]
 extern crate prettytable;
 
-use clap::{load_yaml, App};
+use clap::{load_yaml, App, ArgMatches};
 use prettytable::Table;
 use std::{
     env, fs,
-    io::{Error, ErrorKind},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
     process,
     str::FromStr,
+    time::Duration,
 };
 
 use common::{
-    config,
-    constants::CONFIG_PATH,
-    rocksdb,
-    structs::{default_download_path, Anime, Config, Quality},
+    config::{self, ConfigError, MigrationOutcome},
+    config_format::Format,
+    constants::CONFIG_VERSION,
+    interchange::{ExportFormat, MergeStrategy},
+    ipc,
+    paths::{self, PathOverrides},
+    store::WatchStore,
+    structs::{Anime, Config, Quality, QualityProfile},
 };
 
 fn create_default() {
-    let default_config = Config {
-        download_path: default_download_path(),
-    };
-    let serialized = serde_json::to_string(&default_config).unwrap();
-    let write_result = config::write(&serialized);
+    let write_result = config::write(&config::new_user_config());
 
     match write_result {
-        Err(e) => match (*e).kind() {
+        Err(e) => match e.kind() {
             ErrorKind::PermissionDenied => {
                 eprintln!(
                     "Failed to generate default animated config due to insufficient permissions. \
-                    Make sure that you have write permission to `/etc` then try again."
+                    Make sure that you have write permission to `{}` then try again, or pick \
+                    another location with `--config` or `ANIMATED_CONFIG`.",
+                    paths::get().config_file.display()
                 );
                 process::exit(1);
             }
@@ -48,35 +60,129 @@ fn create_default() {
     }
 }
 
+// `$EDITOR` and `$VISUAL` may carry arguments, e.g. `code --wait`.
+fn editor_candidates() -> Vec<String> {
+    let mut candidates: Vec<String> = ["EDITOR", "VISUAL"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .filter(|editor| !editor.trim().is_empty())
+        .collect();
+    candidates.push(String::from("vi"));
+    candidates.push(String::from("nano"));
+    return candidates;
+}
+
+fn run_editor(file_path: &Path) {
+    for candidate in editor_candidates() {
+        let mut words = candidate.split_whitespace();
+        let program = words.next().unwrap();
+        let status = process::Command::new(program)
+            .args(words)
+            .arg(file_path)
+            .status();
+
+        match status {
+            Ok(status) if status.success() => return,
+            Ok(status) => {
+                eprintln!(
+                    "`{}` exited with {}, so the config was not changed. Your edits are in `{}`.",
+                    candidate,
+                    status,
+                    file_path.display()
+                );
+                process::exit(1);
+            }
+            // Try the next editor if this one is not installed.
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => {
+                eprintln!(
+                    "Failed to run `{}` to edit animated config.\nError: {}",
+                    candidate, e
+                );
+                process::exit(1);
+            }
+        }
+    }
+
+    eprintln!(
+        "Could not find an editor to open `{}` with. Set $EDITOR or $VISUAL, or install vi or nano.",
+        file_path.display()
+    );
+    process::exit(1);
+}
+
+fn ask_to_edit_again() -> bool {
+    eprint!("Edit again? [Y/n] ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        // Nobody is there to answer, e.g. when stdin is not a terminal.
+        Ok(0) | Err(_) => false,
+        Ok(_) => !answer.trim().eq_ignore_ascii_case("n"),
+    }
+}
+
 fn open_config_editor() {
-    let editor = env::var("EDITOR").unwrap();
+    let config_file = paths::get().config_file;
+    // Keep the extension so that editors highlight the right format.
     let mut temp_file_path = env::temp_dir();
-    temp_file_path.push("animated.config.tmp");
+    temp_file_path.push(format!(
+        "animated.config.tmp.{}",
+        Format::of(&config_file).extension()
+    ));
 
-    fs::copy(CONFIG_PATH, &temp_file_path)
+    let original_config =
+        fs::read_to_string(&config_file).expect("Failed to read animated config for editing.");
+    fs::write(&temp_file_path, &original_config)
         .expect("Failed to write temporary animated config for editing.");
 
-    process::Command::new(editor)
-        .arg(&temp_file_path)
-        .status()
-        .expect("Failed to open $EDITOR for editing animated config.");
+    // Keep the config we already have rather than replacing it with one that
+    // the daemon would refuse to load.
+    let edited_config = loop {
+        run_editor(&temp_file_path);
+        let edited_config = fs::read_to_string(&temp_file_path)
+            .expect("Failed to read newly edited animated config. Did something happen to it?");
+
+        match config::check(&edited_config, &temp_file_path) {
+            Ok(_) => break edited_config,
+            Err(e) => {
+                eprintln!("The edited config is invalid.\n{}", e);
+                if !ask_to_edit_again() {
+                    eprintln!(
+                        "The config was not saved. Your edits are still in `{}`.",
+                        temp_file_path.display()
+                    );
+                    process::exit(1);
+                }
+            }
+        }
+    };
 
-    let edited_config = fs::read_to_string(&temp_file_path)
-        .expect("Failed to read newly edited animated config. Did something happen to it?");
+    let config_name = config_file.display().to_string();
+    let changes = match diff::render(&original_config, &edited_config, &config_name, &config_name) {
+        Some(changes) => changes,
+        None => {
+            println!("No changes were made.");
+            return;
+        }
+    };
     let write_result = config::write(&edited_config);
 
     match write_result {
-        Err(e) => match (*e).kind() {
+        Err(e) => match e.kind() {
             ErrorKind::PermissionDenied => {
                 eprintln!(
                     "Failed to finalize newly edited animated config due to insufficient permissions. \
-                    Make sure that you have write permission to `/etc` then try again."
+                    Make sure that you have write permission to `{}` then try again. Your edits \
+                    are in `{}`.",
+                    config_name,
+                    temp_file_path.display()
                 );
                 process::exit(1);
             }
             _ => {
                 eprintln!(
-                    "An error occurred while generating default animated config. \
+                    "An error occurred while saving the edited animated config. \
                         \nError: {}",
                     e.to_string()
                 );
@@ -84,28 +190,226 @@ fn open_config_editor() {
             }
         },
         Ok(_) => {
-            println!("{}", format!("Config was set to: {}", edited_config).trim());
+            let _ = fs::remove_file(&temp_file_path);
+            print!("{}", changes);
         }
     }
 }
 
-pub fn edit(config_result: &Result<Config, Box<Error>>) {
-    match config_result {
-        Err(e) => match (*e).kind() {
-            ErrorKind::NotFound => create_default(),
+// Anything but a missing config can be fixed in the editor.
+pub fn edit() {
+    if !paths::get().config_file.exists() {
+        create_default();
+    }
+
+    open_config_editor();
+}
+
+fn migrate_watch_ids() {
+    let report = match watchlist::local_store().migrate_watch_ids() {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Failed to migrate watch IDs.\nError: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if !report.rekeyed.is_empty() {
+        println!(
+            "Migrated {} watched anime to stable watch IDs.",
+            report.rekeyed.len()
+        );
+        for (old_id, new_id) in report.rekeyed {
+            println!("  {} -> {}", old_id, new_id);
+        }
+    }
+
+    for (old_id, collision) in report.collisions {
+        eprintln!(
+            "Could not migrate watch ID {}: {}. It was left under its old ID until one of \
+            them is unwatched with `animated unwatch <watch ID>`.",
+            old_id, collision
+        );
+    }
+}
+
+fn gc(dry_run: bool) {
+    let report = watchlist::collect_garbage(dry_run);
+    if report.is_empty() {
+        println!("Nothing to collect.");
+        return;
+    }
+
+    let (purged, removed) = if dry_run {
+        ("Would purge", "Would remove")
+    } else {
+        ("Purged", "Removed")
+    };
+    for (watch_id, anime) in report.purged.iter() {
+        println!("{} {} / Watch ID: {}", purged, anime, watch_id);
+    }
+    println!(
+        "{} {} episode(s) and {} event(s) from the history.",
+        removed, report.purged_episodes, report.dropped_events
+    );
+}
+
+fn modify(watch_id: &str, modify_matches: &ArgMatches) {
+    let mut anime = match watchlist::get(watch_id) {
+        Some(anime) if !anime.tombstone => anime,
+        _ => {
+            eprintln!("No anime is being watched with watch ID {}.", watch_id);
+            process::exit(1);
+        }
+    };
+
+    if let Some(name) = modify_matches.value_of("name") {
+        anime.title = String::from(name);
+    }
+    if let Some(values) = modify_matches.values_of("quality") {
+        let mut qualities = parse_qualities(values);
+        anime.quality = qualities.remove(0);
+        anime.profile.fallbacks = qualities;
+    }
+    if let Some(minimum) = modify_matches.value_of("min_quality") {
+        anime.profile.minimum = Some(Quality::from_str(minimum).unwrap());
+    }
+    if modify_matches.is_present("no_min_quality") {
+        anime.profile.minimum = None;
+    }
+    if let Some(cutoff) = modify_matches.value_of("upgrade_until") {
+        anime.profile.upgrade_until = Some(Quality::from_str(cutoff).unwrap());
+    }
+    if modify_matches.is_present("no_upgrades") {
+        anime.profile.upgrade_until = None;
+    }
+    if let Some(subgroups) = modify_matches.values_of("subgroup") {
+        let mut subgroups: Vec<String> = subgroups.map(String::from).collect();
+        anime.subgroup = subgroups.remove(0);
+        anime.fallback_subgroups = subgroups;
+    }
+    if let Some(delay) = modify_matches.value_of("delay") {
+        anime.delay_hours = parse_delay(delay);
+    }
+    if let Some(episode) = modify_matches.value_of("episode") {
+        anime.last_seen_episode = match episode.parse::<i32>() {
+            Ok(episode) if episode >= -1 => episode,
             _ => {
-                eprintln!(
-                    "An error occurred while reading animated config. \
-                        \nError: {}",
-                    e.to_string()
-                );
+                eprintln!("`--episode` must be a whole number of at least -1.");
                 process::exit(1);
             }
-        },
-        Ok(_) => (),
+        };
+    }
+    for alias in modify_matches.values_of("alias").into_iter().flatten() {
+        if !anime.aliases.iter().any(|existing| existing == alias) {
+            anime.aliases.push(String::from(alias));
+        }
+    }
+    for alias in modify_matches
+        .values_of("remove_alias")
+        .into_iter()
+        .flatten()
+    {
+        anime.aliases.retain(|existing| existing != alias);
+    }
+    if let Some(source) = modify_matches.value_of("link") {
+        metadata::link(&mut anime, source);
+    }
+    if modify_matches.is_present("unlink") {
+        anime.metadata = None;
+    }
+    if modify_matches.is_present("pause") {
+        anime.paused = true;
+    }
+    if modify_matches.is_present("resume") {
+        anime.paused = false;
     }
 
-    open_config_editor();
+    check_preferences(&anime);
+    watchlist::modify(watch_id, &anime);
+    println!("Modified {} / Watch ID: {}", anime, watch_id);
+    metadata::print_link(&anime);
+}
+
+// We can directly unwrap these because clap automatically performs allowed
+// value validation for us, and requires at least one.
+fn parse_qualities<'a, I: Iterator<Item = &'a str>>(values: I) -> Vec<Quality> {
+    return values
+        .map(|quality| Quality::from_str(quality).unwrap())
+        .collect();
+}
+
+fn parse_delay(delay: &str) -> u64 {
+    match delay.parse::<u64>() {
+        Ok(hours) => hours,
+        Err(_) => {
+            eprintln!("`--delay` must be a whole number of hours.");
+            process::exit(1);
+        }
+    }
+}
+
+fn check_preferences(anime: &Anime) {
+    if let Some(problem) = anime.preference_problem() {
+        eprintln!("The preferences of {} do not work: {}.", anime, problem);
+        process::exit(1);
+    }
+}
+
+// Like `config::read`, but tells the user about migrations and unknown keys
+// directly since the CLI has no logger.
+fn read_config() -> Result<Config, ConfigError> {
+    let loaded = config::load()?;
+    let path = paths::get().config_file;
+
+    match &loaded.migration {
+        Some(MigrationOutcome::Rewritten { from, backup }) => eprintln!(
+            "Migrated animated config `{}` from version {} to {}. The original was saved to `{}`.",
+            path.display(),
+            from,
+            CONFIG_VERSION,
+            backup.display()
+        ),
+        Some(MigrationOutcome::NotWritten { from, reason }) => eprintln!(
+            "Warning: animated config `{}` is at version {} and could not be migrated to {}. \
+            Run `animated config migrate` with write permission to it.\nError: {}",
+            path.display(),
+            from,
+            CONFIG_VERSION,
+            reason
+        ),
+        None => (),
+    }
+    for (key, layer) in loaded.unknown_keys.iter() {
+        eprintln!("Warning: ignoring unknown key `{}` in {}.", key, layer);
+    }
+
+    return loaded.into_valid();
+}
+
+// `--config` and `--data-dir` may be given before or after the subcommand.
+fn global_value(matches: &ArgMatches, name: &str) -> Option<PathBuf> {
+    let subcommand_matches = matches.subcommand().1;
+    return matches
+        .value_of(name)
+        .or_else(|| subcommand_matches.and_then(|sub| sub.value_of(name)))
+        .map(PathBuf::from);
+}
+
+fn global_values(matches: &ArgMatches, name: &str) -> Vec<String> {
+    let mut values: Vec<String> = matches
+        .values_of(name)
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default();
+    if let Some(subcommand_matches) = matches.subcommand().1 {
+        // Global arguments given before the subcommand show up in both.
+        for value in subcommand_matches.values_of(name).into_iter().flatten() {
+            if !values.iter().any(|known| known == value) {
+                values.push(String::from(value));
+            }
+        }
+    }
+    return values;
 }
 
 fn main() {
@@ -113,51 +417,230 @@ fn main() {
     let app = App::from(yaml);
     let matches = app.get_matches();
 
-    let config_result = config::read();
+    paths::init(&PathOverrides {
+        config: global_value(&matches, "config"),
+        data_dir: global_value(&matches, "data_dir"),
+    });
+    config::set_overrides(global_values(&matches, "set"));
+
+    // Migrating has to see the file before `read_config` upgrades it.
+    if let ("config", Some(config_matches)) = matches.subcommand() {
+        if let ("migrate", Some(migrate_matches)) = config_matches.subcommand() {
+            configure::migrate(migrate_matches.is_present("dry_run"));
+            return;
+        }
+    }
+
+    let config_result = read_config();
 
     if matches.is_present("edit") {
-        edit(&config_result);
+        edit();
         return;
     }
 
+    // Managing the daemon does not need a valid config.
+    match matches.subcommand() {
+        ("status", Some(_status_matches)) => {
+            daemon::status();
+            return;
+        }
+        ("kill", Some(kill_matches)) => {
+            let timeout = match kill_matches.value_of("timeout").unwrap().parse::<u64>() {
+                Ok(timeout) => timeout,
+                Err(_) => {
+                    eprintln!("`--timeout` must be a whole number of seconds.");
+                    process::exit(1);
+                }
+            };
+            daemon::kill(Duration::from_secs(timeout));
+            return;
+        }
+        _ => (),
+    }
+
+    // The CLI only needs the config to be readable. Anything that would stop
+    // the daemon from using it is worth pointing out, but not fatal here.
+    let config_result = match config_result {
+        Err(ConfigError::Invalid { .. }) => {
+            eprintln!("Warning: {}", config_result.unwrap_err());
+            Ok(())
+        }
+        config_result => config_result.map(|_| ()),
+    };
+
     match config_result {
         Ok(_) => {
+            // A running daemon migrates the database itself when it starts.
+            let uses_database = match matches.subcommand_name() {
+                Some("config") | None => false,
+                Some(_) => true,
+            };
+            if uses_database && !ipc::is_daemon_listening() {
+                migrate_watch_ids();
+            }
+
             match matches.subcommand() {
                 ("watch", Some(watch_matches)) => {
                     let name = watch_matches
                         .value_of("name")
                         .expect("Expected `--name` to have been specified.");
-                    // We can directly unwrap this because clap automatically
-                    // performs allowed value validation for us.
-                    let quality = Quality::from_str(
+                    let mut qualities = parse_qualities(
                         watch_matches
-                            .value_of("quality")
+                            .values_of("quality")
                             .expect("Expected `--quality` to have been specified."),
-                    )
-                    .unwrap();
-                    let subgroup = watch_matches
-                        .value_of("subgroup")
-                        .expect("Expected `--subgroup` to have been specified.");
+                    );
+                    let quality = qualities.remove(0);
+                    let mut subgroups: Vec<String> = watch_matches
+                        .values_of("subgroup")
+                        .expect("Expected `--subgroup` to have been specified.")
+                        .map(String::from)
+                        .collect();
+                    let subgroup = subgroups.remove(0);
 
-                    let anime = Anime {
+                    let mut anime = Anime {
                         title: String::from(name),
                         quality: quality,
-                        subgroup: String::from(subgroup),
+                        subgroup: subgroup,
                         last_seen_episode: -1,
                         tombstone: false,
+                        paused: false,
+                        tombstoned_at: None,
+                        aliases: watch_matches
+                            .values_of("alias")
+                            .into_iter()
+                            .flatten()
+                            .map(String::from)
+                            .collect(),
+                        matched_alias: None,
+                        metadata: None,
+                        profile: QualityProfile {
+                            fallbacks: qualities,
+                            minimum: watch_matches
+                                .value_of("min_quality")
+                                .map(|minimum| Quality::from_str(minimum).unwrap()),
+                            upgrade_until: watch_matches
+                                .value_of("upgrade_until")
+                                .map(|cutoff| Quality::from_str(cutoff).unwrap()),
+                        },
+                        fallback_subgroups: subgroups,
+                        delay_hours: watch_matches.value_of("delay").map_or(0, parse_delay),
                     };
-                    let watch_id = rocksdb::upsert_anime(&anime);
+                    check_preferences(&anime);
+                    if !watch_matches.is_present("no_link") {
+                        metadata::link_new(&mut anime, watch_matches.value_of("link"));
+                    }
+                    let watch_id = watchlist::watch(&anime);
                     println!("Now watching {} / Watch ID: {}", anime, watch_id);
+                    metadata::print_link(&anime);
+                }
+                ("modify", Some(modify_matches)) => {
+                    let watch_id = modify_matches
+                        .value_of("watch_id")
+                        .expect("Expected `watch_id` to have been specified.");
+                    modify(watch_id, modify_matches);
+                }
+                ("unwatch", Some(unwatch_matches)) => {
+                    let watch_id = unwatch_matches
+                        .value_of("watch_id")
+                        .expect("Expected `watch_id` to have been specified.");
+                    watchlist::unwatch(watch_id);
+                    println!("No longer watching watch ID {}.", watch_id);
+                }
+                ("gc", Some(gc_matches)) => gc(gc_matches.is_present("dry_run")),
+                ("backup", Some(backup_matches)) => {
+                    let keep = match backup_matches.value_of("keep").map(str::parse::<usize>) {
+                        None => None,
+                        Some(Ok(keep)) if keep > 0 => Some(keep),
+                        Some(_) => {
+                            eprintln!("`--keep` must be a whole number of at least 1.");
+                            process::exit(1);
+                        }
+                    };
+                    backup::backup(backup_matches.value_of("dir").unwrap(), keep);
+                }
+                ("restore", Some(restore_matches)) => {
+                    backup::restore(restore_matches.value_of("dir").unwrap())
                 }
-                ("unwatch", Some(_unwatch_matches)) => {}
+                // clap only lets through the formats and strategies listed
+                // in cli.yaml.
+                ("export", Some(export_matches)) => interchange::export(
+                    export_matches.value_of("output"),
+                    export_matches
+                        .value_of("format")
+                        .map(|format| ExportFormat::from_str(format).unwrap()),
+                ),
+                ("import", Some(import_matches)) => interchange::import(
+                    import_matches.value_of("input").unwrap(),
+                    import_matches.value_of("format"),
+                    MergeStrategy::from_str(import_matches.value_of("strategy").unwrap()).unwrap(),
+                    import_matches.is_present("dry_run"),
+                ),
+                ("config", Some(config_matches)) => match config_matches.subcommand() {
+                    ("get", Some(get_matches)) => {
+                        configure::get(get_matches.value_of("key").unwrap())
+                    }
+                    ("set", Some(set_matches)) => configure::set(
+                        set_matches.value_of("key").unwrap(),
+                        set_matches.value_of("value").unwrap(),
+                    ),
+                    ("unset", Some(unset_matches)) => {
+                        configure::unset(unset_matches.value_of("key").unwrap())
+                    }
+                    ("show", Some(show_matches)) => {
+                        configure::show(show_matches.is_present("json"))
+                    }
+                    ("explain", Some(explain_matches)) => {
+                        configure::explain(explain_matches.value_of("key"))
+                    }
+                    ("convert", Some(convert_matches)) => configure::convert(
+                        // clap only lets through the formats listed in cli.yaml.
+                        Format::from_str(convert_matches.value_of("to").unwrap()).unwrap(),
+                    ),
+                    _ => unreachable!(),
+                },
                 ("list", Some(_list_matches)) => {
-                    let all_anime = rocksdb::list_anime();
+                    let all_anime = watchlist::list();
                     let mut table = Table::new();
 
-                    table.add_row(row!["Title", "Sub Group", "Quality", "Watch ID"]);
+                    table.add_row(row![
+                        "Title",
+                        "Sub Group",
+                        "Quality",
+                        "Last Episode",
+                        "Paused",
+                        "Aliases",
+                        "Matched As",
+                        "Linked To",
+                        "Watch ID"
+                    ]);
 
                     for (watch_id, anime) in all_anime {
-                        table.add_row(row![anime.title, anime.subgroup, anime.quality, watch_id]);
+                        table.add_row(row![
+                            anime.title,
+                            match anime.delay_hours {
+                                0 => anime.subgroups().collect::<Vec<&str>>().join(" > "),
+                                hours => format!(
+                                    "{}\nwaits {}h",
+                                    anime.subgroups().collect::<Vec<&str>>().join(" > "),
+                                    hours
+                                ),
+                            },
+                            format!("{}{}", anime.quality, anime.profile),
+                            anime.last_seen_episode,
+                            anime.paused,
+                            anime.aliases.join("\n"),
+                            anime.matched_alias.as_deref().unwrap_or("-"),
+                            anime
+                                .metadata
+                                .as_ref()
+                                .map_or(String::from("-"), |metadata| {
+                                    let ids = metadata.site_ids().into_iter();
+                                    ids.map(|(site, id)| format!("{} {}", site, id))
+                                        .collect::<Vec<String>>()
+                                        .join("\n")
+                                }),
+                            watch_id
+                        ]);
                     }
 
                     table.printstd();
@@ -170,10 +653,14 @@ fn main() {
             }
         }
         Err(e) => {
+            let hint = if e.is_not_found() {
+                "You may need to create one with `animated -e`."
+            } else {
+                "You can fix it with `animated -e`."
+            };
             eprintln!(
-                "An error occurred while reading animated config. \
-                You may need to create one with `animated -e`.\nError: {}",
-                e.to_string()
+                "An error occurred while reading animated config. {}\nError: {}",
+                hint, e
             );
             process::exit(1);
         }
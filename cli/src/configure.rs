@@ -0,0 +1,313 @@
+use crate::diff;
+use common::{
+    config::{self, ConfigError, Layer, LoadedConfig},
+    config_format::{self, Edit, Format},
+    config_keys::{self, KeyError},
+    constants::CONFIG_VERSION,
+    paths,
+};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+fn read_file(path: &Path) -> String {
+    match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!(
+                "Failed to read animated config `{}`.\nError: {}",
+                path.display(),
+                e
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn migrate_diff(original: &str, migrated: &str, path: &str, from: u32, to: u32) -> String {
+    return diff::render(
+        original,
+        migrated,
+        &format!("{} (version {})", path, from),
+        &format!("{} (version {})", path, to),
+    )
+    .unwrap_or_default();
+}
+
+// `animated config migrate`. With `dry_run`, only shows what would change.
+pub fn migrate(dry_run: bool) {
+    let path = paths::get().config_file;
+    let contents = read_file(&path);
+
+    let plan = match config::plan_migration(&contents, &path) {
+        Ok(Some(plan)) => plan,
+        Ok(None) => {
+            println!(
+                "`{}` is already at config version {}.",
+                path.display(),
+                CONFIG_VERSION
+            );
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let path_name = path.display().to_string();
+    print!(
+        "{}",
+        migrate_diff(
+            &plan.original,
+            &plan.migrated,
+            &path_name,
+            plan.from,
+            plan.to
+        )
+    );
+    if dry_run {
+        println!(
+            "Dry run: `{}` would be migrated from version {} to {}.",
+            path_name, plan.from, plan.to
+        );
+        return;
+    }
+
+    match config::apply_migration(&plan, &path) {
+        Ok(backup) => println!(
+            "Migrated `{}` from version {} to {}. The original was saved to `{}`.",
+            path_name,
+            plan.from,
+            plan.to,
+            backup.display()
+        ),
+        Err(e) => {
+            eprintln!("Failed to migrate `{}`.\nError: {}", path_name, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn key_error(e: KeyError) -> ! {
+    eprintln!("{}.", e);
+    if let KeyError::Unknown(_) = e {
+        eprintln!("Run `animated config show` to list the config keys.");
+    }
+    process::exit(1);
+}
+
+fn load() -> LoadedConfig {
+    match config::load() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// The config as animated understands it after merging every layer, as
+// JSON. Validation problems are left out so that `set` can still fix them.
+fn understood() -> Value {
+    return serde_json::to_value(load().config).unwrap();
+}
+
+// `animated config get <key>`.
+pub fn get(key: &str) {
+    let understood = understood();
+    match config_keys::lookup(&understood, key) {
+        Some(value @ Value::Object(_)) => {
+            println!("{}", serde_json::to_string_pretty(value).unwrap())
+        }
+        Some(value) => println!("{}", config_keys::display(value)),
+        None => key_error(KeyError::Unknown(String::from(key))),
+    }
+}
+
+// `animated config show [--json]`.
+pub fn show(json: bool) {
+    let understood = understood();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&understood).unwrap());
+        return;
+    }
+    for (key, value) in config_keys::flatten(&understood) {
+        println!("{} = {}", key, value);
+    }
+}
+
+// `animated config explain [key]`.
+pub fn explain(key: Option<&str>) {
+    let loaded = load();
+    let understood = serde_json::to_value(&loaded.config).unwrap();
+    let (prefix, value) = match key {
+        Some(key) => match config_keys::lookup(&understood, key) {
+            Some(value) => (format!("{}.", key), value),
+            None => key_error(KeyError::Unknown(String::from(key))),
+        },
+        None => (String::new(), &understood),
+    };
+
+    for (leaf, value) in config_keys::flatten(value) {
+        let key = format!("{}{}", prefix, leaf);
+        let key = key.trim_end_matches('.');
+        println!("{} = {}", key, value);
+
+        let mut sources = loaded.explain(key);
+        match sources.pop() {
+            Some((layer, _)) => println!("  from {}", layer),
+            // Optional values nobody set, which serde fills in.
+            None => println!("  from {}", Layer::Default),
+        }
+        for (layer, overridden) in sources.iter().rev() {
+            println!("  overrides {} ({})", layer, overridden);
+        }
+    }
+}
+
+fn user_config_contents(path: &Path) -> String {
+    if path.exists() {
+        return read_file(path);
+    }
+    return config::new_user_config();
+}
+
+// Applies `edit` to the config file as it is on disk, so that formatting
+// choices the user made and keys we do not know about survive. The result
+// has to pass the same checks as the editor before it is written.
+fn rewrite(edit: Edit) {
+    let path = paths::get().config_file;
+    let contents = user_config_contents(&path);
+    let new_contents = match config_format::apply(&contents, Format::of(&path), &path, &edit) {
+        Ok(new_contents) => new_contents,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    };
+
+    match config::check(&new_contents, &path) {
+        Ok(_) => (),
+        // Line numbers would point into the rewritten file, which does not
+        // exist yet, so only say what is wrong.
+        Err(ConfigError::Invalid { problems, .. }) => {
+            eprintln!("Refusing to change the config, it would be invalid:");
+            for problem in problems {
+                eprintln!("  `{}` {}", problem.field, problem.message);
+            }
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!(
+                "Refusing to change the config, it would be invalid:\n  {}",
+                e
+            );
+            process::exit(1);
+        }
+    }
+
+    if let Err(e) = config::write(&new_contents) {
+        eprintln!(
+            "Failed to write animated config `{}`.\nError: {}",
+            path.display(),
+            e
+        );
+        process::exit(1);
+    }
+    let path_name = path.display().to_string();
+    match diff::render(&contents, &new_contents, &path_name, &path_name) {
+        Some(rendered) => print!("{}", rendered),
+        None => println!("No changes were made."),
+    }
+}
+
+// `animated config set <key> <value>`.
+pub fn set(key: &str, raw: &str) {
+    let understood = understood();
+    let value = match config_keys::coerce(&understood, key, raw) {
+        Ok(value) => value,
+        Err(e) => key_error(e),
+    };
+    rewrite(Edit::Set(String::from(key), value));
+}
+
+// `animated config unset <key>`.
+// Unknown keys may be unset too, to get rid of the warnings about them.
+pub fn unset(key: &str) {
+    let understood = understood();
+    let path = paths::get().config_file;
+    let document =
+        match config_format::parse(&user_config_contents(&path), Format::of(&path), &path) {
+            Ok(document) => document,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+    if config_keys::lookup(&document, key).is_none()
+        && config_keys::lookup(&understood, key).is_none()
+    {
+        key_error(KeyError::Unknown(String::from(key)));
+    }
+    rewrite(Edit::Unset(String::from(key)));
+}
+
+// `animated config convert --to <format>`. Writes the user config next to
+// the current one with the new extension and moves the old file aside, so
+// that the new one is found from then on.
+pub fn convert(to: Format) {
+    let path = paths::get().config_file;
+    let from = Format::of(&path);
+    if from == to {
+        println!("`{}` is already {}.", path.display(), to);
+        return;
+    }
+
+    let contents = read_file(&path);
+    let document = match config_format::parse(&contents, from, &path) {
+        Ok(document) => document,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let converted_path = path.with_extension(to.extension());
+    if converted_path.exists() {
+        eprintln!(
+            "Refusing to overwrite `{}`. Move it out of the way first.",
+            converted_path.display()
+        );
+        process::exit(1);
+    }
+
+    let backup = PathBuf::from(format!("{}.bak", path.display()));
+    let converted = config_format::render(&document, to);
+    if let Err(e) = fs::write(&converted_path, &converted).and_then(|_| fs::rename(&path, &backup))
+    {
+        eprintln!(
+            "Failed to convert `{}` to {}.\nError: {}",
+            path.display(),
+            to,
+            e
+        );
+        process::exit(1);
+    }
+
+    println!(
+        "Converted `{}` to `{}`. The original was moved to `{}`.",
+        path.display(),
+        converted_path.display(),
+        backup.display()
+    );
+    // Only `config.*` files are found without being pointed at.
+    if paths::find_config_file(path.parent().unwrap_or(&path)) != converted_path {
+        println!(
+            "Point `--config` or `ANIMATED_CONFIG` at `{}` from now on.",
+            converted_path.display()
+        );
+    }
+}
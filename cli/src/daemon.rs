@@ -0,0 +1,162 @@
+use common::{
+    ipc::{self, IpcError, Request, Response},
+    lock::{self, LockState},
+};
+use std::{
+    process, thread,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long to wait for the daemon to go away after forcing it to quit.
+const FORCE_KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn format_duration(total_seconds: u64) -> String {
+    let days = total_seconds / 86400;
+    let hours = (total_seconds % 86400) / 3600;
+    let minutes = (total_seconds % 3600) / 60;
+    let seconds = total_seconds % 60;
+
+    if days > 0 {
+        return format!("{}d {}h {}m {}s", days, hours, minutes, seconds);
+    } else if hours > 0 {
+        return format!("{}h {}m {}s", hours, minutes, seconds);
+    } else if minutes > 0 {
+        return format!("{}m {}s", minutes, seconds);
+    }
+    return format!("{}s", seconds);
+}
+
+fn inspect_lock() -> LockState {
+    match lock::inspect() {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!(
+                "An error occurred while reading the daemon lock file.\nError: {}",
+                e
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn print_not_running() {
+    match inspect_lock() {
+        LockState::Stale(Some(info)) => println!(
+            "The animated daemon is not running. PID {} exited without \
+            releasing its lock; the next daemon to start will take it over.",
+            info.pid
+        ),
+        _ => println!("The animated daemon is not running."),
+    }
+}
+
+pub fn status() {
+    match ipc::send(Request::Status) {
+        Ok(Response::Status(status)) => {
+            println!("The animated daemon is running.");
+            println!("PID: {}", status.pid);
+            println!(
+                "Uptime: {}",
+                format_duration(lock::now().saturating_sub(status.started_at))
+            );
+            println!("Config: {}", status.config_path);
+            println!("Watched anime: {}", status.watched);
+            println!("Active downloads: {}", status.active_downloads);
+            println!("Pending episodes: {}", status.pending.len());
+            for pending in status.pending.iter() {
+                println!(
+                    "  Episode {} of {} (watch ID {}): {}, taken in {} unless a better \
+                    ranked group releases it",
+                    pending.episode,
+                    pending.anime,
+                    pending.watch_id,
+                    pending.release_title,
+                    format_duration(pending.decide_at.saturating_sub(lock::now()))
+                );
+            }
+        }
+        Ok(response) => {
+            eprintln!(
+                "The animated daemon sent an unexpected response: {:?}",
+                response
+            );
+            process::exit(1);
+        }
+        Err(IpcError::DaemonUnavailable) => print_not_running(),
+        Err(e) => {
+            eprintln!(
+                "An error occurred while talking to the animated daemon.\nError: {}",
+                e
+            );
+            process::exit(1);
+        }
+    }
+}
+
+// Polls the lock until the daemon with `pid` no longer holds it.
+fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        match inspect_lock() {
+            LockState::Held(Some(info)) if info.pid == pid => thread::sleep(POLL_INTERVAL),
+            _ => return true,
+        }
+    }
+    return false;
+}
+
+pub fn kill(timeout: Duration) {
+    let pid = match inspect_lock() {
+        LockState::Held(Some(info)) => info.pid,
+        LockState::Held(None) => {
+            eprintln!(
+                "The animated daemon is starting up and has not recorded its PID yet. \
+                Try again in a moment."
+            );
+            process::exit(1);
+        }
+        LockState::Stale(_) | LockState::Free => {
+            print_not_running();
+            return;
+        }
+    };
+
+    println!("Asking the animated daemon (PID {}) to shut down.", pid);
+    if let Err(e) = lock::signal(pid, false) {
+        eprintln!("Failed to signal PID {}.\nError: {}", pid, e);
+        process::exit(1);
+    }
+    if wait_for_exit(pid, timeout) {
+        println!("The animated daemon has stopped.");
+        return;
+    }
+
+    // A second SIGTERM makes the daemon quit without waiting for running
+    // work, but still clean up after itself.
+    println!(
+        "The animated daemon did not stop within {}. Asking it to quit right away.",
+        format_duration(timeout.as_secs())
+    );
+    if let Err(e) = lock::signal(pid, false) {
+        eprintln!("Failed to signal PID {}.\nError: {}", pid, e);
+        process::exit(1);
+    }
+    if wait_for_exit(pid, FORCE_KILL_TIMEOUT) {
+        println!("The animated daemon has stopped.");
+        return;
+    }
+
+    println!("The animated daemon is not responding. Killing it.");
+    if let Err(e) = lock::signal(pid, true) {
+        eprintln!("Failed to kill PID {}.\nError: {}", pid, e);
+        process::exit(1);
+    }
+    if wait_for_exit(pid, FORCE_KILL_TIMEOUT) {
+        println!("The animated daemon has been killed.");
+        return;
+    }
+
+    eprintln!("PID {} is still holding the daemon lock.", pid);
+    process::exit(1);
+}
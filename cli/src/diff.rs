@@ -0,0 +1,66 @@
+/*
+ * A line based diff for showing config changes. Config files are a few dozen
+ * lines at most, so a plain longest common subsequence table is plenty.
+ */
+
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    // `common[i][j]` is the length of the longest common subsequence of
+    // `old[i..]` and `new[j..]`.
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            lines.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|line| Line::Removed(line)));
+    lines.extend(new[j..].iter().map(|line| Line::Added(line)));
+    return lines;
+}
+
+// Renders the changes from `old` to `new`, or `None` if there are none.
+pub fn render(old: &str, new: &str, old_name: &str, new_name: &str) -> Option<String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    if old_lines == new_lines {
+        return None;
+    }
+
+    let mut rendered = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for line in diff_lines(&old_lines, &new_lines) {
+        let (marker, text) = match line {
+            Line::Same(text) => (' ', text),
+            Line::Removed(text) => ('-', text),
+            Line::Added(text) => ('+', text),
+        };
+        rendered.push(marker);
+        rendered.push_str(text);
+        rendered.push('\n');
+    }
+    return Some(rendered);
+}
@@ -0,0 +1,147 @@
+use crate::watchlist;
+use common::{
+    anime_lists::{self, ListEntry, ListFormat},
+    config,
+    interchange::{self, Export, ExportFormat, MergeStrategy},
+    structs::WatchDefaults,
+};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    process,
+    str::FromStr,
+};
+
+pub fn export(output: Option<&str>, format: Option<ExportFormat>) {
+    let export = watchlist::export();
+    let output = match output {
+        Some(output) => Path::new(output),
+        None => {
+            let format = format.unwrap_or(ExportFormat::Json);
+            let rendered = interchange::render(&export, format);
+            // E.g. when piped into `head`.
+            let _ = io::stdout().write_all(rendered.as_bytes());
+            return;
+        }
+    };
+
+    let format = format.unwrap_or_else(|| ExportFormat::of(output));
+    if let Err(e) = fs::write(output, interchange::render(&export, format)) {
+        eprintln!("Failed to write `{}`.\nError: {}", output.display(), e);
+        process::exit(1);
+    }
+    println!(
+        "Exported {} anime to `{}`.",
+        export.anime.len(),
+        output.display()
+    );
+}
+
+fn read_input(input: &str) -> String {
+    let mut contents = String::new();
+    let read = if input == "-" {
+        io::stdin().read_to_string(&mut contents).map(|_| ())
+    } else {
+        fs::read_to_string(input).map(|read| contents = read)
+    };
+    if let Err(e) = read {
+        eprintln!("Failed to read `{}`.\nError: {}", input, e);
+        process::exit(1);
+    }
+    return contents;
+}
+
+fn parse_failed(input: &str, e: String) -> ! {
+    eprintln!("Failed to import `{}`: {}", input, e);
+    process::exit(1);
+}
+
+// Lists of anime being watched elsewhere only name shows, so they are
+// watched with the `defaults` config and whatever still needs a subgroup is
+// handed back.
+fn read_list(input: &str, contents: &str, format: ListFormat) -> (Export, Vec<ListEntry>) {
+    let entries = anime_lists::parse(contents, format).unwrap_or_else(|e| parse_failed(input, e));
+    let imported = anime_lists::convert(entries, &watch_defaults());
+    return (imported.export, imported.unresolved);
+}
+
+fn watch_defaults() -> WatchDefaults {
+    return config::load()
+        .map(|loaded| loaded.config.defaults)
+        .unwrap_or_default();
+}
+
+fn list_unresolved(unresolved: &[ListEntry]) {
+    if unresolved.is_empty() {
+        return;
+    }
+    let quality = watch_defaults().quality;
+    println!(
+        "\n{} anime need a subgroup. Pick one for all of them with `animated config set \
+        defaults.subgroup <subgroup>` and import again, or watch them one by one:",
+        unresolved.len()
+    );
+    for entry in unresolved.iter() {
+        print!(
+            "  animated watch --name {:?} --quality {} --subgroup <subgroup>",
+            entry.title, quality
+        );
+        if entry.watched_episodes > 0 {
+            print!(
+                "\n      then `animated modify <watch ID> --episode {}`",
+                entry.last_seen_episode()
+            );
+        }
+        println!();
+    }
+}
+
+// `format` is one of animated's own export formats or a list format. Left
+// out, lists are recognized by their contents and anything else goes by the
+// extension of `input`, so stdin is read as JSON.
+pub fn import(input: &str, format: Option<&str>, strategy: MergeStrategy, dry_run: bool) {
+    let contents = read_input(input);
+    let list_format = match format {
+        Some(format) => ListFormat::from_str(format).ok(),
+        None => ListFormat::detect(&contents),
+    };
+    let (export, unresolved) = match list_format {
+        Some(list_format) => read_list(input, &contents, list_format),
+        None => {
+            let format = format
+                .and_then(|format| ExportFormat::from_str(format).ok())
+                .unwrap_or_else(|| ExportFormat::of(Path::new(input)));
+            let export =
+                interchange::parse(&contents, format).unwrap_or_else(|e| parse_failed(input, e));
+            (export, vec![])
+        }
+    };
+
+    let report = watchlist::import(&export, strategy, dry_run);
+    let (added, updated) = if dry_run {
+        ("Would watch", "Would update")
+    } else {
+        ("Now watching", "Updated")
+    };
+    for (watch_id, anime) in report.added.iter() {
+        println!("{} {} / Watch ID: {}", added, anime, watch_id);
+    }
+    for (watch_id, anime) in report.updated.iter() {
+        println!("{} {} / Watch ID: {}", updated, anime, watch_id);
+    }
+    for (watch_id, anime) in report.unchanged.iter() {
+        println!("Already watching {} / Watch ID: {}", anime, watch_id);
+    }
+    for (anime, reason) in report.rejected.iter() {
+        eprintln!("Skipped {}: {}.", anime, reason);
+    }
+    println!(
+        "{} new, {} updated and {} unchanged anime, {} episode(s) of history.",
+        report.added.len(),
+        report.updated.len(),
+        report.unchanged.len(),
+        report.episodes
+    );
+    list_unresolved(&unresolved);
+}
@@ -0,0 +1,115 @@
+use common::{
+    config,
+    metadata::{Candidate, MetadataDatabase, Resolution},
+    structs::{Anime, AnimeMetadata, Config},
+};
+use std::{path::Path, process};
+
+// At most this many candidates are listed for an ambiguous name.
+const CANDIDATES_SHOWN: usize = 10;
+
+fn read_config() -> Config {
+    return config::load()
+        .map(|loaded| loaded.config)
+        .unwrap_or_default();
+}
+
+// The configured anime-offline-database, or None if there is none.
+fn database(config: &Config) -> Option<MetadataDatabase> {
+    let path = config.metadata.database.as_ref()?;
+    match MetadataDatabase::load(Path::new(path)) {
+        Ok(database) => Some(database),
+        Err(e) => {
+            eprintln!("Failed to load the anime-offline-database: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn required_database(config: &Config) -> MetadataDatabase {
+    match database(config) {
+        Some(database) => database,
+        None => {
+            eprintln!(
+                "No anime-offline-database is configured. Download `anime-offline-database.json` \
+                and run `animated config set metadata.database <path>`."
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn entry(database: &MetadataDatabase, source: &str) -> AnimeMetadata {
+    match database.get(source) {
+        Some(metadata) => metadata.clone(),
+        None => {
+            eprintln!(
+                "No entry of the anime-offline-database has the source `{}`.",
+                source
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn describe(metadata: &AnimeMetadata) -> String {
+    return format!("{} / {}", metadata, metadata.source);
+}
+
+fn list_candidates(name: &str, candidates: &[Candidate]) {
+    eprintln!(
+        "`{}` could be any of these {} anime in the anime-offline-database:",
+        name,
+        candidates.len()
+    );
+    for candidate in candidates.iter().take(CANDIDATES_SHOWN) {
+        eprint!("  {}", candidate.metadata.source);
+        eprint!("\n      {}", candidate.metadata);
+        if candidate.matched != candidate.metadata.title {
+            eprint!(", also known as {:?}", candidate.matched);
+        }
+        eprintln!();
+    }
+    if candidates.len() > CANDIDATES_SHOWN {
+        eprintln!("  and {} more", candidates.len() - CANDIDATES_SHOWN);
+    }
+    eprintln!("Pick one with `--link <source>`, or watch it without metadata with `--no-link`.");
+}
+
+// Links an anime about to be watched to its entry in the
+// anime-offline-database, the one given by `source` or else the one its name
+// resolves to. Exits listing the candidates if the name is ambiguous.
+pub fn link_new(anime: &mut Anime, source: Option<&str>) {
+    let config = read_config();
+    if let Some(source) = source {
+        anime.metadata = Some(entry(&required_database(&config), source));
+        return;
+    }
+
+    let database = match database(&config) {
+        Some(database) => database,
+        None => return,
+    };
+    match database.resolve(&anime.title, config.matching.threshold()) {
+        Resolution::Found(metadata) => anime.metadata = Some(metadata.clone()),
+        Resolution::Ambiguous(candidates) => {
+            list_candidates(&anime.title, &candidates);
+            process::exit(1);
+        }
+        Resolution::NotFound => println!(
+            "`{}` is not in the anime-offline-database, so it is watched without metadata.",
+            anime.title
+        ),
+    }
+}
+
+// Links an anime already being watched to the entry with `source`.
+pub fn link(anime: &mut Anime, source: &str) {
+    anime.metadata = Some(entry(&required_database(&read_config()), source));
+}
+
+pub fn print_link(anime: &Anime) {
+    if let Some(metadata) = &anime.metadata {
+        println!("Linked to {}", describe(metadata));
+    }
+}
@@ -0,0 +1,186 @@
+use common::{
+    config,
+    interchange::{self, Export, ImportReport, MergeStrategy},
+    ipc::{self, IpcError, Request, Response},
+    paths,
+    retention::{self, GcReport},
+    store::{BackupInfo, RocksStore, StoreError, WatchStore},
+    structs::Anime,
+};
+use std::{collections::HashMap, path::Path, process};
+
+// RocksDB only lets one process open the database, so while the daemon is up
+// every watch list operation has to be forwarded to it over the control
+// socket. When it is not running we fall back to opening the database here.
+fn forward(request: Request) -> Option<Response> {
+    match ipc::send(request) {
+        Ok(Response::Error(message)) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+        Ok(response) => Some(response),
+        Err(IpcError::DaemonUnavailable) => None,
+        Err(e) => {
+            eprintln!(
+                "An error occurred while talking to the animated daemon.\nError: {}",
+                e
+            );
+            process::exit(1);
+        }
+    }
+}
+
+pub fn local_store() -> RocksStore {
+    match RocksStore::open(&paths::get().rocksdb) {
+        Ok(store) => store,
+        Err(e) => store_error(e),
+    }
+}
+
+fn store_error(e: StoreError) -> ! {
+    eprintln!("{}", e);
+    process::exit(1);
+}
+
+fn unexpected(response: Response) -> ! {
+    eprintln!(
+        "The animated daemon sent an unexpected response: {:?}",
+        response
+    );
+    process::exit(1);
+}
+
+pub fn watch(anime: &Anime) -> String {
+    match forward(Request::Watch(anime.clone())) {
+        Some(Response::Watched { watch_id }) => watch_id,
+        Some(response) => unexpected(response),
+        None => match local_store().upsert_anime(anime) {
+            Ok(watch_id) => watch_id,
+            Err(e) => {
+                eprintln!("Failed to watch {}. {}.", anime, e);
+                process::exit(1);
+            }
+        },
+    }
+}
+
+pub fn unwatch(watch_id: &str) {
+    let request = Request::Unwatch {
+        watch_id: String::from(watch_id),
+    };
+    match forward(request) {
+        Some(Response::Unwatched { .. }) => (),
+        Some(response) => unexpected(response),
+        None => match local_store().tombstone_anime(watch_id) {
+            Ok(true) => (),
+            Ok(false) => {
+                eprintln!("No anime is being watched with watch ID {}.", watch_id);
+                process::exit(1);
+            }
+            Err(e) => store_error(e),
+        },
+    }
+}
+
+pub fn get(watch_id: &str) -> Option<Anime> {
+    let request = Request::Get {
+        watch_id: String::from(watch_id),
+    };
+    match forward(request) {
+        Some(Response::Anime(anime)) => anime.map(|anime| *anime),
+        Some(response) => unexpected(response),
+        None => local_store()
+            .get_anime(watch_id)
+            .unwrap_or_else(|e| store_error(e)),
+    }
+}
+
+pub fn modify(watch_id: &str, anime: &Anime) {
+    let request = Request::Modify {
+        watch_id: String::from(watch_id),
+        anime: anime.clone(),
+    };
+    match forward(request) {
+        Some(Response::Modified { .. }) => (),
+        Some(response) => unexpected(response),
+        None => match local_store().modify_anime(watch_id, anime) {
+            Ok(()) => (),
+            Err(StoreError::Collision(collision)) => {
+                eprintln!(
+                    "Refusing to modify watch ID {}. {} with the same title, \
+                    subgroup and quality.",
+                    watch_id, collision
+                );
+                process::exit(1);
+            }
+            Err(e) => store_error(e),
+        },
+    }
+}
+
+pub fn list() -> HashMap<String, Anime> {
+    match forward(Request::List) {
+        Some(Response::List(all_anime)) => all_anime,
+        Some(response) => unexpected(response),
+        None => local_store()
+            .list_anime()
+            .unwrap_or_else(|e| store_error(e)),
+    }
+}
+
+// A running daemon collects garbage with the policy of its own config.
+pub fn collect_garbage(dry_run: bool) -> GcReport {
+    match forward(Request::Gc { dry_run: dry_run }) {
+        Some(Response::Collected(report)) => report,
+        Some(response) => unexpected(response),
+        None => {
+            let policy = config::load()
+                .map(|loaded| loaded.config.retention)
+                .unwrap_or_default();
+            match retention::collect_garbage(&local_store(), &policy, dry_run) {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("Failed to collect garbage. {}.", e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+pub fn export() -> Export {
+    match forward(Request::Export) {
+        Some(Response::Exported(export)) => export,
+        Some(response) => unexpected(response),
+        None => interchange::export(&local_store()).unwrap_or_else(|e| store_error(e)),
+    }
+}
+
+pub fn import(export: &Export, strategy: MergeStrategy, dry_run: bool) -> ImportReport {
+    let request = Request::Import {
+        export: export.clone(),
+        strategy: strategy,
+        dry_run: dry_run,
+    };
+    match forward(request) {
+        Some(Response::Imported(report)) => report,
+        Some(response) => unexpected(response),
+        None => interchange::import(&local_store(), export, strategy, dry_run)
+            .unwrap_or_else(|e| store_error(e)),
+    }
+}
+
+// A running daemon has the database open, so it takes the backup itself.
+pub fn backup(dir: &Path, keep: Option<usize>) -> BackupInfo {
+    let request = Request::Backup {
+        dir: dir.to_path_buf(),
+        keep: keep,
+    };
+    match forward(request) {
+        Some(Response::BackedUp(backup)) => backup,
+        Some(response) => unexpected(response),
+        None => local_store()
+            .backup(dir, keep)
+            .unwrap_or_else(|e| store_error(e)),
+    }
+}
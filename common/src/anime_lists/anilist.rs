@@ -0,0 +1,204 @@
+use super::{ListEntry, ListStatus};
+use serde::Deserialize;
+use serde_json::Value;
+
+/*
+ * AniList lists come as the `MediaListCollection` of its GraphQL API, either
+ * as the full response or just the collection, e.g.
+ *
+ *   {"lists": [{"status": "CURRENT", "entries": [
+ *     {"status": "CURRENT", "progress": 5,
+ *      "media": {"title": {"romaji": "Cowboy Bebop"}}}]}]}
+ *
+ * Other fields are ignored.
+ */
+
+#[derive(Deserialize)]
+struct Collection {
+    lists: Vec<List>,
+}
+
+#[derive(Deserialize)]
+struct List {
+    // Custom lists have none, their entries carry their own.
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    entries: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    progress: Option<i32>,
+    media: Media,
+}
+
+#[derive(Deserialize)]
+struct Media {
+    title: Title,
+}
+
+#[derive(Deserialize)]
+struct Title {
+    #[serde(rename = "userPreferred", default)]
+    user_preferred: Option<String>,
+    #[serde(default)]
+    romaji: Option<String>,
+    #[serde(default)]
+    english: Option<String>,
+    #[serde(default)]
+    native: Option<String>,
+}
+
+impl Title {
+    // Every distinct title, the preferred one first.
+    fn all(self) -> Vec<String> {
+        let mut titles: Vec<String> = vec![];
+        let candidates = vec![self.user_preferred, self.romaji, self.english, self.native];
+        for title in candidates.into_iter().flatten() {
+            let title = title.trim().to_string();
+            if !title.is_empty() && !titles.contains(&title) {
+                titles.push(title);
+            }
+        }
+        return titles;
+    }
+}
+
+fn status(name: &str) -> ListStatus {
+    match name {
+        "CURRENT" => ListStatus::Watching,
+        "PLANNING" => ListStatus::PlanToWatch,
+        _ => ListStatus::Other,
+    }
+}
+
+// Unwraps `{"data": {"MediaListCollection": ...}}` down to the collection.
+fn collection(mut document: Value) -> Option<Value> {
+    if let Some(data) = document.get_mut("data") {
+        document = data.take();
+    }
+    if let Some(collection) = document.get_mut("MediaListCollection") {
+        document = collection.take();
+    }
+    if document.get("lists").is_some_and(Value::is_array) {
+        return Some(document);
+    }
+    return None;
+}
+
+pub fn looks_like(contents: &str) -> bool {
+    return serde_json::from_str(contents)
+        .ok()
+        .and_then(collection)
+        .is_some();
+}
+
+pub fn parse(contents: &str) -> Result<Vec<ListEntry>, String> {
+    let document: Value = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    let collection: Collection = match collection(document) {
+        Some(collection) => serde_json::from_value(collection).map_err(|e| e.to_string())?,
+        None => {
+            return Err(String::from(
+                "this is not an AniList export, it has no lists",
+            ))
+        }
+    };
+
+    let mut entries = vec![];
+    for list in collection.lists {
+        for entry in list.entries {
+            let mut titles = entry.media.title.all();
+            if titles.is_empty() {
+                continue;
+            }
+            let title = titles.remove(0);
+            let status = entry
+                .status
+                .as_ref()
+                .or(list.status.as_ref())
+                .map_or(ListStatus::Other, |name| status(name));
+            entries.push(ListEntry {
+                title: title,
+                aliases: titles,
+                status: status,
+                watched_episodes: entry.progress.unwrap_or(0),
+            });
+        }
+    }
+    return Ok(entries);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str = r#"{"data": {"MediaListCollection": {"lists": [
+        {"name": "Watching", "status": "CURRENT", "entries": [
+            {"status": "CURRENT", "progress": 12, "media": {"title": {
+                "userPreferred": "Sousou no Frieren",
+                "romaji": "Sousou no Frieren",
+                "english": "Frieren: Beyond Journey's End",
+                "native": "葬送のフリーレン"}}}]},
+        {"name": "Planning", "status": "PLANNING", "entries": [
+            {"progress": 0, "media": {"title": {"romaji": "Dungeon Meshi"}}}]},
+        {"name": "Completed", "status": "COMPLETED", "entries": [
+            {"status": "COMPLETED", "progress": 26, "media": {"title": {"romaji": "Cowboy Bebop"}}}]},
+        {"name": "Rewatch", "isCustomList": true, "entries": [
+            {"status": "CURRENT", "progress": 3, "media": {"title": {"english": "Mushishi"}}},
+            {"status": "CURRENT", "media": {"title": {"romaji": " "}}}]}
+    ]}}}"#;
+
+    fn summary(entries: &[ListEntry]) -> Vec<(&str, ListStatus, i32)> {
+        return entries
+            .iter()
+            .map(|entry| (entry.title.as_str(), entry.status, entry.watched_episodes))
+            .collect();
+    }
+
+    #[test]
+    fn reads_every_list_of_a_full_response() {
+        assert!(looks_like(RESPONSE));
+        let entries = parse(RESPONSE).unwrap();
+        assert_eq!(
+            summary(&entries),
+            vec![
+                ("Sousou no Frieren", ListStatus::Watching, 12),
+                ("Dungeon Meshi", ListStatus::PlanToWatch, 0),
+                ("Cowboy Bebop", ListStatus::Other, 26),
+                ("Mushishi", ListStatus::Watching, 3),
+            ]
+        );
+        assert_eq!(
+            entries[0].aliases,
+            vec![
+                String::from("Frieren: Beyond Journey's End"),
+                String::from("葬送のフリーレン"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_a_bare_collection() {
+        let collection = r#"{"lists": [{"status": "PLANNING", "entries": [
+            {"media": {"title": {"romaji": "Dungeon Meshi"}}}]}]}"#;
+        assert!(looks_like(collection));
+        assert_eq!(
+            summary(&parse(collection).unwrap()),
+            vec![("Dungeon Meshi", ListStatus::PlanToWatch, 0)]
+        );
+    }
+
+    #[test]
+    fn rejects_json_without_lists() {
+        let export = r#"{"version": 1, "exported_at": 0, "anime": []}"#;
+        assert!(!looks_like(export));
+        assert_eq!(
+            parse(export).unwrap_err(),
+            "this is not an AniList export, it has no lists"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use super::{ListEntry, ListStatus};
+use quick_xml::{events::Event, Reader};
+
+/*
+ * MyAnimeList exports one `<anime>` element per show, e.g.
+ *
+ *   <anime>
+ *     <series_title><![CDATA[Cowboy Bebop]]></series_title>
+ *     <my_watched_episodes>5</my_watched_episodes>
+ *     <my_status>Watching</my_status>
+ *   </anime>
+ *
+ * Other elements are ignored.
+ */
+
+fn status(name: &str) -> ListStatus {
+    // Older exports number the statuses instead of naming them.
+    match name {
+        "Watching" | "1" => ListStatus::Watching,
+        "Plan to Watch" | "6" => ListStatus::PlanToWatch,
+        _ => ListStatus::Other,
+    }
+}
+
+#[derive(Default)]
+struct Fields {
+    title: Option<String>,
+    watched_episodes: Option<String>,
+    status: Option<String>,
+}
+
+impl Fields {
+    fn entry(self, index: usize) -> Result<ListEntry, String> {
+        let title = match self.title {
+            Some(title) if !title.trim().is_empty() => title.trim().to_string(),
+            _ => return Err(format!("anime #{} has no `series_title`", index)),
+        };
+        let watched_episodes = match self.watched_episodes {
+            Some(count) => count.trim().parse::<i32>().map_err(|_| {
+                format!(
+                    "`{}` has `{}` watched episodes, which is not a number",
+                    title, count
+                )
+            })?,
+            None => 0,
+        };
+        return Ok(ListEntry {
+            title: title,
+            aliases: vec![],
+            status: status(self.status.as_deref().unwrap_or("").trim()),
+            watched_episodes: watched_episodes,
+        });
+    }
+}
+
+pub fn parse(contents: &str) -> Result<Vec<ListEntry>, String> {
+    let mut reader = Reader::from_str(contents);
+    reader.trim_text(true);
+
+    let mut entries = vec![];
+    let mut current: Option<Fields> = None;
+    let mut element: Vec<u8> = vec![];
+    let mut buf = vec![];
+    loop {
+        let text = match reader.read_event(&mut buf) {
+            Ok(Event::Start(start)) => {
+                if start.name() == b"anime" {
+                    current = Some(Fields::default());
+                }
+                element = start.name().to_vec();
+                None
+            }
+            Ok(Event::End(end)) => {
+                if end.name() == b"anime" {
+                    if let Some(fields) = current.take() {
+                        entries.push(fields.entry(entries.len() + 1)?);
+                    }
+                }
+                element.clear();
+                None
+            }
+            Ok(Event::Text(text)) => Some(
+                text.unescape_and_decode(&reader)
+                    .map_err(|e| format!("at byte {}: {}", reader.buffer_position(), e))?,
+            ),
+            // Titles are usually wrapped in CDATA, which is not escaped.
+            Ok(Event::CData(text)) => Some(String::from_utf8_lossy(&text).into_owned()),
+            Ok(Event::Eof) => break,
+            Ok(_) => None,
+            Err(e) => return Err(format!("at byte {}: {}", reader.buffer_position(), e)),
+        };
+
+        if let (Some(text), Some(fields)) = (text, current.as_mut()) {
+            let field = match element.as_slice() {
+                b"series_title" => Some(&mut fields.title),
+                b"my_watched_episodes" => Some(&mut fields.watched_episodes),
+                b"my_status" => Some(&mut fields.status),
+                _ => None,
+            };
+            if let Some(field) = field {
+                field.get_or_insert_with(String::new).push_str(&text);
+            }
+        }
+        buf.clear();
+    }
+
+    if entries.is_empty() && !contents.contains("<myanimelist") {
+        return Err(String::from("this is not a MyAnimeList export"));
+    }
+    return Ok(entries);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPORT: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
+<myanimelist>
+  <myinfo>
+    <user_name>someone</user_name>
+  </myinfo>
+  <anime>
+    <series_animedb_id>52991</series_animedb_id>
+    <series_title><![CDATA[Sousou no Frieren]]></series_title>
+    <my_watched_episodes>12</my_watched_episodes>
+    <my_status>Watching</my_status>
+  </anime>
+  <anime>
+    <series_title><![CDATA[Dungeon Meshi]]></series_title>
+    <my_watched_episodes>0</my_watched_episodes>
+    <my_status>Plan to Watch</my_status>
+  </anime>
+  <anime>
+    <series_title><![CDATA[Cowboy Bebop]]></series_title>
+    <my_watched_episodes>26</my_watched_episodes>
+    <my_status>Completed</my_status>
+  </anime>
+  <anime>
+    <series_title>Kaguya-sama: Love is War &amp; More</series_title>
+    <my_watched_episodes>3</my_watched_episodes>
+    <my_status>On-Hold</my_status>
+  </anime>
+</myanimelist>
+"#;
+
+    fn summary(entries: &[ListEntry]) -> Vec<(&str, ListStatus, i32)> {
+        return entries
+            .iter()
+            .map(|entry| (entry.title.as_str(), entry.status, entry.watched_episodes))
+            .collect();
+    }
+
+    #[test]
+    fn reads_every_anime_with_its_status_and_progress() {
+        let entries = parse(EXPORT).unwrap();
+        assert_eq!(
+            summary(&entries),
+            vec![
+                ("Sousou no Frieren", ListStatus::Watching, 12),
+                ("Dungeon Meshi", ListStatus::PlanToWatch, 0),
+                ("Cowboy Bebop", ListStatus::Other, 26),
+                ("Kaguya-sama: Love is War & More", ListStatus::Other, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn understands_numbered_statuses() {
+        let export = "<myanimelist>\
+            <anime><series_title>A</series_title><my_status>1</my_status></anime>\
+            <anime><series_title>B</series_title><my_status>6</my_status></anime>\
+            <anime><series_title>C</series_title><my_status>2</my_status></anime>\
+            </myanimelist>";
+        assert_eq!(
+            summary(&parse(export).unwrap()),
+            vec![
+                ("A", ListStatus::Watching, 0),
+                ("B", ListStatus::PlanToWatch, 0),
+                ("C", ListStatus::Other, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_broken_entries() {
+        let untitled = "<myanimelist><anime><my_status>Watching</my_status></anime></myanimelist>";
+        assert_eq!(
+            parse(untitled).unwrap_err(),
+            "anime #1 has no `series_title`"
+        );
+        let uncounted = "<myanimelist><anime><series_title>A</series_title>\
+            <my_watched_episodes>lots</my_watched_episodes></anime></myanimelist>";
+        assert_eq!(
+            parse(uncounted).unwrap_err(),
+            "`A` has `lots` watched episodes, which is not a number"
+        );
+        assert_eq!(
+            parse("<rss></rss>").unwrap_err(),
+            "this is not a MyAnimeList export"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use crate::interchange::{Export, ExportedAnime, INTERCHANGE_VERSION};
+use crate::lock;
+use crate::structs::{QualityProfile, WatchDefaults};
+use std::str::FromStr;
+
+/*
+ * Reads the list exports of MyAnimeList and AniList so that `animated
+ * import` can start watching whatever users are watching or plan to watch
+ * there. Those lists know titles and how far along users are, but nothing
+ * about releases, so quality and subgroup come from the `defaults` config.
+ */
+
+mod anilist;
+mod mal;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    // The XML of MyAnimeList's export, `animelist_*.xml`.
+    Mal,
+    // The JSON AniList exports lists as.
+    AniList,
+}
+
+impl FromStr for ListFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ListFormat, ()> {
+        match s {
+            "mal" => Ok(ListFormat::Mal),
+            "anilist" => Ok(ListFormat::AniList),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ListFormat {
+    // Recognizes list exports by their contents, since AniList's is a
+    // `.json` file just like animated's own exports.
+    pub fn detect(contents: &str) -> Option<ListFormat> {
+        let contents = contents.trim_start();
+        if contents.starts_with('<') && contents.contains("<myanimelist") {
+            return Some(ListFormat::Mal);
+        }
+        if contents.starts_with('{') && anilist::looks_like(contents) {
+            return Some(ListFormat::AniList);
+        }
+        return None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListStatus {
+    Watching,
+    PlanToWatch,
+    // Completed, on hold, dropped and so on, none of which are imported.
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListEntry {
+    pub title: String,
+    // Other titles the list knows the show by.
+    pub aliases: Vec<String>,
+    pub status: ListStatus,
+    pub watched_episodes: i32,
+}
+
+impl ListEntry {
+    // Episodes up to the watched count are not downloaded again. Nothing
+    // watched yet is the same as a fresh `animated watch`.
+    pub fn last_seen_episode(&self) -> i32 {
+        if self.watched_episodes > 0 {
+            return self.watched_episodes;
+        }
+        return -1;
+    }
+}
+
+pub fn parse(contents: &str, format: ListFormat) -> Result<Vec<ListEntry>, String> {
+    match format {
+        ListFormat::Mal => mal::parse(contents),
+        ListFormat::AniList => anilist::parse(contents),
+    }
+}
+
+#[derive(Debug)]
+pub struct ListImport {
+    // What can be watched with the configured defaults, ready for
+    // `interchange::import`.
+    pub export: Export,
+    // Titles to be watched that still need a subgroup picked.
+    pub unresolved: Vec<ListEntry>,
+}
+
+// Keeps the shows that are being watched or planned and gives them the
+// default quality and subgroup.
+pub fn convert(entries: Vec<ListEntry>, defaults: &WatchDefaults) -> ListImport {
+    let mut anime = vec![];
+    let mut unresolved = vec![];
+    for entry in entries {
+        if entry.status == ListStatus::Other {
+            continue;
+        }
+        match &defaults.subgroup {
+            Some(subgroup) => anime.push(ExportedAnime {
+                title: entry.title.clone(),
+                subgroup: subgroup.clone(),
+                quality: defaults.quality,
+                last_seen_episode: entry.last_seen_episode(),
+                paused: false,
+                aliases: entry.aliases.clone(),
+                fallback_subgroups: vec![],
+                delay_hours: 0,
+                profile: QualityProfile::default(),
+                episodes: vec![],
+            }),
+            None => unresolved.push(entry),
+        }
+    }
+
+    return ListImport {
+        export: Export {
+            version: INTERCHANGE_VERSION,
+            exported_at: lock::now(),
+            anime: anime,
+        },
+        unresolved: unresolved,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::Quality;
+
+    fn entry(title: &str, status: ListStatus, watched_episodes: i32) -> ListEntry {
+        return ListEntry {
+            title: String::from(title),
+            aliases: vec![],
+            status: status,
+            watched_episodes: watched_episodes,
+        };
+    }
+
+    fn entries() -> Vec<ListEntry> {
+        return vec![
+            entry("Sousou no Frieren", ListStatus::Watching, 12),
+            entry("Dungeon Meshi", ListStatus::PlanToWatch, 0),
+            entry("Cowboy Bebop", ListStatus::Other, 26),
+        ];
+    }
+
+    #[test]
+    fn detects_the_list_format() {
+        assert_eq!(
+            ListFormat::detect("\n<?xml version=\"1.0\"?>\n<myanimelist></myanimelist>"),
+            Some(ListFormat::Mal)
+        );
+        assert_eq!(
+            ListFormat::detect(r#"{"data": {"MediaListCollection": {"lists": []}}}"#),
+            Some(ListFormat::AniList)
+        );
+        assert_eq!(
+            ListFormat::detect(r#"{"version": 1, "exported_at": 0, "anime": []}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn imports_only_what_is_watched_or_planned() {
+        let defaults = WatchDefaults {
+            quality: Quality::Res720,
+            subgroup: Some(String::from("SubsPlease")),
+        };
+        let import = convert(entries(), &defaults);
+        assert!(import.unresolved.is_empty());
+
+        let anime: Vec<(&str, &str, Quality, i32)> = import
+            .export
+            .anime
+            .iter()
+            .map(|anime| {
+                (
+                    anime.title.as_str(),
+                    anime.subgroup.as_str(),
+                    anime.quality,
+                    anime.last_seen_episode,
+                )
+            })
+            .collect();
+        // The watched count seeds the episode counter, nothing watched
+        // starts from scratch.
+        assert_eq!(
+            anime,
+            vec![
+                ("Sousou no Frieren", "SubsPlease", Quality::Res720, 12),
+                ("Dungeon Meshi", "SubsPlease", Quality::Res720, -1),
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_the_titles_that_need_a_subgroup() {
+        let defaults = WatchDefaults {
+            quality: Quality::Res1080,
+            subgroup: None,
+        };
+        let import = convert(entries(), &defaults);
+        assert!(import.export.anime.is_empty());
+        let unresolved: Vec<(&str, i32)> = import
+            .unresolved
+            .iter()
+            .map(|entry| (entry.title.as_str(), entry.last_seen_episode()))
+            .collect();
+        assert_eq!(
+            unresolved,
+            vec![("Sousou no Frieren", 12), ("Dungeon Meshi", -1)]
+        );
+    }
+}
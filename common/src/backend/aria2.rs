@@ -0,0 +1,272 @@
+use super::{
+    check_transport, BackendError, DownloadBackend, DownloadState, TorrentPayload, REQUEST_TIMEOUT,
+};
+use data_encoding::BASE64;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+// aria2 only reports this many stopped downloads per call, newest first.
+const MAX_STOPPED_LISTED: u64 = 1000;
+
+/*
+ * aria2 JSON-RPC, see https://aria2.github.io/manual/en/html/aria2c.html#rpc-interface.
+ * aria2 identifies downloads by its own GID rather than the info hash, so
+ * lookups go through its download lists. A magnet link first becomes a
+ * metadata download that is followed by the real download once the metadata
+ * is in, and both carry the same info hash.
+ */
+pub struct Aria2Backend {
+    agent: ureq::Agent,
+    url: String,
+    secret: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcError {
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcResponse {
+    #[serde(default)]
+    result: Value,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Download {
+    gid: String,
+    #[serde(default)]
+    info_hash: String,
+    status: String,
+    completed_length: String,
+    total_length: String,
+    #[serde(default)]
+    error_message: String,
+    #[serde(default)]
+    followed_by: Vec<String>,
+}
+
+const STATUS_KEYS: [&str; 7] = [
+    "gid",
+    "infoHash",
+    "status",
+    "completedLength",
+    "totalLength",
+    "errorMessage",
+    "followedBy",
+];
+
+impl Aria2Backend {
+    pub fn new(url: &str, secret: Option<&str>) -> Aria2Backend {
+        return Aria2Backend {
+            agent: ureq::Agent::new(),
+            url: url.to_string(),
+            secret: secret.map(String::from),
+        };
+    }
+
+    fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, BackendError> {
+        let mut all_params = vec![];
+        if let Some(secret) = &self.secret {
+            all_params.push(json!(format!("token:{}", secret)));
+        }
+        all_params.extend(params);
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": "animated",
+            "method": method,
+            "params": all_params,
+        });
+
+        let response = self
+            .agent
+            .post(&self.url)
+            .timeout(REQUEST_TIMEOUT)
+            .send_json(body);
+        let response = check_transport(response, &self.url)?;
+        // aria2 answers RPC errors with a 400 and an error object, so only
+        // give up on the status if the body is not a JSON-RPC reply.
+        let status = response.status();
+        let body = response.into_string().unwrap_or_default();
+        let reply: RpcResponse = match serde_json::from_str(&body) {
+            Ok(reply) => reply,
+            Err(_) if status >= 400 => {
+                return Err(BackendError::Rejected(format!(
+                    "HTTP {}: {}",
+                    status,
+                    body.trim()
+                )))
+            }
+            Err(e) => return Err(BackendError::Protocol(e.to_string())),
+        };
+
+        if let Some(error) = reply.error {
+            if error.message.contains("Unauthorized") {
+                return Err(BackendError::Unauthorized(String::from(
+                    "aria2 rejected the RPC secret.",
+                )));
+            }
+            return Err(BackendError::Rejected(error.message));
+        }
+        return Ok(reply.result);
+    }
+
+    fn downloads_for(&self, info_hash: &str) -> Result<Vec<Download>, BackendError> {
+        let lists = vec![
+            self.call("aria2.tellActive", vec![json!(STATUS_KEYS)])?,
+            self.call(
+                "aria2.tellWaiting",
+                vec![json!(0), json!(MAX_STOPPED_LISTED), json!(STATUS_KEYS)],
+            )?,
+            self.call(
+                "aria2.tellStopped",
+                vec![json!(0), json!(MAX_STOPPED_LISTED), json!(STATUS_KEYS)],
+            )?,
+        ];
+
+        let mut downloads = vec![];
+        for list in lists {
+            let list: Vec<Download> =
+                serde_json::from_value(list).map_err(|e| BackendError::Protocol(e.to_string()))?;
+            downloads.extend(
+                list.into_iter()
+                    .filter(|download| download.info_hash.eq_ignore_ascii_case(info_hash)),
+            );
+        }
+        return Ok(downloads);
+    }
+}
+
+impl DownloadBackend for Aria2Backend {
+    fn name(&self) -> &'static str {
+        return "aria2";
+    }
+
+    fn add(
+        &self,
+        _info_hash: &str,
+        _name: &str,
+        payload: &TorrentPayload,
+        download_path: &str,
+    ) -> Result<(), BackendError> {
+        let options = json!({ "dir": download_path });
+        match payload {
+            TorrentPayload::Magnet(magnet) => {
+                self.call("aria2.addUri", vec![json!([magnet]), options])?
+            }
+            TorrentPayload::TorrentFile(bytes) => self.call(
+                "aria2.addTorrent",
+                vec![json!(BASE64.encode(bytes)), json!([]), options],
+            )?,
+        };
+        return Ok(());
+    }
+
+    fn poll(&self, info_hash: &str) -> Result<DownloadState, BackendError> {
+        let downloads = self.downloads_for(info_hash)?;
+
+        // Skip metadata downloads that have already handed over to the real
+        // download.
+        let download = match downloads
+            .iter()
+            .find(|download| download.followed_by.is_empty())
+        {
+            Some(download) => download,
+            None if downloads.is_empty() => return Ok(DownloadState::Missing),
+            None => return Ok(DownloadState::Queued),
+        };
+
+        let downloaded_bytes: u64 = download.completed_length.parse().unwrap_or(0);
+        let total_bytes: u64 = download.total_length.parse().unwrap_or(0);
+        let state = match download.status.as_str() {
+            "complete" => DownloadState::Completed,
+            "removed" => DownloadState::Missing,
+            // Finished torrents stay active while aria2 seeds them.
+            _ if total_bytes > 0 && downloaded_bytes == total_bytes => DownloadState::Completed,
+            "error" => DownloadState::Failed(download.error_message.clone()),
+            "waiting" | "paused" => DownloadState::Queued,
+            _ => DownloadState::Downloading {
+                downloaded_bytes: downloaded_bytes,
+                total_bytes: total_bytes,
+            },
+        };
+        return Ok(state);
+    }
+
+    fn remove(&self, info_hash: &str, _delete_files: bool) -> Result<(), BackendError> {
+        // aria2 has no way to delete downloaded files over RPC.
+        for download in self.downloads_for(info_hash)? {
+            let method = match download.status.as_str() {
+                "active" | "waiting" | "paused" => "aria2.forceRemove",
+                _ => "aria2.removeDownloadResult",
+            };
+            self.call(method, vec![json!(download.gid)])?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stand_in::{Response, StandIn};
+
+    const INFO_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    // Lists one download with `status` among the active ones.
+    fn stand_in(status: &'static str, completed: &'static str, total: &'static str) -> StandIn {
+        return StandIn::start(move |request| {
+            let call: Value = serde_json::from_str(&request.body).unwrap();
+            let result = match call["method"].as_str().unwrap() {
+                "aria2.tellActive" => json!([{
+                    "gid": "2089b05ecca3d829",
+                    "infoHash": INFO_HASH,
+                    "status": status,
+                    "completedLength": completed,
+                    "totalLength": total,
+                }]),
+                _ => json!([]),
+            };
+            let reply = json!({ "jsonrpc": "2.0", "id": "animated", "result": result });
+            return Response::new(200, &reply.to_string());
+        });
+    }
+
+    #[test]
+    fn seeding_downloads_are_completed() {
+        let aria2 = stand_in("active", "1048576", "1048576");
+        let backend = Aria2Backend::new(&format!("{}/jsonrpc", aria2.url), Some("s3cret"));
+        assert_eq!(backend.poll(INFO_HASH).unwrap(), DownloadState::Completed);
+
+        let call: Value = serde_json::from_str(&aria2.requests()[0].body).unwrap();
+        assert_eq!(call["params"][0], "token:s3cret");
+    }
+
+    #[test]
+    fn active_downloads_report_progress() {
+        let aria2 = stand_in("active", "524288", "1048576");
+        let backend = Aria2Backend::new(&format!("{}/jsonrpc", aria2.url), None);
+        assert_eq!(
+            backend.poll(INFO_HASH).unwrap(),
+            DownloadState::Downloading {
+                downloaded_bytes: 524288,
+                total_bytes: 1048576,
+            }
+        );
+    }
+
+    #[test]
+    fn magnets_without_metadata_are_not_completed() {
+        let aria2 = stand_in("active", "0", "0");
+        let backend = Aria2Backend::new(&format!("{}/jsonrpc", aria2.url), None);
+        assert_eq!(
+            backend.poll(INFO_HASH).unwrap(),
+            DownloadState::Downloading {
+                downloaded_bytes: 0,
+                total_bytes: 0,
+            }
+        );
+    }
+}
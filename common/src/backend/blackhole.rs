@@ -0,0 +1,236 @@
+use super::{BackendError, DownloadBackend, DownloadState, TorrentPayload};
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{mpsc::channel, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/*
+ * Hands torrents to a client we cannot talk to by dropping them into a
+ * directory that the client watches, as a .torrent file or as a .magnet file
+ * holding the link. If the client moves finished downloads into a completed
+ * directory, that directory is watched to tell when a torrent is done, by
+ * its name or by its info hash appearing in a file name. Without one, a
+ * torrent counts as done as soon as the client has picked up its file.
+ */
+pub struct BlackholeBackend {
+    watch_dir: PathBuf,
+    completed_dir: Option<PathBuf>,
+    // Names of the entries in the completed directory, kept up to date by a
+    // filesystem watcher.
+    completed: Arc<Mutex<HashSet<String>>>,
+    // Torrent names by info hash, for torrents added since the daemon started.
+    names: Mutex<HashMap<String, String>>,
+    // Held so that the watcher keeps running, `None` if it failed to start.
+    watcher: Option<Mutex<RecommendedWatcher>>,
+}
+
+fn file_name(path: &Path) -> Option<String> {
+    return path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string());
+}
+
+fn scan(dir: &Path) -> HashSet<String> {
+    let mut names = HashSet::new();
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            names.insert(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    return names;
+}
+
+fn watch_completed(
+    dir: &Path,
+    completed: Arc<Mutex<HashSet<String>>>,
+) -> Option<RecommendedWatcher> {
+    let (tx, rx) = channel();
+    let mut completed_watcher = match watcher(tx, Duration::from_secs(2)) {
+        Ok(completed_watcher) => completed_watcher,
+        Err(e) => {
+            warn!(
+                "Failed to create a watcher for the completed directory: {:?}",
+                e
+            );
+            return None;
+        }
+    };
+    if let Err(e) = completed_watcher.watch(dir, RecursiveMode::NonRecursive) {
+        warn!(
+            "Failed to watch completed directory `{}`: {:?}. Falling back to scanning it.",
+            dir.display(),
+            e
+        );
+        return None;
+    }
+
+    let dir = dir.to_path_buf();
+    thread::spawn(move || {
+        // Ends once the watcher, and with it the sender, is dropped.
+        for event in rx {
+            let mut completed = completed.lock().unwrap();
+            match event {
+                DebouncedEvent::Create(path) => {
+                    completed.extend(file_name(&path));
+                }
+                DebouncedEvent::Rename(from, to) => {
+                    if let Some(name) = file_name(&from) {
+                        completed.remove(&name);
+                    }
+                    completed.extend(file_name(&to));
+                }
+                DebouncedEvent::Remove(path) => {
+                    if let Some(name) = file_name(&path) {
+                        completed.remove(&name);
+                    }
+                }
+                DebouncedEvent::Rescan => {
+                    *completed = scan(&dir);
+                }
+                _ => (),
+            }
+        }
+    });
+
+    return Some(completed_watcher);
+}
+
+impl BlackholeBackend {
+    pub fn new(watch_dir: &str, completed_dir: Option<&str>) -> BlackholeBackend {
+        let completed = Arc::new(Mutex::new(HashSet::new()));
+        let completed_dir = completed_dir.map(PathBuf::from);
+
+        let completed_watcher = match &completed_dir {
+            Some(dir) => {
+                *completed.lock().unwrap() = scan(dir);
+                watch_completed(dir, Arc::clone(&completed))
+            }
+            None => None,
+        };
+
+        return BlackholeBackend {
+            watch_dir: PathBuf::from(watch_dir),
+            completed_dir: completed_dir,
+            completed: completed,
+            names: Mutex::new(HashMap::new()),
+            watcher: completed_watcher.map(Mutex::new),
+        };
+    }
+
+    fn watch_files(&self, info_hash: &str) -> [PathBuf; 2] {
+        return [
+            self.watch_dir.join(format!("{}.torrent", info_hash)),
+            self.watch_dir.join(format!("{}.magnet", info_hash)),
+        ];
+    }
+
+    fn is_completed(&self, info_hash: &str) -> bool {
+        let completed_dir = match &self.completed_dir {
+            Some(completed_dir) => completed_dir,
+            None => return false,
+        };
+        let completed = if self.watcher.is_some() {
+            self.completed.lock().unwrap().clone()
+        } else {
+            scan(completed_dir)
+        };
+        let name = self.names.lock().unwrap().get(info_hash).cloned();
+
+        return completed.iter().any(|entry| {
+            let stem = Path::new(entry).file_stem().and_then(OsStr::to_str);
+            let matches_name = match &name {
+                Some(name) => entry == name || stem == Some(name.as_str()),
+                None => false,
+            };
+            matches_name || entry.to_lowercase().contains(info_hash)
+        });
+    }
+}
+
+impl DownloadBackend for BlackholeBackend {
+    fn name(&self) -> &'static str {
+        return "blackhole";
+    }
+
+    fn add(
+        &self,
+        info_hash: &str,
+        name: &str,
+        payload: &TorrentPayload,
+        _download_path: &str,
+    ) -> Result<(), BackendError> {
+        fs::create_dir_all(&self.watch_dir).map_err(|e| {
+            BackendError::Unreachable(format!(
+                "Failed to create watch directory `{}`: {}",
+                self.watch_dir.display(),
+                e
+            ))
+        })?;
+
+        let [torrent_path, magnet_path] = self.watch_files(info_hash);
+        let (path, contents) = match payload {
+            TorrentPayload::TorrentFile(bytes) => (torrent_path, bytes.clone()),
+            TorrentPayload::Magnet(magnet) => (magnet_path, format!("{}\n", magnet).into_bytes()),
+        };
+        // Written under a name the client ignores and then renamed, so that it
+        // never picks up a half written file.
+        let partial_path = path.with_extension("part");
+        fs::write(&partial_path, contents)
+            .and_then(|_| fs::rename(&partial_path, &path))
+            .map_err(|e| {
+                BackendError::Rejected(format!("Failed to write `{}`: {}", path.display(), e))
+            })?;
+
+        self.names
+            .lock()
+            .unwrap()
+            .insert(info_hash.to_string(), name.to_string());
+        return Ok(());
+    }
+
+    fn poll(&self, info_hash: &str) -> Result<DownloadState, BackendError> {
+        if self.is_completed(info_hash) {
+            return Ok(DownloadState::Completed);
+        }
+
+        let waiting = self.watch_files(info_hash).iter().any(|path| path.exists());
+        if waiting {
+            return Ok(DownloadState::Queued);
+        }
+        // Only torrents added since the daemon started are known to have been
+        // picked up. Anything else is dropped off again, which clients ignore
+        // if they already have the torrent.
+        if !self.names.lock().unwrap().contains_key(info_hash) {
+            return Ok(DownloadState::Missing);
+        }
+        if self.completed_dir.is_none() {
+            return Ok(DownloadState::Completed);
+        }
+        return Ok(DownloadState::Queued);
+    }
+
+    fn remove(&self, info_hash: &str, _delete_files: bool) -> Result<(), BackendError> {
+        // Whatever the client already picked up is out of our hands.
+        for path in self.watch_files(info_hash).iter() {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(BackendError::Rejected(format!(
+                        "Failed to remove `{}`: {}",
+                        path.display(),
+                        e
+                    )))
+                }
+                _ => (),
+            }
+        }
+        self.names.lock().unwrap().remove(info_hash);
+        return Ok(());
+    }
+}
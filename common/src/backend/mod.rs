@@ -0,0 +1,135 @@
+use crate::structs::BackendConfig;
+use std::{fmt, time::Duration};
+
+mod aria2;
+mod blackhole;
+mod qbittorrent;
+#[cfg(test)]
+mod stand_in;
+mod transmission;
+
+pub use aria2::Aria2Backend;
+pub use blackhole::BlackholeBackend;
+pub use qbittorrent::QBittorrentBackend;
+pub use transmission::TransmissionBackend;
+
+// How long to wait on a torrent client before treating it as unreachable.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub enum TorrentPayload {
+    Magnet(String),
+    // The raw bytes of a .torrent file.
+    TorrentFile(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadState {
+    Queued,
+    Downloading {
+        downloaded_bytes: u64,
+        total_bytes: u64,
+    },
+    Completed,
+    Failed(String),
+    // The backend does not know about the torrent, e.g. because it was removed
+    // by hand or the backend was restarted without keeping its state.
+    Missing,
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    Unreachable(String),
+    Unauthorized(String),
+    Rejected(String),
+    Protocol(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Unreachable(reason) => write!(f, "Backend is unreachable: {}", reason),
+            BackendError::Unauthorized(reason) => {
+                write!(f, "Backend refused our credentials: {}", reason)
+            }
+            BackendError::Rejected(reason) => write!(f, "Backend rejected the request: {}", reason),
+            BackendError::Protocol(reason) => {
+                write!(f, "Backend sent an unexpected response: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/*
+ * Something that can download torrents for the daemon. The daemon decides
+ * what to grab and then only talks to the backend through this trait, so it
+ * does not matter whether the torrent ends up in the built-in engine or in a
+ * client running elsewhere on the network. Torrents are always identified by
+ * their lowercase hex encoded info hash.
+ */
+pub trait DownloadBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // `name` is the name of the torrent, or of the release if the torrent does
+    // not have one yet.
+    fn add(
+        &self,
+        info_hash: &str,
+        name: &str,
+        payload: &TorrentPayload,
+        download_path: &str,
+    ) -> Result<(), BackendError>;
+
+    fn poll(&self, info_hash: &str) -> Result<DownloadState, BackendError>;
+
+    fn remove(&self, info_hash: &str, delete_files: bool) -> Result<(), BackendError>;
+}
+
+// Builds the backend for a client outside of animated. The built-in engine
+// lives in the daemon, so `None` is returned for it.
+pub fn external(config: &BackendConfig) -> Option<Box<dyn DownloadBackend>> {
+    match config {
+        BackendConfig::Builtin => None,
+        BackendConfig::QBittorrent {
+            url,
+            username,
+            password,
+        } => Some(Box::new(QBittorrentBackend::new(url, username, password))),
+        BackendConfig::Transmission {
+            url,
+            username,
+            password,
+        } => Some(Box::new(TransmissionBackend::new(
+            url,
+            username.as_deref(),
+            password.as_deref(),
+        ))),
+        BackendConfig::Aria2 { url, secret } => {
+            Some(Box::new(Aria2Backend::new(url, secret.as_deref())))
+        }
+        BackendConfig::Blackhole {
+            watch_dir,
+            completed_dir,
+        } => Some(Box::new(BlackholeBackend::new(
+            watch_dir,
+            completed_dir.as_deref(),
+        ))),
+    }
+}
+
+// Turns transport failures into errors and leaves HTTP error statuses for
+// the caller to interpret, since several clients use them for signalling.
+fn check_transport(response: ureq::Response, url: &str) -> Result<ureq::Response, BackendError> {
+    if let Some(e) = response.synthetic_error() {
+        return Err(BackendError::Unreachable(format!("{}: {}", url, e)));
+    }
+    return Ok(response);
+}
+
+fn unexpected_status(response: ureq::Response) -> BackendError {
+    let status = response.status();
+    let body = response.into_string().unwrap_or_default();
+    return BackendError::Rejected(format!("HTTP {}: {}", status, body.trim()));
+}
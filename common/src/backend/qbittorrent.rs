@@ -0,0 +1,247 @@
+use super::{
+    check_transport, unexpected_status, BackendError, DownloadBackend, DownloadState,
+    TorrentPayload, REQUEST_TIMEOUT,
+};
+use serde::Deserialize;
+
+/*
+ * qBittorrent Web API v2, see
+ * https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1).
+ * Logging in sets an `SID` cookie which the agent keeps for later requests.
+ * The cookie expires after a period of inactivity, so a 403 means we have
+ * to log in again.
+ */
+pub struct QBittorrentBackend {
+    agent: ureq::Agent,
+    url: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TorrentInfo {
+    state: String,
+    progress: f64,
+    size: u64,
+    amount_left: u64,
+}
+
+const MULTIPART_BOUNDARY: &str = "----animated-boundary-7MA4YWxkTrZu0gW";
+
+// The add endpoint only accepts multipart bodies, which ureq cannot build.
+fn multipart_body(text_fields: &[(&str, &str)], torrent: Option<&[u8]>) -> Vec<u8> {
+    let mut body = vec![];
+    for (name, value) in text_fields {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                MULTIPART_BOUNDARY, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    if let Some(torrent) = torrent {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"torrents\"; \
+                filename=\"animated.torrent\"\r\nContent-Type: application/x-bittorrent\r\n\r\n",
+                MULTIPART_BOUNDARY
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(torrent);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", MULTIPART_BOUNDARY).as_bytes());
+    return body;
+}
+
+impl QBittorrentBackend {
+    pub fn new(url: &str, username: &str, password: &str) -> QBittorrentBackend {
+        return QBittorrentBackend {
+            agent: ureq::Agent::new(),
+            url: url.trim_end_matches('/').to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        return format!("{}/api/v2/{}", self.url, path);
+    }
+
+    fn login(&self) -> Result<(), BackendError> {
+        let url = self.endpoint("auth/login");
+        let response = self
+            .agent
+            .post(&url)
+            // Requests without a matching Referer are refused by default.
+            .set("Referer", &self.url)
+            .timeout(REQUEST_TIMEOUT)
+            .send_form(&[("username", &self.username), ("password", &self.password)]);
+        let response = check_transport(response, &url)?;
+
+        // A failed login is still a 200, only the body tells them apart.
+        let body = response.into_string().unwrap_or_default();
+        if body.trim() != "Ok." {
+            return Err(BackendError::Unauthorized(format!(
+                "qBittorrent login as `{}` failed: {}",
+                self.username,
+                body.trim()
+            )));
+        }
+        return Ok(());
+    }
+
+    // Sends a request, logging in first if our session has expired.
+    fn send<F>(&self, path: &str, send: F) -> Result<ureq::Response, BackendError>
+    where
+        F: Fn(&mut ureq::Request) -> ureq::Response,
+    {
+        let url = self.endpoint(path);
+        for attempt in 0..2 {
+            let mut request = self.agent.post(&url);
+            request.set("Referer", &self.url).timeout(REQUEST_TIMEOUT);
+            let response = check_transport(send(&mut request), &url)?;
+
+            if response.status() == 403 && attempt == 0 {
+                self.login()?;
+                continue;
+            }
+            if response.error() {
+                return Err(unexpected_status(response));
+            }
+            return Ok(response);
+        }
+        return Err(BackendError::Unauthorized(String::from(
+            "qBittorrent refused the request after logging in.",
+        )));
+    }
+}
+
+impl DownloadBackend for QBittorrentBackend {
+    fn name(&self) -> &'static str {
+        return "qbittorrent";
+    }
+
+    fn add(
+        &self,
+        _info_hash: &str,
+        _name: &str,
+        payload: &TorrentPayload,
+        download_path: &str,
+    ) -> Result<(), BackendError> {
+        let body = match payload {
+            TorrentPayload::Magnet(magnet) => {
+                multipart_body(&[("urls", magnet), ("savepath", download_path)], None)
+            }
+            TorrentPayload::TorrentFile(bytes) => {
+                multipart_body(&[("savepath", download_path)], Some(bytes))
+            }
+        };
+        let content_type = format!("multipart/form-data; boundary={}", MULTIPART_BOUNDARY);
+
+        let response = self.send("torrents/add", |request| {
+            request.set("Content-Type", &content_type).send_bytes(&body)
+        })?;
+        let reply = response.into_string().unwrap_or_default();
+        if reply.trim() == "Fails." {
+            return Err(BackendError::Rejected(String::from(
+                "qBittorrent could not add the torrent.",
+            )));
+        }
+        return Ok(());
+    }
+
+    fn poll(&self, info_hash: &str) -> Result<DownloadState, BackendError> {
+        let response = self.send("torrents/info", |request| {
+            request.send_form(&[("hashes", info_hash)])
+        })?;
+        let torrents: Vec<TorrentInfo> = response
+            .into_json_deserialize()
+            .map_err(|e| BackendError::Protocol(e.to_string()))?;
+
+        let torrent = match torrents.into_iter().next() {
+            Some(torrent) => torrent,
+            None => return Ok(DownloadState::Missing),
+        };
+        let state = match torrent.state.as_str() {
+            "error" | "missingFiles" => {
+                DownloadState::Failed(format!("qBittorrent reports `{}`.", torrent.state))
+            }
+            "uploading" | "stalledUP" | "pausedUP" | "queuedUP" | "forcedUP" | "checkingUP" => {
+                DownloadState::Completed
+            }
+            "metaDL" | "queuedDL" | "allocating" => DownloadState::Queued,
+            _ if torrent.progress >= 1.0 => DownloadState::Completed,
+            _ => DownloadState::Downloading {
+                downloaded_bytes: torrent.size.saturating_sub(torrent.amount_left),
+                total_bytes: torrent.size,
+            },
+        };
+        return Ok(state);
+    }
+
+    fn remove(&self, info_hash: &str, delete_files: bool) -> Result<(), BackendError> {
+        let delete_files = if delete_files { "true" } else { "false" };
+        self.send("torrents/delete", |request| {
+            request.send_form(&[("hashes", info_hash), ("deleteFiles", delete_files)])
+        })?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stand_in::{Response, StandIn};
+
+    const INFO_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn logs_in_again_when_the_session_expired() {
+        let qbittorrent = StandIn::start(|request| match request.path.as_str() {
+            "/api/v2/auth/login" => {
+                Response::new(200, "Ok.").with_header("Set-Cookie", "SID=fresh; path=/")
+            }
+            "/api/v2/torrents/info" if request.header("Cookie") == Some("SID=fresh") => {
+                Response::new(
+                    200,
+                    r#"[{"state": "stalledUP", "progress": 1.0, "size": 10, "amount_left": 0}]"#,
+                )
+            }
+            _ => Response::new(403, "Forbidden"),
+        });
+        let backend = QBittorrentBackend::new(&qbittorrent.url, "admin", "hunter2");
+        assert_eq!(backend.poll(INFO_HASH).unwrap(), DownloadState::Completed);
+
+        let requests = qbittorrent.requests();
+        let paths: Vec<&str> = requests
+            .iter()
+            .map(|request| request.path.as_str())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/api/v2/torrents/info",
+                "/api/v2/auth/login",
+                "/api/v2/torrents/info"
+            ]
+        );
+        assert_eq!(requests[1].body, "username=admin&password=hunter2");
+        assert!(requests.iter().all(|request| request.method == "POST"));
+    }
+
+    #[test]
+    fn rejected_logins_are_unauthorized() {
+        let qbittorrent = StandIn::start(|request| match request.path.as_str() {
+            "/api/v2/auth/login" => Response::new(200, "Fails."),
+            _ => Response::new(403, "Forbidden"),
+        });
+        let backend = QBittorrentBackend::new(&qbittorrent.url, "admin", "wrong");
+        match backend.poll(INFO_HASH) {
+            Err(BackendError::Unauthorized(_)) => (),
+            result => panic!("Expected the login to be refused, got {:?}", result),
+        }
+    }
+}
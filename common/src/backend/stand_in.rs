@@ -0,0 +1,140 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/*
+ * A local HTTP server standing in for a torrent client in tests. Every
+ * request is answered by `respond` over a fresh connection, and kept so that
+ * tests can check what the backend sent.
+ */
+pub struct StandIn {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    // With lowercase names.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        return self
+            .headers
+            .iter()
+            .find(|(header, _)| *header == name)
+            .map(|(_, value)| value.as_str());
+    }
+}
+
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, body: &str) -> Response {
+        return Response {
+            status: status,
+            headers: vec![],
+            body: String::from(body),
+        };
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((String::from(name), String::from(value)));
+        return self;
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = String::from(parts.next()?);
+    let path = String::from(parts.next()?);
+
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_lowercase(), String::from(value.trim())));
+    }
+
+    let length = headers
+        .iter()
+        .find(|(name, _)| name == "content-length")
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    return Some(Request {
+        method: method,
+        path: path,
+        headers: headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    });
+}
+
+fn write_response(mut stream: &TcpStream, response: &Response) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} Stand-In\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    )?;
+    for (name, value) in response.headers.iter() {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    write!(stream, "\r\n{}", response.body)?;
+    return stream.flush();
+}
+
+impl StandIn {
+    pub fn start<F>(respond: F) -> StandIn
+    where
+        F: Fn(&Request) -> Response + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Tests can listen on localhost.");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                if let Some(request) = read_request(&stream) {
+                    let response = respond(&request);
+                    received.lock().unwrap().push(request);
+                    let _ = write_response(&stream, &response);
+                }
+            }
+        });
+        return StandIn {
+            url: url,
+            requests: requests,
+        };
+    }
+
+    // Everything received so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        return self.requests.lock().unwrap().clone();
+    }
+}
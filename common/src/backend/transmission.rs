@@ -0,0 +1,234 @@
+use super::{
+    check_transport, unexpected_status, BackendError, DownloadBackend, DownloadState,
+    TorrentPayload, REQUEST_TIMEOUT,
+};
+use data_encoding::BASE64;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Mutex;
+
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+/*
+ * Transmission RPC, see
+ * https://github.com/transmission/transmission/blob/main/docs/rpc-spec.md.
+ * Every request has to carry the session ID handed out by the server. The
+ * first request, and any request after the ID rotates, is answered with a
+ * 409 carrying the new ID, after which the request is sent again.
+ */
+pub struct TransmissionBackend {
+    agent: ureq::Agent,
+    url: String,
+    credentials: Option<(String, String)>,
+    session_id: Mutex<Option<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcResponse {
+    result: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TorrentFields {
+    error: i64,
+    error_string: String,
+    left_until_done: u64,
+    size_when_done: u64,
+    // 0 means stopped, 1 to 4 are checking and downloading, 5 and 6 seeding.
+    status: i64,
+}
+
+impl TransmissionBackend {
+    pub fn new(url: &str, username: Option<&str>, password: Option<&str>) -> TransmissionBackend {
+        let credentials = username.map(|username| {
+            (
+                username.to_string(),
+                password.unwrap_or_default().to_string(),
+            )
+        });
+        return TransmissionBackend {
+            agent: ureq::Agent::new(),
+            url: url.to_string(),
+            credentials: credentials,
+            session_id: Mutex::new(None),
+        };
+    }
+
+    fn call(&self, method: &str, arguments: Value) -> Result<Value, BackendError> {
+        let body = json!({ "method": method, "arguments": arguments });
+
+        for _ in 0..2 {
+            let mut request = self.agent.post(&self.url);
+            request.timeout(REQUEST_TIMEOUT);
+            if let Some((username, password)) = &self.credentials {
+                request.auth(username, password);
+            }
+            if let Some(session_id) = self.session_id.lock().unwrap().as_ref() {
+                request.set(SESSION_ID_HEADER, session_id);
+            }
+            let response = check_transport(request.send_json(body.clone()), &self.url)?;
+
+            match response.status() {
+                409 => {
+                    let session_id = response.header(SESSION_ID_HEADER).map(String::from);
+                    if session_id.is_none() {
+                        return Err(BackendError::Protocol(String::from(
+                            "Transmission sent a 409 without a session ID.",
+                        )));
+                    }
+                    *self.session_id.lock().unwrap() = session_id;
+                    continue;
+                }
+                401 => {
+                    return Err(BackendError::Unauthorized(String::from(
+                        "Transmission requires a valid username and password.",
+                    )))
+                }
+                _ if response.error() => return Err(unexpected_status(response)),
+                _ => (),
+            }
+
+            let response: RpcResponse = response
+                .into_json_deserialize()
+                .map_err(|e| BackendError::Protocol(e.to_string()))?;
+            if response.result != "success" {
+                return Err(BackendError::Rejected(response.result));
+            }
+            return Ok(response.arguments);
+        }
+        return Err(BackendError::Protocol(String::from(
+            "Transmission kept rotating its session ID.",
+        )));
+    }
+}
+
+impl DownloadBackend for TransmissionBackend {
+    fn name(&self) -> &'static str {
+        return "transmission";
+    }
+
+    fn add(
+        &self,
+        _info_hash: &str,
+        _name: &str,
+        payload: &TorrentPayload,
+        download_path: &str,
+    ) -> Result<(), BackendError> {
+        let arguments = match payload {
+            TorrentPayload::Magnet(magnet) => json!({
+                "filename": magnet,
+                "download-dir": download_path,
+            }),
+            TorrentPayload::TorrentFile(bytes) => json!({
+                "metainfo": BASE64.encode(bytes),
+                "download-dir": download_path,
+            }),
+        };
+        // Adding a torrent that already exists is reported as
+        // `torrent-duplicate` rather than as an error, which is what we want.
+        self.call("torrent-add", arguments)?;
+        return Ok(());
+    }
+
+    fn poll(&self, info_hash: &str) -> Result<DownloadState, BackendError> {
+        let arguments = self.call(
+            "torrent-get",
+            json!({
+                "ids": [info_hash],
+                "fields": ["error", "errorString", "leftUntilDone", "sizeWhenDone", "status"],
+            }),
+        )?;
+        let mut torrents: Vec<TorrentFields> =
+            serde_json::from_value(arguments["torrents"].clone())
+                .map_err(|e| BackendError::Protocol(e.to_string()))?;
+
+        let torrent = match torrents.pop() {
+            Some(torrent) => torrent,
+            None => return Ok(DownloadState::Missing),
+        };
+        // Errors 1 and 2 are tracker warnings and errors, which do not stop
+        // the download. Only local errors do.
+        if torrent.error == 3 {
+            return Ok(DownloadState::Failed(torrent.error_string));
+        }
+        if torrent.size_when_done == 0 {
+            // Still fetching the metadata of a magnet link.
+            return Ok(DownloadState::Queued);
+        }
+        if torrent.left_until_done == 0 {
+            return Ok(DownloadState::Completed);
+        }
+        if torrent.status == 3 {
+            return Ok(DownloadState::Queued);
+        }
+        return Ok(DownloadState::Downloading {
+            downloaded_bytes: torrent.size_when_done - torrent.left_until_done,
+            total_bytes: torrent.size_when_done,
+        });
+    }
+
+    fn remove(&self, info_hash: &str, delete_files: bool) -> Result<(), BackendError> {
+        self.call(
+            "torrent-remove",
+            json!({ "ids": [info_hash], "delete-local-data": delete_files }),
+        )?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stand_in::{Response, StandIn};
+
+    const INFO_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn hands_over_the_session_id() {
+        let transmission = StandIn::start(|request| {
+            if request.header(SESSION_ID_HEADER) != Some("session-1") {
+                return Response::new(409, "").with_header(SESSION_ID_HEADER, "session-1");
+            }
+            let torrents = json!({
+                "result": "success",
+                "arguments": { "torrents": [{
+                    "error": 0,
+                    "errorString": "",
+                    "leftUntilDone": 0,
+                    "sizeWhenDone": 1048576,
+                    "status": 6,
+                }]},
+            });
+            return Response::new(200, &torrents.to_string());
+        });
+        let backend = TransmissionBackend::new(
+            &format!("{}/transmission/rpc", transmission.url),
+            None,
+            None,
+        );
+        assert_eq!(backend.poll(INFO_HASH).unwrap(), DownloadState::Completed);
+        assert_eq!(transmission.requests().len(), 2);
+
+        // The ID is kept for later requests.
+        assert_eq!(backend.poll(INFO_HASH).unwrap(), DownloadState::Completed);
+        let requests = transmission.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].header(SESSION_ID_HEADER), Some("session-1"));
+        let call: Value = serde_json::from_str(&requests[2].body).unwrap();
+        assert_eq!(call["method"], "torrent-get");
+        assert_eq!(call["arguments"]["ids"][0], INFO_HASH);
+    }
+
+    #[test]
+    fn a_409_without_a_session_id_is_a_protocol_error() {
+        let transmission = StandIn::start(|_| Response::new(409, ""));
+        let backend = TransmissionBackend::new(&transmission.url, None, None);
+        match backend.poll(INFO_HASH) {
+            Err(BackendError::Protocol(_)) => (),
+            result => panic!("Expected a protocol error, got {:?}", result),
+        }
+    }
+}
@@ -1,16 +1,1196 @@
-use crate::constants::{ANIMATED_DIR, CONFIG_PATH};
-use crate::structs::Config;
+use crate::config_format::{self, Format};
+use crate::config_keys::{self, KeyError};
+use crate::config_migrations::{self, MigrationError};
+use crate::constants::{CONFIG_ENV_PREFIX, CONFIG_VERSION, SYSTEM_CONFIG_DIR};
+use crate::lock;
+use crate::paths;
+use crate::structs::{BackendConfig, Config};
+use serde_json::{json, Map, Value};
 
-use std::{fs, io::Error};
+use std::{
+    env,
+    ffi::CString,
+    fmt, fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
 
-pub fn read() -> Result<Config, Box<Error>> {
-    let file_contents: String = fs::read_to_string(CONFIG_PATH)?;
-    let config: Config = serde_json::from_str(&file_contents).unwrap();
-    return Ok(config);
+// Feeds polled more often than this get us rate limited, and anything slower
+// than a day is almost certainly a typo.
+const POLL_INTERVAL_RANGE_SECS: (u64, u64) = (60, 24 * 60 * 60);
+
+#[derive(Debug)]
+pub struct ConfigProblem {
+    // Dotted key of the offending value, e.g. `backend.url`.
+    pub field: String,
+    pub message: String,
+    // Where the value was set, e.g. `path:line:column` or an environment
+    // variable, unless it is a built-in default.
+    pub location: Option<String>,
+}
+
+// Where a config value can come from, lowest precedence first.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layer {
+    Default,
+    System(PathBuf),
+    User(PathBuf),
+    Env(String),
+    // The `key=value` given to `--set`.
+    Cli(String),
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::Default => write!(f, "built-in default"),
+            Layer::System(path) => write!(f, "system config `{}`", path.display()),
+            Layer::User(path) => write!(f, "user config `{}`", path.display()),
+            Layer::Env(name) => write!(f, "environment variable `{}`", name),
+            Layer::Cli(assignment) => write!(f, "`--set {}`", assignment),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    // The file is not valid JSON.
+    Syntax {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    // The file is valid JSON, but a value has the wrong type or is missing.
+    Type {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    // Every value has the right type, but some make no sense.
+    Invalid {
+        path: PathBuf,
+        problems: Vec<ConfigProblem>,
+    },
+    // Written by a newer animated than this one.
+    Unsupported {
+        path: PathBuf,
+        message: String,
+    },
+    // An environment variable or `--set` that cannot be applied, or layers
+    // that only fail once they are merged.
+    Override {
+        source: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            ConfigError::Io { source, .. } => source.kind() == io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            ConfigError::Syntax {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "{}:{}:{}: syntax error: {}",
+                path.display(),
+                line,
+                column,
+                message
+            ),
+            ConfigError::Type {
+                path,
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "{}:{}:{}: invalid value: {}",
+                path.display(),
+                line,
+                column,
+                message
+            ),
+            ConfigError::Invalid { path, problems } => {
+                write!(f, "{} has {} problem(s):", path.display(), problems.len())?;
+                for problem in problems {
+                    match &problem.location {
+                        Some(location) => write!(
+                            f,
+                            "\n  {}: `{}` {}",
+                            location, problem.field, problem.message
+                        )?,
+                        None => write!(f, "\n  `{}` {}", problem.field, problem.message)?,
+                    }
+                }
+                Ok(())
+            }
+            ConfigError::Unsupported { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
+            }
+            ConfigError::Override { source, message } => write!(f, "{}: {}", source, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn is_writable_dir(path: &Path) -> bool {
+    if !path.is_dir() {
+        return false;
+    }
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => return false,
+    };
+    return unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } == 0;
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    match url::Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(()),
+        Ok(parsed) => Err(format!(
+            "must be an http or https URL, not `{}`",
+            parsed.scheme()
+        )),
+        Err(e) => Err(format!("is not a valid URL: {}", e)),
+    }
+}
+
+fn problem(field: &str, message: String) -> ConfigProblem {
+    return ConfigProblem {
+        field: String::from(field),
+        message: message,
+        location: None,
+    };
+}
+
+pub fn validate(config: &Config) -> Vec<ConfigProblem> {
+    let mut problems = vec![];
+
+    // External clients download to paths on their own machine, which we
+    // cannot check from here.
+    if config.backend == BackendConfig::Builtin {
+        let download_path = Path::new(&config.download_path);
+        if !download_path.exists() {
+            problems.push(problem(
+                "download_path",
+                format!("`{}` does not exist", config.download_path),
+            ));
+        } else if !is_writable_dir(download_path) {
+            problems.push(problem(
+                "download_path",
+                format!("`{}` is not a writable directory", config.download_path),
+            ));
+        }
+    }
+
+    let (min_interval, max_interval) = POLL_INTERVAL_RANGE_SECS;
+    if config.poll_interval_secs < min_interval || config.poll_interval_secs > max_interval {
+        problems.push(problem(
+            "poll_interval_secs",
+            format!(
+                "must be between {} and {} seconds, not {}",
+                min_interval, max_interval, config.poll_interval_secs
+            ),
+        ));
+    }
+
+    if config.listen_port == 0 {
+        problems.push(problem(
+            "listen_port",
+            String::from("must be between 1 and 65535"),
+        ));
+    }
+
+    if config.retention.gc_interval_hours == 0 {
+        problems.push(problem(
+            "retention.gc_interval_hours",
+            String::from("must be at least 1"),
+        ));
+    }
+
+    if config.matching.threshold == 0 || config.matching.threshold > 100 {
+        problems.push(problem(
+            "matching.threshold",
+            format!(
+                "must be a percentage between 1 and 100, not {}",
+                config.matching.threshold
+            ),
+        ));
+    }
+
+    if let Some(database) = &config.metadata.database {
+        if !Path::new(database).is_file() {
+            problems.push(problem(
+                "metadata.database",
+                format!("`{}` is not a file", database),
+            ));
+        }
+    }
+    if config.metadata.refresh_hours == 0 {
+        problems.push(problem(
+            "metadata.refresh_hours",
+            String::from("must be at least 1"),
+        ));
+    }
+
+    if config.backups.interval_hours == 0 {
+        problems.push(problem(
+            "backups.interval_hours",
+            String::from("must be at least 1"),
+        ));
+    }
+    if config.backups.keep == 0 {
+        problems.push(problem(
+            "backups.keep",
+            String::from("must be at least 1, set `backups.enabled` to false instead"),
+        ));
+    }
+
+    if let Some(subgroup) = &config.defaults.subgroup {
+        if subgroup.trim().is_empty() {
+            problems.push(problem(
+                "defaults.subgroup",
+                String::from("must not be empty, unset it instead"),
+            ));
+        }
+    }
+
+    for feed in config.feeds.iter() {
+        if let Err(message) = check_url(feed) {
+            problems.push(problem("feeds", format!("entry `{}` {}", feed, message)));
+        }
+    }
+
+    match &config.backend {
+        BackendConfig::Builtin => (),
+        BackendConfig::QBittorrent { url, .. }
+        | BackendConfig::Transmission { url, .. }
+        | BackendConfig::Aria2 { url, .. } => {
+            if let Err(message) = check_url(url) {
+                problems.push(problem("backend.url", message));
+            }
+        }
+        BackendConfig::Blackhole { watch_dir, .. } => {
+            if watch_dir.trim().is_empty() {
+                problems.push(problem(
+                    "backend.watch_dir",
+                    String::from("must not be empty"),
+                ));
+            }
+        }
+    }
+
+    return problems;
+}
+
+// Parses the contents of a config file without validating them. `path` is
+// only used for error messages.
+pub fn deserialize(contents: &str, path: &Path) -> Result<Config, ConfigError> {
+    return config_format::deserialize(contents, Format::of(path), path);
 }
 
-pub fn write(config_contents: &str) -> Result<(), Box<Error>> {
-    fs::create_dir_all(ANIMATED_DIR)?;
-    fs::write(CONFIG_PATH, config_contents)?;
+#[derive(Debug)]
+pub struct MigrationPlan {
+    pub from: u32,
+    pub to: u32,
+    pub original: String,
+    pub migrated: String,
+}
+
+// Works out what upgrading `contents` to the current version would look
+// like, without touching any files. Returns `None` if it is already current.
+pub fn plan_migration(contents: &str, path: &Path) -> Result<Option<MigrationPlan>, ConfigError> {
+    let format = Format::of(path);
+    let mut document = config_format::parse(contents, format, path)?;
+
+    let from = match config_migrations::migrate(&mut document) {
+        Ok(from) => from,
+        Err(MigrationError::InvalidVersion(version)) => {
+            let (line, column) =
+                config_format::locate_key(contents, format, "version").unwrap_or((1, 1));
+            return Err(ConfigError::Type {
+                path: path.to_path_buf(),
+                line: line,
+                column: column,
+                message: MigrationError::InvalidVersion(version).to_string(),
+            });
+        }
+        Err(e) => {
+            return Err(ConfigError::Unsupported {
+                path: path.to_path_buf(),
+                message: e.to_string(),
+            })
+        }
+    };
+    if from == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    return Ok(Some(MigrationPlan {
+        from: from,
+        to: CONFIG_VERSION,
+        original: String::from(contents),
+        migrated: config_format::rewrite(contents, format, path, &document),
+    }));
+}
+
+// Copies the config file aside, e.g. to `config.json.v1.bak`, and writes the
+// migrated config in its place. Returns where the original went.
+pub fn apply_migration(plan: &MigrationPlan, path: &Path) -> Result<PathBuf, io::Error> {
+    let mut backup = PathBuf::from(format!("{}.v{}.bak", path.display(), plan.from));
+    if backup.exists() {
+        backup = PathBuf::from(format!(
+            "{}.v{}.{}.bak",
+            path.display(),
+            plan.from,
+            lock::now()
+        ));
+    }
+    fs::write(&backup, &plan.original)?;
+    fs::write(path, &plan.migrated)?;
+    return Ok(backup);
+}
+
+#[derive(Debug)]
+pub enum MigrationOutcome {
+    Rewritten { from: u32, backup: PathBuf },
+    // The file could not be rewritten, e.g. because it belongs to root, so
+    // the migrated config was only used in memory.
+    NotWritten { from: u32, reason: io::Error },
+}
+
+lazy_static! {
+    // `--set key=value` arguments, the topmost layer.
+    static ref CLI_OVERRIDES: RwLock<Vec<String>> = RwLock::new(vec![]);
+}
+
+// Binaries pass their `--set` arguments here before reading the config.
+pub fn set_overrides(overrides: Vec<String>) {
+    *CLI_OVERRIDES.write().unwrap() = overrides;
+}
+
+pub fn system_config_file() -> PathBuf {
+    return paths::find_config_file(Path::new(SYSTEM_CONFIG_DIR));
+}
+
+// Overlays `overlay` onto `base` key by key. A backend of a different type
+// replaces the one below it instead, since their fields do not mix.
+fn merge(base: &mut Value, overlay: &Value) {
+    if let (Value::Object(base), Value::Object(overlay)) = (&mut *base, overlay) {
+        let same_type = overlay
+            .get("type")
+            .map_or(true, |overlay_type| base.get("type") == Some(overlay_type));
+        if same_type {
+            for (key, value) in overlay.iter() {
+                match base.get_mut(key) {
+                    Some(base_value) => merge(base_value, value),
+                    None => {
+                        base.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            return;
+        }
+    }
+    *base = overlay.clone();
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>, ConfigError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ConfigError::Io {
+            path: path.to_path_buf(),
+            source: e,
+        }),
+    }
+}
+
+// A config file as a layer, upgraded to the current version in memory.
+struct FileLayer {
+    layer: Layer,
+    path: PathBuf,
+    contents: String,
+}
+
+impl FileLayer {
+    fn new(layer: Layer, path: &Path, contents: String) -> Result<FileLayer, ConfigError> {
+        let contents = match plan_migration(&contents, path)? {
+            Some(plan) => plan.migrated,
+            None => contents,
+        };
+        return Ok(FileLayer {
+            layer: layer,
+            path: path.to_path_buf(),
+            contents: contents,
+        });
+    }
+
+    fn document(&self) -> Value {
+        // `plan_migration` has already checked that this parses.
+        return config_format::parse(&self.contents, Format::of(&self.path), &self.path).unwrap();
+    }
+}
+
+// Turns `ANIMATED__BACKEND__URL` into `backend.url`.
+fn env_key(name: &str) -> Option<String> {
+    let rest = name.strip_prefix(CONFIG_ENV_PREFIX)?;
+    if rest.is_empty() {
+        return None;
+    }
+    return Some(
+        rest.split("__")
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join("."),
+    );
+}
+
+// Builds the layer for one environment variable or `--set`. The value gets
+// the type of what it replaces, or is taken as JSON, or else as a string, if
+// the layers below do not have the key, e.g. the URL of a backend whose type
+// is set in the same breath.
+fn override_layer(
+    layer: Layer,
+    below: &Value,
+    key: &str,
+    raw: &str,
+) -> Result<(Layer, Value), ConfigError> {
+    let value = match config_keys::coerce(below, key, raw) {
+        Ok(value) => value,
+        Err(KeyError::Unknown(_)) => {
+            serde_json::from_str(raw).unwrap_or_else(|_| Value::String(String::from(raw)))
+        }
+        Err(e) => {
+            return Err(ConfigError::Override {
+                source: layer.to_string(),
+                message: e.to_string(),
+            })
+        }
+    };
+    let mut document = Value::Object(Map::new());
+    config_keys::set(&mut document, key, value);
+    return Ok((layer, document));
+}
+
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    // Values that have the right type but make no sense.
+    pub problems: Vec<ConfigProblem>,
+    // Keys animated does not understand and ignores, with where they are.
+    pub unknown_keys: Vec<(String, Layer)>,
+    pub migration: Option<MigrationOutcome>,
+    // Every layer that was merged, lowest precedence first.
+    pub layers: Vec<(Layer, Value)>,
+    // Where the user config is, or would be.
+    path: PathBuf,
+}
+
+impl LoadedConfig {
+    // The layers that set `key`, lowest precedence first. The last one
+    // supplied the value in use.
+    pub fn explain(&self, key: &str) -> Vec<(&Layer, &Value)> {
+        return self
+            .layers
+            .iter()
+            .filter_map(|(layer, document)| {
+                config_keys::lookup(document, key).map(|value| (layer, value))
+            })
+            .collect();
+    }
+
+    pub fn into_valid(self) -> Result<Config, ConfigError> {
+        if !self.problems.is_empty() {
+            return Err(ConfigError::Invalid {
+                path: self.path,
+                problems: self.problems,
+            });
+        }
+        return Ok(self.config);
+    }
+}
+
+// Merges the built-in defaults, the system config, the user config, then
+// `ANIMATED__SECTION__KEY` environment variables and finally `--set`
+// arguments. `user` is the user config as it would be written to `path`.
+fn assemble(user: Option<String>, path: &Path) -> Result<LoadedConfig, ConfigError> {
+    let mut files = vec![];
+    let system_path = system_config_file();
+    // Root uses the system config as its user config.
+    if system_path != path {
+        if let Some(contents) = read_optional(&system_path)? {
+            files.push(FileLayer::new(
+                Layer::System(system_path.clone()),
+                &system_path,
+                contents,
+            )?);
+        }
+    }
+    if let Some(contents) = user {
+        files.push(FileLayer::new(
+            Layer::User(path.to_path_buf()),
+            path,
+            contents,
+        )?);
+    }
+    let cli = CLI_OVERRIDES.read().unwrap().clone();
+    return merge_layers(files, env::vars().collect(), &cli, path);
+}
+
+// Does the work of `assemble` once the files, the environment and the
+// `--set` arguments have been read.
+fn merge_layers(
+    files: Vec<FileLayer>,
+    vars: Vec<(String, String)>,
+    cli: &[String],
+    path: &Path,
+) -> Result<LoadedConfig, ConfigError> {
+    let mut layers = vec![(
+        Layer::Default,
+        serde_json::to_value(Config::default()).unwrap(),
+    )];
+    layers.extend(
+        files
+            .iter()
+            .map(|file| (file.layer.clone(), file.document())),
+    );
+
+    let mut merged = Value::Object(Map::new());
+    for (_, document) in layers.iter() {
+        merge(&mut merged, document);
+    }
+
+    let mut overrides: Vec<(Layer, String, String)> = vars
+        .into_iter()
+        .filter_map(|(name, value)| env_key(&name).map(|key| (Layer::Env(name), key, value)))
+        .collect();
+    overrides.sort_by(|a, b| a.1.cmp(&b.1));
+    for assignment in cli.iter() {
+        let layer = Layer::Cli(assignment.clone());
+        match assignment.find('=') {
+            Some(index) => overrides.push((
+                layer,
+                String::from(assignment[..index].trim()),
+                String::from(&assignment[index + 1..]),
+            )),
+            None => {
+                return Err(ConfigError::Override {
+                    source: layer.to_string(),
+                    message: String::from("expected `key=value`"),
+                })
+            }
+        }
+    }
+    for (layer, key, raw) in overrides {
+        let (layer, document) = override_layer(layer, &merged, &key, &raw)?;
+        merge(&mut merged, &document);
+        layers.push((layer, document));
+    }
+
+    let config: Config = match serde_json::from_value(merged) {
+        Ok(config) => config,
+        Err(e) => {
+            // Point at the file if it is broken on its own, since then we
+            // know the line.
+            for file in files.iter().rev() {
+                deserialize(&file.contents, &file.path)?;
+            }
+            return Err(ConfigError::Override {
+                source: String::from("merged config"),
+                message: e.to_string(),
+            });
+        }
+    };
+
+    let understood = serde_json::to_value(&config).unwrap();
+    let mut unknown_keys = vec![];
+    for (index, (layer, document)) in layers.iter().enumerate() {
+        for key in config_migrations::unknown_keys(document, &understood) {
+            if !is_replaced(&layers[index + 1..], &key) {
+                unknown_keys.push((key, layer.clone()));
+            }
+        }
+    }
+
+    let mut loaded = LoadedConfig {
+        config: config,
+        problems: vec![],
+        unknown_keys: unknown_keys,
+        migration: None,
+        layers: layers,
+        path: path.to_path_buf(),
+    };
+    loaded.problems = validate(&loaded.config)
+        .into_iter()
+        .map(|problem| {
+            let location = locate_problem(&loaded, &files, &problem.field);
+            ConfigProblem {
+                location: location,
+                ..problem
+            }
+        })
+        .collect();
+    return Ok(loaded);
+}
+
+// Whether a backend from a higher layer replaced the object holding `key`,
+// e.g. the `username` of a qBittorrent backend the user swapped for aria2.
+fn is_replaced(higher: &[(Layer, Value)], key: &str) -> bool {
+    let parents: Vec<&str> = key
+        .match_indices('.')
+        .map(|(index, _)| &key[..index])
+        .collect();
+    return higher.iter().any(|(_, document)| {
+        parents.iter().any(|parent| {
+            config_keys::lookup(document, parent).map_or(false, |value| value.get("type").is_some())
+        })
+    });
+}
+
+fn locate_problem(loaded: &LoadedConfig, files: &[FileLayer], field: &str) -> Option<String> {
+    let (layer, _) = loaded.explain(field).pop()?;
+    match layer {
+        Layer::Default => None,
+        Layer::System(_) | Layer::User(_) => {
+            let file = files.iter().find(|file| file.layer == *layer)?;
+            let name = field.rsplit('.').next().unwrap();
+            Some(
+                match config_format::locate_key(&file.contents, Format::of(&file.path), name) {
+                    Some((line, column)) => format!("{}:{}:{}", file.path.display(), line, column),
+                    None => file.path.display().to_string(),
+                },
+            )
+        }
+        Layer::Env(_) | Layer::Cli(_) => Some(layer.to_string()),
+    }
+}
+
+// Loads every config layer. Migrates the user config on disk if it is out of
+// date, but leaves validation to the caller.
+pub fn load() -> Result<LoadedConfig, ConfigError> {
+    let path = paths::get().config_file;
+    let mut contents = read_optional(&path)?;
+
+    let mut migration = None;
+    if let Some(original) = contents.as_ref() {
+        if let Some(plan) = plan_migration(original, &path)? {
+            migration = Some(match apply_migration(&plan, &path) {
+                Ok(backup) => MigrationOutcome::Rewritten {
+                    from: plan.from,
+                    backup: backup,
+                },
+                Err(e) => MigrationOutcome::NotWritten {
+                    from: plan.from,
+                    reason: e,
+                },
+            });
+            contents = Some(plan.migrated);
+        }
+    }
+
+    // Either file is enough on its own.
+    if contents.is_none() && !system_config_file().exists() {
+        return Err(ConfigError::Io {
+            path: path,
+            source: io::Error::from(io::ErrorKind::NotFound),
+        });
+    }
+
+    let mut loaded = assemble(contents, &path)?;
+    loaded.migration = migration;
+    return Ok(loaded);
+}
+
+// Checks `contents` as a replacement for the user config, together with the
+// other layers. `path` is only used for error messages.
+pub fn check(contents: &str, path: &Path) -> Result<Config, ConfigError> {
+    let user_path = paths::get().config_file;
+    let loaded = assemble(Some(String::from(contents)), &user_path).map_err(|e| match e {
+        // Errors about the user config should point at what was checked.
+        ConfigError::Syntax {
+            path: error_path,
+            line,
+            column,
+            message,
+        } if error_path == user_path => ConfigError::Syntax {
+            path: path.to_path_buf(),
+            line: line,
+            column: column,
+            message: message,
+        },
+        ConfigError::Type {
+            path: error_path,
+            line,
+            column,
+            message,
+        } if error_path == user_path => ConfigError::Type {
+            path: path.to_path_buf(),
+            line: line,
+            column: column,
+            message: message,
+        },
+        e => e,
+    })?;
+    return loaded.into_valid();
+}
+
+// Like `load`, but reports migrations and unknown keys through the log.
+pub fn read() -> Result<Config, ConfigError> {
+    let loaded = load()?;
+    let path = paths::get().config_file;
+
+    match &loaded.migration {
+        Some(MigrationOutcome::Rewritten { from, backup }) => info!(
+            "Migrated config `{}` from version {} to {}. The original was saved to `{}`.",
+            path.display(),
+            from,
+            CONFIG_VERSION,
+            backup.display()
+        ),
+        Some(MigrationOutcome::NotWritten { from, reason }) => warn!(
+            "Config `{}` is at version {} and could not be migrated to {}: {}. \
+            Using the migrated config without saving it.",
+            path.display(),
+            from,
+            CONFIG_VERSION,
+            reason
+        ),
+        None => (),
+    }
+    for (key, layer) in loaded.unknown_keys.iter() {
+        warn!("Ignoring unknown key `{}` in {}.", key, layer);
+    }
+
+    return loaded.into_valid();
+}
+
+// One value that differs between two configs, by dotted key. `None` means
+// the key is not there, e.g. `backend.url` after switching to the builtin
+// backend.
+#[derive(Debug, Clone)]
+pub struct FieldChange {
+    pub key: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+impl FieldChange {
+    // Whether this is `key` or something inside it, e.g. `backend` covers
+    // `backend.url`.
+    pub fn is(&self, key: &str) -> bool {
+        return self.key == key || self.key.starts_with(&format!("{}.", key));
+    }
+}
+
+// Passwords and RPC secrets end up in the daemon log otherwise.
+fn display_field(key: &str, value: &Option<Value>) -> String {
+    match value {
+        None => String::from("(unset)"),
+        Some(_) if key.ends_with("password") || key.ends_with("secret") => String::from("(hidden)"),
+        Some(value) => value.to_string(),
+    }
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "`{}`: {} -> {}",
+            self.key,
+            display_field(&self.key, &self.old),
+            display_field(&self.key, &self.new)
+        );
+    }
+}
+
+// Every value that differs between `old` and `new`. Lists are compared as a
+// whole.
+pub fn diff(old: &Config, new: &Config) -> Vec<FieldChange> {
+    let old_fields = config_keys::flatten(&serde_json::to_value(old).unwrap());
+    let new_fields = config_keys::flatten(&serde_json::to_value(new).unwrap());
+    let find = |fields: &[(String, Value)], key: &str| {
+        fields
+            .iter()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value.clone())
+    };
+
+    let mut changes = vec![];
+    for (key, value) in new_fields.iter() {
+        let old_value = find(&old_fields, key);
+        if old_value.as_ref() != Some(value) {
+            changes.push(FieldChange {
+                key: key.clone(),
+                old: old_value,
+                new: Some(value.clone()),
+            });
+        }
+    }
+    for (key, value) in old_fields.iter() {
+        if find(&new_fields, key).is_none() {
+            changes.push(FieldChange {
+                key: key.clone(),
+                old: Some(value.clone()),
+                new: None,
+            });
+        }
+    }
+    return changes;
+}
+
+// What a new user config starts out as. Over a system config it only records
+// the version, so that everything else keeps coming from the system config.
+pub fn new_user_config() -> String {
+    let document =
+        if paths::get().config_file != system_config_file() && system_config_file().exists() {
+            json!({ "version": CONFIG_VERSION })
+        } else {
+            serde_json::to_value(Config::default()).unwrap()
+        };
+    return config_format::render(&document, Format::of(&paths::get().config_file));
+}
+
+pub fn write(config_contents: &str) -> Result<(), io::Error> {
+    let config_file = paths::get().config_file;
+    if let Some(config_dir) = config_file.parent() {
+        fs::create_dir_all(config_dir)?;
+    }
+    fs::write(config_file, config_contents)?;
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The defaults, with a download path that exists wherever tests run.
+    fn valid_config() -> Config {
+        return Config {
+            download_path: env::temp_dir().display().to_string(),
+            ..Config::default()
+        };
+    }
+
+    fn fields(problems: &[ConfigProblem]) -> Vec<&str> {
+        return problems
+            .iter()
+            .map(|problem| problem.field.as_str())
+            .collect();
+    }
+
+    #[test]
+    fn accepts_the_defaults() {
+        assert!(validate(&valid_config()).is_empty());
+    }
+
+    #[test]
+    fn names_every_invalid_field() {
+        let mut config = valid_config();
+        config.download_path = String::from("/does/not/exist");
+        config.poll_interval_secs = 5;
+        config.listen_port = 0;
+        config.matching.threshold = 101;
+        config.feeds = vec![String::from("ftp://example.org/rss")];
+
+        assert_eq!(
+            fields(&validate(&config)),
+            vec![
+                "download_path",
+                "poll_interval_secs",
+                "listen_port",
+                "matching.threshold",
+                "feeds",
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_the_download_path_of_external_clients_alone() {
+        let mut config = valid_config();
+        config.download_path = String::from("/does/not/exist");
+        config.backend = BackendConfig::QBittorrent {
+            url: String::from("not a url"),
+            username: String::from("admin"),
+            password: String::from("secret"),
+        };
+
+        assert_eq!(fields(&validate(&config)), vec!["backend.url"]);
+    }
+
+    #[test]
+    fn reports_where_a_syntax_error_is() {
+        let contents = "{\n  \"poll_interval_secs\": 60,\n  \"feeds\": [,]\n}\n";
+        match deserialize(contents, Path::new("config.json")) {
+            Err(ConfigError::Syntax {
+                path, line, column, ..
+            }) => {
+                assert_eq!(path, Path::new("config.json"));
+                assert_eq!((line, column), (3, 13));
+            }
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_where_a_value_of_the_wrong_type_is() {
+        let contents =
+            "{\n  \"download_path\": \"/tmp\",\n  \"poll_interval_secs\": \"often\"\n}\n";
+        match deserialize(contents, Path::new("config.json")) {
+            Err(ConfigError::Type { line, message, .. }) => {
+                assert_eq!(line, 3);
+                assert!(message.contains("often"), "{}", message);
+                assert!(!message.contains(" at line "), "{}", message);
+            }
+            other => panic!("expected a type error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn migrates_old_files_and_keeps_the_original() {
+        let dir = env::temp_dir().join(format!("animated-migration-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        let original = "{\n  \"download_path\": \"/srv/anime\"\n}\n";
+        fs::write(&path, original).unwrap();
+
+        let plan = plan_migration(original, &path).unwrap().unwrap();
+        assert_eq!((plan.from, plan.to), (1, CONFIG_VERSION));
+        let migrated = deserialize(&plan.migrated, &path).unwrap();
+        assert_eq!(migrated.version, CONFIG_VERSION);
+        assert_eq!(migrated.download_path, "/srv/anime");
+
+        let backup = apply_migration(&plan, &path).unwrap();
+        assert_eq!(backup, dir.join("config.json.v1.bak"));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), original);
+        assert_eq!(fs::read_to_string(&path).unwrap(), plan.migrated);
+        assert!(plan_migration(&plan.migrated, &path).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn points_at_a_malformed_version() {
+        let contents = "{\n  \"download_path\": \"/srv/anime\",\n  \"version\": \"two\"\n}\n";
+        match plan_migration(contents, Path::new("config.json")) {
+            Err(ConfigError::Type { line, column, .. }) => assert_eq!((line, column), (3, 3)),
+            other => panic!("expected a type error, got {:?}", other),
+        }
+        match plan_migration("{ \"version\": 99 }", Path::new("config.json")) {
+            Err(ConfigError::Unsupported { .. }) => (),
+            other => panic!("expected an unsupported version, got {:?}", other),
+        }
+    }
+
+    const SYSTEM_PATH: &str = "/etc/animated/config.json";
+    const USER_PATH: &str = "/home/user/.config/animated/config.json";
+
+    // Merges the given layers the way `assemble` would.
+    fn layered(
+        system: Option<&str>,
+        user: Option<&str>,
+        vars: &[(&str, &str)],
+        cli: &[&str],
+    ) -> Result<LoadedConfig, ConfigError> {
+        let mut files = vec![];
+        if let Some(contents) = system {
+            let path = Path::new(SYSTEM_PATH);
+            files.push(FileLayer::new(
+                Layer::System(path.to_path_buf()),
+                path,
+                String::from(contents),
+            )?);
+        }
+        if let Some(contents) = user {
+            let path = Path::new(USER_PATH);
+            files.push(FileLayer::new(
+                Layer::User(path.to_path_buf()),
+                path,
+                String::from(contents),
+            )?);
+        }
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (String::from(*name), String::from(*value)))
+            .collect();
+        let cli: Vec<String> = cli
+            .iter()
+            .map(|assignment| String::from(*assignment))
+            .collect();
+        return merge_layers(files, vars, &cli, Path::new(USER_PATH));
+    }
+
+    // The layer that supplied `key`.
+    fn source(loaded: &LoadedConfig, key: &str) -> Layer {
+        return loaded.explain(key).pop().unwrap().0.clone();
+    }
+
+    #[test]
+    fn starts_from_the_defaults() {
+        let loaded = layered(None, None, &[], &[]).unwrap();
+        assert_eq!(
+            loaded.config.poll_interval_secs,
+            Config::default().poll_interval_secs
+        );
+        assert_eq!(source(&loaded, "poll_interval_secs"), Layer::Default);
+    }
+
+    #[test]
+    fn system_config_overrides_the_defaults() {
+        let loaded = layered(Some("{ \"poll_interval_secs\": 120 }"), None, &[], &[]).unwrap();
+        assert_eq!(loaded.config.poll_interval_secs, 120);
+        assert_eq!(
+            source(&loaded, "poll_interval_secs"),
+            Layer::System(PathBuf::from(SYSTEM_PATH))
+        );
+        assert_eq!(loaded.config.listen_port, Config::default().listen_port);
+    }
+
+    #[test]
+    fn user_config_overrides_the_system_config() {
+        let loaded = layered(
+            Some("{ \"poll_interval_secs\": 120, \"retention\": { \"tombstone_days\": 3 } }"),
+            Some("{ \"poll_interval_secs\": 180, \"retention\": { \"gc_interval_hours\": 2 } }"),
+            &[],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(loaded.config.poll_interval_secs, 180);
+        assert_eq!(
+            source(&loaded, "poll_interval_secs"),
+            Layer::User(PathBuf::from(USER_PATH))
+        );
+        // Sections are merged key by key.
+        assert_eq!(loaded.config.retention.tombstone_days, 3);
+        assert_eq!(loaded.config.retention.gc_interval_hours, 2);
+    }
+
+    #[test]
+    fn environment_overrides_the_user_config() {
+        let loaded = layered(
+            None,
+            Some("{ \"poll_interval_secs\": 180 }"),
+            &[
+                ("ANIMATED__POLL_INTERVAL_SECS", "240"),
+                ("ANIMATED__RETENTION__TOMBSTONE_DAYS", "9"),
+                ("UNRELATED", "1"),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(loaded.config.poll_interval_secs, 240);
+        assert_eq!(loaded.config.retention.tombstone_days, 9);
+        assert_eq!(
+            source(&loaded, "poll_interval_secs"),
+            Layer::Env(String::from("ANIMATED__POLL_INTERVAL_SECS"))
+        );
+        assert_eq!(loaded.explain("poll_interval_secs").len(), 3);
+    }
+
+    #[test]
+    fn set_overrides_the_environment() {
+        let loaded = layered(
+            None,
+            None,
+            &[("ANIMATED__POLL_INTERVAL_SECS", "240")],
+            &["poll_interval_secs=300"],
+        )
+        .unwrap();
+        assert_eq!(loaded.config.poll_interval_secs, 300);
+        assert_eq!(
+            source(&loaded, "poll_interval_secs"),
+            Layer::Cli(String::from("poll_interval_secs=300"))
+        );
+    }
+
+    #[test]
+    fn a_backend_of_another_type_replaces_the_one_below() {
+        let system = r#"{
+            "backend": {
+                "type": "qbittorrent",
+                "url": "http://nas:8080",
+                "username": "admin",
+                "password": "secret"
+            }
+        }"#;
+        let loaded = layered(
+            Some(system),
+            None,
+            &[],
+            &["backend.type=blackhole", "backend.watch_dir=/srv/watch"],
+        )
+        .unwrap();
+        match &loaded.config.backend {
+            BackendConfig::Blackhole { watch_dir, .. } => assert_eq!(watch_dir, "/srv/watch"),
+            other => panic!("expected the blackhole backend, got {:?}", other),
+        }
+        assert!(loaded.unknown_keys.is_empty(), "{:?}", loaded.unknown_keys);
+    }
+
+    #[test]
+    fn rejects_malformed_overrides() {
+        match layered(None, None, &[], &["poll_interval_secs"]) {
+            Err(ConfigError::Override { .. }) => (),
+            other => panic!("expected an override error, got {:?}", other),
+        }
+        match layered(None, None, &[("ANIMATED__LISTEN_PORT", "many")], &[]) {
+            Err(ConfigError::Override { source, .. }) => {
+                assert!(source.contains("ANIMATED__LISTEN_PORT"), "{}", source)
+            }
+            other => panic!("expected an override error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locates_problems_in_the_layer_that_set_them() {
+        let loaded = layered(
+            None,
+            Some("{\n  \"version\": 7,\n  \"poll_interval_secs\": 5\n}\n"),
+            &[("ANIMATED__LISTEN_PORT", "0")],
+            &[],
+        )
+        .unwrap();
+        let locations: Vec<(&str, Option<&str>)> = loaded
+            .problems
+            .iter()
+            .filter(|problem| problem.field != "download_path")
+            .map(|problem| (problem.field.as_str(), problem.location.as_deref()))
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                (
+                    "poll_interval_secs",
+                    Some("/home/user/.config/animated/config.json:3:3")
+                ),
+                (
+                    "listen_port",
+                    Some("environment variable `ANIMATED__LISTEN_PORT`")
+                ),
+            ]
+        );
+    }
+}
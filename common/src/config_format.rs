@@ -0,0 +1,468 @@
+use crate::config::ConfigError;
+use crate::config_keys;
+use crate::structs::Config;
+use serde_json::{Map, Value};
+use std::{fmt, path::Path, str::FromStr};
+
+/*
+ * The file formats a config can be written in, chosen by file extension.
+ * Everything else works on the JSON form of a config, so each format only
+ * has to get documents in and out of a `serde_json::Value`.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+// In the order they are looked for when no config path is given.
+pub const EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+impl Format {
+    // Files with any other extension are taken to be JSON, which is what
+    // animated has always written.
+    pub fn of(path: &Path) -> Format {
+        return path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| Format::from_str(&extension.to_lowercase()).ok())
+            .unwrap_or(Format::Json);
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Format, ()> {
+        match s {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Format::Json => write!(f, "JSON"),
+            Format::Toml => write!(f, "TOML"),
+            Format::Yaml => write!(f, "YAML"),
+        }
+    }
+}
+
+// Parser messages end with their own position, which we already report in
+// front of the message.
+fn strip_position(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(index) => String::from(&message[..index]),
+        None => String::from(message),
+    }
+}
+
+fn syntax_error(path: &Path, position: Option<(usize, usize)>, message: String) -> ConfigError {
+    let (line, column) = position.unwrap_or((1, 1));
+    return ConfigError::Syntax {
+        path: path.to_path_buf(),
+        line: line,
+        column: column,
+        message: strip_position(&message),
+    };
+}
+
+fn type_error(path: &Path, position: Option<(usize, usize)>, message: String) -> ConfigError {
+    let (line, column) = position.unwrap_or((1, 1));
+    return ConfigError::Type {
+        path: path.to_path_buf(),
+        line: line,
+        column: column,
+        message: strip_position(&message),
+    };
+}
+
+fn json_error(e: serde_json::Error, path: &Path) -> ConfigError {
+    let position = Some((e.line(), e.column()));
+    match e.classify() {
+        serde_json::error::Category::Data => type_error(path, position, e.to_string()),
+        _ => syntax_error(path, position, e.to_string()),
+    }
+}
+
+// toml counts lines and columns from zero.
+fn toml_position(e: &toml::de::Error) -> Option<(usize, usize)> {
+    return e.line_col().map(|(line, column)| (line + 1, column + 1));
+}
+
+fn yaml_position(e: &serde_yaml::Error) -> Option<(usize, usize)> {
+    return e
+        .location()
+        .map(|location| (location.line(), location.column()));
+}
+
+// Parses a config file of the given format into its JSON form. `path` is
+// only used for error messages.
+pub fn parse(contents: &str, format: Format, path: &Path) -> Result<Value, ConfigError> {
+    match format {
+        Format::Json => serde_json::from_str(contents).map_err(|e| json_error(e, path)),
+        Format::Toml => toml::from_str(contents)
+            .map_err(|e| syntax_error(path, toml_position(&e), e.to_string())),
+        Format::Yaml => {
+            // An empty YAML file is a document without any keys. serde_yaml
+            // only sees it that way if it has a `---` or `~`.
+            let is_empty = contents.lines().all(|line| {
+                let line = line.trim();
+                line.is_empty() || line.starts_with('#')
+            });
+            if is_empty {
+                return Ok(Value::Object(Map::new()));
+            }
+            match serde_yaml::from_str(contents) {
+                Ok(Value::Null) => Ok(Value::Object(Map::new())),
+                Ok(document) => Ok(document),
+                Err(e) => Err(syntax_error(path, yaml_position(&e), e.to_string())),
+            }
+        }
+    }
+}
+
+// Parses a config file straight into `Config`, so that type errors point at
+// the right line.
+pub fn deserialize(contents: &str, format: Format, path: &Path) -> Result<Config, ConfigError> {
+    match format {
+        Format::Json => serde_json::from_str(contents).map_err(|e| json_error(e, path)),
+        Format::Toml => {
+            parse(contents, format, path)?;
+            toml::from_str(contents).map_err(|e| type_error(path, toml_position(&e), e.to_string()))
+        }
+        Format::Yaml => {
+            let document = parse(contents, format, path)?;
+            if document
+                .as_object()
+                .map_or(false, |object| object.is_empty())
+            {
+                return Ok(serde_json::from_value(document).unwrap());
+            }
+            serde_yaml::from_str(contents)
+                .map_err(|e| type_error(path, yaml_position(&e), e.to_string()))
+        }
+    }
+}
+
+// TOML has no null, and every plain value of a table has to come before its
+// subtables.
+fn prepare_for_toml(value: &Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let (tables, values): (Vec<_>, Vec<_>) = object
+                .iter()
+                .filter(|(_, child)| !child.is_null())
+                .partition(|(_, child)| child.is_object());
+            let mut prepared = Map::new();
+            for (key, child) in values.into_iter().chain(tables) {
+                prepared.insert(key.clone(), prepare_for_toml(child));
+            }
+            Value::Object(prepared)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(prepare_for_toml).collect()),
+        _ => value.clone(),
+    }
+}
+
+pub fn render(document: &Value, format: Format) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(document).unwrap() + "\n",
+        Format::Toml => toml::to_string_pretty(&prepare_for_toml(document))
+            .expect("Config documents are always representable in TOML."),
+        Format::Yaml => serde_yaml::to_string(document).unwrap(),
+    }
+}
+
+// Finds where `key` is first spelled out in the file. Good enough to point
+// at a field, since every key we validate is unique within the config.
+pub fn locate_key(contents: &str, format: Format, key: &str) -> Option<(usize, usize)> {
+    for (index, line) in contents.lines().enumerate() {
+        let column = match format {
+            Format::Json => line.find(&format!("\"{}\"", key)),
+            // Bare keys at the start of a line, e.g. `key = 1` or `key: 1`.
+            Format::Toml | Format::Yaml => {
+                let trimmed = line.trim_start();
+                let rest = trimmed
+                    .trim_start_matches("- ")
+                    .trim_start_matches(|c| c == '"' || c == '\'');
+                let separator = if format == Format::Toml { '=' } else { ':' };
+                match rest.strip_prefix(key) {
+                    Some(after)
+                        if after
+                            .trim_start_matches(|c| c == '"' || c == '\'')
+                            .trim_start()
+                            .starts_with(separator) =>
+                    {
+                        Some(line.len() - rest.len())
+                    }
+                    _ => None,
+                }
+            }
+        };
+        if let Some(column) = column {
+            return Some((index + 1, line[..column].chars().count() + 1));
+        }
+    }
+    return None;
+}
+
+pub enum Edit {
+    Set(String, Value),
+    Unset(String),
+}
+
+// Applies `edit` to the contents of a config file. TOML files are edited in
+// place so that comments and layout survive. The other formats are
+// rewritten from their JSON form.
+pub fn apply(contents: &str, format: Format, path: &Path, edit: &Edit) -> Result<String, String> {
+    let key = match edit {
+        Edit::Set(key, _) | Edit::Unset(key) => key,
+    };
+    config_keys::ensure_writable(key).map_err(|e| e.to_string())?;
+    if format == Format::Toml {
+        return apply_toml(contents, &[edit]);
+    }
+
+    let mut document = parse(contents, format, path).map_err(|e| e.to_string())?;
+    match edit {
+        Edit::Set(key, value) => config_keys::set(&mut document, key, value.clone()),
+        Edit::Unset(key) => {
+            config_keys::unset(&mut document, key).map_err(|e| e.to_string())?;
+        }
+    }
+    return Ok(render(&document, format));
+}
+
+// Replaces the contents of a config file with `document`, keeping the
+// comments of a TOML file by only touching the top-level keys that changed.
+pub fn rewrite(contents: &str, format: Format, path: &Path, document: &Value) -> String {
+    if format != Format::Toml {
+        return render(document, format);
+    }
+    let original = parse(contents, format, path).unwrap_or_default();
+    let empty = Map::new();
+    let (before, after) = (
+        original.as_object().unwrap_or(&empty),
+        document.as_object().unwrap_or(&empty),
+    );
+
+    let mut edits = vec![];
+    for (key, value) in after.iter() {
+        if before.get(key) != Some(value) {
+            edits.push(Edit::Set(key.clone(), value.clone()));
+        }
+    }
+    for key in before.keys() {
+        if !after.contains_key(key) {
+            edits.push(Edit::Unset(key.clone()));
+        }
+    }
+    let edits: Vec<&Edit> = edits.iter().collect();
+    return apply_toml(contents, &edits).unwrap_or_else(|_| render(document, format));
+}
+
+fn toml_item(value: &Value, as_table: bool) -> toml_edit::Item {
+    if as_table && value.is_object() {
+        let rendered = render(value, Format::Toml);
+        let document: toml_edit::Document = rendered.parse().unwrap();
+        return document.root;
+    }
+    // `toml::Value` prints inline, e.g. `["a", "b"]` or `{ url = "..." }`.
+    let rendered = toml::Value::try_from(prepare_for_toml(value))
+        .unwrap()
+        .to_string();
+    return toml_edit::value(rendered.parse::<toml_edit::Value>().unwrap());
+}
+
+fn apply_toml(contents: &str, edits: &[&Edit]) -> Result<String, String> {
+    let mut document: toml_edit::Document = contents.parse().map_err(|e| format!("{}", e))?;
+    for edit in edits {
+        apply_toml_edit(&mut document, edit);
+    }
+    return Ok(document.to_string());
+}
+
+fn apply_toml_edit(document: &mut toml_edit::Document, edit: &Edit) {
+    let key = match edit {
+        Edit::Set(key, _) | Edit::Unset(key) => key,
+    };
+    let parts: Vec<&str> = key.split('.').collect();
+    let (last, parents) = parts.split_last().unwrap();
+
+    match edit {
+        Edit::Set(_, value) => {
+            let mut item = &mut document.root;
+            for part in parents {
+                item = &mut item[*part];
+            }
+            let as_table = item.is_table();
+            item[*last] = toml_item(value, as_table);
+        }
+        Edit::Unset(_) => {
+            let mut item = &mut document.root;
+            for part in parents {
+                if item[*part].is_none() {
+                    return;
+                }
+                item = &mut item[*part];
+            }
+            if let Some(table) = item.as_table_mut() {
+                table.remove(last);
+            } else if let Some(table) = item.as_inline_table_mut() {
+                table.remove(last);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn default_document() -> Value {
+        return serde_json::to_value(Config::default()).unwrap();
+    }
+
+    #[test]
+    fn picks_the_format_by_extension() {
+        assert_eq!(Format::of(Path::new("config.toml")), Format::Toml);
+        assert_eq!(Format::of(Path::new("config.YML")), Format::Yaml);
+        assert_eq!(Format::of(Path::new("config.yaml")), Format::Yaml);
+        assert_eq!(Format::of(Path::new("config.json")), Format::Json);
+        assert_eq!(Format::of(Path::new("config")), Format::Json);
+    }
+
+    #[test]
+    fn round_trips_the_defaults_through_every_format() {
+        let document = default_document();
+        for format in [Format::Json, Format::Toml, Format::Yaml].iter() {
+            let path = PathBuf::from(format!("config.{}", format.extension()));
+            let rendered = render(&document, *format);
+            let parsed = parse(&rendered, *format, &path).unwrap();
+            // TOML has no null, so unset values come back missing, which `Config`
+            // reads as unset again.
+            let config: Config = serde_json::from_value(parsed).unwrap();
+            assert_eq!(
+                serde_json::to_value(&config).unwrap(),
+                document,
+                "{}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn converts_between_toml_and_yaml() {
+        let toml = "download_path = \"/srv/anime\"\n\
+                    feeds = [\"https://a/rss\"]\n\
+                    \n\
+                    [backend]\n\
+                    type = \"aria2\"\n\
+                    url = \"http://localhost:6800/jsonrpc\"\n";
+        let document = parse(toml, Format::Toml, Path::new("config.toml")).unwrap();
+        let yaml = render(&document, Format::Yaml);
+        let converted = parse(&yaml, Format::Yaml, Path::new("config.yaml")).unwrap();
+        assert_eq!(converted, document);
+        assert_eq!(converted["backend"]["type"], json!("aria2"));
+
+        let back = render(&converted, Format::Toml);
+        assert_eq!(
+            parse(&back, Format::Toml, Path::new("config.toml")).unwrap(),
+            document
+        );
+    }
+
+    #[test]
+    fn reads_an_empty_yaml_file_as_the_defaults() {
+        for contents in ["", "\n", "# Nothing yet.\n", "---\n"].iter() {
+            let config = deserialize(contents, Format::Yaml, Path::new("config.yaml")).unwrap();
+            assert_eq!(config.listen_port, Config::default().listen_port);
+        }
+    }
+
+    #[test]
+    fn reports_toml_and_yaml_positions_from_one() {
+        match parse(
+            "listen_port = 1\nfeeds = [\n",
+            Format::Toml,
+            Path::new("c.toml"),
+        ) {
+            Err(ConfigError::Syntax { line, .. }) => assert_eq!(line, 3),
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+        match deserialize(
+            "listen_port: 1\npoll_interval_secs: often\n",
+            Format::Yaml,
+            Path::new("c.yaml"),
+        ) {
+            Err(ConfigError::Type { line, .. }) => assert_eq!(line, 2),
+            other => panic!("expected a type error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn locates_keys_in_every_format() {
+        let json = "{\n  \"backend\": {\n    \"url\": \"x\"\n  }\n}\n";
+        assert_eq!(locate_key(json, Format::Json, "url"), Some((3, 5)));
+        let toml = "[backend]\n  url = \"x\"\n";
+        assert_eq!(locate_key(toml, Format::Toml, "url"), Some((2, 3)));
+        let yaml = "backend:\n  url: x\nfeeds:\n  - \"urlish\"\n";
+        assert_eq!(locate_key(yaml, Format::Yaml, "url"), Some((2, 3)));
+        assert_eq!(locate_key(yaml, Format::Yaml, "missing"), None);
+    }
+
+    #[test]
+    fn keeps_toml_comments_when_setting_a_key() {
+        let contents = "# Where episodes go.\n\
+                        download_path = \"/srv/anime\"\n\
+                        \n\
+                        [retention]\n\
+                        # A week.\n\
+                        tombstone_days = 7\n";
+        let edited = apply(
+            contents,
+            Format::Toml,
+            Path::new("config.toml"),
+            &Edit::Set(String::from("retention.tombstone_days"), json!(14)),
+        )
+        .unwrap();
+        assert!(edited.contains("# Where episodes go."), "{}", edited);
+        assert!(edited.contains("# A week."), "{}", edited);
+        let document = parse(&edited, Format::Toml, Path::new("config.toml")).unwrap();
+        assert_eq!(document["retention"]["tombstone_days"], json!(14));
+        assert_eq!(document["download_path"], json!("/srv/anime"));
+    }
+
+    #[test]
+    fn keeps_toml_comments_when_rewriting() {
+        let contents = "# Where episodes go.\ndownload_path = \"/srv/anime\"\n";
+        let mut document = parse(contents, Format::Toml, Path::new("config.toml")).unwrap();
+        document["version"] = json!(7);
+        document["matching"] = json!({ "threshold": 80 });
+
+        let rewritten = rewrite(contents, Format::Toml, Path::new("config.toml"), &document);
+        assert!(rewritten.contains("# Where episodes go."), "{}", rewritten);
+        assert_eq!(
+            parse(&rewritten, Format::Toml, Path::new("config.toml")).unwrap(),
+            document
+        );
+    }
+}
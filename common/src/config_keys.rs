@@ -0,0 +1,179 @@
+use serde_json::{Map, Value};
+use std::fmt;
+
+/*
+ * Dotted key paths into the config, e.g. `backend.url`, as used by
+ * `animated config get/set/unset`. Everything works on the JSON form of the
+ * config so that keys match what users see in the file.
+ */
+
+// Managed by `config_migrations`, not by users.
+const READ_ONLY_KEYS: [&str; 1] = ["version"];
+
+#[derive(Debug)]
+pub enum KeyError {
+    Unknown(String),
+    ReadOnly(String),
+    // `value` cannot be turned into what `key` holds.
+    Type {
+        key: String,
+        expected: &'static str,
+        value: String,
+    },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Unknown(key) => write!(f, "`{}` is not a config key", key),
+            KeyError::ReadOnly(key) => write!(f, "`{}` is managed by animated", key),
+            KeyError::Type {
+                key,
+                expected,
+                value,
+            } => write!(f, "`{}` expects {}, not `{}`", key, expected, value),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+pub fn lookup<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    let mut current = value;
+    for part in key.split('.') {
+        current = current.as_object()?.get(part)?;
+    }
+    return Some(current);
+}
+
+// Turns a value given on the command line into the JSON type that `key`
+// holds in `understood`, the config as animated sees it.
+pub fn ensure_writable(key: &str) -> Result<(), KeyError> {
+    if READ_ONLY_KEYS.contains(&key) {
+        return Err(KeyError::ReadOnly(String::from(key)));
+    }
+    return Ok(());
+}
+
+pub fn coerce(understood: &Value, key: &str, raw: &str) -> Result<Value, KeyError> {
+    ensure_writable(key)?;
+    let current = match lookup(understood, key) {
+        Some(current) => current,
+        None => return Err(KeyError::Unknown(String::from(key))),
+    };
+    let type_error = |expected: &'static str| KeyError::Type {
+        key: String::from(key),
+        expected: expected,
+        value: String::from(raw),
+    };
+
+    match current {
+        // Unset optional values, which are all strings so far.
+        Value::String(_) | Value::Null => Ok(Value::String(String::from(raw))),
+        Value::Number(_) => match raw.parse::<u64>() {
+            Ok(number) => Ok(Value::from(number)),
+            Err(_) => Err(type_error("a whole number")),
+        },
+        Value::Bool(_) => match raw {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => Err(type_error("`true` or `false`")),
+        },
+        // Lists of strings may also be given comma separated, e.g.
+        // `animated config set feeds https://a/rss,https://b/rss`.
+        Value::Array(_) => match serde_json::from_str(raw) {
+            Ok(Value::Array(items)) => Ok(Value::Array(items)),
+            _ => Ok(Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| Value::String(String::from(item)))
+                    .collect(),
+            )),
+        },
+        Value::Object(_) => match serde_json::from_str(raw) {
+            Ok(Value::Object(object)) => Ok(Value::Object(object)),
+            _ => Err(type_error("a JSON object")),
+        },
+    }
+}
+
+// Sets `key` in `document`, creating any objects along the way.
+pub fn set(document: &mut Value, key: &str, value: Value) {
+    let mut current = document;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if !current.is_object() {
+            *current = Value::Object(Map::new());
+        }
+        let object = current.as_object_mut().unwrap();
+        if parts.peek().is_none() {
+            object.insert(String::from(part), value);
+            return;
+        }
+        current = object
+            .entry(part)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+// Removes `key` from `document`, so that it goes back to its default.
+// Returns whether it was there.
+pub fn unset(document: &mut Value, key: &str) -> Result<bool, KeyError> {
+    ensure_writable(key)?;
+    let (parent, last) = match key.rfind('.') {
+        Some(index) => (lookup_mut(document, &key[..index]), &key[index + 1..]),
+        None => (Some(document), key),
+    };
+    let object = match parent.and_then(Value::as_object_mut) {
+        Some(object) => object,
+        None => return Ok(false),
+    };
+
+    // Rebuilt rather than using `remove`, which moves the last key into the
+    // gap and would shuffle the user's file.
+    let mut removed = false;
+    for (name, value) in std::mem::take(object) {
+        if name == last {
+            removed = true;
+        } else {
+            object.insert(name, value);
+        }
+    }
+    return Ok(removed);
+}
+
+fn lookup_mut<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    let mut current = value;
+    for part in key.split('.') {
+        current = current.as_object_mut()?.get_mut(part)?;
+    }
+    return Some(current);
+}
+
+// Every leaf of `value` with its dotted key, in document order.
+pub fn flatten(value: &Value) -> Vec<(String, Value)> {
+    let mut leaves = vec![];
+    collect_leaves(value, "", &mut leaves);
+    return leaves;
+}
+
+fn collect_leaves(value: &Value, prefix: &str, leaves: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(object) if !object.is_empty() => {
+            for (key, child) in object.iter() {
+                collect_leaves(child, &format!("{}{}.", prefix, key), leaves);
+            }
+        }
+        _ => leaves.push((String::from(prefix.trim_end_matches('.')), value.clone())),
+    }
+}
+
+// How a value is printed for humans: strings as they are, anything else as
+// JSON.
+pub fn display(value: &Value) -> String {
+    match value {
+        Value::String(string) => string.clone(),
+        _ => value.to_string(),
+    }
+}
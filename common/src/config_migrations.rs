@@ -0,0 +1,361 @@
+use crate::constants::CONFIG_VERSION;
+use crate::structs::{
+    default_listen_port, default_poll_interval_secs, BackendConfig, BackupConfig, MatchingConfig,
+    MetadataConfig, RetentionConfig, WatchDefaults,
+};
+use serde_json::{Map, Value};
+use std::fmt;
+
+/*
+ * Upgrades config documents written by older versions of animated. Each step
+ * takes a document of one version to the next, so a file that is several
+ * versions behind goes through every step in turn. Steps work on the JSON
+ * document rather than on `Config` so that they can see the old layout.
+ */
+
+type Migration = fn(&mut Map<String, Value>);
+
+// Files written before the version field existed.
+const UNVERSIONED: u32 = 1;
+
+// `MIGRATIONS[i]` upgrades version `i + 1` to version `i + 2`.
+const MIGRATIONS: [Migration; 6] = [
+    spell_out_download_settings,
+    spell_out_retention,
+    spell_out_watch_defaults,
+    spell_out_backups,
+    spell_out_matching,
+    spell_out_metadata,
+];
+
+// Version 2 added feeds, the poll interval, the tracker port and the
+// download backend. They all have defaults, but writing them out shows users
+// what they can change.
+fn spell_out_download_settings(document: &mut Map<String, Value>) {
+    let defaults = [
+        ("feeds", Value::Array(vec![])),
+        (
+            "poll_interval_secs",
+            Value::from(default_poll_interval_secs()),
+        ),
+        ("listen_port", Value::from(default_listen_port())),
+        (
+            "backend",
+            serde_json::to_value(BackendConfig::default()).unwrap(),
+        ),
+    ];
+    for (key, value) in defaults.iter() {
+        if !document.contains_key(*key) {
+            document.insert(key.to_string(), value.clone());
+        }
+    }
+}
+
+// Version 3 added the retention policy.
+fn spell_out_retention(document: &mut Map<String, Value>) {
+    if !document.contains_key("retention") {
+        document.insert(
+            String::from("retention"),
+            serde_json::to_value(RetentionConfig::default()).unwrap(),
+        );
+    }
+}
+
+// Version 4 added the quality and subgroup imported anime are watched with.
+fn spell_out_watch_defaults(document: &mut Map<String, Value>) {
+    if !document.contains_key("defaults") {
+        document.insert(
+            String::from("defaults"),
+            serde_json::to_value(WatchDefaults::default()).unwrap(),
+        );
+    }
+}
+
+// Version 5 added automatic backups.
+fn spell_out_backups(document: &mut Map<String, Value>) {
+    if !document.contains_key("backups") {
+        document.insert(
+            String::from("backups"),
+            serde_json::to_value(BackupConfig::default()).unwrap(),
+        );
+    }
+}
+
+// Version 6 added fuzzy matching of release titles.
+fn spell_out_matching(document: &mut Map<String, Value>) {
+    if !document.contains_key("matching") {
+        document.insert(
+            String::from("matching"),
+            serde_json::to_value(MatchingConfig::default()).unwrap(),
+        );
+    }
+}
+
+// Version 7 added the anime-offline-database.
+fn spell_out_metadata(document: &mut Map<String, Value>) {
+    if !document.contains_key("metadata") {
+        document.insert(
+            String::from("metadata"),
+            serde_json::to_value(MetadataConfig::default()).unwrap(),
+        );
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    InvalidVersion(Value),
+    // Written by a newer animated than this one.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::InvalidVersion(version) => {
+                write!(f, "`version` must be a whole number, not {}", version)
+            }
+            MigrationError::UnsupportedVersion(version) => write!(
+                f,
+                "config version {} is newer than this animated understands (up to {}). \
+                Please upgrade animated.",
+                version, CONFIG_VERSION
+            ),
+        }
+    }
+}
+
+pub fn version_of(document: &Value) -> Result<u32, MigrationError> {
+    match document.get("version") {
+        None => Ok(UNVERSIONED),
+        Some(version) => match version.as_u64() {
+            Some(number) if number >= UNVERSIONED as u64 && number <= u32::MAX as u64 => {
+                Ok(number as u32)
+            }
+            _ => Err(MigrationError::InvalidVersion(version.clone())),
+        },
+    }
+}
+
+// Brings `document` up to the current version. Returns the version it
+// started at. Documents that are not objects are left for deserialization to
+// complain about.
+pub fn migrate(document: &mut Value) -> Result<u32, MigrationError> {
+    let from = version_of(document)?;
+    if from > CONFIG_VERSION {
+        return Err(MigrationError::UnsupportedVersion(from));
+    }
+
+    if let Value::Object(map) = document {
+        // Keep the version at the top of files that did not have one, where
+        // people will see it.
+        if from < CONFIG_VERSION && !map.contains_key("version") {
+            let rest = std::mem::take(map);
+            map.insert(String::from("version"), Value::from(from));
+            map.extend(rest);
+        }
+        for version in from..CONFIG_VERSION {
+            MIGRATIONS[(version - UNVERSIONED) as usize](map);
+            map.insert(String::from("version"), Value::from(version + 1));
+        }
+    }
+    return Ok(from);
+}
+
+// Keys in `document` that do not survive a round trip through `Config`,
+// i.e. the ones serde silently ignores. Nested keys are joined with dots.
+pub fn unknown_keys(document: &Value, understood: &Value) -> Vec<String> {
+    let mut unknown = vec![];
+    collect_unknown_keys(document, understood, "", &mut unknown);
+    return unknown;
+}
+
+fn collect_unknown_keys(
+    document: &Value,
+    understood: &Value,
+    prefix: &str,
+    unknown: &mut Vec<String>,
+) {
+    let (document, understood) = match (document, understood) {
+        (Value::Object(document), Value::Object(understood)) => (document, understood),
+        _ => return,
+    };
+    for (key, value) in document.iter() {
+        let path = format!("{}{}", prefix, key);
+        match understood.get(key) {
+            Some(understood_value) => {
+                collect_unknown_keys(value, understood_value, &format!("{}.", path), unknown)
+            }
+            None => unknown.push(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn object(document: Value) -> Map<String, Value> {
+        match document {
+            Value::Object(map) => map,
+            _ => panic!("not an object"),
+        }
+    }
+
+    // Runs the single step from `version` to the next one.
+    fn step(version: u32, document: Value) -> Map<String, Value> {
+        let mut map = object(document);
+        MIGRATIONS[(version - UNVERSIONED) as usize](&mut map);
+        return map;
+    }
+
+    #[test]
+    fn spells_out_the_download_settings_in_version_2() {
+        let map = step(
+            1,
+            json!({ "download_path": "/srv/anime", "feeds": ["https://a/rss"] }),
+        );
+        assert_eq!(map["feeds"], json!(["https://a/rss"]));
+        assert_eq!(
+            map["poll_interval_secs"],
+            json!(default_poll_interval_secs())
+        );
+        assert_eq!(map["listen_port"], json!(default_listen_port()));
+        assert_eq!(
+            map["backend"],
+            serde_json::to_value(BackendConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn spells_out_the_retention_policy_in_version_3() {
+        let map = step(2, json!({}));
+        assert_eq!(
+            map["retention"],
+            serde_json::to_value(RetentionConfig::default()).unwrap()
+        );
+        let map = step(2, json!({ "retention": { "tombstone_days": 3 } }));
+        assert_eq!(map["retention"], json!({ "tombstone_days": 3 }));
+    }
+
+    #[test]
+    fn spells_out_the_watch_defaults_in_version_4() {
+        let map = step(3, json!({}));
+        assert_eq!(
+            map["defaults"],
+            serde_json::to_value(WatchDefaults::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn spells_out_the_backups_in_version_5() {
+        let map = step(4, json!({}));
+        assert_eq!(
+            map["backups"],
+            serde_json::to_value(BackupConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn spells_out_the_matching_in_version_6() {
+        let map = step(5, json!({}));
+        assert_eq!(
+            map["matching"],
+            serde_json::to_value(MatchingConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn spells_out_the_metadata_in_version_7() {
+        let map = step(6, json!({}));
+        assert_eq!(
+            map["metadata"],
+            serde_json::to_value(MetadataConfig::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn has_a_step_for_every_version() {
+        assert_eq!(MIGRATIONS.len() as u32, CONFIG_VERSION - UNVERSIONED);
+    }
+
+    #[test]
+    fn upgrades_unversioned_files_through_every_step() {
+        let mut document = json!({ "download_path": "/srv/anime" });
+        assert_eq!(migrate(&mut document).unwrap(), UNVERSIONED);
+
+        let map = object(document);
+        assert_eq!(map.keys().next().unwrap(), "version");
+        assert_eq!(map["version"], json!(CONFIG_VERSION));
+        assert_eq!(map["download_path"], json!("/srv/anime"));
+        for key in [
+            "feeds",
+            "backend",
+            "retention",
+            "defaults",
+            "backups",
+            "matching",
+            "metadata",
+        ]
+        .iter()
+        {
+            assert!(map.contains_key(*key), "missing `{}`", key);
+        }
+    }
+
+    #[test]
+    fn only_runs_the_steps_a_file_is_missing() {
+        let mut document = json!({ "version": 6, "backups": { "keep": 2 } });
+        assert_eq!(migrate(&mut document).unwrap(), 6);
+
+        let map = object(document);
+        assert_eq!(map["version"], json!(CONFIG_VERSION));
+        assert!(map.contains_key("metadata"));
+        assert!(!map.contains_key("retention"));
+        assert_eq!(map["backups"], json!({ "keep": 2 }));
+    }
+
+    #[test]
+    fn leaves_current_files_alone() {
+        let original = json!({ "version": CONFIG_VERSION, "download_path": "/srv/anime" });
+        let mut document = original.clone();
+        assert_eq!(migrate(&mut document).unwrap(), CONFIG_VERSION);
+        assert_eq!(document, original);
+    }
+
+    #[test]
+    fn rejects_newer_and_malformed_versions() {
+        let mut document = json!({ "version": CONFIG_VERSION + 1 });
+        match migrate(&mut document) {
+            Err(MigrationError::UnsupportedVersion(version)) => {
+                assert_eq!(version, CONFIG_VERSION + 1)
+            }
+            other => panic!("expected an unsupported version, got {:?}", other),
+        }
+        for version in [json!("7"), json!(0), json!(2.5), json!(-1)].iter() {
+            let mut document = json!({ "version": version });
+            assert!(
+                matches!(
+                    migrate(&mut document),
+                    Err(MigrationError::InvalidVersion(_))
+                ),
+                "accepted {}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn finds_nested_unknown_keys() {
+        let understood = json!({ "backend": { "type": "builtin" }, "feeds": [] });
+        let document = json!({
+            "backend": { "type": "builtin", "ulr": "x" },
+            "feeds": [],
+            "colour": "blue",
+        });
+        assert_eq!(
+            unknown_keys(&document, &understood),
+            vec![String::from("backend.ulr"), String::from("colour")]
+        );
+    }
+}
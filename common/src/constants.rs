@@ -1,4 +1,24 @@
-pub const ANIMATED_DIR: &str = "/etc/animated/";
-pub const CONFIG_PATH: &str = "/etc/animated/config.json";
-pub const ROCKSDB_PATH: &str = "/etc/animated/animated.rocksdb";
-pub const DAEMON_LOCK_PATH: &str = "/etc/animated/animated.lock";
+// Locations used by a system-wide install, i.e. when running as root or
+// when a user has not run a daemon of their own. The system config is also
+// the layer below every user config.
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/animated";
+pub const SYSTEM_DATA_DIR: &str = "/var/lib/animated";
+// Where the database lived before the data directory could be configured.
+pub const LEGACY_ROCKSDB_PATH: &str = "/etc/animated/animated.rocksdb";
+
+// `config.json`, `config.toml`, `config.yaml` or `config.yml`, see
+// `config_format::EXTENSIONS`.
+pub const CONFIG_FILE_STEM: &str = "config";
+pub const ROCKSDB_DIR_NAME: &str = "animated.rocksdb";
+pub const DAEMON_LOCK_FILE_NAME: &str = "animated.lock";
+pub const DAEMON_SOCKET_FILE_NAME: &str = "animated.sock";
+pub const BACKUP_DIR_NAME: &str = "backups";
+
+pub const CONFIG_PATH_ENV: &str = "ANIMATED_CONFIG";
+pub const DATA_DIR_ENV: &str = "ANIMATED_DATA_DIR";
+// `ANIMATED__BACKEND__URL` overrides `backend.url`, and so on.
+pub const CONFIG_ENV_PREFIX: &str = "ANIMATED__";
+
+// Bump this and add a step to `config_migrations::MIGRATIONS` whenever the
+// config layout changes in a way older files need converting for.
+pub const CONFIG_VERSION: u32 = 7;
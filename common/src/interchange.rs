@@ -0,0 +1,844 @@
+use crate::lock;
+use crate::store::{self, StoreError, WatchIdCollision, WatchStore};
+use crate::structs::{
+    by_name, Anime, EpisodeRecord, EpisodeState, EventKind, Quality, QualityProfile,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{collections::HashSet, path::Path, str::FromStr};
+
+/*
+ * The file format of `animated export` and `animated import`. It is
+ * versioned separately from the database and the config so that exports
+ * stay importable however those change. Enums are written by name, e.g.
+ * `1080p`, rather than however serde would encode them.
+ */
+
+// Bump this and add a step to `UPGRADES` whenever the layout of `Export`
+// changes.
+pub const INTERCHANGE_VERSION: u32 = 1;
+
+// `UPGRADES[i]` upgrades version `i + 1` to version `i + 2`, like
+// `config_migrations::MIGRATIONS`.
+const UPGRADES: [fn(&mut Map<String, Value>); 0] = [];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    Toml,
+}
+
+impl ExportFormat {
+    // Files with any other extension are taken to be JSON.
+    pub fn of(path: &Path) -> ExportFormat {
+        return path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| ExportFormat::from_str(&extension.to_lowercase()).ok())
+            .unwrap_or(ExportFormat::Json);
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ExportFormat, ()> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            "toml" => Ok(ExportFormat::Toml),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedEpisode {
+    pub episode: i32,
+    pub release_title: String,
+    pub link: String,
+    #[serde(default)]
+    pub info_hash: Option<String>,
+    #[serde(with = "by_name")]
+    pub state: EpisodeState,
+    // Seconds since the Unix epoch.
+    pub updated_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedAnime {
+    pub title: String,
+    pub subgroup: String,
+    #[serde(with = "by_name")]
+    pub quality: Quality,
+    pub last_seen_episode: i32,
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub fallback_subgroups: Vec<String>,
+    #[serde(default)]
+    pub delay_hours: u64,
+    // This and `episodes` last so that TOML can write them as tables.
+    #[serde(default)]
+    pub profile: QualityProfile,
+    #[serde(default)]
+    pub episodes: Vec<ExportedEpisode>,
+}
+
+impl ExportedAnime {
+    fn anime(&self) -> Anime {
+        return Anime {
+            title: self.title.clone(),
+            quality: self.quality,
+            subgroup: self.subgroup.clone(),
+            last_seen_episode: self.last_seen_episode,
+            tombstone: false,
+            paused: self.paused,
+            tombstoned_at: None,
+            aliases: self.aliases.clone(),
+            matched_alias: None,
+            metadata: None,
+            profile: self.profile.clone(),
+            fallback_subgroups: self.fallback_subgroups.clone(),
+            delay_hours: self.delay_hours,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Export {
+    pub version: u32,
+    // Seconds since the Unix epoch.
+    pub exported_at: u64,
+    #[serde(default)]
+    pub anime: Vec<ExportedAnime>,
+}
+
+// Every watched anime, ordered by title, with its episodes.
+pub fn export(store: &dyn WatchStore) -> Result<Export, StoreError> {
+    let mut watched: Vec<(String, Anime)> = store.list_anime()?.into_iter().collect();
+    watched.sort_by(|(a_id, a), (b_id, b)| (&a.title, a_id).cmp(&(&b.title, b_id)));
+    let episodes = store.list_episodes()?;
+
+    let mut exported = vec![];
+    for (watch_id, anime) in watched {
+        exported.push(ExportedAnime {
+            title: anime.title,
+            subgroup: anime.subgroup,
+            quality: anime.quality,
+            last_seen_episode: anime.last_seen_episode,
+            paused: anime.paused,
+            aliases: anime.aliases,
+            fallback_subgroups: anime.fallback_subgroups,
+            delay_hours: anime.delay_hours,
+            profile: anime.profile,
+            episodes: episodes
+                .iter()
+                .filter(|record| record.watch_id == watch_id)
+                .map(|record| ExportedEpisode {
+                    episode: record.episode,
+                    release_title: record.release_title.clone(),
+                    link: record.link.clone(),
+                    info_hash: record.info_hash.clone(),
+                    state: record.state,
+                    updated_at: record.updated_at,
+                })
+                .collect(),
+        });
+    }
+    return Ok(Export {
+        version: INTERCHANGE_VERSION,
+        exported_at: lock::now(),
+        anime: exported,
+    });
+}
+
+// CSV cannot nest, so every anime and every episode gets a row of its own.
+// Episode rows repeat the title, subgroup and quality of their anime and
+// leave `last_seen_episode`, `paused`, `aliases`, the fallback subgroups,
+// the delay and the quality profile empty, anime rows leave the episode
+// columns empty. Aliases are separated by `ALIAS_SEPARATOR`, fallback
+// subgroups and qualities by `FALLBACK_SEPARATOR`.
+const ALIAS_SEPARATOR: &str = " | ";
+const FALLBACK_SEPARATOR: &str = " > ";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CsvRow {
+    version: u32,
+    title: String,
+    subgroup: String,
+    quality: String,
+    last_seen_episode: Option<i32>,
+    paused: Option<bool>,
+    aliases: Option<String>,
+    fallback_subgroups: Option<String>,
+    delay_hours: Option<u64>,
+    fallback_qualities: Option<String>,
+    minimum_quality: Option<String>,
+    upgrade_until: Option<String>,
+    episode: Option<i32>,
+    release_title: Option<String>,
+    link: Option<String>,
+    info_hash: Option<String>,
+    state: Option<String>,
+    updated_at: Option<u64>,
+}
+
+fn render_csv(export: &Export) -> String {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for anime in export.anime.iter() {
+        writer
+            .serialize(CsvRow {
+                version: export.version,
+                title: anime.title.clone(),
+                subgroup: anime.subgroup.clone(),
+                quality: anime.quality.to_string(),
+                last_seen_episode: Some(anime.last_seen_episode),
+                paused: Some(anime.paused),
+                aliases: Some(anime.aliases.join(ALIAS_SEPARATOR)),
+                fallback_subgroups: Some(anime.fallback_subgroups.join(FALLBACK_SEPARATOR)),
+                delay_hours: Some(anime.delay_hours),
+                fallback_qualities: Some(
+                    anime
+                        .profile
+                        .fallbacks
+                        .iter()
+                        .map(Quality::to_string)
+                        .collect::<Vec<String>>()
+                        .join(FALLBACK_SEPARATOR),
+                ),
+                minimum_quality: anime.profile.minimum.map(|quality| quality.to_string()),
+                upgrade_until: anime
+                    .profile
+                    .upgrade_until
+                    .map(|quality| quality.to_string()),
+                ..CsvRow::default()
+            })
+            .expect("Exports always serialize to CSV.");
+        for episode in anime.episodes.iter() {
+            writer
+                .serialize(CsvRow {
+                    version: export.version,
+                    title: anime.title.clone(),
+                    subgroup: anime.subgroup.clone(),
+                    quality: anime.quality.to_string(),
+                    episode: Some(episode.episode),
+                    release_title: Some(episode.release_title.clone()),
+                    link: Some(episode.link.clone()),
+                    info_hash: episode.info_hash.clone(),
+                    state: Some(episode.state.to_string()),
+                    updated_at: Some(episode.updated_at),
+                    ..CsvRow::default()
+                })
+                .expect("Exports always serialize to CSV.");
+        }
+    }
+    let bytes = writer.into_inner().expect("Writing to memory cannot fail.");
+    return String::from_utf8(bytes).expect("CSV of UTF-8 strings is UTF-8.");
+}
+
+// Turns CSV rows back into the document the other formats describe, so that
+// all of them go through the same upgrades.
+fn parse_csv(contents: &str) -> Result<Value, String> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let mut version = None;
+    let mut anime: Vec<Map<String, Value>> = vec![];
+
+    for (index, row) in reader.deserialize::<CsvRow>().enumerate() {
+        // The header is line 1.
+        let line = index + 2;
+        let row = row.map_err(|e| format!("line {}: {}", line, e))?;
+        if *version.get_or_insert(row.version) != row.version {
+            return Err(format!(
+                "line {}: every row must have the same version",
+                line
+            ));
+        }
+        let identity = |entry: &Map<String, Value>| {
+            entry["title"] == row.title
+                && entry["subgroup"] == row.subgroup
+                && entry["quality"] == row.quality
+        };
+
+        let episode = match row.episode {
+            Some(episode) => episode,
+            None => {
+                let mut entry = Map::new();
+                entry.insert(String::from("title"), Value::from(row.title.clone()));
+                entry.insert(String::from("subgroup"), Value::from(row.subgroup.clone()));
+                entry.insert(String::from("quality"), Value::from(row.quality.clone()));
+                let last_seen_episode = row
+                    .last_seen_episode
+                    .ok_or_else(|| format!("line {}: `last_seen_episode` is empty", line))?;
+                entry.insert(
+                    String::from("last_seen_episode"),
+                    Value::from(last_seen_episode),
+                );
+                entry.insert(
+                    String::from("paused"),
+                    Value::from(row.paused.unwrap_or(false)),
+                );
+                let aliases = row.aliases.as_deref().unwrap_or("");
+                entry.insert(
+                    String::from("aliases"),
+                    aliases
+                        .split(ALIAS_SEPARATOR)
+                        .map(str::trim)
+                        .filter(|alias| !alias.is_empty())
+                        .collect(),
+                );
+                let fallback_subgroups = row.fallback_subgroups.as_deref().unwrap_or("");
+                entry.insert(
+                    String::from("fallback_subgroups"),
+                    fallback_subgroups
+                        .split(FALLBACK_SEPARATOR)
+                        .map(str::trim)
+                        .filter(|subgroup| !subgroup.is_empty())
+                        .collect(),
+                );
+                entry.insert(
+                    String::from("delay_hours"),
+                    Value::from(row.delay_hours.unwrap_or(0)),
+                );
+                let mut profile = Map::new();
+                let fallbacks = row.fallback_qualities.as_deref().unwrap_or("");
+                profile.insert(
+                    String::from("fallbacks"),
+                    fallbacks
+                        .split(FALLBACK_SEPARATOR)
+                        .map(str::trim)
+                        .filter(|quality| !quality.is_empty())
+                        .collect(),
+                );
+                if let Some(minimum) = row.minimum_quality.clone() {
+                    profile.insert(String::from("minimum"), Value::from(minimum));
+                }
+                if let Some(cutoff) = row.upgrade_until.clone() {
+                    profile.insert(String::from("upgrade_until"), Value::from(cutoff));
+                }
+                entry.insert(String::from("profile"), Value::Object(profile));
+                entry.insert(String::from("episodes"), Value::Array(vec![]));
+                anime.push(entry);
+                continue;
+            }
+        };
+
+        let entry = anime
+            .iter_mut()
+            .find(|entry| identity(entry))
+            .ok_or_else(|| {
+                format!(
+                    "line {}: episode {} of [{}] {} @ {} comes before the row of the anime",
+                    line, episode, row.subgroup, row.title, row.quality
+                )
+            })?;
+        let mut record = Map::new();
+        record.insert(String::from("episode"), Value::from(episode));
+        record.insert(
+            String::from("release_title"),
+            Value::from(row.release_title.unwrap_or_default()),
+        );
+        record.insert(
+            String::from("link"),
+            Value::from(row.link.unwrap_or_default()),
+        );
+        if let Some(info_hash) = row.info_hash {
+            record.insert(String::from("info_hash"), Value::from(info_hash));
+        }
+        record.insert(
+            String::from("state"),
+            Value::from(row.state.unwrap_or_default()),
+        );
+        record.insert(
+            String::from("updated_at"),
+            Value::from(row.updated_at.unwrap_or(0)),
+        );
+        entry["episodes"]
+            .as_array_mut()
+            .expect("Anime rows start with an empty list of episodes.")
+            .push(Value::Object(record));
+    }
+
+    let mut document = Map::new();
+    // An empty file has no rows to take the version from.
+    document.insert(
+        String::from("version"),
+        Value::from(version.unwrap_or(INTERCHANGE_VERSION)),
+    );
+    document.insert(String::from("exported_at"), Value::from(0));
+    document.insert(
+        String::from("anime"),
+        Value::Array(anime.into_iter().map(Value::Object).collect()),
+    );
+    return Ok(Value::Object(document));
+}
+
+pub fn render(export: &Export, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Json => {
+            let mut rendered =
+                serde_json::to_string_pretty(export).expect("Exports always serialize to JSON.");
+            rendered.push('\n');
+            rendered
+        }
+        ExportFormat::Csv => render_csv(export),
+        ExportFormat::Toml => {
+            // Going through `toml::Value` puts plain values before tables,
+            // which an anime without episodes needs: its empty list of
+            // episodes would come after the table of its profile otherwise.
+            let document =
+                toml::Value::try_from(export).expect("Exports always serialize to TOML.");
+            toml::to_string(&document).expect("Exports always serialize to TOML.")
+        }
+    }
+}
+
+pub fn parse(contents: &str, format: ExportFormat) -> Result<Export, String> {
+    let mut document: Value = match format {
+        ExportFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string())?,
+        ExportFormat::Csv => parse_csv(contents)?,
+        ExportFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string())?,
+    };
+
+    let version = match document.get("version").and_then(|version| version.as_u64()) {
+        Some(version) if version >= 1 && version <= u32::MAX as u64 => version as u32,
+        _ => {
+            return Err(String::from(
+                "this is not an animated export, it has no version",
+            ))
+        }
+    };
+    if version > INTERCHANGE_VERSION {
+        return Err(format!(
+            "it was exported by a newer animated (format version {}, this version reads up \
+            to {}). Please upgrade animated.",
+            version, INTERCHANGE_VERSION
+        ));
+    }
+    if let Value::Object(map) = &mut document {
+        for version in version..INTERCHANGE_VERSION {
+            UPGRADES[(version - 1) as usize](map);
+            map.insert(String::from("version"), Value::from(version + 1));
+        }
+    }
+    return serde_json::from_value(document).map_err(|e| e.to_string());
+}
+
+// What to do with an imported anime that is already being watched.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MergeStrategy {
+    // Leave the watched one alone.
+    Skip,
+    // Replace it with the imported one.
+    Overwrite,
+    // Keep the watched one, but take the last seen episode of the imported
+    // one if it is further along.
+    Highest,
+}
+
+impl FromStr for MergeStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<MergeStrategy, ()> {
+        match s {
+            "skip" => Ok(MergeStrategy::Skip),
+            "overwrite" => Ok(MergeStrategy::Overwrite),
+            "highest" => Ok(MergeStrategy::Highest),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ImportReport {
+    // Watch IDs and the entries as they are after the import.
+    pub added: Vec<(String, Anime)>,
+    pub updated: Vec<(String, Anime)>,
+    pub unchanged: Vec<(String, Anime)>,
+    // Entries that could not be imported, and why.
+    pub rejected: Vec<(Anime, String)>,
+    // Episodes added to or replaced in the ledger.
+    pub episodes: usize,
+}
+
+fn episode_record(watch_id: &str, episode: &ExportedEpisode) -> EpisodeRecord {
+    return EpisodeRecord {
+        watch_id: String::from(watch_id),
+        episode: episode.episode,
+        release_title: episode.release_title.clone(),
+        link: episode.link.clone(),
+        info_hash: episode.info_hash.clone(),
+        state: episode.state,
+        matched_title: None,
+        replaces: None,
+        decide_at: None,
+        updated_at: episode.updated_at,
+        upgrade: None,
+    };
+}
+
+// Imports every entry of `export` in a single transaction. Unwatched entries
+// come back under their old watch ID. With `dry_run` nothing is changed and
+// the report says what would have happened.
+pub fn import(
+    store: &dyn WatchStore,
+    export: &Export,
+    strategy: MergeStrategy,
+    dry_run: bool,
+) -> Result<ImportReport, StoreError> {
+    let mut report = ImportReport::default();
+    store.transaction(&mut |transaction| {
+        report = ImportReport::default();
+        let mut seen = HashSet::new();
+
+        for entry in export.anime.iter() {
+            let imported = entry.anime();
+            if !seen.insert(imported.identity()) {
+                report
+                    .rejected
+                    .push((imported, String::from("it is listed more than once")));
+                continue;
+            }
+            if let Some(problem) = imported.preference_problem() {
+                report.rejected.push((imported, problem));
+                continue;
+            }
+
+            let (watch_id, anime, existing) = match store::find_by_identity(store, &imported)? {
+                Some((watch_id, existing)) if !existing.tombstone => {
+                    let anime = match strategy {
+                        MergeStrategy::Skip => existing.clone(),
+                        // Exports do not carry links to the anime-offline-database.
+                        MergeStrategy::Overwrite => Anime {
+                            metadata: existing.metadata.clone(),
+                            ..imported
+                        },
+                        MergeStrategy::Highest => Anime {
+                            last_seen_episode: existing
+                                .last_seen_episode
+                                .max(imported.last_seen_episode),
+                            ..existing.clone()
+                        },
+                    };
+                    (watch_id, anime, Some(existing))
+                }
+                Some((watch_id, _)) => (watch_id, imported, None),
+                None => {
+                    let watch_id = imported.watch_id();
+                    if let Some(other) = store.get_anime(&watch_id)? {
+                        let collision = WatchIdCollision {
+                            watch_id: watch_id,
+                            existing: Box::new(other),
+                        };
+                        report.rejected.push((imported, collision.to_string()));
+                        continue;
+                    }
+                    (watch_id, imported, None)
+                }
+            };
+
+            let mut episodes = 0;
+            for episode in entry.episodes.iter() {
+                let replace = match (strategy, &existing) {
+                    (MergeStrategy::Skip, Some(_)) => false,
+                    (MergeStrategy::Overwrite, _) | (_, None) => true,
+                    (MergeStrategy::Highest, Some(_)) => {
+                        store.get_episode(&watch_id, episode.episode)?.is_none()
+                    }
+                };
+                if replace {
+                    episodes += 1;
+                    if !dry_run {
+                        transaction.put_episode(&episode_record(&watch_id, episode));
+                    }
+                }
+            }
+            report.episodes += episodes;
+
+            let changed = match &existing {
+                Some(existing) => {
+                    episodes > 0
+                        || existing.title != anime.title
+                        || existing.subgroup != anime.subgroup
+                        || existing.last_seen_episode != anime.last_seen_episode
+                        || existing.paused != anime.paused
+                        || existing.aliases != anime.aliases
+                        || existing.fallback_subgroups != anime.fallback_subgroups
+                        || existing.delay_hours != anime.delay_hours
+                        || existing.profile != anime.profile
+                }
+                None => true,
+            };
+            if changed && !dry_run {
+                transaction.put_anime(&watch_id, &anime);
+                let kind = match existing {
+                    Some(_) => EventKind::Modified,
+                    None => EventKind::Watched,
+                };
+                transaction.record_event(kind, &watch_id, None, &anime.to_string());
+            }
+            match (existing, changed) {
+                (None, _) => report.added.push((watch_id, anime)),
+                (Some(_), true) => report.updated.push((watch_id, anime)),
+                (Some(_), false) => report.unchanged.push((watch_id, anime)),
+            }
+        }
+        return Ok(());
+    })?;
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn exported_episode(episode: i32, release_title: &str) -> ExportedEpisode {
+        return ExportedEpisode {
+            episode: episode,
+            release_title: String::from(release_title),
+            link: format!("magnet:?xt=urn:btih:{:040}", episode),
+            info_hash: Some(format!("{:040}", episode)),
+            state: EpisodeState::Completed,
+            updated_at: 1_700_000_000 + episode as u64,
+        };
+    }
+
+    fn exported(
+        title: &str,
+        last_seen_episode: i32,
+        episodes: Vec<ExportedEpisode>,
+    ) -> ExportedAnime {
+        return ExportedAnime {
+            title: String::from(title),
+            subgroup: String::from("SubsPlease"),
+            quality: Quality::Res1080,
+            last_seen_episode: last_seen_episode,
+            paused: false,
+            aliases: vec![],
+            fallback_subgroups: vec![],
+            delay_hours: 0,
+            profile: QualityProfile::default(),
+            episodes: episodes,
+        };
+    }
+
+    fn sample() -> Export {
+        let mut frieren = exported(
+            "Sousou no Frieren",
+            2,
+            vec![
+                exported_episode(1, "[SubsPlease] Sousou no Frieren - 01 (1080p).mkv"),
+                exported_episode(2, "[SubsPlease] Sousou no Frieren - 02 (1080p).mkv"),
+            ],
+        );
+        frieren.paused = true;
+        frieren.aliases = vec![
+            String::from("Frieren"),
+            String::from("Frieren: Beyond Journey's End"),
+        ];
+        // Group names may contain the separator without its spaces.
+        frieren.fallback_subgroups = vec![String::from("Erai-raws"), String::from("Sub>Group")];
+        frieren.delay_hours = 6;
+        frieren.profile = QualityProfile {
+            fallbacks: vec![Quality::Res720, Quality::Res480],
+            minimum: Some(Quality::Res720),
+            upgrade_until: Some(Quality::Res1080),
+        };
+        return Export {
+            version: INTERCHANGE_VERSION,
+            exported_at: 1_700_000_000,
+            anime: vec![frieren, exported("Dungeon Meshi", 0, vec![])],
+        };
+    }
+
+    fn round_trip(format: ExportFormat) {
+        let export = sample();
+        let parsed = parse(&render(&export, format), format).unwrap();
+        assert_eq!(parsed.version, INTERCHANGE_VERSION);
+        assert_eq!(
+            serde_json::to_value(&parsed.anime).unwrap(),
+            serde_json::to_value(&export.anime).unwrap(),
+            "{:?}",
+            format
+        );
+    }
+
+    #[test]
+    fn round_trips_json() {
+        round_trip(ExportFormat::Json);
+    }
+
+    #[test]
+    fn round_trips_csv() {
+        round_trip(ExportFormat::Csv);
+    }
+
+    #[test]
+    fn round_trips_toml() {
+        round_trip(ExportFormat::Toml);
+    }
+
+    #[test]
+    fn reports_the_line_of_broken_csv_rows() {
+        let header = "version,title,subgroup,quality,last_seen_episode,paused,aliases,\
+                      fallback_subgroups,delay_hours,fallback_qualities,minimum_quality,\
+                      upgrade_until,episode,release_title,link,info_hash,state,updated_at\n";
+        let anime_row = "1,Frieren,SubsPlease,1080p,2,false,,,0,,,,,,,,,\n";
+        let episode_row =
+            "1,Frieren,SubsPlease,1080p,,,,,,,,,1,Frieren - 01,magnet:,,Completed,0\n";
+
+        let orphan = format!("{}{}", header, episode_row);
+        let message = parse_csv(&orphan).unwrap_err();
+        assert!(
+            message.starts_with("line 2: episode 1 of [SubsPlease] Frieren"),
+            "{}",
+            message
+        );
+
+        let mixed = format!("{}{}{}", header, anime_row, anime_row.replacen('1', "2", 1));
+        assert_eq!(
+            parse_csv(&mixed).unwrap_err(),
+            "line 3: every row must have the same version"
+        );
+
+        let unseen = format!("{}{}", header, anime_row.replacen(",2,", ",,", 1));
+        assert_eq!(
+            parse_csv(&unseen).unwrap_err(),
+            "line 2: `last_seen_episode` is empty"
+        );
+
+        let malformed = format!("{}{}", header, anime_row.replacen(",2,", ",two,", 1));
+        assert!(parse_csv(&malformed).unwrap_err().starts_with("line 2: "));
+    }
+
+    #[test]
+    fn rejects_exports_from_newer_versions() {
+        let newer = format!(
+            "{{ \"version\": {}, \"exported_at\": 0, \"anime\": [] }}",
+            INTERCHANGE_VERSION + 1
+        );
+        let message = parse(&newer, ExportFormat::Json).unwrap_err();
+        assert!(message.contains("newer animated"), "{}", message);
+
+        let message = parse("{ \"anime\": [] }", ExportFormat::Json).unwrap_err();
+        assert!(message.contains("no version"), "{}", message);
+    }
+
+    // A store watching Frieren at episode 5 with episode 1 downloaded, and
+    // an export that is behind on it, pauses it, has another release of
+    // episode 1 and also has episode 2.
+    fn diverged() -> (MemoryStore, String, Export) {
+        let store = MemoryStore::new();
+        let mut watched = exported("Sousou no Frieren", 5, vec![]).anime();
+        watched.aliases = vec![String::from("Frieren")];
+        let watch_id = store.upsert_anime(&watched).unwrap();
+        store
+            .put_episode(&episode_record(
+                &watch_id,
+                &exported_episode(1, "[SubsPlease] Sousou no Frieren - 01 (1080p).mkv"),
+            ))
+            .unwrap();
+
+        let mut behind = exported(
+            "Sousou no Frieren",
+            3,
+            vec![
+                exported_episode(1, "[SubsPlease] Sousou no Frieren - 01v2 (1080p).mkv"),
+                exported_episode(2, "[SubsPlease] Sousou no Frieren - 02 (1080p).mkv"),
+            ],
+        );
+        behind.paused = true;
+        let export = Export {
+            version: INTERCHANGE_VERSION,
+            exported_at: 0,
+            anime: vec![behind, exported("Dungeon Meshi", 0, vec![])],
+        };
+        return (store, watch_id, export);
+    }
+
+    fn release_of(store: &MemoryStore, watch_id: &str, episode: i32) -> Option<String> {
+        return store
+            .get_episode(watch_id, episode)
+            .unwrap()
+            .map(|record| record.release_title);
+    }
+
+    #[test]
+    fn import_skips_watched_anime() {
+        let (store, watch_id, export) = diverged();
+        let report = import(&store, &export, MergeStrategy::Skip, false).unwrap();
+        assert_eq!(report.added.len(), 1);
+        assert_eq!(report.unchanged.len(), 1);
+        assert!(report.updated.is_empty());
+        assert_eq!(report.episodes, 0);
+
+        let anime = store.get_anime(&watch_id).unwrap().unwrap();
+        assert_eq!(anime.last_seen_episode, 5);
+        assert!(!anime.paused);
+        assert_eq!(
+            release_of(&store, &watch_id, 1).unwrap(),
+            "[SubsPlease] Sousou no Frieren - 01 (1080p).mkv"
+        );
+        assert!(release_of(&store, &watch_id, 2).is_none());
+    }
+
+    #[test]
+    fn import_overwrites_watched_anime() {
+        let (store, watch_id, export) = diverged();
+        let report = import(&store, &export, MergeStrategy::Overwrite, false).unwrap();
+        assert_eq!(report.added.len(), 1);
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.episodes, 2);
+
+        let anime = store.get_anime(&watch_id).unwrap().unwrap();
+        assert_eq!(anime.last_seen_episode, 3);
+        assert!(anime.paused);
+        assert!(anime.aliases.is_empty());
+        assert_eq!(
+            release_of(&store, &watch_id, 1).unwrap(),
+            "[SubsPlease] Sousou no Frieren - 01v2 (1080p).mkv"
+        );
+        assert!(release_of(&store, &watch_id, 2).is_some());
+    }
+
+    #[test]
+    fn import_takes_the_highest_episode_and_only_new_episodes() {
+        let (store, watch_id, export) = diverged();
+        let report = import(&store, &export, MergeStrategy::Highest, false).unwrap();
+        assert_eq!(report.added.len(), 1);
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.episodes, 1);
+
+        let anime = store.get_anime(&watch_id).unwrap().unwrap();
+        assert_eq!(anime.last_seen_episode, 5);
+        assert!(!anime.paused);
+        assert_eq!(anime.aliases, vec![String::from("Frieren")]);
+        assert_eq!(
+            release_of(&store, &watch_id, 1).unwrap(),
+            "[SubsPlease] Sousou no Frieren - 01 (1080p).mkv"
+        );
+        assert!(release_of(&store, &watch_id, 2).is_some());
+    }
+
+    #[test]
+    fn import_dry_run_changes_nothing() {
+        let (store, watch_id, export) = diverged();
+        let report = import(&store, &export, MergeStrategy::Overwrite, true).unwrap();
+        assert_eq!(report.updated.len(), 1);
+        assert_eq!(report.episodes, 2);
+        assert_eq!(store.list_anime().unwrap().len(), 1);
+        assert_eq!(
+            store
+                .get_anime(&watch_id)
+                .unwrap()
+                .unwrap()
+                .last_seen_episode,
+            5
+        );
+        assert!(release_of(&store, &watch_id, 2).is_none());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{
+    interchange::{Export, ImportReport, MergeStrategy},
+    paths,
+    retention::GcReport,
+    store::BackupInfo,
+    structs::Anime,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+// Bump this whenever an existing `Request` or `Response` changes shape. The
+// daemon refuses requests from a CLI speaking a different version instead of
+// guessing. Newly added variants are rejected by older peers on their own.
+// Version 2 covers what the messages gained since the first, e.g. the
+// quality profile of `Anime` and the pending episodes of `DaemonStatus`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    Watch(Anime),
+    Unwatch {
+        watch_id: String,
+    },
+    Get {
+        watch_id: String,
+    },
+    Modify {
+        watch_id: String,
+        anime: Anime,
+    },
+    List,
+    Status,
+    Gc {
+        dry_run: bool,
+    },
+    Export,
+    Import {
+        export: Export,
+        strategy: MergeStrategy,
+        dry_run: bool,
+    },
+    // `dir` is absolute, since the daemon runs elsewhere. It has to belong
+    // to the user asking, since the daemon writes there.
+    Backup {
+        dir: PathBuf,
+        keep: Option<usize>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    // Seconds since the Unix epoch.
+    pub started_at: u64,
+    pub config_path: String,
+    pub watched: usize,
+    pub active_downloads: usize,
+    // Soonest decision first.
+    #[serde(default)]
+    pub pending: Vec<PendingEpisode>,
+}
+
+// An episode held back in case a better ranked group releases it, see
+// `Anime.delay_hours`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingEpisode {
+    pub watch_id: String,
+    pub anime: String,
+    pub episode: i32,
+    // The best release so far.
+    pub release_title: String,
+    // Seconds since the Unix epoch.
+    pub decide_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    Watched { watch_id: String },
+    Unwatched { watch_id: String },
+    Anime(Option<Box<Anime>>),
+    Modified { watch_id: String },
+    List(HashMap<String, Anime>),
+    Status(DaemonStatus),
+    Collected(GcReport),
+    Exported(Export),
+    Imported(ImportReport),
+    BackedUp(BackupInfo),
+    Error(String),
+}
+
+// Every message is a single line of JSON wrapped in an envelope carrying the
+// protocol version, which keeps the socket easy to poke at with `socat`.
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope<T> {
+    version: u32,
+    body: T,
+}
+
+#[derive(Debug)]
+pub enum IpcError {
+    // Nothing is listening on the control socket, i.e. the daemon is not up.
+    DaemonUnavailable,
+    VersionMismatch { ours: u32, theirs: u32 },
+    Protocol(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpcError::DaemonUnavailable => write!(f, "The animated daemon is not running."),
+            IpcError::VersionMismatch { ours, theirs } => write!(
+                f,
+                "Protocol version mismatch: expected {} but the other side speaks {}. \
+                Make sure `animated` and `animated-server` are the same version.",
+                ours, theirs
+            ),
+            IpcError::Protocol(message) => write!(f, "Malformed control message: {}", message),
+            IpcError::Io(e) => write!(f, "Control socket error: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for IpcError {
+    fn from(e: io::Error) -> IpcError {
+        return IpcError::Io(e);
+    }
+}
+
+fn write_message<T: Serialize>(stream: &mut UnixStream, body: T) -> Result<(), IpcError> {
+    let envelope = Envelope {
+        version: PROTOCOL_VERSION,
+        body: body,
+    };
+    let mut line =
+        serde_json::to_string(&envelope).map_err(|e| IpcError::Protocol(e.to_string()))?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    stream.flush()?;
+    return Ok(());
+}
+
+fn read_message<T: for<'de> Deserialize<'de>>(stream: &UnixStream) -> Result<T, IpcError> {
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    if line.is_empty() {
+        return Err(IpcError::Io(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "connection closed before a message was received",
+        )));
+    }
+
+    // Check the version before the body so that a newer peer gets a useful
+    // error instead of a deserialization failure.
+    let raw: Envelope<serde_json::Value> =
+        serde_json::from_str(&line).map_err(|e| IpcError::Protocol(e.to_string()))?;
+    if raw.version != PROTOCOL_VERSION {
+        return Err(IpcError::VersionMismatch {
+            ours: PROTOCOL_VERSION,
+            theirs: raw.version,
+        });
+    }
+
+    return serde_json::from_value(raw.body).map_err(|e| IpcError::Protocol(e.to_string()));
+}
+
+pub fn read_request(stream: &UnixStream) -> Result<Request, IpcError> {
+    return read_message(stream);
+}
+
+pub fn write_response(stream: &mut UnixStream, response: Response) -> Result<(), IpcError> {
+    return write_message(stream, response);
+}
+
+fn connect() -> Result<UnixStream, IpcError> {
+    match UnixStream::connect(paths::get().socket()) {
+        Ok(stream) => Ok(stream),
+        // A missing socket means the daemon never started, while a refused
+        // connection means it left a stale socket behind.
+        Err(e) if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::ConnectionRefused => {
+            Err(IpcError::DaemonUnavailable)
+        }
+        Err(e) => Err(IpcError::Io(e)),
+    }
+}
+
+pub fn is_daemon_listening() -> bool {
+    return connect().is_ok();
+}
+
+pub fn send(request: Request) -> Result<Response, IpcError> {
+    let mut stream = connect()?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    write_message(&mut stream, request)?;
+    return read_message(&stream);
+}
@@ -1,4 +1,22 @@
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+
+pub mod anime_lists;
+pub mod backend;
 pub mod config;
+pub mod config_format;
+pub mod config_keys;
+pub mod config_migrations;
 pub mod constants;
-pub mod rocksdb;
+pub mod interchange;
+pub mod ipc;
+pub mod lock;
+pub mod metadata;
+pub mod paths;
+pub mod release;
+pub mod retention;
+pub mod store;
 pub mod structs;
+pub mod titles;
@@ -0,0 +1,175 @@
+use crate::paths;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Written into the lock file by the daemon holding it, so that other
+// processes can tell who owns the lock and for how long.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockInfo {
+    pub pid: u32,
+    // Seconds since the Unix epoch.
+    pub started_at: u64,
+    pub config_path: String,
+}
+
+#[derive(Debug)]
+pub enum LockState {
+    // No lock file exists.
+    Free,
+    // A lock file exists but no process holds the lock on it, e.g. because
+    // the daemon crashed or was killed with SIGKILL.
+    Stale(Option<LockInfo>),
+    // The daemon locks the file before writing its details to it, so the
+    // details may be missing if we catch it in between.
+    Held(Option<LockInfo>),
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    AlreadyHeld(Option<LockInfo>),
+    Io(io::Error),
+}
+
+impl From<io::Error> for LockError {
+    fn from(e: io::Error) -> LockError {
+        return LockError::Io(e);
+    }
+}
+
+fn read_info(file: &mut File) -> Option<LockInfo> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    return serde_json::from_str(&contents).ok();
+}
+
+pub fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is set before the Unix epoch.")
+        .as_secs();
+}
+
+// The lock is an `flock` on the lock file rather than the file's existence.
+// The kernel drops it whenever the holding process exits, however it exits,
+// so a lock file left behind by a crash never blocks a new daemon.
+pub struct DaemonLock {
+    path: PathBuf,
+    file: File,
+    info: LockInfo,
+    released: AtomicBool,
+}
+
+impl DaemonLock {
+    pub fn acquire(config_path: &str) -> Result<(DaemonLock, Option<LockInfo>), LockError> {
+        let path = paths::get().lock_file();
+        if let Some(state_dir) = path.parent() {
+            fs::create_dir_all(state_dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+
+        if let Err(e) = FileExt::try_lock_exclusive(&file) {
+            if e.kind() == fs2::lock_contended_error().kind() {
+                return Err(LockError::AlreadyHeld(read_info(&mut file)));
+            }
+            return Err(LockError::Io(e));
+        }
+
+        // Whatever is left in the file was written by a daemon that no longer
+        // holds the lock. Hand it back so the caller can report it.
+        let stale_info = read_info(&mut file);
+
+        let info = LockInfo {
+            pid: std::process::id(),
+            started_at: now(),
+            config_path: String::from(config_path),
+        };
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(serde_json::to_string(&info).unwrap().as_bytes())?;
+        file.sync_all()?;
+
+        let lock = DaemonLock {
+            path: path,
+            file: file,
+            info: info,
+            released: AtomicBool::new(false),
+        };
+        return Ok((lock, stale_info));
+    }
+
+    pub fn info(&self) -> &LockInfo {
+        return &self.info;
+    }
+
+    pub fn release(&self) {
+        if self.released.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        info!("Releasing lock file.");
+
+        // Remove the file before unlocking so another daemon can never lock
+        // the file we are about to delete.
+        match fs::remove_file(&self.path) {
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => warn!(
+                "Failed to delete lock file `{}`: {:?}",
+                self.path.display(),
+                e
+            ),
+        }
+        if let Err(e) = FileExt::unlock(&self.file) {
+            warn!("Failed to unlock lock file: {:?}", e);
+        }
+
+        info!("Lock file has been released.");
+    }
+}
+
+impl Drop for DaemonLock {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+pub fn inspect() -> io::Result<LockState> {
+    let mut file = match OpenOptions::new().read(true).open(paths::get().lock_file()) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(LockState::Free),
+        Err(e) => return Err(e),
+    };
+
+    let info = read_info(&mut file);
+    match FileExt::try_lock_shared(&file) {
+        Ok(_) => {
+            FileExt::unlock(&file)?;
+            return Ok(LockState::Stale(info));
+        }
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+            return Ok(LockState::Held(info));
+        }
+        Err(e) => return Err(e),
+    }
+}
+
+pub fn signal(pid: u32, force: bool) -> io::Result<()> {
+    let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+    let result = unsafe { libc::kill(pid as libc::pid_t, signal) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
@@ -0,0 +1,410 @@
+use crate::store::{StoreError, WatchStore};
+use crate::structs::{AiringStatus, AnimeMetadata};
+use crate::titles;
+use serde::Deserialize;
+use std::{cmp::Ordering, fs, path::Path};
+
+/*
+ * The anime-offline-database (github.com/manami-project/anime-offline-database)
+ * collects what MyAnimeList, AniList, AniDB, Kitsu and others know about
+ * every anime into one JSON file, e.g.
+ *
+ *   {"data": [{"sources": ["https://myanimelist.net/anime/1"],
+ *     "title": "Cowboy Bebop", "type": "TV", "episodes": 26,
+ *     "status": "FINISHED", "animeSeason": {"season": "SPRING", "year": 1998},
+ *     "synonyms": ["カウボーイビバップ"]}]}
+ *
+ * Users download it themselves and point `metadata.database` at it. Watched
+ * anime linked to an entry keep a copy of it, so the daemon can match
+ * releases by its synonyms and knows when a show has no episodes left.
+ */
+
+#[derive(Deserialize)]
+struct Document {
+    data: Vec<Entry>,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    sources: Vec<String>,
+    title: String,
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
+    // 0 while unknown.
+    #[serde(default)]
+    episodes: u32,
+    #[serde(default)]
+    status: Option<AiringStatus>,
+    #[serde(rename = "animeSeason", default)]
+    season: Option<Season>,
+    #[serde(default)]
+    synonyms: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Season {
+    // `UNDEFINED` while unknown.
+    #[serde(default)]
+    season: Option<String>,
+    #[serde(default)]
+    year: Option<u32>,
+}
+
+impl Entry {
+    fn into_metadata(self) -> Option<AnimeMetadata> {
+        let source = self.sources.first()?.clone();
+        let (season, year) = match self.season {
+            Some(season) => (
+                season.season.filter(|season| season != "UNDEFINED"),
+                season.year.filter(|year| *year > 0),
+            ),
+            None => (None, None),
+        };
+        return Some(AnimeMetadata {
+            source: source,
+            title: self.title,
+            synonyms: self.synonyms,
+            kind: self.kind.unwrap_or_else(|| String::from("UNKNOWN")),
+            episodes: if self.episodes > 0 {
+                Some(self.episodes)
+            } else {
+                None
+            },
+            status: self.status.unwrap_or(AiringStatus::Unknown),
+            season: season,
+            year: year,
+            sources: self.sources,
+        });
+    }
+}
+
+pub struct MetadataDatabase {
+    entries: Vec<AnimeMetadata>,
+    // The normalized title and synonyms of each entry, see `titles`.
+    normalized: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    pub metadata: &'a AnimeMetadata,
+    // The title or synonym that resembles the name best.
+    pub matched: &'a str,
+    pub score: f64,
+}
+
+#[derive(Debug)]
+pub enum Resolution<'a> {
+    Found(&'a AnimeMetadata),
+    // Best first.
+    Ambiguous(Vec<Candidate<'a>>),
+    NotFound,
+}
+
+impl MetadataDatabase {
+    pub fn load(path: &Path) -> Result<MetadataDatabase, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("could not read `{}`: {}", path.display(), e))?;
+        return MetadataDatabase::parse(&contents).map_err(|e| {
+            format!(
+                "`{}` is not an anime-offline-database: {}",
+                path.display(),
+                e
+            )
+        });
+    }
+
+    pub fn parse(contents: &str) -> Result<MetadataDatabase, String> {
+        let document: Document = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+        let entries: Vec<AnimeMetadata> = document
+            .data
+            .into_iter()
+            .filter_map(Entry::into_metadata)
+            .collect();
+        let normalized = entries
+            .iter()
+            .map(|entry| {
+                std::iter::once(&entry.title)
+                    .chain(entry.synonyms.iter())
+                    .map(|title| titles::normalize(title))
+                    .collect()
+            })
+            .collect();
+        return Ok(MetadataDatabase {
+            entries: entries,
+            normalized: normalized,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.entries.is_empty();
+    }
+
+    // The entry with `source` among its sources, so it can be found by its
+    // page on any of the sites.
+    pub fn get(&self, source: &str) -> Option<&AnimeMetadata> {
+        let source = source.trim().trim_end_matches('/');
+        return self
+            .entries
+            .iter()
+            .find(|entry| entry.sources.iter().any(|known| known == source));
+    }
+
+    // Entries with a title or synonym at least `threshold` like `name`, best
+    // first.
+    pub fn candidates(&self, name: &str, threshold: f64) -> Vec<Candidate<'_>> {
+        let name = titles::normalize(name);
+        let mut candidates = vec![];
+        for (entry, normalized) in self.entries.iter().zip(self.normalized.iter()) {
+            let mut best: Option<(usize, f64)> = None;
+            for (index, title) in normalized.iter().enumerate() {
+                let score = titles::normalized_similarity(&name, title);
+                let better = match best {
+                    Some((_, best_score)) => score > best_score,
+                    None => true,
+                };
+                if score >= threshold && better {
+                    best = Some((index, score));
+                }
+            }
+            if let Some((index, score)) = best {
+                let matched = match index {
+                    0 => &entry.title,
+                    _ => &entry.synonyms[index - 1],
+                };
+                candidates.push(Candidate {
+                    metadata: entry,
+                    matched: matched,
+                    score: score,
+                });
+            }
+        }
+        candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        return candidates;
+    }
+
+    // The entry `name` refers to, if only one resembles it or only one has
+    // it as its title or a synonym.
+    pub fn resolve(&self, name: &str, threshold: f64) -> Resolution<'_> {
+        let candidates = self.candidates(name, threshold);
+        let exact: Vec<&Candidate> = candidates
+            .iter()
+            .filter(|candidate| candidate.score >= 1.0)
+            .collect();
+        if exact.len() == 1 {
+            return Resolution::Found(exact[0].metadata);
+        }
+        return match candidates.len() {
+            0 => Resolution::NotFound,
+            1 => Resolution::Found(candidates[0].metadata),
+            _ => Resolution::Ambiguous(candidates),
+        };
+    }
+}
+
+// Updates the copies linked anime keep of their entry, e.g. once a show has
+// finished airing and its episode count is known. Returns how many changed.
+pub fn refresh(store: &dyn WatchStore, database: &MetadataDatabase) -> Result<usize, StoreError> {
+    let mut refreshed = 0;
+    for (watch_id, anime) in store.list_anime()? {
+        let linked = match &anime.metadata {
+            Some(linked) => linked,
+            None => continue,
+        };
+        if let Some(current) = database.get(&linked.source) {
+            if store.refresh_metadata(&watch_id, current)? {
+                refreshed += 1;
+            }
+        }
+    }
+    return Ok(refreshed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::structs::{Anime, Quality, QualityProfile};
+
+    const DATABASE: &str = r#"{"data": [
+        {"sources": ["https://myanimelist.net/anime/52991", "https://anilist.co/anime/154587"],
+         "title": "Sousou no Frieren", "type": "TV", "episodes": 28, "status": "FINISHED",
+         "animeSeason": {"season": "FALL", "year": 2023},
+         "synonyms": ["Frieren: Beyond Journey's End", "葬送のフリーレン"]},
+        {"sources": ["https://myanimelist.net/anime/37999"],
+         "title": "Kaguya-sama wa Kokurasetai: Tensai-tachi no Renai Zunousen",
+         "type": "TV", "episodes": 12, "status": "FINISHED",
+         "synonyms": ["Kaguya-sama: Love is War"]},
+        {"sources": ["https://myanimelist.net/anime/40591"],
+         "title": "Kaguya-sama wa Kokurasetai? Tensai-tachi no Renai Zunousen",
+         "type": "TV", "episodes": 12, "status": "FINISHED",
+         "synonyms": ["Kaguya-sama: Love is War Season 2"]},
+        {"sources": ["https://myanimelist.net/anime/52198"],
+         "title": "Kaguya-sama wa Kokurasetai: First Kiss wa Owaranai",
+         "type": "MOVIE", "episodes": 1, "status": "FINISHED",
+         "synonyms": ["Kaguya-sama: Love is War - The First Kiss That Never Ends"]},
+        {"sources": ["https://myanimelist.net/anime/58514"],
+         "title": "Kusuriya no Hitorigoto 2nd Season", "type": "TV", "episodes": 0,
+         "status": "UPCOMING", "animeSeason": {"season": "UNDEFINED", "year": 0}},
+        {"sources": [], "title": "Nowhere to be found", "type": "TV", "status": "FINISHED"}
+    ]}"#;
+
+    fn database() -> MetadataDatabase {
+        return MetadataDatabase::parse(DATABASE).unwrap();
+    }
+
+    fn titles_of<'a>(candidates: &[Candidate<'a>]) -> Vec<&'a str> {
+        return candidates
+            .iter()
+            .map(|candidate| candidate.metadata.title.as_str())
+            .collect();
+    }
+
+    #[test]
+    fn reads_entries_and_leaves_out_unknown_values() {
+        let database = database();
+        // Entries without any source cannot be linked to.
+        assert_eq!(database.len(), 5);
+
+        let frieren = database.get("https://myanimelist.net/anime/52991").unwrap();
+        assert_eq!(frieren.episodes, Some(28));
+        assert_eq!(frieren.status, AiringStatus::Finished);
+        assert_eq!(
+            (frieren.season.as_deref(), frieren.year),
+            (Some("FALL"), Some(2023))
+        );
+
+        let upcoming = database.get("https://myanimelist.net/anime/58514").unwrap();
+        assert_eq!(upcoming.episodes, None);
+        assert_eq!((upcoming.season.as_deref(), upcoming.year), (None, None));
+        assert_eq!(upcoming.status, AiringStatus::Upcoming);
+    }
+
+    #[test]
+    fn finds_entries_by_any_of_their_sources() {
+        let database = database();
+        let by_anilist = database.get(" https://anilist.co/anime/154587/ ").unwrap();
+        assert_eq!(by_anilist.title, "Sousou no Frieren");
+        assert_eq!(by_anilist.source, "https://myanimelist.net/anime/52991");
+        assert!(database.get("https://anilist.co/anime/1").is_none());
+    }
+
+    #[test]
+    fn matches_synonyms() {
+        let database = database();
+        let candidates = database.candidates("Frieren - Beyond Journeys End", 0.8);
+        assert_eq!(titles_of(&candidates), vec!["Sousou no Frieren"]);
+        assert_eq!(candidates[0].matched, "Frieren: Beyond Journey's End");
+        assert!(candidates[0].score >= 1.0);
+
+        match database.resolve("葬送のフリーレン", 0.8) {
+            Resolution::Found(metadata) => assert_eq!(metadata.title, "Sousou no Frieren"),
+            other => panic!("expected Frieren, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn prefers_the_only_exact_match() {
+        // The movie resembles it too, but only the first season is called
+        // that.
+        let database = database();
+        assert!(database.candidates("Kaguya-sama: Love is War", 0.5).len() > 1);
+        match database.resolve("Kaguya-sama: Love is War", 0.5) {
+            Resolution::Found(metadata) => {
+                assert_eq!(metadata.source, "https://myanimelist.net/anime/37999")
+            }
+            other => panic!("expected the first season, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_ambiguous_and_unknown_names() {
+        let database = database();
+        match database.resolve("Kaguya-sama wa Kokurasetai", 0.5) {
+            Resolution::Ambiguous(candidates) => {
+                // Both seasons and the movie.
+                assert_eq!(candidates.len(), 3);
+                assert!(candidates[0].score >= candidates[1].score);
+            }
+            other => panic!("expected every Kaguya-sama entry, got {:?}", other),
+        }
+        assert!(matches!(
+            database.resolve("Cowboy Bebop", 0.8),
+            Resolution::NotFound
+        ));
+    }
+
+    fn anime(title: &str, metadata: Option<AnimeMetadata>) -> Anime {
+        return Anime {
+            title: String::from(title),
+            quality: Quality::Res1080,
+            subgroup: String::from("SubsPlease"),
+            last_seen_episode: 10,
+            tombstone: false,
+            paused: false,
+            tombstoned_at: None,
+            aliases: vec![],
+            matched_alias: None,
+            metadata: metadata,
+            profile: QualityProfile::default(),
+            fallback_subgroups: vec![],
+            delay_hours: 0,
+        };
+    }
+
+    #[test]
+    fn refreshes_the_copies_of_linked_anime() {
+        let database = database();
+        let current = database
+            .get("https://myanimelist.net/anime/52991")
+            .unwrap()
+            .clone();
+        // As linked while it was still airing.
+        let stale = AnimeMetadata {
+            episodes: None,
+            status: AiringStatus::Ongoing,
+            ..current.clone()
+        };
+        assert!(!stale.is_final_episode(28));
+
+        let store = MemoryStore::new();
+        let linked = store
+            .upsert_anime(&anime("Sousou no Frieren", Some(stale)))
+            .unwrap();
+        let unlinked = store.upsert_anime(&anime("Dungeon Meshi", None)).unwrap();
+
+        assert_eq!(refresh(&store, &database).unwrap(), 1);
+        let refreshed = store.get_anime(&linked).unwrap().unwrap().metadata.unwrap();
+        assert_eq!(refreshed, current);
+        assert!(store
+            .get_anime(&unlinked)
+            .unwrap()
+            .unwrap()
+            .metadata
+            .is_none());
+
+        // Only the last episode of the finished show completes it.
+        assert!(refreshed.is_final_episode(28));
+        assert!(!refreshed.is_final_episode(27));
+
+        assert_eq!(refresh(&store, &database).unwrap(), 0);
+    }
+
+    #[test]
+    fn does_not_complete_shows_without_a_known_end() {
+        let database = database();
+        let upcoming = database.get("https://myanimelist.net/anime/58514").unwrap();
+        assert!(!upcoming.is_final_episode(1_000));
+        let airing = AnimeMetadata {
+            status: AiringStatus::Ongoing,
+            ..database
+                .get("https://myanimelist.net/anime/37999")
+                .unwrap()
+                .clone()
+        };
+        assert!(!airing.is_final_episode(12));
+    }
+}
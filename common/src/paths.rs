@@ -0,0 +1,188 @@
+use crate::config_format;
+use crate::constants::{
+    BACKUP_DIR_NAME, CONFIG_FILE_STEM, CONFIG_PATH_ENV, DAEMON_LOCK_FILE_NAME,
+    DAEMON_SOCKET_FILE_NAME, DATA_DIR_ENV, LEGACY_ROCKSDB_PATH, ROCKSDB_DIR_NAME,
+    SYSTEM_CONFIG_DIR, SYSTEM_DATA_DIR,
+};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+/*
+ * Works out where animated keeps its files. Each location is taken from the
+ * first of these that applies:
+ *
+ *   1. the `--config` and `--data-dir` flags,
+ *   2. the `ANIMATED_CONFIG` and `ANIMATED_DATA_DIR` environment variables,
+ *   3. the XDG config, data and state directories for non-root users,
+ *   4. `/etc/animated` and `/var/lib/animated` for system installs.
+ *
+ * Non-root users always get their own config file, which `config` layers
+ * over the system one. Until they run a daemon of their own, i.e. have no
+ * data directory yet, they share the data and state directories of the
+ * system install if there is one, so that the CLI talks to a daemon running
+ * as root.
+ */
+
+#[derive(Debug, Clone, Default)]
+pub struct PathOverrides {
+    pub config: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Paths {
+    pub config_file: PathBuf,
+    pub data_dir: PathBuf,
+    // Holds the daemon lock and control socket. This is the data directory
+    // unless the XDG state directory applies.
+    pub state_dir: PathBuf,
+    pub rocksdb: PathBuf,
+}
+
+impl Paths {
+    pub fn lock_file(&self) -> PathBuf {
+        return self.state_dir.join(DAEMON_LOCK_FILE_NAME);
+    }
+
+    pub fn socket(&self) -> PathBuf {
+        return self.state_dir.join(DAEMON_SOCKET_FILE_NAME);
+    }
+
+    // Where the daemon keeps its backups unless `backups.dir` says
+    // otherwise.
+    pub fn backups(&self, configured: &Option<String>) -> PathBuf {
+        match configured {
+            Some(dir) => PathBuf::from(dir),
+            None => self.data_dir.join(BACKUP_DIR_NAME),
+        }
+    }
+}
+
+lazy_static! {
+    static ref PATHS: RwLock<Option<Paths>> = RwLock::new(None);
+}
+
+fn is_root() -> bool {
+    return unsafe { libc::geteuid() } == 0;
+}
+
+// An XDG base directory, falling back to its default under $HOME.
+fn xdg_dir(var: &str, home_relative: &str) -> Option<PathBuf> {
+    match env::var_os(var) {
+        // The spec says relative paths are invalid and must be ignored.
+        Some(dir) if Path::new(&dir).is_absolute() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(home_relative)),
+    }
+}
+
+fn env_path(var: &str) -> Option<PathBuf> {
+    return env::var_os(var)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from);
+}
+
+// The first config file in `dir` with a known extension, or where a new
+// JSON config would go.
+pub fn find_config_file(dir: &Path) -> PathBuf {
+    for extension in config_format::EXTENSIONS.iter() {
+        let candidate = dir.join(CONFIG_FILE_STEM).with_extension(extension);
+        if candidate.exists() {
+            return candidate;
+        }
+    }
+    return dir.join(CONFIG_FILE_STEM).with_extension("json");
+}
+
+struct Defaults {
+    config_file: PathBuf,
+    data_dir: PathBuf,
+    state_dir: PathBuf,
+}
+
+fn system_defaults() -> Defaults {
+    return Defaults {
+        config_file: find_config_file(Path::new(SYSTEM_CONFIG_DIR)),
+        data_dir: PathBuf::from(SYSTEM_DATA_DIR),
+        state_dir: PathBuf::from(SYSTEM_DATA_DIR),
+    };
+}
+
+fn user_defaults() -> Option<Defaults> {
+    let config_dir = xdg_dir("XDG_CONFIG_HOME", ".config")?;
+    let data_dir = xdg_dir("XDG_DATA_HOME", ".local/share")?;
+    let state_dir = xdg_dir("XDG_STATE_HOME", ".local/state")?;
+    return Some(Defaults {
+        config_file: find_config_file(&config_dir.join("animated")),
+        data_dir: data_dir.join("animated"),
+        state_dir: state_dir.join("animated"),
+    });
+}
+
+fn defaults() -> Defaults {
+    if is_root() {
+        return system_defaults();
+    }
+    let system = system_defaults();
+    match user_defaults() {
+        Some(user) if user.data_dir.exists() || !system.config_file.exists() => user,
+        Some(user) => Defaults {
+            config_file: user.config_file,
+            ..system
+        },
+        None => system,
+    }
+}
+
+pub fn resolve(overrides: &PathOverrides) -> Paths {
+    let defaults = defaults();
+    let data_override = overrides
+        .data_dir
+        .clone()
+        .or_else(|| env_path(DATA_DIR_ENV));
+
+    let config_file = overrides
+        .config
+        .clone()
+        .or_else(|| env_path(CONFIG_PATH_ENV))
+        .unwrap_or(defaults.config_file);
+
+    // Everything the daemon writes follows an explicit data directory, so
+    // that several instances can run side by side.
+    let (data_dir, state_dir) = match data_override {
+        Some(data_dir) => (data_dir.clone(), data_dir),
+        None => (defaults.data_dir, defaults.state_dir),
+    };
+
+    let mut rocksdb = data_dir.join(ROCKSDB_DIR_NAME);
+    if data_dir == Path::new(SYSTEM_DATA_DIR)
+        && !rocksdb.exists()
+        && Path::new(LEGACY_ROCKSDB_PATH).exists()
+    {
+        rocksdb = PathBuf::from(LEGACY_ROCKSDB_PATH);
+    }
+
+    return Paths {
+        config_file: config_file,
+        data_dir: data_dir,
+        state_dir: state_dir,
+        rocksdb: rocksdb,
+    };
+}
+
+// Resolves the paths once for the whole process. Binaries call this before
+// anything touches the filesystem.
+pub fn init(overrides: &PathOverrides) -> Paths {
+    let paths = resolve(overrides);
+    *PATHS.write().unwrap() = Some(paths.clone());
+    return paths;
+}
+
+pub fn get() -> Paths {
+    if let Some(paths) = PATHS.read().unwrap().as_ref() {
+        return paths.clone();
+    }
+    return init(&PathOverrides::default());
+}
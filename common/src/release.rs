@@ -0,0 +1,71 @@
+use crate::structs::{Anime, Quality};
+use crate::titles;
+use regex::Regex;
+use std::str::FromStr;
+
+lazy_static! {
+    // Matches the usual fansub naming scheme, e.g.
+    // `[SubsPlease] Re:Zero kara Hajimeru Isekai Seikatsu - 05v2 (1080p) [ABCD1234].mkv`.
+    static ref RELEASE_TITLE: Regex = Regex::new(
+        r"^\[(?P<subgroup>[^\]]+)\]\s*(?P<title>.+?)\s+-\s+(?P<episode>\d+)(?:v\d+)?(?P<rest>(?:\s.*)?)$"
+    )
+    .unwrap();
+    static ref RELEASE_QUALITY: Regex = Regex::new(r"(?i)\b(?P<quality>\d{3,4}p|4k)\b").unwrap();
+}
+
+// How much a release is wanted, lower being better: by the rank of its
+// subgroup first and that of its quality second, see `Anime::subgroup_rank`
+// and `Anime::quality_rank`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rank {
+    pub subgroup: usize,
+    pub quality: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Release {
+    pub subgroup: String,
+    pub title: String,
+    pub episode: i32,
+    // Releases that do not mention a resolution cannot be matched against a
+    // watched quality and are skipped.
+    pub quality: Option<Quality>,
+}
+
+impl Release {
+    pub fn parse(release_title: &str) -> Option<Release> {
+        let captures = RELEASE_TITLE.captures(release_title.trim())?;
+        let quality = RELEASE_QUALITY
+            .captures(&captures["rest"])
+            .and_then(|quality| Quality::from_str(&quality["quality"].to_lowercase()).ok());
+
+        return Some(Release {
+            subgroup: captures["subgroup"].to_string(),
+            title: captures["title"].to_string(),
+            episode: captures["episode"].parse().ok()?,
+            quality: quality,
+        });
+    }
+
+    // None if `anime` takes neither the subgroup nor the quality.
+    pub fn rank(&self, anime: &Anime) -> Option<Rank> {
+        return Some(Rank {
+            subgroup: anime.subgroup_rank(&self.subgroup)?,
+            quality: anime.quality_rank(self.quality?)?,
+        });
+    }
+
+    // If this is a release of `anime` by a subgroup and in a quality it
+    // accepts, the title or alias of `anime` it was recognized by and its
+    // rank. Titles match if they are at least `threshold` alike, see
+    // `titles::similarity`. Whether the episode is still needed is up to the
+    // caller, since better releases may replace seen ones.
+    pub fn wanted_by<'a>(&self, anime: &'a Anime, threshold: f64) -> Option<(&'a str, Rank)> {
+        if anime.tombstone || anime.paused {
+            return None;
+        }
+        let rank = self.rank(anime)?;
+        return titles::best_match(&self.title, anime.titles(), threshold)
+            .map(|(title, _)| (title, rank));
+    }
+}
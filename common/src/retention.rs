@@ -0,0 +1,302 @@
+use crate::lock;
+use crate::store::{StoreError, WatchStore};
+use crate::structs::{Anime, RetentionConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/*
+ * Keeps the database from growing without limit. Anime that were unwatched
+ * long enough ago are purged together with their episodes and history, and
+ * only the most recent download events of every show are kept. Used by the
+ * daemon on a schedule and by `animated gc`.
+ */
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GcReport {
+    // Watch IDs and the entries that were under them.
+    pub purged: Vec<(String, Anime)>,
+    pub purged_episodes: usize,
+    pub dropped_events: usize,
+}
+
+impl GcReport {
+    pub fn is_empty(&self) -> bool {
+        return self.purged.is_empty() && self.purged_episodes == 0 && self.dropped_events == 0;
+    }
+}
+
+#[derive(Default)]
+struct Plan {
+    report: GcReport,
+    // Tombstones from before their time was recorded, which start aging now.
+    stamp: Vec<(String, Anime)>,
+    episodes: Vec<(String, i32)>,
+    events: Vec<u64>,
+}
+
+fn plan(store: &dyn WatchStore, policy: &RetentionConfig, now: u64) -> Result<Plan, StoreError> {
+    let cutoff = now.saturating_sub(policy.tombstone_days.saturating_mul(SECONDS_PER_DAY));
+    let mut plan = Plan::default();
+
+    let mut purged_ids = HashSet::new();
+    for (watch_id, mut anime) in store.all_anime()? {
+        if !anime.tombstone {
+            continue;
+        }
+        match anime.tombstoned_at {
+            Some(at) if at <= cutoff => {
+                purged_ids.insert(watch_id.clone());
+                plan.report.purged.push((watch_id, anime));
+            }
+            Some(_) => (),
+            None => {
+                anime.tombstoned_at = Some(now);
+                plan.stamp.push((watch_id, anime));
+            }
+        }
+    }
+
+    for record in store.list_episodes()? {
+        if purged_ids.contains(&record.watch_id) {
+            plan.episodes.push((record.watch_id, record.episode));
+        }
+    }
+    plan.report.purged_episodes = plan.episodes.len();
+
+    // Newest first, so that the download events counted first are the ones
+    // that are kept.
+    let mut kept: HashMap<String, usize> = HashMap::new();
+    for (sequence, event) in store.list_events()?.into_iter().rev() {
+        let drop = if purged_ids.contains(&event.watch_id) {
+            true
+        } else if event.kind.is_download() {
+            let count = kept.entry(event.watch_id).or_insert(0);
+            *count += 1;
+            *count > policy.download_events_per_show
+        } else {
+            false
+        };
+        if drop {
+            plan.events.push(sequence);
+        }
+    }
+    plan.report.dropped_events = plan.events.len();
+
+    return Ok(plan);
+}
+
+// Removes whatever `policy` no longer keeps in a single transaction and
+// compacts the store afterwards. With `dry_run` nothing is changed and the
+// report says what would have been removed.
+pub fn collect_garbage(
+    store: &dyn WatchStore,
+    policy: &RetentionConfig,
+    dry_run: bool,
+) -> Result<GcReport, StoreError> {
+    let mut report = GcReport::default();
+    store.transaction(&mut |transaction| {
+        let plan = plan(store, policy, lock::now())?;
+        report = plan.report;
+        if dry_run {
+            return Ok(());
+        }
+        for (watch_id, anime) in plan.stamp.iter() {
+            transaction.put_anime(watch_id, anime);
+        }
+        for (watch_id, _) in report.purged.iter() {
+            transaction.delete_anime(watch_id);
+        }
+        for (watch_id, episode) in plan.episodes.iter() {
+            transaction.delete_episode(watch_id, *episode);
+        }
+        for sequence in plan.events.iter() {
+            transaction.delete_event(*sequence);
+        }
+        return Ok(());
+    })?;
+
+    if !dry_run && !report.is_empty() {
+        store.compact()?;
+    }
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::structs::{EpisodeRecord, EpisodeState, EventKind, Quality, QualityProfile};
+
+    fn anime(title: &str, tombstoned_at: Option<u64>, tombstone: bool) -> Anime {
+        return Anime {
+            title: String::from(title),
+            quality: Quality::Res1080,
+            subgroup: String::from("SubsPlease"),
+            last_seen_episode: 3,
+            tombstone: tombstone,
+            paused: false,
+            tombstoned_at: tombstoned_at,
+            aliases: vec![],
+            matched_alias: None,
+            metadata: None,
+            profile: QualityProfile::default(),
+            fallback_subgroups: vec![],
+            delay_hours: 0,
+        };
+    }
+
+    fn episode(watch_id: &str, episode: i32) -> EpisodeRecord {
+        return EpisodeRecord {
+            watch_id: String::from(watch_id),
+            episode: episode,
+            release_title: format!("[SubsPlease] Show - {:02} (1080p).mkv", episode),
+            link: format!("magnet:?xt=urn:btih:{:040}", episode),
+            info_hash: Some(format!("{:040}", episode)),
+            state: EpisodeState::Completed,
+            matched_title: None,
+            replaces: None,
+            decide_at: None,
+            updated_at: 0,
+            upgrade: None,
+        };
+    }
+
+    // Puts `anime` under `watch_id` with one episode and a download event
+    // for each of `episodes`.
+    fn plant(store: &MemoryStore, watch_id: &str, anime: &Anime, episodes: &[i32]) {
+        store
+            .transaction(&mut |transaction| {
+                transaction.put_anime(watch_id, anime);
+                transaction.record_event(EventKind::Watched, watch_id, None, &anime.title);
+                for number in episodes.iter() {
+                    transaction.put_episode(&episode(watch_id, *number));
+                    transaction.record_event(
+                        EventKind::DownloadCompleted,
+                        watch_id,
+                        Some(*number),
+                        "",
+                    );
+                }
+                return Ok(());
+            })
+            .unwrap();
+    }
+
+    fn policy(tombstone_days: u64, download_events_per_show: usize) -> RetentionConfig {
+        return RetentionConfig {
+            tombstone_days: tombstone_days,
+            download_events_per_show: download_events_per_show,
+            ..RetentionConfig::default()
+        };
+    }
+
+    fn days_ago(days: u64) -> Option<u64> {
+        return Some(lock::now() - days * SECONDS_PER_DAY);
+    }
+
+    fn events_of(store: &MemoryStore, watch_id: &str) -> Vec<(EventKind, Option<i32>)> {
+        return store
+            .list_events()
+            .unwrap()
+            .into_iter()
+            .filter(|(_, event)| event.watch_id == watch_id)
+            .map(|(_, event)| (event.kind, event.episode))
+            .collect();
+    }
+
+    fn episodes_of(store: &MemoryStore, watch_id: &str) -> Vec<i32> {
+        return store
+            .list_episodes()
+            .unwrap()
+            .into_iter()
+            .filter(|record| record.watch_id == watch_id)
+            .map(|record| record.episode)
+            .collect();
+    }
+
+    #[test]
+    fn purges_old_tombstones_with_their_episodes_and_events() {
+        let store = MemoryStore::new();
+        plant(&store, "old", &anime("Old", days_ago(31), true), &[1, 2]);
+        plant(&store, "recent", &anime("Recent", days_ago(29), true), &[1]);
+        plant(&store, "live", &anime("Live", None, false), &[1]);
+
+        let report = collect_garbage(&store, &policy(30, 100), false).unwrap();
+        let purged: Vec<&str> = report.purged.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(purged, vec!["old"]);
+        assert_eq!(report.purged_episodes, 2);
+        assert_eq!(report.dropped_events, 3);
+
+        assert!(store.get_anime("old").unwrap().is_none());
+        assert!(episodes_of(&store, "old").is_empty());
+        assert!(events_of(&store, "old").is_empty());
+        for watch_id in ["recent", "live"].iter() {
+            assert!(store.get_anime(watch_id).unwrap().is_some());
+            assert_eq!(episodes_of(&store, watch_id), vec![1]);
+            assert_eq!(events_of(&store, watch_id).len(), 2);
+        }
+    }
+
+    #[test]
+    fn stamps_tombstones_without_a_time_instead_of_purging_them() {
+        let store = MemoryStore::new();
+        plant(&store, "unstamped", &anime("Unstamped", None, true), &[1]);
+        let before = lock::now();
+
+        // Even a policy that keeps nothing gives them a full period.
+        let report = collect_garbage(&store, &policy(0, 100), false).unwrap();
+        assert!(report.is_empty(), "{:?}", report);
+
+        let stamped = store.get_anime("unstamped").unwrap().unwrap();
+        assert!(stamped.tombstoned_at.unwrap() >= before);
+        assert_eq!(episodes_of(&store, "unstamped"), vec![1]);
+    }
+
+    #[test]
+    fn keeps_only_the_newest_download_events_of_every_show() {
+        let store = MemoryStore::new();
+        plant(&store, "busy", &anime("Busy", None, false), &[1, 2, 3, 4]);
+        plant(&store, "quiet", &anime("Quiet", None, false), &[1]);
+
+        let report = collect_garbage(&store, &policy(30, 2), false).unwrap();
+        assert_eq!(report.dropped_events, 2);
+        assert_eq!(report.purged_episodes, 0);
+
+        assert_eq!(
+            events_of(&store, "busy"),
+            vec![
+                (EventKind::Watched, None),
+                (EventKind::DownloadCompleted, Some(3)),
+                (EventKind::DownloadCompleted, Some(4)),
+            ]
+        );
+        assert_eq!(events_of(&store, "quiet").len(), 2);
+        // Only history goes, the episodes themselves stay.
+        assert_eq!(episodes_of(&store, "busy"), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let store = MemoryStore::new();
+        plant(&store, "old", &anime("Old", days_ago(31), true), &[1, 2]);
+        plant(&store, "unstamped", &anime("Unstamped", None, true), &[1]);
+        plant(&store, "busy", &anime("Busy", None, false), &[1, 2, 3]);
+        let snapshot = |store: &MemoryStore| {
+            return serde_json::to_string(&(
+                store.all_anime().unwrap(),
+                store.list_episodes().unwrap(),
+                store.list_events().unwrap(),
+            ))
+            .unwrap();
+        };
+        let before = snapshot(&store);
+
+        let report = collect_garbage(&store, &policy(30, 1), true).unwrap();
+        assert_eq!(report.purged.len(), 1);
+        assert_eq!(report.purged_episodes, 2);
+        assert_eq!(report.dropped_events, 5);
+        assert_eq!(snapshot(&store), before);
+    }
+}
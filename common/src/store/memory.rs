@@ -0,0 +1,136 @@
+use super::{StoreError, Transaction, WatchStore, Write};
+use crate::structs::{Anime, CachedFeed, EpisodeRecord, Event};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+#[derive(Default)]
+struct Records {
+    anime: BTreeMap<String, Anime>,
+    episodes: BTreeMap<(String, i32), EpisodeRecord>,
+    meta: HashMap<String, String>,
+    feeds: HashMap<String, CachedFeed>,
+    events: BTreeMap<u64, Event>,
+    next_event: u64,
+}
+
+// Keeps everything in memory and forgets it when dropped. Used where nothing
+// should touch the database on disk, e.g. in tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<Records>,
+    // Held for the whole of a transaction, from its first read to its write.
+    transaction_lock: Mutex<()>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        return MemoryStore::default();
+    }
+}
+
+impl WatchStore for MemoryStore {
+    fn get_anime(&self, watch_id: &str) -> Result<Option<Anime>, StoreError> {
+        return Ok(self.records.lock().unwrap().anime.get(watch_id).cloned());
+    }
+
+    fn all_anime(&self) -> Result<Vec<(String, Anime)>, StoreError> {
+        let records = self.records.lock().unwrap();
+        return Ok(records
+            .anime
+            .iter()
+            .map(|(watch_id, anime)| (watch_id.clone(), anime.clone()))
+            .collect());
+    }
+
+    fn get_episode(
+        &self,
+        watch_id: &str,
+        episode: i32,
+    ) -> Result<Option<EpisodeRecord>, StoreError> {
+        let records = self.records.lock().unwrap();
+        return Ok(records
+            .episodes
+            .get(&(String::from(watch_id), episode))
+            .cloned());
+    }
+
+    fn list_episodes(&self) -> Result<Vec<EpisodeRecord>, StoreError> {
+        return Ok(self
+            .records
+            .lock()
+            .unwrap()
+            .episodes
+            .values()
+            .cloned()
+            .collect());
+    }
+
+    fn get_meta(&self, key: &str) -> Result<Option<String>, StoreError> {
+        return Ok(self.records.lock().unwrap().meta.get(key).cloned());
+    }
+
+    fn get_feed(&self, url: &str) -> Result<Option<CachedFeed>, StoreError> {
+        return Ok(self.records.lock().unwrap().feeds.get(url).cloned());
+    }
+
+    fn list_events(&self) -> Result<Vec<(u64, Event)>, StoreError> {
+        let records = self.records.lock().unwrap();
+        return Ok(records
+            .events
+            .iter()
+            .map(|(sequence, event)| (*sequence, event.clone()))
+            .collect());
+    }
+
+    fn transaction(
+        &self,
+        body: &mut dyn FnMut(&mut Transaction) -> Result<(), StoreError>,
+    ) -> Result<(), StoreError> {
+        let _guard = self
+            .transaction_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut transaction = Transaction::default();
+        body(&mut transaction)?;
+
+        let mut records = self.records.lock().unwrap();
+        for write in transaction.writes {
+            match write {
+                Write::PutAnime(watch_id, anime) => {
+                    records.anime.insert(watch_id, anime);
+                }
+                Write::DeleteAnime(watch_id) => {
+                    records.anime.remove(&watch_id);
+                }
+                Write::PutEpisode(record) => {
+                    records
+                        .episodes
+                        .insert((record.watch_id.clone(), record.episode), record);
+                }
+                Write::PutMeta(key, value) => {
+                    records.meta.insert(key, value);
+                }
+                Write::PutFeed(url, feed) => {
+                    records.feeds.insert(url, feed);
+                }
+                Write::DeleteEpisode(watch_id, episode) => {
+                    records.episodes.remove(&(watch_id, episode));
+                }
+                Write::RecordEvent(event) => {
+                    let sequence = records.next_event;
+                    records.next_event += 1;
+                    records.events.insert(sequence, event);
+                }
+                Write::PutEvent(sequence, event) => {
+                    records.events.insert(sequence, event);
+                }
+                Write::DeleteEvent(sequence) => {
+                    records.events.remove(&sequence);
+                }
+            }
+        }
+        return Ok(());
+    }
+}
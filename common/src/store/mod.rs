@@ -0,0 +1,907 @@
+use crate::lock;
+use crate::structs::{
+    normalize_identity_part, Anime, AnimeMetadata, CachedFeed, EpisodeRecord, EpisodeState, Event,
+    EventKind, WATCH_ID_SCHEME,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+mod memory;
+mod rocks;
+
+pub use memory::MemoryStore;
+pub use rocks::RocksStore;
+
+const WATCH_ID_SCHEME_KEY: &str = "watch_id_scheme";
+
+#[derive(Debug)]
+pub struct WatchIdCollision {
+    pub watch_id: String,
+    // Boxed to keep `StoreError` small.
+    pub existing: Box<Anime>,
+}
+
+impl fmt::Display for WatchIdCollision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(
+            f,
+            "Watch ID {} is already used by {}",
+            self.watch_id, self.existing
+        );
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    // E.g. because another process has the database open.
+    Open { path: PathBuf, message: String },
+    Io(String),
+    // A record that cannot be decoded.
+    Corrupt { key: String, message: String },
+    Collision(WatchIdCollision),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Open { path, message } => write!(
+                f,
+                "Failed to open the watch list database `{}`: {}",
+                path.display(),
+                message
+            ),
+            StoreError::Io(message) => write!(f, "Failed to access the watch list: {}", message),
+            StoreError::Corrupt { key, message } => {
+                write!(f, "Watch list record `{}` is corrupt: {}", key, message)
+            }
+            StoreError::Collision(collision) => write!(f, "{}", collision),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+// One backup of the watch list, see `WatchStore::backup`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupInfo {
+    // Increases with every backup in the same directory.
+    pub id: u32,
+    // Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub size_bytes: u64,
+}
+
+enum Write {
+    PutAnime(String, Anime),
+    DeleteAnime(String),
+    PutEpisode(EpisodeRecord),
+    DeleteEpisode(String, i32),
+    PutMeta(String, String),
+    PutFeed(String, CachedFeed),
+    RecordEvent(Event),
+    PutEvent(u64, Event),
+    DeleteEvent(u64),
+}
+
+// Writes queued by a transaction, which are applied all at once when it
+// finishes.
+#[derive(Default)]
+pub struct Transaction {
+    writes: Vec<Write>,
+}
+
+impl Transaction {
+    pub fn put_anime(&mut self, watch_id: &str, anime: &Anime) {
+        self.writes
+            .push(Write::PutAnime(String::from(watch_id), anime.clone()));
+    }
+
+    pub fn delete_anime(&mut self, watch_id: &str) {
+        self.writes.push(Write::DeleteAnime(String::from(watch_id)));
+    }
+
+    pub fn put_episode(&mut self, record: &EpisodeRecord) {
+        self.writes.push(Write::PutEpisode(record.clone()));
+    }
+
+    pub fn delete_episode(&mut self, watch_id: &str, episode: i32) {
+        self.writes
+            .push(Write::DeleteEpisode(String::from(watch_id), episode));
+    }
+
+    pub fn put_meta(&mut self, key: &str, value: &str) {
+        self.writes
+            .push(Write::PutMeta(String::from(key), String::from(value)));
+    }
+
+    pub fn put_feed(&mut self, url: &str, feed: &CachedFeed) {
+        self.writes
+            .push(Write::PutFeed(String::from(url), feed.clone()));
+    }
+
+    pub fn record_event(
+        &mut self,
+        kind: EventKind,
+        watch_id: &str,
+        episode: Option<i32>,
+        detail: &str,
+    ) {
+        self.writes.push(Write::RecordEvent(Event {
+            at: lock::now(),
+            kind: kind,
+            watch_id: String::from(watch_id),
+            episode: episode,
+            detail: String::from(detail),
+        }));
+    }
+
+    // Overwrites a recorded event in place, e.g. to move it to another watch
+    // ID, where `record_event` would append a new one.
+    pub fn put_event(&mut self, sequence: u64, event: &Event) {
+        self.writes.push(Write::PutEvent(sequence, event.clone()));
+    }
+
+    pub fn delete_event(&mut self, sequence: u64) {
+        self.writes.push(Write::DeleteEvent(sequence));
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WatchIdMigrationReport {
+    pub rekeyed: Vec<(String, String)>,
+    // Entries that were left under their old key because another entry
+    // already claimed the stable ID they would have moved to. They are
+    // migrated by a later run once that is no longer the case.
+    pub collisions: Vec<(String, WatchIdCollision)>,
+}
+
+/*
+ * Where the watch list and the ledger of downloaded episodes are kept. Stores
+ * only have to read records and apply transactions. Everything built on top,
+ * like keeping watch IDs unique, is shared by every store.
+ */
+pub trait WatchStore: Send + Sync {
+    fn get_anime(&self, watch_id: &str) -> Result<Option<Anime>, StoreError>;
+
+    // Every entry by watch ID, including tombstoned ones.
+    fn all_anime(&self) -> Result<Vec<(String, Anime)>, StoreError>;
+
+    fn get_episode(
+        &self,
+        watch_id: &str,
+        episode: i32,
+    ) -> Result<Option<EpisodeRecord>, StoreError>;
+
+    // Ordered by watch ID and then episode.
+    fn list_episodes(&self) -> Result<Vec<EpisodeRecord>, StoreError>;
+
+    // Bookkeeping values of the store itself, e.g. the watch ID scheme.
+    fn get_meta(&self, key: &str) -> Result<Option<String>, StoreError>;
+
+    fn get_feed(&self, url: &str) -> Result<Option<CachedFeed>, StoreError>;
+
+    // Events by sequence number, oldest first.
+    fn list_events(&self) -> Result<Vec<(u64, Event)>, StoreError>;
+
+    // Runs `body` while no other transaction can run and then applies the
+    // writes it queued all at once. Nothing is written if `body` fails.
+    // Reads made by `body` do not see its own queued writes.
+    fn transaction(
+        &self,
+        body: &mut dyn FnMut(&mut Transaction) -> Result<(), StoreError>,
+    ) -> Result<(), StoreError>;
+
+    // Reclaims the space of deleted records. Only worth it after deleting
+    // many of them.
+    fn compact(&self) -> Result<(), StoreError> {
+        return Ok(());
+    }
+
+    // Adds a consistent copy of the store to the backups in `dir`, then
+    // drops all but the newest `keep` of them if given. Safe to call while
+    // other threads use the store.
+    fn backup(&self, dir: &Path, _keep: Option<usize>) -> Result<BackupInfo, StoreError> {
+        return Err(StoreError::Io(format!(
+            "this watch list only lives in memory and cannot be backed up to `{}`",
+            dir.display()
+        )));
+    }
+
+    // Every entry, including tombstoned ones, whose title normalizes the
+    // same as `title`. Stores with an index should override the scan.
+    fn find_anime_by_title(&self, title: &str) -> Result<Vec<(String, Anime)>, StoreError> {
+        let title = normalize_identity_part(title);
+        return Ok(self
+            .all_anime()?
+            .into_iter()
+            .filter(|(_, anime)| normalize_identity_part(&anime.title) == title)
+            .collect());
+    }
+
+    // Every episode downloaded from the torrent with `info_hash`. More than
+    // one if a batch covers several episodes. For upgrades, only the upgrade
+    // itself is returned, see `EpisodeRecord::upgrade`. Stores with an index
+    // should override the scan.
+    fn find_episodes_by_info_hash(
+        &self,
+        info_hash: &str,
+    ) -> Result<Vec<EpisodeRecord>, StoreError> {
+        return Ok(self
+            .list_episodes()?
+            .iter()
+            .flat_map(EpisodeRecord::downloads)
+            .filter(|record| {
+                record
+                    .info_hash
+                    .as_ref()
+                    .map_or(false, |hash| hash.eq_ignore_ascii_case(info_hash))
+            })
+            .cloned()
+            .collect());
+    }
+
+    // Re-watching the same show simply overwrites it, but two different
+    // shows must never share a watch ID.
+    fn upsert_anime(&self, anime: &Anime) -> Result<String, StoreError> {
+        let mut watch_id = String::new();
+        self.transaction(&mut |transaction| {
+            watch_id = match find_by_identity(self, anime)? {
+                Some((existing_id, _)) => existing_id,
+                None => anime.watch_id(),
+            };
+            if let Some(existing) = self.get_anime(&watch_id)? {
+                if existing.identity() != anime.identity() {
+                    return Err(StoreError::Collision(WatchIdCollision {
+                        watch_id: watch_id.clone(),
+                        existing: Box::new(existing),
+                    }));
+                }
+            }
+            transaction.put_anime(&watch_id, anime);
+            transaction.record_event(EventKind::Watched, &watch_id, None, &anime.to_string());
+            return Ok(());
+        })?;
+        return Ok(watch_id);
+    }
+
+    // Overwrites the entry stored under `watch_id` without re-deriving its
+    // key, so the watch ID and any history recorded against it are
+    // preserved. Fails if another live entry already has the same title,
+    // subgroup and quality.
+    fn modify_anime(&self, watch_id: &str, anime: &Anime) -> Result<(), StoreError> {
+        return self.transaction(&mut |transaction| {
+            if let Some((existing_id, existing)) = find_by_identity(self, anime)? {
+                if existing_id != watch_id && !existing.tombstone {
+                    return Err(StoreError::Collision(WatchIdCollision {
+                        watch_id: existing_id,
+                        existing: Box::new(existing),
+                    }));
+                }
+            }
+            transaction.put_anime(watch_id, anime);
+            transaction.record_event(EventKind::Modified, watch_id, None, &anime.to_string());
+            return Ok(());
+        });
+    }
+
+    // Marks the entry as deleted rather than removing it. Returns false if
+    // there is no live entry under `watch_id`.
+    fn tombstone_anime(&self, watch_id: &str) -> Result<bool, StoreError> {
+        return tombstone(self, watch_id, EventKind::Unwatched);
+    }
+
+    // Unwatches a show that has no episodes left to download. Only the
+    // event differs from `tombstone_anime`.
+    fn complete_anime(&self, watch_id: &str) -> Result<bool, StoreError> {
+        return tombstone(self, watch_id, EventKind::Completed);
+    }
+
+    fn list_anime(&self) -> Result<HashMap<String, Anime>, StoreError> {
+        return Ok(self
+            .all_anime()?
+            .into_iter()
+            .filter(|(_, anime)| !anime.tombstone)
+            .collect());
+    }
+
+    // Records an event whenever the state of the episode changes, but not
+    // when e.g. a download is merely resumed. An upgrade of a completed
+    // episode is kept beside it until the upgrade completes too, so that a
+    // failed one does not lose track of the download it was to replace.
+    fn put_episode(&self, record: &EpisodeRecord) -> Result<(), StoreError> {
+        return self.transaction(&mut |transaction| {
+            let old = self.get_episode(&record.watch_id, record.episode)?;
+            // That of the upgrade if `record` is one.
+            let old_state = old.as_ref().map(|old| match &old.upgrade {
+                Some(upgrade) if upgrade.link == record.link => upgrade.state,
+                _ => old.state,
+            });
+            let stored = match old {
+                Some(mut completed)
+                    if completed.state == EpisodeState::Completed
+                        && record.state != EpisodeState::Completed
+                        && record.replaces.is_some()
+                        && record.replaces == completed.info_hash =>
+                {
+                    completed.upgrade = Some(Box::new(record.clone()));
+                    completed
+                }
+                _ => record.clone(),
+            };
+
+            if old_state != Some(record.state) {
+                let kind = match record.state {
+                    EpisodeState::Downloading => EventKind::DownloadStarted,
+                    EpisodeState::Completed => EventKind::DownloadCompleted,
+                    EpisodeState::Failed => EventKind::DownloadFailed,
+                    EpisodeState::Pending => EventKind::DownloadPending,
+                };
+                transaction.record_event(
+                    kind,
+                    &record.watch_id,
+                    Some(record.episode),
+                    &record.release_title,
+                );
+            }
+            // Remembered on the anime so that `animated list` can show it.
+            if let Some(matched) = &record.matched_title {
+                match self.get_anime(&record.watch_id)? {
+                    Some(mut anime) if anime.matched_alias.as_ref() != Some(matched) => {
+                        anime.matched_alias = Some(matched.clone());
+                        transaction.put_anime(&record.watch_id, &anime);
+                    }
+                    _ => (),
+                }
+            }
+            transaction.put_episode(&stored);
+            return Ok(());
+        });
+    }
+
+    fn put_feed(&self, url: &str, feed: &CachedFeed) -> Result<(), StoreError> {
+        return self.transaction(&mut |transaction| {
+            transaction.put_feed(url, feed);
+            return Ok(());
+        });
+    }
+
+    // Upgrades count on their own.
+    fn count_episodes_in_state(&self, state: EpisodeState) -> Result<usize, StoreError> {
+        return Ok(self
+            .list_episodes()?
+            .iter()
+            .flat_map(EpisodeRecord::downloads)
+            .filter(|record| record.state == state)
+            .count());
+    }
+
+    // Raises the last seen episode of a watched anime, never lowering it, so
+    // that episodes finishing out of order do not cause re-downloads.
+    fn advance_last_seen_episode(&self, watch_id: &str, episode: i32) -> Result<bool, StoreError> {
+        let mut advanced = false;
+        self.transaction(&mut |transaction| {
+            let mut anime = match self.get_anime(watch_id)? {
+                Some(anime) if anime.last_seen_episode < episode => anime,
+                _ => return Ok(()),
+            };
+            anime.last_seen_episode = episode;
+            transaction.put_anime(watch_id, &anime);
+            advanced = true;
+            return Ok(());
+        })?;
+        return Ok(advanced);
+    }
+
+    // Replaces the copy of the anime-offline-database entry kept on a linked
+    // anime. Returns false if the anime is gone or the copy was current.
+    fn refresh_metadata(
+        &self,
+        watch_id: &str,
+        metadata: &AnimeMetadata,
+    ) -> Result<bool, StoreError> {
+        let mut refreshed = false;
+        self.transaction(&mut |transaction| {
+            let mut anime = match self.get_anime(watch_id)? {
+                Some(anime)
+                    if anime.metadata.is_some() && anime.metadata.as_ref() != Some(metadata) =>
+                {
+                    anime
+                }
+                _ => return Ok(()),
+            };
+            anime.metadata = Some(metadata.clone());
+            transaction.put_anime(watch_id, &anime);
+            refreshed = true;
+            return Ok(());
+        })?;
+        return Ok(refreshed);
+    }
+
+    // Re-keys every entry written under the watch ID scheme from before
+    // `WATCH_ID_SCHEME`, along with its episodes and events. Entries are
+    // moved in a single transaction so an interrupted migration leaves the
+    // store untouched. Only legacy keys are moved, since entries changed by
+    // `modify_anime` keep an ID that no longer matches their digest. The
+    // scheme marker is only written once nothing collides, so entries left
+    // under their legacy key are retried until the collision is resolved,
+    // e.g. by unwatching one of them.
+    fn migrate_watch_ids(&self) -> Result<WatchIdMigrationReport, StoreError> {
+        let mut report = WatchIdMigrationReport::default();
+        self.transaction(&mut |transaction| {
+            if self.get_meta(WATCH_ID_SCHEME_KEY)?.as_deref() == Some(WATCH_ID_SCHEME) {
+                return Ok(());
+            }
+            let records = self.all_anime()?;
+            let episodes = self.list_episodes()?;
+            let events = self.list_events()?;
+
+            // Entries already under a stable ID claim it first so that they
+            // are never displaced by a legacy entry that normalizes the same.
+            let mut claimed: HashMap<String, Anime> = records
+                .iter()
+                .filter(|(key, _)| !is_legacy_watch_id(key))
+                .cloned()
+                .collect();
+
+            for (key, anime) in records {
+                if !is_legacy_watch_id(&key) {
+                    continue;
+                }
+                let watch_id = anime.watch_id();
+
+                // Unwatched entries give way to watched ones of the same show,
+                // and are purged by `gc` in time.
+                match claimed.get(&watch_id) {
+                    Some(_) if anime.tombstone => continue,
+                    Some(existing) if !existing.tombstone => {
+                        report.collisions.push((
+                            key,
+                            WatchIdCollision {
+                                watch_id: watch_id,
+                                existing: Box::new(existing.clone()),
+                            },
+                        ));
+                        continue;
+                    }
+                    _ => (),
+                }
+
+                transaction.put_anime(&watch_id, &anime);
+                transaction.delete_anime(&key);
+                for record in episodes.iter().filter(|record| record.watch_id == key) {
+                    let mut moved = record.clone();
+                    moved.watch_id = watch_id.clone();
+                    if let Some(upgrade) = moved.upgrade.as_mut() {
+                        upgrade.watch_id = watch_id.clone();
+                    }
+                    transaction.delete_episode(&key, record.episode);
+                    transaction.put_episode(&moved);
+                }
+                for (sequence, event) in events.iter().filter(|(_, event)| event.watch_id == key) {
+                    let mut moved = event.clone();
+                    moved.watch_id = watch_id.clone();
+                    transaction.put_event(*sequence, &moved);
+                }
+                claimed.insert(watch_id.clone(), anime);
+                report.rekeyed.push((key, watch_id));
+            }
+            if report.collisions.is_empty() {
+                transaction.put_meta(WATCH_ID_SCHEME_KEY, WATCH_ID_SCHEME);
+            }
+            return Ok(());
+        })?;
+        return Ok(report);
+    }
+}
+
+// Watch IDs from before `WATCH_ID_SCHEME` are the decimal output of a 64-bit
+// hash. A base32 digest made up of digits alone is too unlikely to matter.
+fn is_legacy_watch_id(key: &str) -> bool {
+    return key.chars().all(|c| c.is_ascii_digit()) && key.parse::<u64>().is_ok();
+}
+
+// Watch IDs are only derived from the identity when an entry is created, so
+// an entry that has since been modified lives under a key that no longer
+// matches its digest. Lookups by identity therefore go through the title.
+// Prefers a live entry over a tombstoned one.
+pub fn find_by_identity<S: WatchStore + ?Sized>(
+    store: &S,
+    anime: &Anime,
+) -> Result<Option<(String, Anime)>, StoreError> {
+    let identity = anime.identity();
+    let mut tombstoned = None;
+    for (key, anime) in store.find_anime_by_title(&anime.title)? {
+        if anime.identity() != identity {
+            continue;
+        }
+        if !anime.tombstone {
+            return Ok(Some((key, anime)));
+        }
+        tombstoned = Some((key, anime));
+    }
+    return Ok(tombstoned);
+}
+
+// Shared by `tombstone_anime` and `complete_anime`.
+fn tombstone<S: WatchStore + ?Sized>(
+    store: &S,
+    watch_id: &str,
+    kind: EventKind,
+) -> Result<bool, StoreError> {
+    let mut tombstoned = false;
+    store.transaction(&mut |transaction| {
+        let mut anime = match store.get_anime(watch_id)? {
+            Some(anime) if !anime.tombstone => anime,
+            _ => return Ok(()),
+        };
+        anime.tombstone = true;
+        anime.tombstoned_at = Some(lock::now());
+        transaction.put_anime(watch_id, &anime);
+        transaction.record_event(kind, watch_id, None, &anime.to_string());
+        tombstoned = true;
+        return Ok(());
+    })?;
+    return Ok(tombstoned);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{Quality, QualityProfile};
+
+    fn anime(title: &str, last_seen_episode: i32) -> Anime {
+        return Anime {
+            title: String::from(title),
+            quality: Quality::Res1080,
+            subgroup: String::from("SubsPlease"),
+            last_seen_episode: last_seen_episode,
+            tombstone: false,
+            paused: false,
+            tombstoned_at: None,
+            aliases: vec![],
+            matched_alias: None,
+            metadata: None,
+            profile: QualityProfile::default(),
+            fallback_subgroups: vec![],
+            delay_hours: 0,
+        };
+    }
+
+    fn episode(watch_id: &str, episode: i32, state: EpisodeState) -> EpisodeRecord {
+        return EpisodeRecord {
+            watch_id: String::from(watch_id),
+            episode: episode,
+            release_title: format!("[SubsPlease] Frieren - {:02} (1080p).mkv", episode),
+            link: format!("magnet:?xt=urn:btih:{:040}", episode),
+            info_hash: Some(format!("{:040}", episode)),
+            state: state,
+            matched_title: None,
+            replaces: None,
+            decide_at: None,
+            updated_at: 0,
+            upgrade: None,
+        };
+    }
+
+    // Writes `anime` under `key` as an older scheme or version would have.
+    fn plant(store: &MemoryStore, key: &str, anime: &Anime) {
+        store
+            .transaction(&mut |transaction| {
+                transaction.put_anime(key, anime);
+                return Ok(());
+            })
+            .unwrap();
+    }
+
+    // As written by the `DefaultHasher` scheme from before `WATCH_ID_SCHEME`.
+    const LEGACY_ID: &str = "4696812834165468218";
+
+    fn download_events(store: &MemoryStore) -> Vec<(EventKind, Option<i32>)> {
+        return store
+            .list_events()
+            .unwrap()
+            .into_iter()
+            .filter(|(_, event)| event.kind.is_download())
+            .map(|(_, event)| (event.kind, event.episode))
+            .collect();
+    }
+
+    #[test]
+    fn upsert_anime_overwrites_the_same_show() {
+        let store = MemoryStore::new();
+        let watch_id = store.upsert_anime(&anime("Frieren", 1)).unwrap();
+        assert_eq!(watch_id, anime("Frieren", 1).watch_id());
+
+        // Normalizes the same, so it is the same show.
+        assert_eq!(store.upsert_anime(&anime("frieren ", 5)).unwrap(), watch_id);
+        assert_eq!(store.all_anime().unwrap().len(), 1);
+        let stored = store.get_anime(&watch_id).unwrap().unwrap();
+        assert_eq!(stored.last_seen_episode, 5);
+    }
+
+    #[test]
+    fn upsert_anime_rejects_another_show_under_its_watch_id() {
+        let store = MemoryStore::new();
+        let wanted = anime("Frieren", 0);
+        plant(&store, &wanted.watch_id(), &anime("Dungeon Meshi", 2));
+
+        match store.upsert_anime(&wanted) {
+            Err(StoreError::Collision(collision)) => {
+                assert_eq!(collision.watch_id, wanted.watch_id());
+                assert_eq!(collision.existing.title, "Dungeon Meshi");
+            }
+            other => panic!("Expected a collision, got {:?}", other),
+        }
+        let stored = store.get_anime(&wanted.watch_id()).unwrap().unwrap();
+        assert_eq!(stored.title, "Dungeon Meshi");
+    }
+
+    #[test]
+    fn modify_anime_rejects_a_live_duplicate() {
+        let store = MemoryStore::new();
+        let frieren_id = store.upsert_anime(&anime("Frieren", 0)).unwrap();
+        let meshi_id = store.upsert_anime(&anime("Dungeon Meshi", 0)).unwrap();
+
+        match store.modify_anime(&meshi_id, &anime("Frieren", 3)) {
+            Err(StoreError::Collision(collision)) => assert_eq!(collision.watch_id, frieren_id),
+            other => panic!("Expected a collision, got {:?}", other),
+        }
+        let stored = store.get_anime(&meshi_id).unwrap().unwrap();
+        assert_eq!(stored.title, "Dungeon Meshi");
+
+        // An unwatched duplicate does not stand in the way, and the entry
+        // keeps its watch ID.
+        assert!(store.tombstone_anime(&frieren_id).unwrap());
+        store.modify_anime(&meshi_id, &anime("Frieren", 3)).unwrap();
+        let stored = store.get_anime(&meshi_id).unwrap().unwrap();
+        assert_eq!(stored.title, "Frieren");
+        assert_eq!(stored.last_seen_episode, 3);
+    }
+
+    #[test]
+    fn put_episode_records_an_event_only_when_the_state_changes() {
+        let store = MemoryStore::new();
+        let watch_id = store.upsert_anime(&anime("Frieren", 0)).unwrap();
+
+        store
+            .put_episode(&episode(&watch_id, 1, EpisodeState::Downloading))
+            .unwrap();
+        // E.g. a resumed download.
+        store
+            .put_episode(&episode(&watch_id, 1, EpisodeState::Downloading))
+            .unwrap();
+        store
+            .put_episode(&episode(&watch_id, 1, EpisodeState::Completed))
+            .unwrap();
+        store
+            .put_episode(&episode(&watch_id, 2, EpisodeState::Failed))
+            .unwrap();
+
+        assert_eq!(
+            download_events(&store),
+            vec![
+                (EventKind::DownloadStarted, Some(1)),
+                (EventKind::DownloadCompleted, Some(1)),
+                (EventKind::DownloadFailed, Some(2)),
+            ]
+        );
+        let record = store.get_episode(&watch_id, 1).unwrap().unwrap();
+        assert_eq!(record.state, EpisodeState::Completed);
+    }
+
+    #[test]
+    fn put_episode_keeps_completed_episodes_until_their_upgrade_completes() {
+        let store = MemoryStore::new();
+        let watch_id = store.upsert_anime(&anime("Frieren", 0)).unwrap();
+        let completed = episode(&watch_id, 1, EpisodeState::Completed);
+        store.put_episode(&completed).unwrap();
+
+        let mut upgrade = episode(&watch_id, 1, EpisodeState::Downloading);
+        upgrade.release_title = String::from("[SubsPlease] Frieren - 01 (4k).mkv");
+        upgrade.link = String::from("magnet:?xt=urn:btih:upgrade");
+        upgrade.info_hash = Some(String::from("upgrade"));
+        upgrade.replaces = completed.info_hash.clone();
+        store.put_episode(&upgrade).unwrap();
+
+        let record = store.get_episode(&watch_id, 1).unwrap().unwrap();
+        assert_eq!(record.state, EpisodeState::Completed);
+        assert_eq!(record.info_hash, completed.info_hash);
+        assert_eq!(
+            record.upgrade.map(|upgrade| upgrade.state),
+            Some(EpisodeState::Downloading)
+        );
+        let found = store.find_episodes_by_info_hash("upgrade").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].release_title, upgrade.release_title);
+        assert_eq!(
+            store
+                .count_episodes_in_state(EpisodeState::Downloading)
+                .unwrap(),
+            1
+        );
+
+        // A failed upgrade leaves the completed download in place.
+        upgrade.state = EpisodeState::Failed;
+        store.put_episode(&upgrade).unwrap();
+        let record = store.get_episode(&watch_id, 1).unwrap().unwrap();
+        assert_eq!(record.state, EpisodeState::Completed);
+        assert_eq!(record.release_title, completed.release_title);
+
+        // One that completes takes its place.
+        upgrade.state = EpisodeState::Completed;
+        upgrade.replaces = None;
+        store.put_episode(&upgrade).unwrap();
+        let record = store.get_episode(&watch_id, 1).unwrap().unwrap();
+        assert_eq!(record.state, EpisodeState::Completed);
+        assert_eq!(record.release_title, upgrade.release_title);
+        assert!(record.upgrade.is_none());
+
+        assert_eq!(
+            download_events(&store),
+            vec![
+                (EventKind::DownloadCompleted, Some(1)),
+                (EventKind::DownloadStarted, Some(1)),
+                (EventKind::DownloadFailed, Some(1)),
+                (EventKind::DownloadCompleted, Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn put_episode_remembers_the_matched_title() {
+        let store = MemoryStore::new();
+        let watch_id = store.upsert_anime(&anime("Sousou no Frieren", 0)).unwrap();
+
+        let mut record = episode(&watch_id, 1, EpisodeState::Downloading);
+        record.matched_title = Some(String::from("Frieren"));
+        store.put_episode(&record).unwrap();
+
+        let stored = store.get_anime(&watch_id).unwrap().unwrap();
+        assert_eq!(stored.matched_alias.as_deref(), Some("Frieren"));
+    }
+
+    #[test]
+    fn advance_last_seen_episode_never_lowers_it() {
+        let store = MemoryStore::new();
+        let watch_id = store.upsert_anime(&anime("Frieren", 5)).unwrap();
+
+        assert!(!store.advance_last_seen_episode(&watch_id, 3).unwrap());
+        assert!(!store.advance_last_seen_episode(&watch_id, 5).unwrap());
+        let stored = store.get_anime(&watch_id).unwrap().unwrap();
+        assert_eq!(stored.last_seen_episode, 5);
+
+        assert!(store.advance_last_seen_episode(&watch_id, 7).unwrap());
+        let stored = store.get_anime(&watch_id).unwrap().unwrap();
+        assert_eq!(stored.last_seen_episode, 7);
+
+        assert!(!store.advance_last_seen_episode("missing", 9).unwrap());
+    }
+
+    #[test]
+    fn migrate_watch_ids_rekeys_legacy_entries_once() {
+        let store = MemoryStore::new();
+        let frieren = anime("Frieren", 2);
+        plant(&store, LEGACY_ID, &frieren);
+        store
+            .put_episode(&episode(LEGACY_ID, 2, EpisodeState::Completed))
+            .unwrap();
+
+        let report = store.migrate_watch_ids().unwrap();
+        assert_eq!(
+            report.rekeyed,
+            vec![(String::from(LEGACY_ID), frieren.watch_id())]
+        );
+        assert!(report.collisions.is_empty());
+        assert!(store.get_anime(LEGACY_ID).unwrap().is_none());
+        let stored = store.get_anime(&frieren.watch_id()).unwrap().unwrap();
+        assert_eq!(stored.last_seen_episode, 2);
+        assert_eq!(
+            store.get_meta(WATCH_ID_SCHEME_KEY).unwrap().as_deref(),
+            Some(WATCH_ID_SCHEME)
+        );
+
+        // The history of the entry moves along with it.
+        assert!(store.get_episode(LEGACY_ID, 2).unwrap().is_none());
+        let record = store.get_episode(&frieren.watch_id(), 2).unwrap().unwrap();
+        assert_eq!(record.watch_id, frieren.watch_id());
+        assert_eq!(
+            store
+                .find_episodes_by_info_hash(&format!("{:040}", 2))
+                .unwrap()[0]
+                .watch_id,
+            frieren.watch_id()
+        );
+        let events = store.list_events().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].1.watch_id, frieren.watch_id());
+
+        // Once the marker is written, later entries are left alone.
+        plant(&store, "1357924680", &anime("Dungeon Meshi", 0));
+        let report = store.migrate_watch_ids().unwrap();
+        assert!(report.rekeyed.is_empty());
+        assert!(store.get_anime("1357924680").unwrap().is_some());
+    }
+
+    #[test]
+    fn migrate_watch_ids_retries_until_collisions_are_resolved() {
+        let store = MemoryStore::new();
+        let watch_id = store.upsert_anime(&anime("Frieren", 4)).unwrap();
+        plant(&store, LEGACY_ID, &anime("frieren", 2));
+
+        for _ in 0..2 {
+            let report = store.migrate_watch_ids().unwrap();
+            assert!(report.rekeyed.is_empty());
+            assert_eq!(report.collisions.len(), 1);
+            let (key, collision) = &report.collisions[0];
+            assert_eq!(key, LEGACY_ID);
+            assert_eq!(collision.watch_id, watch_id);
+            assert_eq!(collision.existing.last_seen_episode, 4);
+            assert_eq!(store.get_meta(WATCH_ID_SCHEME_KEY).unwrap(), None);
+        }
+
+        // With the stable entry unwatched, the legacy one takes its place.
+        assert!(store.tombstone_anime(&watch_id).unwrap());
+        let report = store.migrate_watch_ids().unwrap();
+        assert_eq!(
+            report.rekeyed,
+            vec![(String::from(LEGACY_ID), watch_id.clone())]
+        );
+        assert!(report.collisions.is_empty());
+        let stored = store.get_anime(&watch_id).unwrap().unwrap();
+        assert!(!stored.tombstone);
+        assert_eq!(stored.last_seen_episode, 2);
+        assert_eq!(
+            store.get_meta(WATCH_ID_SCHEME_KEY).unwrap().as_deref(),
+            Some(WATCH_ID_SCHEME)
+        );
+    }
+
+    #[test]
+    fn migrate_watch_ids_keeps_the_id_of_modified_entries() {
+        let store = MemoryStore::new();
+        let watch_id = store.upsert_anime(&anime("Frieren", 0)).unwrap();
+        store
+            .modify_anime(&watch_id, &anime("Sousou no Frieren", 0))
+            .unwrap();
+        // Keeps the marker from being written.
+        store.upsert_anime(&anime("Dungeon Meshi", 0)).unwrap();
+        plant(&store, LEGACY_ID, &anime("dungeon meshi", 0));
+
+        for _ in 0..2 {
+            let report = store.migrate_watch_ids().unwrap();
+            assert!(report.rekeyed.is_empty());
+            assert_eq!(report.collisions.len(), 1);
+            let stored = store.get_anime(&watch_id).unwrap().unwrap();
+            assert_eq!(stored.title, "Sousou no Frieren");
+        }
+        assert!(store
+            .get_anime(&anime("Sousou no Frieren", 0).watch_id())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn migrate_watch_ids_leaves_colliding_tombstones_behind() {
+        let store = MemoryStore::new();
+        let watch_id = store.upsert_anime(&anime("Frieren", 4)).unwrap();
+        let mut unwatched = anime("frieren", 2);
+        unwatched.tombstone = true;
+        plant(&store, LEGACY_ID, &unwatched);
+
+        let report = store.migrate_watch_ids().unwrap();
+        assert!(report.rekeyed.is_empty());
+        assert!(report.collisions.is_empty());
+        let stored = store.get_anime(&watch_id).unwrap().unwrap();
+        assert_eq!(stored.last_seen_episode, 4);
+        // For `gc` to purge.
+        assert!(store.get_anime(LEGACY_ID).unwrap().unwrap().tombstone);
+        assert_eq!(
+            store.get_meta(WATCH_ID_SCHEME_KEY).unwrap().as_deref(),
+            Some(WATCH_ID_SCHEME)
+        );
+    }
+}
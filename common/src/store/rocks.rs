@@ -0,0 +1,715 @@
+use super::{BackupInfo, StoreError, Transaction, WatchStore, Write};
+use crate::structs::{
+    normalize_identity_part, Anime, BincodeAnime, BincodeEpisodeRecord, CachedFeed, EpisodeRecord,
+    Event, LegacyAnime,
+};
+use rocksdb::{
+    backup::{BackupEngine, BackupEngineOptions, RestoreOptions},
+    ColumnFamily, IteratorMode, Options, WriteBatch, DB,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Mutex,
+};
+
+// Bumped whenever the layout on disk changes, together with a step in
+// `UPGRADES` that brings databases written by older versions up to date.
+const SCHEMA_VERSION: u32 = 2;
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+// Records by watch ID.
+const ANIME_CF: &str = "anime";
+// The ledger of episodes, keyed by watch ID and episode.
+const EPISODES_CF: &str = "episodes";
+// Index of the ledger by info hash, pointing at keys of `EPISODES_CF`.
+const DOWNLOADS_CF: &str = "downloads";
+// Index of the watch list by normalized title, pointing at watch IDs.
+const ANIME_BY_TITLE_CF: &str = "anime_by_title";
+// What every feed returned when it was last fetched, by URL.
+const FEED_CACHE_CF: &str = "feed_cache";
+// The history of the watch list, by sequence number.
+const EVENTS_CF: &str = "events";
+const COLUMN_FAMILIES: [&str; 6] = [
+    ANIME_CF,
+    EPISODES_CF,
+    DOWNLOADS_CF,
+    ANIME_BY_TITLE_CF,
+    FEED_CACHE_CF,
+    EVENTS_CF,
+];
+
+// Bookkeeping values like the schema version live in the default column
+// family. Up to schema version 1 everything did, with anime under their
+// watch ID and everything else under keys starting with `__`. Watch IDs are
+// lowercase base32 so they can never collide with it.
+const INTERNAL_KEY_PREFIX: &str = "__";
+const META_KEY_PREFIX: &str = "__meta__:";
+const LEGACY_EPISODE_KEY_PREFIX: &str = "__episode__:";
+
+// Upgrades from each schema version to the next, applied in order. Every
+// step only queues writes, so an interrupted upgrade leaves the database as
+// it was.
+const UPGRADES: [fn(&RocksStore, &mut WriteBatch) -> Result<(), StoreError>; 1] =
+    [upgrade_flat_layout];
+
+// RocksDB refuses to open a database that is already open, even from the
+// same process, so a process opens it once and shares the store between its
+// threads.
+pub struct RocksStore {
+    db: DB,
+    // Held for the whole of a transaction, from its first read to its write.
+    transaction_lock: Mutex<()>,
+    next_event: AtomicU64,
+}
+
+fn io_error(e: rocksdb::Error) -> StoreError {
+    return StoreError::Io(e.into_string());
+}
+
+fn backup_engine(dir: &Path) -> Result<BackupEngine, StoreError> {
+    fs::create_dir_all(dir)
+        .map_err(|e| StoreError::Io(format!("cannot create `{}`: {}", dir.display(), e)))?;
+    return BackupEngine::open(&BackupEngineOptions::default(), dir).map_err(io_error);
+}
+
+// Oldest first.
+fn backups_of(engine: &BackupEngine) -> Vec<BackupInfo> {
+    let mut backups: Vec<BackupInfo> = engine
+        .get_backup_info()
+        .into_iter()
+        .map(|info| BackupInfo {
+            id: info.backup_id,
+            created_at: info.timestamp.max(0) as u64,
+            size_bytes: info.size,
+        })
+        .collect();
+    backups.sort_by_key(|backup| backup.id);
+    return backups;
+}
+
+// E.g. `rocksdb.pre-restore` next to `rocksdb`.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    return path.with_file_name(name);
+}
+
+fn remove_dir(path: &Path) -> Result<(), StoreError> {
+    match fs::remove_dir_all(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(StoreError::Io(format!(
+            "cannot remove `{}`: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+fn is_internal_key(key: &[u8]) -> bool {
+    return key.starts_with(INTERNAL_KEY_PREFIX.as_bytes());
+}
+
+fn meta_key(key: &str) -> String {
+    return format!("{}{}", META_KEY_PREFIX, key);
+}
+
+fn episode_key(watch_id: &str, episode: i32) -> String {
+    // Zero padded so that a scan returns episodes in order.
+    return format!("{}:{:06}", watch_id, episode);
+}
+
+// Index keys end with the key of the record they point at, so that several
+// records can share a title or an info hash. NUL cannot appear in either.
+fn title_index_key(anime: &Anime, watch_id: &str) -> String {
+    return format!("{}\0{}", normalize_identity_part(&anime.title), watch_id);
+}
+
+fn download_index_key(info_hash: &str, episode_key: &str) -> String {
+    return format!("{}\0{}", info_hash.to_lowercase(), episode_key);
+}
+
+fn event_key(sequence: u64) -> String {
+    return format!("{:020}", sequence);
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    return serde_json::to_vec(value).expect("Records always serialize to JSON.");
+}
+
+fn decode<T: DeserializeOwned>(key: &[u8], bytes: &[u8]) -> Result<T, StoreError> {
+    return serde_json::from_slice(bytes).map_err(|e| StoreError::Corrupt {
+        key: String::from_utf8_lossy(key).into_owned(),
+        message: e.to_string(),
+    });
+}
+
+impl RocksStore {
+    pub fn open(path: &Path) -> Result<RocksStore, StoreError> {
+        let open_error = |message: String| StoreError::Open {
+            path: path.to_path_buf(),
+            message: message,
+        };
+        // RocksDB only creates the last component of the path itself.
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| open_error(e.to_string()))?;
+        }
+
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        // RocksDB refuses to open a database without naming every column
+        // family in it, including ones only a newer version knows about.
+        // Listing fails if there is no database yet.
+        let mut names = DB::list_cf(&options, path).unwrap_or_default();
+        let created = names.is_empty();
+        for name in COLUMN_FAMILIES.iter() {
+            if !names.iter().any(|existing| existing == name) {
+                names.push(name.to_string());
+            }
+        }
+        let db = DB::open_cf(&options, path, &names).map_err(|e| open_error(e.into_string()))?;
+
+        let mut store = RocksStore {
+            db: db,
+            transaction_lock: Mutex::new(()),
+            next_event: AtomicU64::new(0),
+        };
+        if created {
+            store
+                .db
+                .put(
+                    meta_key(SCHEMA_VERSION_KEY).as_bytes(),
+                    SCHEMA_VERSION.to_string(),
+                )
+                .map_err(io_error)?;
+        } else {
+            store.upgrade()?;
+        }
+        let next_event = match store
+            .db
+            .iterator_cf(store.cf(EVENTS_CF), IteratorMode::End)
+            .next()
+        {
+            Some((key, _)) => String::from_utf8_lossy(&*key).parse::<u64>().unwrap_or(0) + 1,
+            None => 0,
+        };
+        store.next_event = AtomicU64::new(next_event);
+        return Ok(store);
+    }
+
+    // The backups in `dir`, oldest first.
+    pub fn list_backups(dir: &Path) -> Result<Vec<BackupInfo>, StoreError> {
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        return Ok(backups_of(&backup_engine(dir)?));
+    }
+
+    // Replaces the database at `path`, which must not be open, with the
+    // newest backup in `dir`. The backup is restored next to `path` and
+    // opened once before it takes its place, so a broken backup leaves the
+    // database alone. The database it replaces is kept at
+    // `<path>.pre-restore` until the next restore.
+    pub fn restore(dir: &Path, path: &Path) -> Result<(BackupInfo, PathBuf), StoreError> {
+        let latest = match RocksStore::list_backups(dir)?.pop() {
+            Some(latest) => latest,
+            None => {
+                return Err(StoreError::Io(format!(
+                    "there are no backups in `{}`",
+                    dir.display()
+                )))
+            }
+        };
+
+        let staging = sibling(path, "restoring");
+        let previous = sibling(path, "pre-restore");
+        remove_dir(&staging)?;
+        backup_engine(dir)?
+            .restore_from_latest_backup(&staging, &staging, &RestoreOptions::default())
+            .map_err(io_error)?;
+        // Also brings backups of older versions up to date.
+        drop(RocksStore::open(&staging)?);
+
+        let rename = |from: &Path, to: &Path| {
+            fs::rename(from, to).map_err(|e| {
+                StoreError::Io(format!(
+                    "cannot move `{}` to `{}`: {}",
+                    from.display(),
+                    to.display(),
+                    e
+                ))
+            })
+        };
+        if path.exists() {
+            remove_dir(&previous)?;
+            rename(path, &previous)?;
+        }
+        rename(&staging, path)?;
+        return Ok((latest, previous));
+    }
+
+    fn cf(&self, name: &str) -> &ColumnFamily {
+        return self
+            .db
+            .cf_handle(name)
+            .expect("Every column family is created when the database is opened.");
+    }
+
+    fn schema_version(&self) -> Result<u32, StoreError> {
+        // Databases from before the schema was versioned have no marker.
+        let version = match self.get_meta(SCHEMA_VERSION_KEY)? {
+            Some(version) => version,
+            None => return Ok(1),
+        };
+        return version.parse().map_err(|_| StoreError::Corrupt {
+            key: meta_key(SCHEMA_VERSION_KEY),
+            message: format!("`{}` is not a schema version", version),
+        });
+    }
+
+    // Brings a database written by an older version up to the current
+    // schema in a single write.
+    fn upgrade(&self) -> Result<(), StoreError> {
+        let version = self.schema_version()?;
+        if version > SCHEMA_VERSION {
+            return Err(StoreError::Open {
+                path: self.db.path().to_path_buf(),
+                message: format!(
+                    "It was written by a newer version of animated (schema version {}, \
+                    this version supports up to {}).",
+                    version, SCHEMA_VERSION
+                ),
+            });
+        }
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        let mut batch = WriteBatch::default();
+        for upgrade in UPGRADES[(version - 1) as usize..].iter() {
+            upgrade(self, &mut batch)?;
+        }
+        batch.put(
+            meta_key(SCHEMA_VERSION_KEY).as_bytes(),
+            SCHEMA_VERSION.to_string(),
+        );
+        self.db.write(batch).map_err(io_error)?;
+        info!(
+            "Upgraded the watch list database from schema version {} to {}.",
+            version, SCHEMA_VERSION
+        );
+        return Ok(());
+    }
+
+    fn get_record<T: DeserializeOwned>(
+        &self,
+        cf: &str,
+        key: &str,
+    ) -> Result<Option<T>, StoreError> {
+        match self
+            .db
+            .get_cf(self.cf(cf), key.as_bytes())
+            .map_err(io_error)?
+        {
+            Some(bytes) => Ok(Some(decode(key.as_bytes(), &bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    // The keys of the records an index points at under `prefix`.
+    fn scan_index(&self, cf: &str, prefix: &str) -> Vec<String> {
+        let mut keys = vec![];
+        for (key, value) in self.db.prefix_iterator_cf(self.cf(cf), prefix.as_bytes()) {
+            // Without a prefix extractor the iterator runs past the prefix.
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            keys.push(String::from_utf8_lossy(&*value).into_owned());
+        }
+        return keys;
+    }
+}
+
+// Schema version 1 kept everything in the default column family, encoded
+// with bincode.
+fn upgrade_flat_layout(store: &RocksStore, batch: &mut WriteBatch) -> Result<(), StoreError> {
+    let corrupt = |key: &[u8], e: bincode::Error| StoreError::Corrupt {
+        key: String::from_utf8_lossy(key).into_owned(),
+        message: e.to_string(),
+    };
+
+    for (key, value) in store.db.iterator(IteratorMode::Start) {
+        if key.starts_with(LEGACY_EPISODE_KEY_PREFIX.as_bytes()) {
+            // Bincode is not self-describing, so the record has to be decoded
+            // with the shape it was written in.
+            let record = bincode::deserialize::<BincodeEpisodeRecord>(&*value)
+                .map(EpisodeRecord::from)
+                .map_err(|e| corrupt(&*key, e))?;
+            let episode_key = episode_key(&record.watch_id, record.episode);
+            batch.put_cf(
+                store.cf(EPISODES_CF),
+                episode_key.as_bytes(),
+                encode(&record),
+            );
+            if let Some(info_hash) = record.info_hash.as_ref() {
+                batch.put_cf(
+                    store.cf(DOWNLOADS_CF),
+                    download_index_key(info_hash, &episode_key).as_bytes(),
+                    episode_key.as_bytes(),
+                );
+            }
+            batch.delete(&*key);
+        } else if !is_internal_key(&*key) {
+            // Records written before `paused` existed have the old shape.
+            let anime = match bincode::deserialize::<BincodeAnime>(&*value) {
+                Ok(anime) => Anime::from(anime),
+                Err(_) => bincode::deserialize::<LegacyAnime>(&*value)
+                    .map(Anime::from)
+                    .map_err(|e| corrupt(&*key, e))?,
+            };
+            let watch_id = String::from_utf8_lossy(&*key).into_owned();
+            batch.put_cf(store.cf(ANIME_CF), watch_id.as_bytes(), encode(&anime));
+            batch.put_cf(
+                store.cf(ANIME_BY_TITLE_CF),
+                title_index_key(&anime, &watch_id).as_bytes(),
+                watch_id.as_bytes(),
+            );
+            batch.delete(&*key);
+        }
+    }
+    return Ok(());
+}
+
+impl WatchStore for RocksStore {
+    fn get_anime(&self, watch_id: &str) -> Result<Option<Anime>, StoreError> {
+        return self.get_record(ANIME_CF, watch_id);
+    }
+
+    fn all_anime(&self) -> Result<Vec<(String, Anime)>, StoreError> {
+        let mut records = vec![];
+        for (key, value) in self.db.iterator_cf(self.cf(ANIME_CF), IteratorMode::Start) {
+            let anime = decode(&*key, &*value)?;
+            records.push((String::from_utf8_lossy(&*key).into_owned(), anime));
+        }
+        return Ok(records);
+    }
+
+    fn get_episode(
+        &self,
+        watch_id: &str,
+        episode: i32,
+    ) -> Result<Option<EpisodeRecord>, StoreError> {
+        return self.get_record(EPISODES_CF, &episode_key(watch_id, episode));
+    }
+
+    fn list_episodes(&self) -> Result<Vec<EpisodeRecord>, StoreError> {
+        let mut records = vec![];
+        for (key, value) in self
+            .db
+            .iterator_cf(self.cf(EPISODES_CF), IteratorMode::Start)
+        {
+            records.push(decode(&*key, &*value)?);
+        }
+        return Ok(records);
+    }
+
+    fn get_meta(&self, key: &str) -> Result<Option<String>, StoreError> {
+        let value = self.db.get(meta_key(key).as_bytes()).map_err(io_error)?;
+        return Ok(value.map(|bytes| String::from_utf8_lossy(&bytes).into_owned()));
+    }
+
+    fn get_feed(&self, url: &str) -> Result<Option<CachedFeed>, StoreError> {
+        return self.get_record(FEED_CACHE_CF, url);
+    }
+
+    fn list_events(&self) -> Result<Vec<(u64, Event)>, StoreError> {
+        let mut events = vec![];
+        for (key, value) in self.db.iterator_cf(self.cf(EVENTS_CF), IteratorMode::Start) {
+            let sequence =
+                String::from_utf8_lossy(&*key)
+                    .parse()
+                    .map_err(|_| StoreError::Corrupt {
+                        key: String::from_utf8_lossy(&*key).into_owned(),
+                        message: String::from("not an event sequence number"),
+                    })?;
+            events.push((sequence, decode(&*key, &*value)?));
+        }
+        return Ok(events);
+    }
+
+    fn backup(&self, dir: &Path, keep: Option<usize>) -> Result<BackupInfo, StoreError> {
+        let mut engine = backup_engine(dir)?;
+        engine
+            .create_new_backup_flush(&self.db, true)
+            .map_err(io_error)?;
+        if let Some(keep) = keep {
+            engine.purge_old_backups(keep).map_err(io_error)?;
+        }
+        let latest = backups_of(&engine).pop();
+        return Ok(latest.expect("A backup was just created."));
+    }
+
+    fn compact(&self) -> Result<(), StoreError> {
+        self.db.compact_range(None::<&[u8]>, None::<&[u8]>);
+        for name in COLUMN_FAMILIES.iter() {
+            self.db
+                .compact_range_cf(self.cf(name), None::<&[u8]>, None::<&[u8]>);
+        }
+        return Ok(());
+    }
+
+    fn find_anime_by_title(&self, title: &str) -> Result<Vec<(String, Anime)>, StoreError> {
+        let prefix = format!("{}\0", normalize_identity_part(title));
+        let mut records = vec![];
+        for watch_id in self.scan_index(ANIME_BY_TITLE_CF, &prefix) {
+            if let Some(anime) = self.get_anime(&watch_id)? {
+                records.push((watch_id, anime));
+            }
+        }
+        return Ok(records);
+    }
+
+    fn find_episodes_by_info_hash(
+        &self,
+        info_hash: &str,
+    ) -> Result<Vec<EpisodeRecord>, StoreError> {
+        let prefix = format!("{}\0", info_hash.to_lowercase());
+        let mut records = vec![];
+        for key in self.scan_index(DOWNLOADS_CF, &prefix) {
+            if let Some(record) = self.get_record::<EpisodeRecord>(EPISODES_CF, &key)? {
+                // Either the record itself or its upgrade.
+                records.extend(
+                    record
+                        .downloads()
+                        .filter(|download| {
+                            download
+                                .info_hash
+                                .as_ref()
+                                .is_some_and(|hash| hash.eq_ignore_ascii_case(info_hash))
+                        })
+                        .cloned(),
+                );
+            }
+        }
+        return Ok(records);
+    }
+
+    // Indexes are updated in the same batch as the records they point at.
+    // The entries of the previous version of a record are found by reading
+    // it, which is safe because no other transaction can write meanwhile.
+    fn transaction(
+        &self,
+        body: &mut dyn FnMut(&mut Transaction) -> Result<(), StoreError>,
+    ) -> Result<(), StoreError> {
+        let _guard = self
+            .transaction_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut transaction = Transaction::default();
+        body(&mut transaction)?;
+
+        let mut batch = WriteBatch::default();
+        for write in transaction.writes {
+            match write {
+                Write::PutAnime(watch_id, anime) => {
+                    if let Some(old) = self.get_anime(&watch_id)? {
+                        batch.delete_cf(
+                            self.cf(ANIME_BY_TITLE_CF),
+                            title_index_key(&old, &watch_id).as_bytes(),
+                        );
+                    }
+                    batch.put_cf(
+                        self.cf(ANIME_BY_TITLE_CF),
+                        title_index_key(&anime, &watch_id).as_bytes(),
+                        watch_id.as_bytes(),
+                    );
+                    batch.put_cf(self.cf(ANIME_CF), watch_id.as_bytes(), encode(&anime));
+                }
+                Write::DeleteAnime(watch_id) => {
+                    if let Some(old) = self.get_anime(&watch_id)? {
+                        batch.delete_cf(
+                            self.cf(ANIME_BY_TITLE_CF),
+                            title_index_key(&old, &watch_id).as_bytes(),
+                        );
+                    }
+                    batch.delete_cf(self.cf(ANIME_CF), watch_id.as_bytes());
+                }
+                Write::PutEpisode(record) => {
+                    let key = episode_key(&record.watch_id, record.episode);
+                    let old: Option<EpisodeRecord> = self.get_record(EPISODES_CF, &key)?;
+                    for download in old.iter().flat_map(EpisodeRecord::downloads) {
+                        if let Some(info_hash) = download.info_hash.as_ref() {
+                            batch.delete_cf(
+                                self.cf(DOWNLOADS_CF),
+                                download_index_key(info_hash, &key).as_bytes(),
+                            );
+                        }
+                    }
+                    for download in record.downloads() {
+                        if let Some(info_hash) = download.info_hash.as_ref() {
+                            batch.put_cf(
+                                self.cf(DOWNLOADS_CF),
+                                download_index_key(info_hash, &key).as_bytes(),
+                                key.as_bytes(),
+                            );
+                        }
+                    }
+                    batch.put_cf(self.cf(EPISODES_CF), key.as_bytes(), encode(&record));
+                }
+                Write::DeleteEpisode(watch_id, episode) => {
+                    let key = episode_key(&watch_id, episode);
+                    let old: Option<EpisodeRecord> = self.get_record(EPISODES_CF, &key)?;
+                    for download in old.iter().flat_map(EpisodeRecord::downloads) {
+                        if let Some(info_hash) = download.info_hash.as_ref() {
+                            batch.delete_cf(
+                                self.cf(DOWNLOADS_CF),
+                                download_index_key(info_hash, &key).as_bytes(),
+                            );
+                        }
+                    }
+                    batch.delete_cf(self.cf(EPISODES_CF), key.as_bytes());
+                }
+                Write::PutMeta(key, value) => batch.put(meta_key(&key).as_bytes(), value),
+                Write::PutFeed(url, feed) => {
+                    batch.put_cf(self.cf(FEED_CACHE_CF), url.as_bytes(), encode(&feed))
+                }
+                Write::RecordEvent(event) => {
+                    let sequence = self.next_event.fetch_add(1, Ordering::SeqCst);
+                    batch.put_cf(
+                        self.cf(EVENTS_CF),
+                        event_key(sequence).as_bytes(),
+                        encode(&event),
+                    );
+                }
+                Write::PutEvent(sequence, event) => batch.put_cf(
+                    self.cf(EVENTS_CF),
+                    event_key(sequence).as_bytes(),
+                    encode(&event),
+                ),
+                Write::DeleteEvent(sequence) => {
+                    batch.delete_cf(self.cf(EVENTS_CF), event_key(sequence).as_bytes())
+                }
+            }
+        }
+        return self.db.write(batch).map_err(io_error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{EpisodeState, Quality};
+    use std::{env, process};
+
+    // A fresh directory for a database, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("animated-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            return TempDir(path);
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn upgrades_schema_1_episode_records() {
+        let dir = TempDir::new("schema-1");
+        let path = dir.0.join("animated.rocksdb");
+        {
+            let db = DB::open_default(&path).unwrap();
+            let anime = BincodeAnime {
+                title: String::from("Frieren"),
+                quality: Quality::Res1080,
+                subgroup: String::from("SubsPlease"),
+                last_seen_episode: 3,
+                tombstone: false,
+                paused: false,
+            };
+            db.put("abcdefghijklmnop", bincode::serialize(&anime).unwrap())
+                .unwrap();
+            let record = BincodeEpisodeRecord {
+                watch_id: String::from("abcdefghijklmnop"),
+                episode: 4,
+                release_title: String::from("[SubsPlease] Frieren - 04 (1080p).mkv"),
+                link: String::from("magnet:?xt=urn:btih:abcd"),
+                info_hash: Some(String::from("abcd")),
+                state: EpisodeState::Completed,
+                updated_at: 1600000000,
+            };
+            db.put(
+                format!("{}abcdefghijklmnop:4", LEGACY_EPISODE_KEY_PREFIX),
+                bincode::serialize(&record).unwrap(),
+            )
+            .unwrap();
+        }
+
+        let store = RocksStore::open(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), SCHEMA_VERSION);
+        let anime = store.get_anime("abcdefghijklmnop").unwrap().unwrap();
+        assert_eq!(anime.last_seen_episode, 3);
+        let record = store.get_episode("abcdefghijklmnop", 4).unwrap().unwrap();
+        assert_eq!(
+            record.release_title,
+            "[SubsPlease] Frieren - 04 (1080p).mkv"
+        );
+        assert_eq!(record.info_hash.as_deref(), Some("abcd"));
+        assert_eq!(record.state, EpisodeState::Completed);
+        assert_eq!(record.matched_title, None);
+        assert_eq!(record.replaces, None);
+        assert_eq!(record.decide_at, None);
+        assert_eq!(record.updated_at, 1600000000);
+        assert_eq!(store.find_episodes_by_info_hash("abcd").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn indexes_the_info_hash_of_upgrades() {
+        let dir = TempDir::new("upgrades");
+        let store = RocksStore::open(&dir.0.join("animated.rocksdb")).unwrap();
+        let completed = EpisodeRecord {
+            watch_id: String::from("abcdefghijklmnop"),
+            episode: 1,
+            release_title: String::from("[SubsPlease] Frieren - 01 (1080p).mkv"),
+            link: String::from("magnet:?xt=urn:btih:abcd"),
+            info_hash: Some(String::from("abcd")),
+            state: EpisodeState::Completed,
+            matched_title: None,
+            replaces: None,
+            decide_at: None,
+            updated_at: 1600000000,
+            upgrade: None,
+        };
+        store.put_episode(&completed).unwrap();
+        let mut upgrade = completed.clone();
+        upgrade.release_title = String::from("[SubsPlease] Frieren - 01 (4k).mkv");
+        upgrade.link = String::from("magnet:?xt=urn:btih:ef01");
+        upgrade.info_hash = Some(String::from("ef01"));
+        upgrade.state = EpisodeState::Downloading;
+        upgrade.replaces = Some(String::from("abcd"));
+        store.put_episode(&upgrade).unwrap();
+
+        let found = store.find_episodes_by_info_hash("EF01").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].state, EpisodeState::Downloading);
+        let found = store.find_episodes_by_info_hash("abcd").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].state, EpisodeState::Completed);
+
+        upgrade.state = EpisodeState::Completed;
+        upgrade.replaces = None;
+        store.put_episode(&upgrade).unwrap();
+        assert!(store.find_episodes_by_info_hash("abcd").unwrap().is_empty());
+        assert_eq!(store.find_episodes_by_info_hash("ef01").unwrap().len(), 1);
+    }
+}
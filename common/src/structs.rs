@@ -1,19 +1,75 @@
+use crate::constants::CONFIG_VERSION;
+use data_encoding::BASE32_NOPAD;
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 use std::{
-    collections::hash_map::DefaultHasher,
+    cmp::Ordering,
     fmt,
     hash::{Hash, Hasher},
     str::FromStr,
 };
 use whoami;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+// Watch IDs are the first 10 bytes of a SHA-1 digest over the normalized
+// identity of an anime, encoded as 16 characters of lowercase base32. Unlike
+// `DefaultHasher`, both SHA-1 and base32 are fixed by their specifications so
+// IDs stay the same across Rust releases and platforms.
+pub const WATCH_ID_SCHEME: &str = "sha1-base32-v1";
+const WATCH_ID_DIGEST_BYTES: usize = 10;
+
+// Variants are compared by resolution, not by their order here. Bincode
+// records of old databases store the index of the variant, so new ones go at
+// the end.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Quality {
     Res360,
     Res480,
     Res720,
     Res1080,
     Res4k,
+    Res540,
+    Res576,
+    Res1440,
+}
+
+impl Quality {
+    // From worst to best.
+    pub const ALL: [Quality; 8] = [
+        Quality::Res360,
+        Quality::Res480,
+        Quality::Res540,
+        Quality::Res576,
+        Quality::Res720,
+        Quality::Res1080,
+        Quality::Res1440,
+        Quality::Res4k,
+    ];
+
+    // The vertical resolution.
+    pub fn lines(&self) -> u32 {
+        match *self {
+            Quality::Res360 => 360,
+            Quality::Res480 => 480,
+            Quality::Res540 => 540,
+            Quality::Res576 => 576,
+            Quality::Res720 => 720,
+            Quality::Res1080 => 1080,
+            Quality::Res1440 => 1440,
+            Quality::Res4k => 2160,
+        }
+    }
+}
+
+impl Ord for Quality {
+    fn cmp(&self, other: &Quality) -> Ordering {
+        return self.lines().cmp(&other.lines());
+    }
+}
+
+impl PartialOrd for Quality {
+    fn partial_cmp(&self, other: &Quality) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
 }
 
 impl FromStr for Quality {
@@ -23,28 +79,315 @@ impl FromStr for Quality {
         match s {
             "360p" => Ok(Quality::Res360),
             "480p" => Ok(Quality::Res480),
+            "540p" => Ok(Quality::Res540),
+            "576p" => Ok(Quality::Res576),
             "720p" => Ok(Quality::Res720),
             "1080p" => Ok(Quality::Res1080),
-            "4k" => Ok(Quality::Res4k),
+            "1440p" => Ok(Quality::Res1440),
+            // Release titles usually go by the resolution.
+            "4k" | "2160p" => Ok(Quality::Res4k),
             _ => Err(()),
         }
     }
 }
 
+// (De)serializes enums through `Display` and `FromStr`, for places users
+// read and write by hand.
+pub(crate) mod by_name {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::{fmt::Display, str::FromStr};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        return serializer.collect_str(value);
+    }
+
+    pub fn deserialize<'de, T: FromStr, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        return T::from_str(&name)
+            .map_err(|_| D::Error::custom(format!("unknown value `{}`", name)));
+    }
+
+    pub mod option {
+        use serde::{de::Error, Deserialize, Deserializer, Serializer};
+        use std::{fmt::Display, str::FromStr};
+
+        pub fn serialize<T: Display, S: Serializer>(
+            value: &Option<T>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match value {
+                Some(value) => serializer.serialize_some(&value.to_string()),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, T: FromStr, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<T>, D::Error> {
+            return match Option::<String>::deserialize(deserializer)? {
+                Some(name) => T::from_str(&name)
+                    .map(Some)
+                    .map_err(|_| D::Error::custom(format!("unknown value `{}`", name))),
+                None => Ok(None),
+            };
+        }
+    }
+
+    pub mod vec {
+        use serde::{de::Error, Deserialize, Deserializer, Serializer};
+        use std::{fmt::Display, str::FromStr};
+
+        pub fn serialize<T: Display, S: Serializer>(
+            values: &[T],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            return serializer.collect_seq(values.iter().map(ToString::to_string));
+        }
+
+        pub fn deserialize<'de, T: FromStr, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<T>, D::Error> {
+            let mut values = vec![];
+            for name in Vec::<String>::deserialize(deserializer)? {
+                match T::from_str(&name) {
+                    Ok(value) => values.push(value),
+                    Err(_) => return Err(D::Error::custom(format!("unknown value `{}`", name))),
+                }
+            }
+            return Ok(values);
+        }
+    }
+}
+
 impl fmt::Display for Quality {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Quality::Res360 => write!(f, "{}", "360p".to_string()),
             Quality::Res480 => write!(f, "{}", "480p".to_string()),
+            Quality::Res540 => write!(f, "540p"),
+            Quality::Res576 => write!(f, "576p"),
             Quality::Res720 => write!(f, "{}", "720p".to_string()),
             Quality::Res1080 => write!(f, "{}", "1080p".to_string()),
+            Quality::Res1440 => write!(f, "1440p"),
             Quality::Res4k => write!(f, "{}", "4k".to_string()),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Anime {
+    pub title: String,
+    // The preferred quality, which is part of the identity. Others may be
+    // acceptable too, see `profile`.
+    pub quality: Quality,
+    // The preferred subtitle group, which is part of the identity.
+    pub subgroup: String,
+    pub last_seen_episode: i32,
+    pub tombstone: bool,
+    pub paused: bool,
+    // Seconds since the Unix epoch. `None` for entries unwatched before this
+    // was recorded.
+    #[serde(default)]
+    pub tombstoned_at: Option<u64>,
+    // Other titles releases of the show go by, e.g. the English or an
+    // abbreviated one. Not part of the identity, so they can change freely.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    // The title or alias the newest release was recognized by.
+    #[serde(default)]
+    pub matched_alias: Option<String>,
+    // Copied from the anime-offline-database when the entry is linked to
+    // it, see `metadata`.
+    #[serde(default)]
+    pub metadata: Option<AnimeMetadata>,
+    #[serde(default)]
+    pub profile: QualityProfile,
+    // Groups whose releases are taken, in this order, when `subgroup` does
+    // not release an episode.
+    #[serde(default)]
+    pub fallback_subgroups: Vec<String>,
+    // How long to wait after the first release of an episode in case a
+    // better ranked group releases it too.
+    #[serde(default)]
+    pub delay_hours: u64,
+}
+
+// Which releases of an anime are acceptable besides those in its preferred
+// quality, and which are worth replacing with a better one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct QualityProfile {
+    // Taken in this order when the preferred quality is not released.
+    #[serde(default, with = "by_name::vec")]
+    pub fallbacks: Vec<Quality>,
+    // Any release at least this good is acceptable too, after the listed
+    // qualities.
+    #[serde(default, with = "by_name::option")]
+    pub minimum: Option<Quality>,
+    // Episodes grabbed in a quality ranked below this one are downloaded
+    // again once a better release shows up. Never without one.
+    #[serde(default, with = "by_name::option")]
+    pub upgrade_until: Option<Quality>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum AiringStatus {
+    Finished,
+    Ongoing,
+    Upcoming,
+    #[serde(other)]
+    Unknown,
+}
+
+impl fmt::Display for AiringStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            AiringStatus::Finished => write!(f, "finished"),
+            AiringStatus::Ongoing => write!(f, "airing"),
+            AiringStatus::Upcoming => write!(f, "upcoming"),
+            AiringStatus::Unknown => write!(f, "status unknown"),
+        }
+    }
+}
+
+// What the anime-offline-database knows about an anime.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnimeMetadata {
+    // The first of `sources`, which identifies the entry in the database.
+    pub source: String,
+    pub title: String,
+    #[serde(default)]
+    pub synonyms: Vec<String>,
+    // E.g. `TV` or `MOVIE`.
+    pub kind: String,
+    // Often unknown while the show is airing.
+    pub episodes: Option<u32>,
+    pub status: AiringStatus,
+    // E.g. `FALL`.
+    pub season: Option<String>,
+    pub year: Option<u32>,
+    // Pages of the anime on MyAnimeList, AniList, AniDB, Kitsu and others.
+    pub sources: Vec<String>,
+}
+
+impl AnimeMetadata {
+    // The ID of the anime on sites with a page under `/anime/<id>`, e.g.
+    // `("myanimelist.net", "52991")`.
+    pub fn site_ids(&self) -> Vec<(&str, &str)> {
+        let mut ids = vec![];
+        for source in self.sources.iter() {
+            let path = match source.split_once("://") {
+                Some((_, path)) => path,
+                None => source,
+            };
+            let mut parts = path.split('/');
+            if let (Some(host), Some("anime"), Some(id)) =
+                (parts.next(), parts.next(), parts.next())
+            {
+                ids.push((host, id));
+            }
+        }
+        return ids;
+    }
+
+    // Whether `episode` is the last one of a show that has finished airing.
+    pub fn is_final_episode(&self, episode: i32) -> bool {
+        if let Some(episodes) = self.episodes {
+            return self.status == AiringStatus::Finished
+                && episode >= 0
+                && episode as u32 >= episodes;
+        }
+        return false;
+    }
+}
+
+impl fmt::Display for AnimeMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}", self.title, self.kind)?;
+        match (&self.season, self.year) {
+            (Some(season), Some(year)) => write!(f, ", {} {}", season.to_lowercase(), year)?,
+            (None, Some(year)) => write!(f, ", {}", year)?,
+            _ => (),
+        }
+        if let Some(episodes) = self.episodes {
+            write!(f, ", {} episodes", episodes)?;
+        }
+        return write!(f, ", {})", self.status);
+    }
+}
+
+// The bincode layout of schema version 1 databases, from before
+// `tombstoned_at` existed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BincodeAnime {
+    pub title: String,
+    pub quality: Quality,
+    pub subgroup: String,
+    pub last_seen_episode: i32,
+    pub tombstone: bool,
+    pub paused: bool,
+}
+
+impl From<BincodeAnime> for Anime {
+    fn from(old: BincodeAnime) -> Anime {
+        return Anime {
+            title: old.title,
+            quality: old.quality,
+            subgroup: old.subgroup,
+            last_seen_episode: old.last_seen_episode,
+            tombstone: old.tombstone,
+            paused: old.paused,
+            tombstoned_at: None,
+            aliases: vec![],
+            matched_alias: None,
+            metadata: None,
+            profile: QualityProfile::default(),
+            fallback_subgroups: vec![],
+            delay_hours: 0,
+        };
+    }
+}
+
+// The bincode layout of episode records in schema version 1 databases,
+// from before `matched_title`, `replaces` and `decide_at` existed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BincodeEpisodeRecord {
+    pub watch_id: String,
+    pub episode: i32,
+    pub release_title: String,
+    pub link: String,
+    pub info_hash: Option<String>,
+    pub state: EpisodeState,
+    pub updated_at: u64,
+}
+
+impl From<BincodeEpisodeRecord> for EpisodeRecord {
+    fn from(old: BincodeEpisodeRecord) -> EpisodeRecord {
+        return EpisodeRecord {
+            watch_id: old.watch_id,
+            episode: old.episode,
+            release_title: old.release_title,
+            link: old.link,
+            info_hash: old.info_hash,
+            state: old.state,
+            matched_title: None,
+            replaces: None,
+            decide_at: None,
+            updated_at: old.updated_at,
+            upgrade: None,
+        };
+    }
+}
+
+// The record layout written before `paused` existed. Bincode is not
+// self-describing, so these records have to be decoded with the old shape.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LegacyAnime {
     pub title: String,
     pub quality: Quality,
     pub subgroup: String,
@@ -52,19 +395,186 @@ pub struct Anime {
     pub tombstone: bool,
 }
 
+impl From<LegacyAnime> for Anime {
+    fn from(legacy: LegacyAnime) -> Anime {
+        return Anime {
+            title: legacy.title,
+            quality: legacy.quality,
+            subgroup: legacy.subgroup,
+            last_seen_episode: legacy.last_seen_episode,
+            tombstone: legacy.tombstone,
+            paused: false,
+            tombstoned_at: None,
+            aliases: vec![],
+            matched_alias: None,
+            metadata: None,
+            profile: QualityProfile::default(),
+            fallback_subgroups: vec![],
+            delay_hours: 0,
+        };
+    }
+}
+
+// Lowercases and collapses runs of whitespace so that `Re:Zero  ` and
+// `re:zero` are treated as the same show.
+pub(crate) fn normalize_identity_part(part: &str) -> String {
+    return part
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase();
+}
+
 impl Hash for Anime {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.title.to_lowercase().hash(state);
-        self.quality.to_string().hash(state);
-        self.subgroup.to_lowercase().hash(state);
+        self.identity().hash(state);
     }
 }
 
 impl Anime {
-    pub fn to_hash(&self) -> String {
-        let mut s = DefaultHasher::new();
-        self.hash(&mut s);
-        return s.finish().to_string();
+    // The normalized title, subgroup and quality joined by NUL bytes, which
+    // cannot appear in any of the individual parts.
+    pub fn identity(&self) -> String {
+        return format!(
+            "{}\0{}\0{}",
+            normalize_identity_part(&self.title),
+            normalize_identity_part(&self.subgroup),
+            self.quality
+        );
+    }
+
+    // The title, the aliases and, for linked anime, the title and synonyms
+    // the anime-offline-database knows, which is the order they are
+    // preferred in when a release matches several equally well.
+    pub fn titles(&self) -> impl Iterator<Item = &str> {
+        let known = self
+            .metadata
+            .iter()
+            .flat_map(|metadata| std::iter::once(&metadata.title).chain(metadata.synonyms.iter()));
+        return std::iter::once(self.title.as_str())
+            .chain(self.aliases.iter().map(String::as_str))
+            .chain(known.map(String::as_str));
+    }
+
+    // The preferred subgroup followed by the fallbacks.
+    pub fn subgroups(&self) -> impl Iterator<Item = &str> {
+        return std::iter::once(self.subgroup.as_str())
+            .chain(self.fallback_subgroups.iter().map(String::as_str));
+    }
+
+    // How much releases by `subgroup` are wanted, 0 being the most, or None
+    // if they are not wanted at all.
+    pub fn subgroup_rank(&self, subgroup: &str) -> Option<usize> {
+        let subgroup = normalize_identity_part(subgroup);
+        return self
+            .subgroups()
+            .position(|listed| normalize_identity_part(listed) == subgroup);
+    }
+
+    // The preferred quality followed by the fallbacks.
+    pub fn qualities(&self) -> impl Iterator<Item = Quality> + '_ {
+        return std::iter::once(self.quality).chain(self.profile.fallbacks.iter().copied());
+    }
+
+    // How much releases in `quality` are wanted, 0 being the most, or None if
+    // they are not wanted at all. Qualities that are only acceptable through
+    // the minimum rank after the listed ones, better ones first.
+    pub fn quality_rank(&self, quality: Quality) -> Option<usize> {
+        if let Some(rank) = self.qualities().position(|listed| listed == quality) {
+            return Some(rank);
+        }
+        return match self.profile.minimum {
+            Some(minimum) if quality >= minimum => {
+                let better = Quality::ALL
+                    .iter()
+                    .filter(|other| **other > quality)
+                    .count();
+                Some(self.qualities().count() + better)
+            }
+            _ => None,
+        };
+    }
+
+    // Whether an episode downloaded in `have` should be replaced by a
+    // release in `offered`.
+    pub fn is_upgrade(&self, have: Quality, offered: Quality) -> bool {
+        let cutoff = match self
+            .profile
+            .upgrade_until
+            .and_then(|q| self.quality_rank(q))
+        {
+            Some(cutoff) => cutoff,
+            None => return false,
+        };
+        // Profiles may have changed since `have` was downloaded.
+        let have = self.quality_rank(have).unwrap_or(usize::MAX);
+        return match self.quality_rank(offered) {
+            Some(offered) => have > cutoff && offered < have,
+            None => false,
+        };
+    }
+
+    // What is wrong with the subgroups or the quality profile, if anything.
+    pub fn preference_problem(&self) -> Option<String> {
+        let normalized: Vec<String> = self.subgroups().map(normalize_identity_part).collect();
+        for (index, subgroup) in self.subgroups().enumerate() {
+            if normalized[index].is_empty() {
+                return Some(String::from("subgroups must not be empty"));
+            }
+            if normalized[..index].contains(&normalized[index]) {
+                return Some(format!(
+                    "the subgroup {} is listed more than once",
+                    subgroup
+                ));
+            }
+        }
+
+        let listed: Vec<Quality> = self.qualities().collect();
+        for (index, quality) in listed.iter().enumerate() {
+            if listed[..index].contains(quality) {
+                return Some(format!("{} is listed more than once", quality));
+            }
+            if let Some(minimum) = self.profile.minimum {
+                if *quality < minimum {
+                    return Some(format!(
+                        "{} is worse than the minimum quality {}",
+                        quality, minimum
+                    ));
+                }
+            }
+        }
+        if let Some(cutoff) = self.profile.upgrade_until {
+            if self.quality_rank(cutoff).is_none() {
+                return Some(format!(
+                    "upgrades cannot go until {}, which is not an acceptable quality",
+                    cutoff
+                ));
+            }
+        }
+        return None;
+    }
+
+    pub fn watch_id(&self) -> String {
+        let digest = Sha1::digest(self.identity().as_bytes());
+        return BASE32_NOPAD
+            .encode(&digest[..WATCH_ID_DIGEST_BYTES])
+            .to_lowercase();
+    }
+}
+
+impl fmt::Display for QualityProfile {
+    // Without the preferred quality, e.g. ` > 720p, at least 480p`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for fallback in self.fallbacks.iter() {
+            write!(f, " > {}", fallback)?;
+        }
+        if let Some(minimum) = self.minimum {
+            write!(f, ", at least {}", minimum)?;
+        }
+        if let Some(cutoff) = self.upgrade_until {
+            write!(f, ", upgrading until {}", cutoff)?;
+        }
+        return Ok(());
     }
 }
 
@@ -74,13 +584,413 @@ impl fmt::Display for Anime {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
 pub struct Config {
+    // Layout version of the config file, see `config_migrations`.
+    #[serde(default = "default_config_version")]
+    pub version: u32,
     #[serde(default = "default_download_path")]
     pub download_path: String,
+    // RSS feeds that are polled for new releases of watched anime.
+    #[serde(default)]
+    pub feeds: Vec<String>,
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    // The port reported to BitTorrent trackers.
+    #[serde(default = "default_listen_port")]
+    pub listen_port: u16,
+    #[serde(default)]
+    pub backend: BackendConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub defaults: WatchDefaults,
+    #[serde(default)]
+    pub backups: BackupConfig,
+    #[serde(default)]
+    pub matching: MatchingConfig,
+    #[serde(default)]
+    pub metadata: MetadataConfig,
+}
+
+// Which torrent client downloads the releases the daemon selects, e.g.
+// `"backend": { "type": "transmission", "url": "http://nas:9091/transmission/rpc" }`.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackendConfig {
+    // The bittorrent engine that ships with animated.
+    Builtin,
+    QBittorrent {
+        url: String,
+        username: String,
+        password: String,
+    },
+    Transmission {
+        url: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    Aria2 {
+        url: String,
+        #[serde(default)]
+        secret: Option<String>,
+    },
+    // Drops torrents into a directory watched by some other client.
+    Blackhole {
+        watch_dir: String,
+        #[serde(default)]
+        completed_dir: Option<String>,
+    },
+}
+
+impl Default for BackendConfig {
+    fn default() -> BackendConfig {
+        return BackendConfig::Builtin;
+    }
+}
+
+// How long history is kept before the daemon or `animated gc` removes it.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq)]
+pub struct RetentionConfig {
+    // Unwatched anime are purged, along with their episodes and events, this
+    // many days after they were unwatched.
+    #[serde(default = "default_tombstone_days")]
+    pub tombstone_days: u64,
+    // Older download events of a show are dropped.
+    #[serde(default = "default_download_events_per_show")]
+    pub download_events_per_show: usize,
+    // How often the daemon collects garbage.
+    #[serde(default = "default_gc_interval_hours")]
+    pub gc_interval_hours: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        return RetentionConfig {
+            tombstone_days: default_tombstone_days(),
+            download_events_per_show: default_download_events_per_show(),
+            gc_interval_hours: default_gc_interval_hours(),
+        };
+    }
+}
+
+// How closely release titles have to resemble a watched title or alias.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq)]
+pub struct MatchingConfig {
+    // In percent, see `titles::similarity`. 100 only accepts titles that
+    // are the same once normalized.
+    #[serde(default = "default_match_threshold")]
+    pub threshold: u32,
+}
+
+impl MatchingConfig {
+    pub fn threshold(&self) -> f64 {
+        return f64::from(self.threshold) / 100.0;
+    }
+}
+
+impl Default for MatchingConfig {
+    fn default() -> MatchingConfig {
+        return MatchingConfig {
+            threshold: default_match_threshold(),
+        };
+    }
+}
+
+// Where the anime-offline-database is and what the daemon does with it.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq)]
+pub struct MetadataConfig {
+    // The path of `anime-offline-database.json`. Anime are not linked
+    // without one.
+    #[serde(default)]
+    pub database: Option<String>,
+    // Unwatch finished shows once their last episode has been downloaded.
+    #[serde(default = "default_complete_finished")]
+    pub complete_finished: bool,
+    // How often the daemon reads the database again to pick up a newer
+    // release of it.
+    #[serde(default = "default_metadata_refresh_hours")]
+    pub refresh_hours: u64,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> MetadataConfig {
+        return MetadataConfig {
+            database: None,
+            complete_finished: default_complete_finished(),
+            refresh_hours: default_metadata_refresh_hours(),
+        };
+    }
+}
+
+// The backups the daemon takes of the watch list on its own.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq)]
+pub struct BackupConfig {
+    #[serde(default = "default_backups_enabled")]
+    pub enabled: bool,
+    // Defaults to `backups` in the data directory.
+    #[serde(default)]
+    pub dir: Option<String>,
+    #[serde(default = "default_backup_interval_hours")]
+    pub interval_hours: u64,
+    // Older backups are removed after every new one.
+    #[serde(default = "default_backups_kept")]
+    pub keep: usize,
+}
+
+impl Default for BackupConfig {
+    fn default() -> BackupConfig {
+        return BackupConfig {
+            enabled: default_backups_enabled(),
+            dir: None,
+            interval_hours: default_backup_interval_hours(),
+            keep: default_backups_kept(),
+        };
+    }
+}
+
+// What anime imported from MyAnimeList or AniList are watched with, since
+// those lists know nothing about releases.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq)]
+pub struct WatchDefaults {
+    #[serde(default = "default_quality", with = "by_name")]
+    pub quality: Quality,
+    // Without one, imported titles are listed for the user to pick a
+    // subgroup for.
+    #[serde(default)]
+    pub subgroup: Option<String>,
+}
+
+impl Default for WatchDefaults {
+    fn default() -> WatchDefaults {
+        return WatchDefaults {
+            quality: default_quality(),
+            subgroup: None,
+        };
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return Config {
+            version: default_config_version(),
+            download_path: default_download_path(),
+            feeds: vec![],
+            poll_interval_secs: default_poll_interval_secs(),
+            listen_port: default_listen_port(),
+            backend: BackendConfig::default(),
+            retention: RetentionConfig::default(),
+            defaults: WatchDefaults::default(),
+            backups: BackupConfig::default(),
+            matching: MatchingConfig::default(),
+            metadata: MetadataConfig::default(),
+        };
+    }
+}
+
+pub fn default_config_version() -> u32 {
+    return CONFIG_VERSION;
 }
 
 pub fn default_download_path() -> String {
     let username = whoami::username();
     return format!("/home/{}/Downloads", username);
 }
+
+pub fn default_poll_interval_secs() -> u64 {
+    return 15 * 60;
+}
+
+pub fn default_listen_port() -> u16 {
+    return 6881;
+}
+
+pub fn default_tombstone_days() -> u64 {
+    return 30;
+}
+
+pub fn default_download_events_per_show() -> usize {
+    return 100;
+}
+
+pub fn default_gc_interval_hours() -> u64 {
+    return 24;
+}
+
+pub fn default_backups_enabled() -> bool {
+    return true;
+}
+
+pub fn default_backup_interval_hours() -> u64 {
+    return 24;
+}
+
+pub fn default_backups_kept() -> usize {
+    return 7;
+}
+
+pub fn default_match_threshold() -> u32 {
+    return 85;
+}
+
+pub fn default_complete_finished() -> bool {
+    return true;
+}
+
+pub fn default_metadata_refresh_hours() -> u64 {
+    return 24;
+}
+
+pub fn default_quality() -> Quality {
+    return Quality::Res1080;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EpisodeState {
+    Downloading,
+    Completed,
+    Failed,
+    // Waiting for a better ranked group to release the episode, see
+    // `Anime.delay_hours`.
+    Pending,
+}
+
+impl FromStr for EpisodeState {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EpisodeState, ()> {
+        match s {
+            "downloading" => Ok(EpisodeState::Downloading),
+            "completed" => Ok(EpisodeState::Completed),
+            "failed" => Ok(EpisodeState::Failed),
+            "pending" => Ok(EpisodeState::Pending),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for EpisodeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EpisodeState::Downloading => write!(f, "downloading"),
+            EpisodeState::Completed => write!(f, "completed"),
+            EpisodeState::Failed => write!(f, "failed"),
+            EpisodeState::Pending => write!(f, "pending"),
+        }
+    }
+}
+
+// One entry in the ledger of episodes the daemon has picked up, so that a
+// release is only fetched once and interrupted downloads resume on restart.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EpisodeRecord {
+    pub watch_id: String,
+    pub episode: i32,
+    pub release_title: String,
+    // A magnet link or the URL of a .torrent file.
+    pub link: String,
+    // Hex encoded, once the torrent has been handed to the download engine.
+    pub info_hash: Option<String>,
+    pub state: EpisodeState,
+    // The title or alias of the anime the release was recognized by.
+    #[serde(default)]
+    pub matched_title: Option<String>,
+    // The info hash of a download of the episode in a worse quality, which
+    // is removed once this one completes.
+    #[serde(default)]
+    pub replaces: Option<String>,
+    // For pending episodes, seconds since the Unix epoch when the best
+    // release so far is downloaded unless a better one shows up first.
+    #[serde(default)]
+    pub decide_at: Option<u64>,
+    // Seconds since the Unix epoch.
+    pub updated_at: u64,
+    // A download of a completed episode in a better quality, kept beside
+    // the record until it completes too and takes its place. A failed one
+    // leaves the episode as it was.
+    #[serde(default)]
+    pub upgrade: Option<Box<EpisodeRecord>>,
+}
+
+impl EpisodeRecord {
+    // This download followed by the upgrade of it, if any.
+    pub fn downloads(&self) -> impl Iterator<Item = &EpisodeRecord> {
+        return std::iter::once(self).chain(self.upgrade.as_deref());
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeedItem {
+    pub title: String,
+    // A magnet link or the URL of a .torrent file.
+    pub link: String,
+}
+
+// What a feed returned the last time it was fetched, so that it is only
+// downloaded again once the server says it changed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedFeed {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub items: Vec<FeedItem>,
+    // Seconds since the Unix epoch.
+    pub fetched_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Watched,
+    Modified,
+    Unwatched,
+    // Unwatched by the daemon after the last episode of a finished show.
+    Completed,
+    DownloadStarted,
+    DownloadCompleted,
+    DownloadFailed,
+    // Held back while waiting for a better ranked group.
+    DownloadPending,
+}
+
+impl EventKind {
+    pub fn is_download(&self) -> bool {
+        match *self {
+            EventKind::DownloadStarted
+            | EventKind::DownloadCompleted
+            | EventKind::DownloadFailed
+            | EventKind::DownloadPending => true,
+            EventKind::Watched
+            | EventKind::Modified
+            | EventKind::Unwatched
+            | EventKind::Completed => false,
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EventKind::Watched => write!(f, "watched"),
+            EventKind::Modified => write!(f, "modified"),
+            EventKind::Unwatched => write!(f, "unwatched"),
+            EventKind::Completed => write!(f, "completed"),
+            EventKind::DownloadStarted => write!(f, "download started"),
+            EventKind::DownloadCompleted => write!(f, "download completed"),
+            EventKind::DownloadFailed => write!(f, "download failed"),
+            EventKind::DownloadPending => write!(f, "download pending"),
+        }
+    }
+}
+
+// One entry in the history of what happened to the watch list.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    // Seconds since the Unix epoch.
+    pub at: u64,
+    pub kind: EventKind,
+    pub watch_id: String,
+    pub episode: Option<i32>,
+    // E.g. the anime or the release title.
+    pub detail: String,
+}
@@ -0,0 +1,262 @@
+use regex::Regex;
+
+/*
+ * Fuzzy matching of release titles against watched titles and aliases.
+ * Releases of one show are named inconsistently, e.g. `Re:Zero 2nd Season`,
+ * `Re Zero - Season 2` and `ＲｅＺｅｒｏ Ｓ２`, so both sides are normalized
+ * before they are compared and the comparison tolerates small differences.
+ * Watch IDs do not go through any of this, see `Anime::identity`.
+ */
+
+lazy_static! {
+    static ref SEASON_WORD: Regex = Regex::new(r"\bseason (\d+)\b").unwrap();
+    static ref SEASON_ORDINAL: Regex = Regex::new(r"\b(\d+)(?:st|nd|rd|th) season\b").unwrap();
+    static ref SEASON_NAMED: Regex =
+        Regex::new(r"\b(first|second|third|fourth|fifth|sixth|seventh|eighth|ninth) season\b")
+            .unwrap();
+    static ref SEASON_SHORT: Regex = Regex::new(r"\bs0*(\d+)\b").unwrap();
+    static ref FIRST_SEASON: Regex = Regex::new(r"\bs1\b").unwrap();
+}
+
+const SEASON_NAMES: [&str; 9] = [
+    "first", "second", "third", "fourth", "fifth", "sixth", "seventh", "eighth", "ninth",
+];
+
+// Full-width forms, as used in Japanese titles, have an ASCII twin 0xFEE0
+// code points further down.
+fn fold_width(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => std::char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{3000}' => ' ',
+        _ => c,
+    }
+}
+
+// Lowercases, folds full-width characters and punctuation, and spells every
+// way of numbering seasons as `s<number>`. The first season is usually not
+// numbered at all, so `Season 1` is dropped.
+pub fn normalize(title: &str) -> String {
+    let mut folded = String::with_capacity(title.len());
+    for c in title.chars().map(fold_width).flat_map(char::to_lowercase) {
+        match c {
+            // `Frieren's` and `Frierens` are the same show.
+            '\'' | '\u{2019}' | '`' => (),
+            '&' => folded.push_str(" and "),
+            c if c.is_alphanumeric() => folded.push(c),
+            _ => folded.push(' '),
+        }
+    }
+    let mut normalized = folded.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    normalized = SEASON_WORD.replace_all(&normalized, "s$1").into_owned();
+    normalized = SEASON_ORDINAL.replace_all(&normalized, "s$1").into_owned();
+    normalized = SEASON_NAMED
+        .replace_all(&normalized, |captures: &regex::Captures| {
+            let position = SEASON_NAMES.iter().position(|name| *name == &captures[1]);
+            format!("s{}", position.unwrap_or(0) + 1)
+        })
+        .into_owned();
+    normalized = SEASON_SHORT.replace_all(&normalized, "s$1").into_owned();
+    normalized = FIRST_SEASON.replace_all(&normalized, "").into_owned();
+    return normalized
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ");
+}
+
+fn is_season(word: &str) -> bool {
+    return word.len() > 1
+        && word.starts_with('s')
+        && word[1..].chars().all(|c| c.is_ascii_digit());
+}
+
+// How alike two titles are, from 0 for nothing in common to 1 for the same
+// title once normalized. Titles of different seasons never match, however
+// alike the rest of them is.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    return normalized_similarity(&normalize(a), &normalize(b));
+}
+
+// `similarity` of titles that went through `normalize` already, for
+// comparing against many titles that are normalized once up front.
+pub fn normalized_similarity(a: &str, b: &str) -> f64 {
+    if a == b {
+        return 1.0;
+    }
+    let split = |title: &str| -> (Vec<String>, String) {
+        let (seasons, rest): (Vec<&str>, Vec<&str>) =
+            title.split_whitespace().partition(|word| is_season(word));
+        return (
+            seasons.into_iter().map(String::from).collect(),
+            rest.join(" "),
+        );
+    };
+    let (a_seasons, a_rest) = split(a);
+    let (b_seasons, b_rest) = split(b);
+    if a_seasons != b_seasons {
+        return 0.0;
+    }
+    return strsim::sorensen_dice(&a_rest, &b_rest);
+}
+
+// The candidate most like `title`, with its score, if it reaches
+// `threshold`. The first candidate wins a tie.
+pub fn best_match<'a, I: IntoIterator<Item = &'a str>>(
+    title: &str,
+    candidates: I,
+    threshold: f64,
+) -> Option<(&'a str, f64)> {
+    let mut best: Option<(&'a str, f64)> = None;
+    for candidate in candidates {
+        let score = similarity(title, candidate);
+        let better = match best {
+            Some((_, best_score)) => score > best_score,
+            None => true,
+        };
+        if score >= threshold && better {
+            best = Some((candidate, score));
+        }
+    }
+    return best;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::release::Release;
+    use crate::structs::{Anime, MatchingConfig, Quality, QualityProfile};
+
+    fn threshold() -> f64 {
+        return MatchingConfig::default().threshold();
+    }
+
+    fn anime(title: &str, aliases: &[&str]) -> Anime {
+        return Anime {
+            title: String::from(title),
+            quality: Quality::Res1080,
+            subgroup: String::from("SubsPlease"),
+            last_seen_episode: 0,
+            tombstone: false,
+            paused: false,
+            tombstoned_at: None,
+            aliases: aliases.iter().map(|alias| String::from(*alias)).collect(),
+            matched_alias: None,
+            metadata: None,
+            profile: QualityProfile::default(),
+            fallback_subgroups: vec![],
+            delay_hours: 0,
+        };
+    }
+
+    #[test]
+    fn normalizes_punctuation_and_seasons() {
+        let cases = [
+            ("Re:Zero 2nd Season", "re zero s2"),
+            ("Re Zero - Season 2", "re zero s2"),
+            ("ＲｅＺｅｒｏ Ｓ２", "rezero s2"),
+            ("Frieren's Journey", "frierens journey"),
+            ("Frieren’s Journey", "frierens journey"),
+            ("Kaguya-sama: Love is War", "kaguya sama love is war"),
+            ("Cells & Blood", "cells and blood"),
+            ("Mushoku Tensei Second Season", "mushoku tensei s2"),
+            ("Oshi no Ko S02", "oshi no ko s2"),
+            ("Spy x Family Season 1", "spy x family"),
+            ("Spy x Family S01", "spy x family"),
+            ("Spy x Family 1st Season", "spy x family"),
+            ("Mushoku Tensei S2 Part 2", "mushoku tensei s2 part 2"),
+        ];
+        for (title, normalized) in cases.iter() {
+            assert_eq!(normalize(title), *normalized, "{}", title);
+        }
+    }
+
+    #[test]
+    fn matches_titles_at_the_threshold() {
+        // The scores are pinned so that a change to the measure shows up
+        // here rather than as missed or unwanted downloads.
+        let cases = [
+            ("Re:Zero 2nd Season", "Re Zero - Season 2", true),
+            ("Bocchi the Rock", "Bocchi the Rock!", true),
+            ("Spy x Family", "Spy x Family Season 1", true),
+            ("Vinland Saga", "Vinland Sagas", true),
+            ("Boku no Hero Academia", "Boku no Hero Academy", true),
+            ("Dungeon Meshi", "Dungeon Meshi OVA", true),
+            // Just below the threshold.
+            ("Spy x Family", "Spy x Familia", false),
+            ("Mushoku Tensei S2", "Mushoku Tensei S2 Part 2", false),
+            ("Made in Abyss", "Made in Abyss Movie", false),
+            (
+                "Kaguya-sama wa Kokurasetai",
+                "Kaguya-sama wa Kokurasetai Ultra Romantic",
+                false,
+            ),
+            ("One Piece", "One Punch Man", false),
+            // Other seasons never match.
+            ("Oshi no Ko", "Oshi no Ko S2", false),
+            ("Frieren", "Frieren Second Season", false),
+            ("Re:Zero 2nd Season", "Re:Zero 3rd Season", false),
+        ];
+        for (watched, released, matches) in cases.iter() {
+            let score = similarity(watched, released);
+            assert_eq!(
+                score >= threshold(),
+                *matches,
+                "{} / {} scored {}",
+                watched,
+                released,
+                score
+            );
+        }
+        assert!(similarity("Spy x Family", "Spy x Familia") > 0.8);
+        assert!(similarity("Mushoku Tensei S2", "Mushoku Tensei S2 Part 2") > 0.8);
+    }
+
+    #[test]
+    fn best_match_prefers_the_closest_candidate() {
+        let candidates = ["Sousou no Frieren", "Frieren: Beyond Journey's End"];
+        assert_eq!(
+            best_match(
+                "Frieren - Beyond Journeys End",
+                candidates.iter().copied(),
+                threshold()
+            ),
+            Some(("Frieren: Beyond Journey's End", 1.0))
+        );
+        assert_eq!(
+            best_match("Sousou no Frieren", candidates.iter().copied(), threshold()),
+            Some(("Sousou no Frieren", 1.0))
+        );
+        assert_eq!(
+            best_match("Dungeon Meshi", candidates.iter().copied(), threshold()),
+            None
+        );
+    }
+
+    #[test]
+    fn releases_are_matched_through_aliases() {
+        let frieren = anime(
+            "Sousou no Frieren",
+            &["Frieren: Beyond Journey's End", "Frieren"],
+        );
+        let cases = [
+            (
+                "[SubsPlease] Sousou no Frieren - 05 (1080p) [ABCD1234].mkv",
+                Some("Sousou no Frieren"),
+            ),
+            (
+                "[SubsPlease] Frieren - Beyond Journeys End - 05 (1080p).mkv",
+                Some("Frieren: Beyond Journey's End"),
+            ),
+            ("[SubsPlease] FRIEREN - 05 (1080p).mkv", Some("Frieren")),
+            ("[SubsPlease] Frieren S2 - 05 (1080p).mkv", None),
+            ("[SubsPlease] Dungeon Meshi - 05 (1080p).mkv", None),
+        ];
+        for (release_title, matched_title) in cases.iter() {
+            let release = Release::parse(release_title).unwrap();
+            let matched = release
+                .wanted_by(&frieren, threshold())
+                .map(|(title, _)| title);
+            assert_eq!(matched, *matched_title, "{}", release_title);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::shutdown::ShutdownToken;
+use common::lock;
+use common::paths;
+use common::store::{BackupInfo, RocksStore, WatchStore};
+use common::structs::{BackupConfig, Config};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+pub fn log_backup(dir: &Path, backup: &BackupInfo) {
+    info!(
+        "Backed up the watch list to `{}` as backup {} ({} bytes).",
+        dir.display(),
+        backup.id,
+        backup.size_bytes
+    );
+}
+
+// Takes a backup if the newest one is due and returns how long to wait
+// before the next one.
+fn backup_if_due(store: &dyn WatchStore, policy: &BackupConfig) -> Duration {
+    let interval = Duration::from_secs(policy.interval_hours * 60 * 60);
+    let dir = paths::get().backups(&policy.dir);
+    let newest = match RocksStore::list_backups(&dir) {
+        Ok(mut backups) => backups.pop(),
+        Err(e) => {
+            warn!("Failed to list the backups in `{}`: {}", dir.display(), e);
+            None
+        }
+    };
+    if let Some(newest) = newest {
+        let age = Duration::from_secs(lock::now().saturating_sub(newest.created_at));
+        if age < interval {
+            return interval - age;
+        }
+    }
+
+    debug!("Backing up the watch list with {:?}", policy);
+    match store.backup(&dir, Some(policy.keep)) {
+        Ok(backup) => log_backup(&dir, &backup),
+        Err(e) => warn!("Failed to back up the watch list: {}", e),
+    }
+    return interval;
+}
+
+// Backs the watch list up whenever the newest backup is older than
+// `backups.interval_hours`, so a daemon that was down when one was due takes
+// it right at startup. Only the newest `backups.keep` are kept. Config
+// changes apply from the next backup on.
+pub fn run(
+    config_mtx: Arc<Mutex<Config>>,
+    store: Arc<dyn WatchStore>,
+    shutdown: ShutdownToken,
+) -> Box<thread::JoinHandle<()>> {
+    let handle = thread::spawn(move || loop {
+        let policy = config_mtx.lock().unwrap().backups.clone();
+        let wait = if policy.enabled {
+            backup_if_due(&*store, &policy)
+        } else {
+            Duration::from_secs(policy.interval_hours * 60 * 60)
+        };
+        if shutdown.wait_timeout(wait) {
+            return;
+        }
+    });
+    return Box::new(handle);
+}
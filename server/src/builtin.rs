@@ -0,0 +1,92 @@
+use bittorrent::session::{Session, SessionError, TorrentId, TorrentStatus};
+use common::backend::{BackendError, DownloadBackend, DownloadState, TorrentPayload};
+use std::{fs, path::Path, sync::Arc};
+
+// Adapts the bittorrent crate's session to the backend trait so that the
+// scheduler treats it like any other client.
+pub struct BuiltinBackend {
+    session: Arc<Session>,
+}
+
+impl BuiltinBackend {
+    pub fn new(session: Arc<Session>) -> BuiltinBackend {
+        return BuiltinBackend { session: session };
+    }
+}
+
+fn parse_id(info_hash: &str) -> Result<TorrentId, BackendError> {
+    return TorrentId::from_hex(info_hash)
+        .ok_or_else(|| BackendError::Rejected(format!("`{}` is not an info hash.", info_hash)));
+}
+
+impl DownloadBackend for BuiltinBackend {
+    fn name(&self) -> &'static str {
+        return "builtin";
+    }
+
+    fn add(
+        &self,
+        info_hash: &str,
+        _name: &str,
+        payload: &TorrentPayload,
+        download_path: &str,
+    ) -> Result<(), BackendError> {
+        let download_dir = Path::new(download_path);
+        fs::create_dir_all(download_dir).map_err(|e| {
+            BackendError::Rejected(format!(
+                "Failed to create download directory `{}`: {}",
+                download_path, e
+            ))
+        })?;
+
+        // A failed torrent stays in the session until removed, so retrying it
+        // means starting over.
+        let id = parse_id(info_hash)?;
+        if let Some(progress) = self.session.progress(id) {
+            if progress.status == TorrentStatus::Failed {
+                let _ = self.session.remove(id, false);
+            }
+        }
+
+        let added = match payload {
+            TorrentPayload::Magnet(magnet) => self.session.add_magnet(magnet, download_dir),
+            TorrentPayload::TorrentFile(bytes) => {
+                self.session.add_torrent_bytes(bytes, download_dir)
+            }
+        };
+        match added {
+            Ok(_) | Err(SessionError::AlreadyAdded(_)) => Ok(()),
+            Err(e) => Err(BackendError::Rejected(e.to_string())),
+        }
+    }
+
+    fn poll(&self, info_hash: &str) -> Result<DownloadState, BackendError> {
+        let progress = match self.session.progress(parse_id(info_hash)?) {
+            Some(progress) => progress,
+            None => return Ok(DownloadState::Missing),
+        };
+        let state = match progress.status {
+            TorrentStatus::Completed => DownloadState::Completed,
+            TorrentStatus::Failed => DownloadState::Failed(
+                progress
+                    .error
+                    .unwrap_or_else(|| String::from("Unknown error.")),
+            ),
+            TorrentStatus::FetchingMetadata | TorrentStatus::Checking | TorrentStatus::Paused => {
+                DownloadState::Queued
+            }
+            TorrentStatus::Downloading => DownloadState::Downloading {
+                downloaded_bytes: progress.downloaded_bytes,
+                total_bytes: progress.total_bytes,
+            },
+        };
+        return Ok(state);
+    }
+
+    fn remove(&self, info_hash: &str, delete_files: bool) -> Result<(), BackendError> {
+        match self.session.remove(parse_id(info_hash)?, delete_files) {
+            Ok(()) | Err(SessionError::UnknownTorrent(_)) => Ok(()),
+            Err(e) => Err(BackendError::Rejected(e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use crate::backups;
+use crate::gc;
+use crate::shutdown::ShutdownToken;
+use common::interchange;
+use common::ipc::{self, DaemonStatus, IpcError, PendingEpisode, Request, Response};
+use common::lock::LockInfo;
+use common::paths;
+use common::retention;
+use common::store::{StoreError, WatchStore};
+use common::structs::{Config, EpisodeState};
+use std::{
+    fs::{self, Permissions},
+    io::{self, ErrorKind},
+    mem,
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+// Requests are served one at a time, so a client that connects and then
+// stalls must not hold up everyone else for long.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Everything requests are served with.
+struct Context {
+    lock_info: LockInfo,
+    store: Arc<dyn WatchStore>,
+    config_mtx: Arc<Mutex<Config>>,
+}
+
+// `client_uid` is the user on the other end of the socket.
+fn dispatch(request: Request, client_uid: u32, context: &Context) -> Response {
+    let store = &*context.store;
+    debug!("Handling control request: {:?}", request);
+
+    match request {
+        Request::Watch(anime) => match store.upsert_anime(&anime) {
+            Ok(watch_id) => {
+                info!("Now watching {} / Watch ID: {}", anime, watch_id);
+                Response::Watched { watch_id: watch_id }
+            }
+            Err(e) => Response::Error(format!("Failed to watch {}. {}.", anime, e)),
+        },
+        Request::Unwatch { watch_id } => match store.tombstone_anime(&watch_id) {
+            Ok(true) => {
+                info!("No longer watching watch ID {}.", watch_id);
+                Response::Unwatched { watch_id: watch_id }
+            }
+            Ok(false) => Response::Error(format!(
+                "No anime is being watched with watch ID {}.",
+                watch_id
+            )),
+            Err(e) => Response::Error(format!("Failed to unwatch {}. {}.", watch_id, e)),
+        },
+        Request::Get { watch_id } => match store.get_anime(&watch_id) {
+            Ok(anime) => Response::Anime(anime.map(Box::new)),
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::Modify { watch_id, anime } => match store.modify_anime(&watch_id, &anime) {
+            Ok(()) => {
+                info!("Modified {} / Watch ID: {}", anime, watch_id);
+                Response::Modified { watch_id: watch_id }
+            }
+            Err(StoreError::Collision(collision)) => Response::Error(format!(
+                "Refusing to modify watch ID {}. {} with the same title, \
+                subgroup and quality.",
+                watch_id, collision
+            )),
+            Err(e) => Response::Error(format!("Failed to modify watch ID {}. {}.", watch_id, e)),
+        },
+        Request::List => match store.list_anime() {
+            Ok(all_anime) => Response::List(all_anime),
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::Status => match status(&context.lock_info, store) {
+            Ok(status) => Response::Status(status),
+            Err(e) => Response::Error(e.to_string()),
+        },
+        Request::Gc { dry_run } => {
+            let policy = context.config_mtx.lock().unwrap().retention.clone();
+            match retention::collect_garbage(store, &policy, dry_run) {
+                Ok(report) => {
+                    if !dry_run {
+                        gc::log_report(&report);
+                    }
+                    Response::Collected(report)
+                }
+                Err(e) => Response::Error(format!("Failed to collect garbage. {}.", e)),
+            }
+        }
+        Request::Export => match interchange::export(store) {
+            Ok(export) => Response::Exported(export),
+            Err(e) => Response::Error(format!("Failed to export the watch list. {}.", e)),
+        },
+        Request::Import {
+            export,
+            strategy,
+            dry_run,
+        } => match interchange::import(store, &export, strategy, dry_run) {
+            Ok(report) => {
+                if !dry_run {
+                    info!(
+                        "Imported {} new and {} updated anime.",
+                        report.added.len(),
+                        report.updated.len()
+                    );
+                }
+                Response::Imported(report)
+            }
+            Err(e) => Response::Error(format!("Failed to import the watch list. {}.", e)),
+        },
+        Request::Backup { dir, keep } => {
+            let dir = match backup_dir(&dir, client_uid) {
+                Ok(dir) => dir,
+                Err(message) => return Response::Error(message),
+            };
+            match store.backup(&dir, keep) {
+                Ok(backup) => {
+                    backups::log_backup(&dir, &backup);
+                    Response::BackedUp(backup)
+                }
+                Err(e) => Response::Error(format!(
+                    "Failed to back up the watch list to `{}`. {}.",
+                    dir.display(),
+                    e
+                )),
+            }
+        }
+    }
+}
+
+// Anyone who can reach the socket may ask for a backup, which the daemon
+// writes with its own privileges. So it only writes into directories that
+// belong to whoever asked. The CLI creates and resolves the directory before
+// asking, which keeps symlinks from pointing the daemon elsewhere.
+fn backup_dir(requested: &Path, client_uid: u32) -> Result<PathBuf, String> {
+    if !requested.is_absolute() {
+        return Err(format!(
+            "The backup directory `{}` is not an absolute path.",
+            requested.display()
+        ));
+    }
+    let existing = requested
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or_else(|| Path::new("/"));
+    check_owner(existing, client_uid)?;
+    if existing != requested {
+        // Directories the daemon creates belong to the daemon, not to the
+        // client.
+        if unsafe { libc::geteuid() } != client_uid {
+            return Err(format!(
+                "The backup directory `{}` does not exist.",
+                requested.display()
+            ));
+        }
+        fs::create_dir_all(requested).map_err(|e| {
+            format!(
+                "Failed to create the backup directory `{}`. {}.",
+                requested.display(),
+                e
+            )
+        })?;
+    }
+    return check_owner(requested, client_uid);
+}
+
+// Resolves `dir` and makes sure it belongs to `client_uid`. Root may back up
+// anywhere.
+fn check_owner(dir: &Path, client_uid: u32) -> Result<PathBuf, String> {
+    let failed = |e: io::Error| {
+        format!(
+            "Failed to use the backup directory `{}`. {}.",
+            dir.display(),
+            e
+        )
+    };
+    let resolved = fs::canonicalize(dir).map_err(failed)?;
+    let owner = fs::metadata(&resolved).map_err(failed)?.uid();
+    if client_uid != 0 && owner != client_uid {
+        return Err(format!(
+            "Refusing to back up to `{}`, which belongs to another user.",
+            resolved.display()
+        ));
+    }
+    return Ok(resolved);
+}
+
+// The user that connected to the control socket.
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(credentials.uid);
+}
+
+pub fn status(lock_info: &LockInfo, store: &dyn WatchStore) -> Result<DaemonStatus, StoreError> {
+    let watched = store.list_anime()?;
+    let mut pending: Vec<PendingEpisode> = store
+        .list_episodes()?
+        .into_iter()
+        .filter(|record| record.state == EpisodeState::Pending)
+        .map(|record| PendingEpisode {
+            anime: watched
+                .get(&record.watch_id)
+                .map_or_else(|| record.watch_id.clone(), |anime| anime.title.clone()),
+            watch_id: record.watch_id,
+            episode: record.episode,
+            release_title: record.release_title,
+            decide_at: record.decide_at.unwrap_or(0),
+        })
+        .collect();
+    pending.sort_by_key(|episode| episode.decide_at);
+
+    return Ok(DaemonStatus {
+        pid: lock_info.pid,
+        started_at: lock_info.started_at,
+        config_path: lock_info.config_path.clone(),
+        watched: watched.len(),
+        active_downloads: store.count_episodes_in_state(EpisodeState::Downloading)?,
+        pending: pending,
+    });
+}
+
+fn handle(mut stream: UnixStream, context: &Context) {
+    let timeouts = stream
+        .set_read_timeout(Some(CONNECTION_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(CONNECTION_TIMEOUT)));
+    if let Err(e) = timeouts {
+        warn!("Failed to set up control connection: {}", e);
+        return;
+    }
+    let client_uid = match peer_uid(&stream) {
+        Ok(uid) => uid,
+        Err(e) => {
+            warn!(
+                "Failed to identify the client of a control connection: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    let response = match ipc::read_request(&stream) {
+        Ok(request) => dispatch(request, client_uid, context),
+        // Clients probing whether the daemon is up connect and immediately
+        // hang up, which is not worth logging.
+        Err(IpcError::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => return,
+        Err(IpcError::Io(ref e))
+            if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+        {
+            warn!(
+                "Dropped a control connection that sent no request within {} seconds.",
+                CONNECTION_TIMEOUT.as_secs()
+            );
+            return;
+        }
+        Err(e) => {
+            warn!("Rejected malformed control request: {}", e);
+            Response::Error(e.to_string())
+        }
+    };
+
+    if let Err(e) = ipc::write_response(&mut stream, response) {
+        warn!("Failed to answer control request: {}", e);
+    }
+}
+
+pub fn remove_socket() {
+    let socket_path = paths::get().socket();
+    match fs::remove_file(&socket_path) {
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => warn!(
+            "Failed to remove control socket `{}`: {:?}",
+            socket_path.display(),
+            e
+        ),
+    }
+}
+
+// Accepting connections blocks, so on shutdown the listener is woken up by
+// connecting to it.
+pub fn wake() {
+    let _ = UnixStream::connect(paths::get().socket());
+}
+
+// Requests are served one at a time on a single thread. Each of them is a
+// single store transaction, so they never see each other half done.
+pub fn listen(
+    lock_info: LockInfo,
+    store: Arc<dyn WatchStore>,
+    config_mtx: Arc<Mutex<Config>>,
+    shutdown: ShutdownToken,
+) -> Box<thread::JoinHandle<()>> {
+    // We hold the daemon lock by now, so any socket left on disk belongs to
+    // an instance that is no longer running.
+    remove_socket();
+
+    let socket_path = paths::get().socket();
+    let listener = match UnixListener::bind(&socket_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(
+                "Failed to bind control socket `{}` due to: {:?}",
+                socket_path.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+    // Only the daemon's user and group may send requests.
+    if let Err(e) = fs::set_permissions(&socket_path, Permissions::from_mode(0o660)) {
+        error!(
+            "Failed to set permissions on control socket `{}` due to: {:?}",
+            socket_path.display(),
+            e
+        );
+        remove_socket();
+        process::exit(1);
+    }
+
+    info!(
+        "Listening for control requests on `{}`.",
+        socket_path.display()
+    );
+
+    let context = Context {
+        lock_info: lock_info,
+        store: store,
+        config_mtx: config_mtx,
+    };
+    let handle = thread::spawn(move || {
+        for stream in listener.incoming() {
+            // See `wake`.
+            if shutdown.is_triggered() {
+                break;
+            }
+            match stream {
+                Ok(stream) => handle(stream, &context),
+                Err(e) => error!("Failed to accept control connection: {:?}", e),
+            }
+        }
+    });
+
+    return Box::new(handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    // A fresh directory to back up into, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("animated-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            return TempDir(fs::canonicalize(&path).unwrap());
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn own_uid() -> u32 {
+        return unsafe { libc::geteuid() };
+    }
+
+    #[test]
+    fn rejects_relative_backup_dirs() {
+        let message = backup_dir(Path::new("backups"), own_uid()).unwrap_err();
+        assert!(message.contains("not an absolute path"), "{}", message);
+    }
+
+    #[test]
+    fn creates_missing_backup_dirs_of_the_daemon_user() {
+        let temp = TempDir::new("backup-create");
+        let requested = temp.0.join("nested").join("backups");
+        assert_eq!(backup_dir(&requested, own_uid()).unwrap(), requested);
+        assert!(requested.is_dir());
+    }
+
+    #[test]
+    fn resolves_backup_dirs_through_symlinks() {
+        let temp = TempDir::new("backup-symlink");
+        let target = temp.0.join("target");
+        fs::create_dir(&target).unwrap();
+        let link = temp.0.join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert_eq!(backup_dir(&link, own_uid()).unwrap(), target);
+    }
+
+    #[test]
+    fn refuses_backup_dirs_of_other_users() {
+        let temp = TempDir::new("backup-other");
+        let other = own_uid() + 1;
+        let message = backup_dir(&temp.0, other).unwrap_err();
+        assert!(message.contains("belongs to another user"), "{}", message);
+
+        // Nor does it create anything for them.
+        let missing = temp.0.join("missing");
+        assert!(backup_dir(&missing, other).is_err());
+        assert!(!missing.exists());
+    }
+}
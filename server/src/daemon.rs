@@ -1,142 +1,318 @@
-use common::config;
-use common::constants::{CONFIG_PATH, DAEMON_LOCK_PATH};
+use crate::backups;
+use crate::control;
+use crate::gc;
+use crate::metadata;
+use crate::reload::{self, ConfigNotifier};
+use crate::scheduler;
+use crate::shutdown::ShutdownToken;
+use bittorrent::session::{Session, SessionConfig};
+use common::lock::{self, DaemonLock, LockError};
+use common::paths;
+use common::store::{RocksStore, WatchStore};
 use common::structs::Config;
-use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
-use std::thread;
 use std::{
-    fs::{remove_file, File},
-    io::ErrorKind,
     process,
     sync::mpsc::channel,
     sync::Arc,
     sync::Mutex,
-    time::Duration,
+    thread,
+    time::{Duration, Instant},
 };
 
-use simple_signal::{self, Signal};
+use signal_hook::{iterator, SIGHUP, SIGINT, SIGTERM, SIGUSR1};
 
-struct DaemonLock;
+// How long threads get to finish what they are doing once the daemon is
+// asked to shut down. A feed being fetched can take up to 30 seconds.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(45);
 
-impl DaemonLock {
-    fn grab(&self) {
-        info!("Attempting to aqcuire exclusive lock on daemon file.");
+fn grab_lock() -> DaemonLock {
+    info!("Attempting to aqcuire exclusive lock on daemon file.");
 
-        match File::open(DAEMON_LOCK_PATH) {
-            Ok(_) => {
-                error!(
-                    "Failed to grab exclusive lock via lock file `{}`. \
-                    Is there another instance of animated running? You can \
-                    run `animated kill` to kill any existing instances and \
-                    immediately release the lock.",
-                    DAEMON_LOCK_PATH
+    let paths = paths::get();
+    match DaemonLock::acquire(&paths.config_file.to_string_lossy()) {
+        Ok((lock, stale_info)) => {
+            if let Some(stale_info) = stale_info {
+                warn!(
+                    "Took over a stale lock left by PID {}, which did not shut down cleanly.",
+                    stale_info.pid
                 );
-                process::exit(1);
             }
-            Err(e) => match e.kind() {
-                ErrorKind::NotFound => {
-                    File::create(DAEMON_LOCK_PATH).expect("Failed to create daemon lock file.");
-                }
-                _ => {
-                    error!("Failed to open daemon lock file due to: {:?}", e);
-                    process::exit(1);
-                }
-            },
+            return lock;
+        }
+        Err(LockError::AlreadyHeld(holder)) => {
+            let holder = match holder {
+                Some(info) => format!("PID {}", info.pid),
+                None => String::from("another process"),
+            };
+            error!(
+                "Failed to grab exclusive lock via lock file `{}` because it is held \
+                by {}. Is there another instance of animated running? You can \
+                run `animated kill` to stop it and release the lock.",
+                paths.lock_file().display(),
+                holder
+            );
+            process::exit(1);
+        }
+        Err(LockError::Io(e)) => {
+            error!("Failed to open daemon lock file due to: {:?}", e);
+            process::exit(1);
         }
     }
+}
 
-    fn release(&self) {
-        info!("Releasing lock file.");
-
-        File::open(DAEMON_LOCK_PATH)
-            .expect("Failed to find daemon lock file. Did something else delete it?");
-        remove_file(DAEMON_LOCK_PATH).expect(
-            format!(
-                "Failed to release exlusive lock on lock file. You will\
-                need to manually delete the file `{}`.",
-                DAEMON_LOCK_PATH
-            )
-            .as_str(),
-        );
+// Joins the daemon's threads, giving up on those that are still busy after
+// `DRAIN_TIMEOUT`.
+fn drain(handles: Vec<(&'static str, thread::JoinHandle<()>)>) {
+    let (tx, rx) = channel();
+    let names: Vec<&str> = handles.iter().map(|(name, _)| *name).collect();
+    for (name, handle) in handles {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            if handle.join().is_err() {
+                error!("The {} panicked while shutting down.", name);
+            }
+            let _ = tx.send(name);
+        });
+    }
 
-        info!("Lock file has been released.");
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let mut running = names;
+    while !running.is_empty() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(remaining) {
+            Ok(name) => {
+                debug!("The {} has stopped.", name);
+                running.retain(|running| *running != name);
+            }
+            Err(_) => {
+                warn!(
+                    "Gave up waiting for the {} after {} seconds.",
+                    running.join(", "),
+                    DRAIN_TIMEOUT.as_secs()
+                );
+                return;
+            }
+        }
     }
 }
 
-impl Drop for DaemonLock {
-    fn drop(&mut self) {
-        self.release();
+// What the signal handler needs to act on the running daemon.
+struct Signals {
+    shutdown: ShutdownToken,
+    lock: Arc<DaemonLock>,
+    notifier: Arc<ConfigNotifier>,
+    config_mtx: Arc<Mutex<Config>>,
+    store: Arc<dyn WatchStore>,
+    session: Arc<Session>,
+}
+
+// Logs what the daemon is up to, for `kill -USR1`.
+fn dump_status(signals: &Signals) {
+    let status = match control::status(signals.lock.info(), &*signals.store) {
+        Ok(status) => status,
+        Err(e) => {
+            warn!("Failed to collect the daemon status: {}", e);
+            return;
+        }
+    };
+    let config = signals.config_mtx.lock().unwrap().clone();
+    let backend = serde_json::to_value(&config.backend).unwrap();
+
+    info!(
+        "Status: PID {}, running for {} seconds with config `{}`.",
+        status.pid,
+        lock::now().saturating_sub(status.started_at),
+        status.config_path
+    );
+    info!(
+        "Watching {} anime with {} download(s) in progress.",
+        status.watched, status.active_downloads
+    );
+    for pending in status.pending.iter() {
+        info!(
+            "  Holding back episode {} of watch ID {} for {} more seconds: {}",
+            pending.episode,
+            pending.watch_id,
+            pending.decide_at.saturating_sub(lock::now()),
+            pending.release_title
+        );
+    }
+    info!(
+        "Polling {} feed(s) every {} seconds and downloading to `{}` with the {} backend.",
+        config.feeds.len(),
+        config.poll_interval_secs,
+        config.download_path,
+        backend["type"].as_str().unwrap_or("unknown")
+    );
+    for torrent in signals.session.torrents() {
+        info!(
+            "  {}: {:?}, {}/{} bytes from {} peer(s)",
+            torrent
+                .name
+                .clone()
+                .unwrap_or_else(|| torrent.id.to_string()),
+            torrent.status,
+            torrent.downloaded_bytes,
+            torrent.total_bytes,
+            torrent.peers
+        );
     }
 }
 
-fn watch_config_changes(config_mtx: Arc<Mutex<Config>>) -> Box<thread::JoinHandle<()>> {
-    let handle = thread::spawn(move || {
-        let (tx, rx) = channel();
-        let mut watcher = watcher(tx, Duration::from_secs(3))
-            .expect("Failed to create filesystem watcher to watch config file.");
-        watcher
-            .watch(CONFIG_PATH, RecursiveMode::NonRecursive)
-            .expect(format!("Failed to watch config file at path `{}`.", CONFIG_PATH).as_str());
-
-        info!("Watching for changes in config file.");
-
-        loop {
-            match rx.recv() {
-                Ok(event) => match event {
-                    DebouncedEvent::Write(_) => {
-                        let config_result = config::read();
-                        let config =
-                            config_result.expect("Failed to read recently written config file.");
-                        debug!("Config file was updated. New contents: {:?}", config);
-                        let mut current_config = config_mtx.lock().unwrap();
-                        *current_config = config;
-                    }
-                    DebouncedEvent::Remove(_) => {
-                        error!("Config file was deleted.");
-                        process::exit(1);
-                    }
-                    DebouncedEvent::Rename(_, _) => {
-                        error!("Config file was moved.");
-                        process::exit(1);
-                    }
-                    DebouncedEvent::Error(e, _) => {
-                        error!("A watch error occured: {:?}", e);
+// SIGINT and SIGTERM shut the daemon down, or quit right away if it already
+// is. SIGHUP reloads the config and SIGUSR1 logs the status.
+fn handle_signals(signals: Signals) {
+    let incoming = iterator::Signals::new(&[SIGINT, SIGTERM, SIGHUP, SIGUSR1])
+        .expect("Failed to set up signal handlers.");
+
+    thread::spawn(move || {
+        for signal in incoming.forever() {
+            match signal {
+                SIGHUP => {
+                    info!("Daemon received SIGHUP, reloading the config.");
+                    signals.notifier.reload();
+                }
+                SIGUSR1 => dump_status(&signals),
+                _ => {
+                    info!("Daemon received SIGINT/SIGTERM.");
+                    if signals.shutdown.trigger() {
+                        warn!("Quitting without waiting for running work to finish.");
+                        control::remove_socket();
+                        signals.lock.release();
                         process::exit(1);
                     }
-                    _ => (),
-                },
-                Err(e) => error!("A watch error occured: {:?}", e),
+                }
             }
         }
     });
-
-    return Box::new(handle);
 }
 
-fn watch_anime(_config_mtx: Arc<Mutex<Config>>) -> Box<thread::JoinHandle<()>> {
-    info!("Watching for new anime to download.");
-
-    let handle = thread::spawn(move || loop {});
-    return Box::new(handle);
+fn start_session(config: &Config) -> Arc<Session> {
+    let session_config = SessionConfig {
+        listen_port: config.listen_port,
+        ..SessionConfig::default()
+    };
+    match Session::new(session_config) {
+        Ok(session) => return Arc::new(session),
+        Err(e) => {
+            error!("Failed to start the download engine: {}", e);
+            process::exit(1);
+        }
+    }
 }
 
 pub fn run(config: Config) {
     info!("Starting animated daemon.");
     debug!("Using current config: {:?}", config);
+    debug!("Using paths: {:?}", paths::get());
 
-    let _lock = DaemonLock {};
-    _lock.grab();
+    let lock = Arc::new(grab_lock());
+    let lock_info = lock.info().clone();
 
-    simple_signal::set_handler(&[Signal::Int, Signal::Term], move |_| {
-        info!("Daemon received SIGINT/SIGTERM.");
-        _lock.release();
-        info!("Exiting.");
-        process::exit(0);
-    });
+    let store: Arc<dyn WatchStore> = match RocksStore::open(&paths::get().rocksdb) {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let migration_report = match store.migrate_watch_ids() {
+        Ok(report) => report,
+        Err(e) => {
+            error!("Failed to migrate watch IDs: {}", e);
+            process::exit(1);
+        }
+    };
+    for (old_id, new_id) in migration_report.rekeyed {
+        info!("Migrated watch ID {} to {}.", old_id, new_id);
+    }
+    for (old_id, collision) in migration_report.collisions {
+        warn!(
+            "Could not migrate watch ID {}: {}. It was left under its old ID until one of \
+            them is unwatched.",
+            old_id, collision
+        );
+    }
 
+    let shutdown = ShutdownToken::new();
+    let session = start_session(&config);
     let config_mtx = Arc::new(Mutex::new(config));
-    let config_watch_handle = *watch_config_changes(Arc::clone(&config_mtx));
-    let anime_watch_handle = *watch_anime(Arc::clone(&config_mtx));
+    let notifier = Arc::new(ConfigNotifier::new(Arc::clone(&config_mtx)));
+    let (scheduler_tx, scheduler_rx) = channel();
+    notifier.subscribe(scheduler_tx);
+
+    handle_signals(Signals {
+        shutdown: shutdown.clone(),
+        lock: Arc::clone(&lock),
+        notifier: Arc::clone(&notifier),
+        config_mtx: Arc::clone(&config_mtx),
+        store: Arc::clone(&store),
+        session: Arc::clone(&session),
+    });
+
+    let handles = vec![
+        (
+            "config watcher",
+            *reload::watch_config_changes(notifier, shutdown.clone()),
+        ),
+        (
+            "scheduler",
+            *scheduler::run(
+                Arc::clone(&config_mtx),
+                scheduler_rx,
+                Arc::clone(&store),
+                Arc::clone(&session),
+                shutdown.clone(),
+            ),
+        ),
+        (
+            "garbage collector",
+            *gc::run(
+                Arc::clone(&config_mtx),
+                Arc::clone(&store),
+                shutdown.clone(),
+            ),
+        ),
+        (
+            "backup scheduler",
+            *backups::run(
+                Arc::clone(&config_mtx),
+                Arc::clone(&store),
+                shutdown.clone(),
+            ),
+        ),
+        (
+            "metadata refresher",
+            *metadata::run(
+                Arc::clone(&config_mtx),
+                Arc::clone(&store),
+                shutdown.clone(),
+            ),
+        ),
+        (
+            "control socket",
+            *control::listen(
+                lock_info,
+                Arc::clone(&store),
+                Arc::clone(&config_mtx),
+                shutdown.clone(),
+            ),
+        ),
+    ];
+
+    shutdown.wait();
+    info!(
+        "Shutting down. Waiting up to {} seconds for running work to finish. \
+        Send SIGINT or SIGTERM again to quit right away.",
+        DRAIN_TIMEOUT.as_secs()
+    );
+    control::wake();
+    drain(handles);
 
-    config_watch_handle.join().unwrap();
-    anime_watch_handle.join().unwrap();
+    // Gives trackers a chance to hear that we are leaving.
+    session.shutdown(Duration::from_secs(5));
+    control::remove_socket();
+    lock.release();
+    info!("Exiting.");
+    process::exit(0);
 }
@@ -0,0 +1,88 @@
+use common::lock;
+use common::structs::{CachedFeed, FeedItem};
+use rss::Channel;
+use std::io::BufReader;
+use std::time::Duration;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const NOT_MODIFIED: u16 = 304;
+
+fn call(url: &str, request: &mut ureq::Request) -> Result<ureq::Response, String> {
+    let response = request.timeout(FETCH_TIMEOUT).call();
+    if let Some(e) = response.synthetic_error() {
+        return Err(format!("Failed to fetch {}: {}", url, e));
+    }
+    if response.error() {
+        return Err(format!(
+            "Failed to fetch {}: HTTP {}",
+            url,
+            response.status()
+        ));
+    }
+    return Ok(response);
+}
+
+fn get(url: &str) -> Result<ureq::Response, String> {
+    return call(url, &mut ureq::get(url));
+}
+
+// Fetches a feed, unless the server says it has not changed since `cached`
+// was fetched, in which case the cached items are returned again.
+pub fn fetch(url: &str, cached: Option<&CachedFeed>) -> Result<CachedFeed, String> {
+    let mut request = ureq::get(url);
+    if let Some(cached) = cached {
+        if let Some(etag) = cached.etag.as_ref() {
+            request.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = cached.last_modified.as_ref() {
+            request.set("If-Modified-Since", last_modified);
+        }
+    }
+    let response = call(url, &mut request)?;
+    if let (NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+        return Ok(CachedFeed {
+            fetched_at: lock::now(),
+            ..cached.clone()
+        });
+    }
+
+    let etag = response.header("ETag").map(String::from);
+    let last_modified = response.header("Last-Modified").map(String::from);
+    let channel = Channel::read_from(BufReader::new(response.into_reader()))
+        .map_err(|e| format!("Failed to parse feed {}: {}", url, e))?;
+
+    let mut items = vec![];
+    for item in channel.items() {
+        let title = match item.title() {
+            Some(title) => title,
+            None => continue,
+        };
+        // Torrent feeds usually attach the .torrent file as an enclosure and
+        // some put the magnet link in the item link instead.
+        let link = match item
+            .enclosure()
+            .map(|enclosure| enclosure.url())
+            .or(item.link())
+        {
+            Some(link) => link,
+            None => continue,
+        };
+        items.push(FeedItem {
+            title: title.to_string(),
+            link: link.to_string(),
+        });
+    }
+    return Ok(CachedFeed {
+        etag: etag,
+        last_modified: last_modified,
+        items: items,
+        fetched_at: lock::now(),
+    });
+}
+
+pub fn fetch_torrent(url: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    std::io::copy(&mut get(url)?.into_reader(), &mut bytes)
+        .map_err(|e| format!("Failed to download {}: {}", url, e))?;
+    return Ok(bytes);
+}
@@ -0,0 +1,48 @@
+use crate::shutdown::ShutdownToken;
+use common::retention::{self, GcReport};
+use common::store::WatchStore;
+use common::structs::{Config, RetentionConfig};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+pub fn log_report(report: &GcReport) {
+    for (watch_id, anime) in report.purged.iter() {
+        info!("Purged {} / Watch ID: {}", anime, watch_id);
+    }
+    if report.purged_episodes > 0 || report.dropped_events > 0 {
+        info!(
+            "Removed {} episode(s) and {} event(s) from the history.",
+            report.purged_episodes, report.dropped_events
+        );
+    }
+}
+
+pub fn collect(store: &dyn WatchStore, policy: &RetentionConfig) {
+    debug!("Collecting garbage with {:?}", policy);
+    match retention::collect_garbage(store, policy, false) {
+        Ok(report) => log_report(&report),
+        Err(e) => warn!("Failed to collect garbage: {}", e),
+    }
+}
+
+// Collects garbage once at startup and then every
+// `retention.gc_interval_hours`. A changed interval applies from the next
+// run on.
+pub fn run(
+    config_mtx: Arc<Mutex<Config>>,
+    store: Arc<dyn WatchStore>,
+    shutdown: ShutdownToken,
+) -> Box<thread::JoinHandle<()>> {
+    let handle = thread::spawn(move || loop {
+        let policy = config_mtx.lock().unwrap().retention.clone();
+        collect(&*store, &policy);
+        let interval = Duration::from_secs(policy.gc_interval_hours * 60 * 60);
+        if shutdown.wait_timeout(interval) {
+            return;
+        }
+    });
+    return Box::new(handle);
+}
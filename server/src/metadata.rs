@@ -0,0 +1,55 @@
+use crate::shutdown::ShutdownToken;
+use common::metadata::{self, MetadataDatabase};
+use common::store::WatchStore;
+use common::structs::{Config, MetadataConfig};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+fn refresh(store: &dyn WatchStore, config: &MetadataConfig) {
+    let path = match &config.database {
+        Some(path) => Path::new(path),
+        None => return,
+    };
+    let database = match MetadataDatabase::load(path) {
+        Ok(database) => database,
+        Err(e) => {
+            warn!("Failed to load the anime-offline-database: {}", e);
+            return;
+        }
+    };
+    debug!(
+        "Loaded {} entries of the anime-offline-database.",
+        database.len()
+    );
+    match metadata::refresh(store, &database) {
+        Ok(0) => (),
+        Ok(refreshed) => info!(
+            "Refreshed the anime-offline-database entries of {} anime.",
+            refreshed
+        ),
+        Err(e) => warn!("Failed to refresh anime metadata: {}", e),
+    }
+}
+
+// Reads the anime-offline-database at startup and then every
+// `metadata.refresh_hours`, so that linked anime learn e.g. that their show
+// has finished airing from a newer copy of the file. The database is not kept
+// in memory in between.
+pub fn run(
+    config_mtx: Arc<Mutex<Config>>,
+    store: Arc<dyn WatchStore>,
+    shutdown: ShutdownToken,
+) -> Box<thread::JoinHandle<()>> {
+    let handle = thread::spawn(move || loop {
+        let config = config_mtx.lock().unwrap().metadata.clone();
+        refresh(&*store, &config);
+        if shutdown.wait_timeout(Duration::from_secs(config.refresh_hours * 60 * 60)) {
+            return;
+        }
+    });
+    return Box::new(handle);
+}
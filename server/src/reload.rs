@@ -0,0 +1,202 @@
+use crate::shutdown::ShutdownToken;
+use common::config::{self, FieldChange};
+use common::paths;
+use common::structs::Config;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+// Settings the running daemon cannot pick up, since they are only read when
+// the daemon starts.
+const RESTART_REQUIRED: [&str; 1] = ["listen_port"];
+// How long the watcher waits for events before checking for shutdown.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sent to every subsystem that subscribed to config changes, once the new
+// config is in place.
+#[derive(Debug)]
+pub struct ConfigChange {
+    pub old: Config,
+    pub new: Config,
+    pub fields: Vec<FieldChange>,
+}
+
+impl ConfigChange {
+    pub fn touches(&self, key: &str) -> bool {
+        return self.fields.iter().any(|field| field.is(key));
+    }
+}
+
+// Owns the config the daemon runs with and tells subscribers when it
+// changes, whether the change came from the config watcher or a reload
+// requested some other way.
+pub struct ConfigNotifier {
+    config_mtx: Arc<Mutex<Config>>,
+    subscribers: Mutex<Vec<Sender<Arc<ConfigChange>>>>,
+}
+
+impl ConfigNotifier {
+    pub fn new(config_mtx: Arc<Mutex<Config>>) -> ConfigNotifier {
+        return ConfigNotifier {
+            config_mtx: config_mtx,
+            subscribers: Mutex::new(vec![]),
+        };
+    }
+
+    pub fn subscribe(&self, subscriber: Sender<Arc<ConfigChange>>) {
+        self.subscribers.lock().unwrap().push(subscriber);
+    }
+
+    // Reads every config layer again and applies the result if it is valid.
+    // An invalid config is logged and the daemon keeps the last good one.
+    pub fn reload(&self) {
+        let new = match config::read() {
+            Ok(config) => config,
+            Err(e) if e.is_not_found() => {
+                warn!("{}. Keeping the last config.", e);
+                return;
+            }
+            Err(e) => {
+                error!(
+                    "Ignoring the updated config and keeping the last good config \
+                    because the new one is invalid.\n{}",
+                    e
+                );
+                return;
+            }
+        };
+
+        let old = {
+            let mut current = self.config_mtx.lock().unwrap();
+            std::mem::replace(&mut *current, new.clone())
+        };
+        let fields = config::diff(&old, &new);
+        if fields.is_empty() {
+            debug!("Config was reloaded without any changes.");
+            return;
+        }
+
+        info!("Config was updated:");
+        for field in fields.iter() {
+            info!("  {}", field);
+        }
+        for key in RESTART_REQUIRED.iter() {
+            if fields.iter().any(|field| field.is(key)) {
+                warn!("`{}` only takes effect once the daemon is restarted.", key);
+            }
+        }
+
+        let change = Arc::new(ConfigChange {
+            old: old,
+            new: new,
+            fields: fields,
+        });
+        // Subscribers that have gone away are dropped.
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(Arc::clone(&change)).is_ok());
+    }
+}
+
+// The config files the merged config is read from. The system config is
+// only watched if its directory exists.
+fn config_files() -> Vec<PathBuf> {
+    let mut files = vec![paths::get().config_file];
+    let system_config_file = config::system_config_file();
+    if !files.contains(&system_config_file) {
+        files.push(system_config_file);
+    }
+    return files;
+}
+
+// Editors often save by writing a temporary file and renaming it over the
+// config, which replaces the file we would be watching. Watching the
+// directory instead sees the new file arrive. The directory is canonicalized
+// so that event paths can be compared with the config paths.
+fn watched_path(file: &Path) -> Option<(PathBuf, PathBuf)> {
+    let dir = match file.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return None,
+    };
+    let dir = fs::canonicalize(dir).ok()?;
+    let file_name = file.file_name()?;
+    return Some((dir.clone(), dir.join(file_name)));
+}
+
+fn affects(event: &DebouncedEvent, files: &[PathBuf]) -> bool {
+    let is_config = |path: &PathBuf| files.contains(path);
+    match event {
+        DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path) => is_config(path),
+        DebouncedEvent::Rename(from, to) => is_config(from) || is_config(to),
+        DebouncedEvent::Rescan => true,
+        _ => false,
+    }
+}
+
+pub fn watch_config_changes(
+    notifier: Arc<ConfigNotifier>,
+    shutdown: ShutdownToken,
+) -> Box<thread::JoinHandle<()>> {
+    let handle = thread::spawn(move || {
+        let (tx, rx) = channel();
+        let mut watcher = watcher(tx, Duration::from_secs(3))
+            .expect("Failed to create filesystem watcher to watch config file.");
+
+        let mut files = vec![];
+        for file in config_files() {
+            let (dir, file) = match watched_path(&file) {
+                Some(watched) => watched,
+                None => {
+                    debug!(
+                        "Not watching `{}` because its directory does not exist.",
+                        file.display()
+                    );
+                    continue;
+                }
+            };
+            match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    info!("Watching for changes in config file `{}`.", file.display());
+                    files.push(file);
+                }
+                Err(e) => error!(
+                    "Failed to watch config directory `{}`: {:?}",
+                    dir.display(),
+                    e
+                ),
+            }
+        }
+
+        while !shutdown.is_triggered() {
+            match rx.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+                Ok(DebouncedEvent::Error(e, path)) => {
+                    error!("A watch error occured on {:?}: {:?}", path, e)
+                }
+                // A deleted user config falls back to the system config and
+                // the defaults, so it is reloaded like any other change.
+                Ok(event) if affects(&event, &files) => {
+                    debug!("Config change detected: {:?}", event);
+                    notifier.reload();
+                }
+                Ok(_) | Err(RecvTimeoutError::Timeout) => (),
+                Err(e) => {
+                    error!("Stopped watching the config: {:?}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    return Box::new(handle);
+}
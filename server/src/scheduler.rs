@@ -0,0 +1,926 @@
+use crate::builtin::BuiltinBackend;
+use crate::feed;
+use crate::reload::ConfigChange;
+use crate::shutdown::ShutdownToken;
+use bittorrent::magnet::MagnetLink;
+use bittorrent::session::Session;
+use bittorrent::torrent::{hex_encode_bytes, TorrentMetainfo};
+use common::backend::{self, DownloadBackend, DownloadState, TorrentPayload};
+use common::lock;
+use common::release::{Rank, Release};
+use common::store::{StoreError, WatchStore};
+use common::structs::{Anime, BackendConfig, Config, EpisodeRecord, EpisodeState, FeedItem};
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often running downloads are checked for completion. Feeds are polled
+// far less often, see `Config.poll_interval_secs`.
+const DOWNLOAD_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const MIN_FEED_POLL_INTERVAL: Duration = Duration::from_secs(60);
+// How long waits between passes last before checking for shutdown.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn build_backend(config: &BackendConfig, session: &Arc<Session>) -> Box<dyn DownloadBackend> {
+    let backend = match backend::external(config) {
+        Some(backend) => backend,
+        None => Box::new(BuiltinBackend::new(Arc::clone(session))),
+    };
+    info!("Downloading with the {} backend.", backend.name());
+    return backend;
+}
+
+struct PreparedTorrent {
+    info_hash: String,
+    name: Option<String>,
+    payload: TorrentPayload,
+}
+
+// Works out the info hash of a release and fetches its .torrent file if it
+// is not a magnet link, so that every backend gets the same thing.
+fn prepare(link: &str) -> Result<PreparedTorrent, String> {
+    if link.starts_with("magnet:") {
+        let magnet = MagnetLink::parse(link).map_err(|e| e.to_string())?;
+        return Ok(PreparedTorrent {
+            info_hash: hex_encode_bytes(&magnet.info_hash),
+            name: magnet.display_name,
+            payload: TorrentPayload::Magnet(link.to_string()),
+        });
+    }
+
+    let bytes = feed::fetch_torrent(link)?;
+    let (metainfo, info_hash) = TorrentMetainfo::from_bytes(&bytes)
+        .map_err(|e| format!("{} is not a valid torrent: {}", link, e))?;
+    return Ok(PreparedTorrent {
+        info_hash: hex_encode_bytes(&info_hash),
+        name: Some(metainfo.info.name),
+        payload: TorrentPayload::TorrentFile(bytes),
+    });
+}
+
+// Records the new state of an episode in the ledger.
+fn save_episode(store: &dyn WatchStore, record: &EpisodeRecord) {
+    if let Err(e) = store.put_episode(record) {
+        error!(
+            "Failed to record episode {} of watch ID {}: {}",
+            record.episode, record.watch_id, e
+        );
+    }
+}
+
+// Another episode that was grabbed from the same torrent, e.g. because it
+// is a batch covering both. Episodes that failed do not count.
+fn grabbed_for_other_episode(
+    store: &dyn WatchStore,
+    record: &EpisodeRecord,
+    info_hash: &str,
+) -> Option<EpisodeRecord> {
+    let records = match store.find_episodes_by_info_hash(info_hash) {
+        Ok(records) => records,
+        Err(e) => {
+            warn!("Failed to look up the torrent {}: {}", info_hash, e);
+            return None;
+        }
+    };
+    return records.into_iter().find(|other| {
+        (other.watch_id != record.watch_id || other.episode != record.episode)
+            && other.state != EpisodeState::Failed
+    });
+}
+
+fn start_download(
+    store: &dyn WatchStore,
+    backend: &dyn DownloadBackend,
+    config: &Config,
+    mut record: EpisodeRecord,
+) {
+    let added = prepare(&record.link).and_then(|torrent| {
+        if let Some(other) = grabbed_for_other_episode(store, &record, &torrent.info_hash) {
+            return Ok((torrent.info_hash, Some(other)));
+        }
+        let name = torrent.name.as_ref().unwrap_or(&record.release_title);
+        backend
+            .add(
+                &torrent.info_hash,
+                name,
+                &torrent.payload,
+                &config.download_path,
+            )
+            .map(|_| (torrent.info_hash, None))
+            .map_err(|e| e.to_string())
+    });
+
+    match added {
+        Ok((info_hash, None)) => {
+            let verb = match record.replaces {
+                Some(_) => "Upgrading",
+                None => "Downloading",
+            };
+            info!(
+                "{} episode {} of watch ID {}: {}",
+                verb, record.episode, record.watch_id, record.release_title
+            );
+            record.info_hash = Some(info_hash);
+            record.state = EpisodeState::Downloading;
+        }
+        // The episode shares the download that is already there.
+        Ok((info_hash, Some(other))) => {
+            info!(
+                "{} was already grabbed for episode {} of watch ID {}.",
+                record.release_title, other.episode, other.watch_id
+            );
+            record.info_hash = Some(info_hash);
+            if other.state == EpisodeState::Completed {
+                return finish_download(store, backend, config, record, EpisodeState::Completed);
+            }
+            record.state = other.state;
+        }
+        Err(e) => {
+            warn!(
+                "Failed to start downloading {}: {}",
+                record.release_title, e
+            );
+            record.state = EpisodeState::Failed;
+        }
+    }
+    record.updated_at = lock::now();
+    save_episode(store, &record);
+}
+
+fn finish_download(
+    store: &dyn WatchStore,
+    backend: &dyn DownloadBackend,
+    config: &Config,
+    mut record: EpisodeRecord,
+    state: EpisodeState,
+) {
+    if state == EpisodeState::Completed {
+        if let Some(replaced) = record.replaces.take() {
+            remove_replaced(store, backend, &record, &replaced);
+        }
+    }
+    record.state = state;
+    record.updated_at = lock::now();
+    save_episode(store, &record);
+
+    if state == EpisodeState::Completed {
+        if let Err(e) = store.advance_last_seen_episode(&record.watch_id, record.episode) {
+            error!(
+                "Failed to mark episode {} of watch ID {} as seen: {}",
+                record.episode, record.watch_id, e
+            );
+        }
+        if config.metadata.complete_finished {
+            complete_if_finished(store, &record);
+        }
+    }
+}
+
+// Removes the download of an episode in a worse quality once the better
+// one is complete, unless another episode still comes from that torrent.
+fn remove_replaced(
+    store: &dyn WatchStore,
+    backend: &dyn DownloadBackend,
+    record: &EpisodeRecord,
+    replaced: &str,
+) {
+    if record.info_hash.as_deref() == Some(replaced)
+        || grabbed_for_other_episode(store, record, replaced).is_some()
+    {
+        return;
+    }
+    match backend.remove(replaced, true) {
+        Ok(()) => info!(
+            "Removed the download episode {} of watch ID {} was upgraded from.",
+            record.episode, record.watch_id
+        ),
+        Err(e) => warn!(
+            "Failed to remove the download episode {} of watch ID {} was upgraded from: {}",
+            record.episode, record.watch_id, e
+        ),
+    }
+}
+
+// Stops watching a show linked to the anime-offline-database once the last
+// of the episodes it lists for a finished show has been downloaded.
+fn complete_if_finished(store: &dyn WatchStore, record: &EpisodeRecord) {
+    let finished = match store.get_anime(&record.watch_id) {
+        Ok(Some(anime)) => anime
+            .metadata
+            .is_some_and(|metadata| metadata.is_final_episode(record.episode)),
+        Ok(None) => false,
+        Err(e) => {
+            warn!(
+                "Failed to check whether watch ID {} is complete: {}",
+                record.watch_id, e
+            );
+            false
+        }
+    };
+    if !finished {
+        return;
+    }
+    match store.complete_anime(&record.watch_id) {
+        Ok(true) => info!(
+            "Episode {} was the last of watch ID {}, which is no longer watched.",
+            record.episode, record.watch_id
+        ),
+        Ok(false) => (),
+        Err(e) => error!("Failed to complete watch ID {}: {}", record.watch_id, e),
+    }
+}
+
+// Asks the backend about every download in the ledger that has not finished
+// yet. Downloads the backend has lost track of, e.g. because the daemon was
+// restarted, are handed to it again and pick up whatever is already on disk,
+// unless `resume` is false as it is while shutting down.
+fn check_downloads(
+    store: &dyn WatchStore,
+    backend: &dyn DownloadBackend,
+    config: &Config,
+    resume: bool,
+) {
+    let records = match store.list_episodes() {
+        Ok(records) => records,
+        Err(e) => {
+            warn!("Failed to check on running downloads: {}", e);
+            return;
+        }
+    };
+    for record in records {
+        // Completed episodes may have an upgrade underway, see
+        // `WatchStore::put_episode`.
+        let record = match record.upgrade {
+            Some(upgrade) => *upgrade,
+            None => record,
+        };
+        if record.state != EpisodeState::Downloading {
+            continue;
+        }
+        let info_hash = match record.info_hash.clone() {
+            Some(info_hash) => info_hash,
+            None => {
+                if resume {
+                    start_download(store, backend, config, record);
+                }
+                continue;
+            }
+        };
+
+        match backend.poll(&info_hash) {
+            Ok(DownloadState::Completed) => {
+                info!("Finished downloading {}.", record.release_title);
+                finish_download(store, backend, config, record, EpisodeState::Completed);
+            }
+            Ok(DownloadState::Failed(reason)) => {
+                warn!("Download of {} failed: {}", record.release_title, reason);
+                finish_download(store, backend, config, record, EpisodeState::Failed);
+            }
+            Ok(DownloadState::Missing) if resume => {
+                info!("Resuming download of {}.", record.release_title);
+                start_download(store, backend, config, record);
+            }
+            Ok(DownloadState::Missing) => (),
+            Ok(DownloadState::Downloading {
+                downloaded_bytes,
+                total_bytes,
+            }) => debug!(
+                "{}: {}/{} bytes",
+                record.release_title, downloaded_bytes, total_bytes
+            ),
+            Ok(DownloadState::Queued) => debug!("{}: queued", record.release_title),
+            Err(e) => warn!(
+                "Failed to check on {} with the {} backend: {}",
+                record.release_title,
+                backend.name(),
+                e
+            ),
+        }
+    }
+}
+
+fn select_releases(
+    store: &dyn WatchStore,
+    items: &[FeedItem],
+    threshold: f64,
+) -> Result<Vec<EpisodeRecord>, StoreError> {
+    let watched = store.list_anime()?;
+    let now = lock::now();
+    // With their rank, see `Release::rank`.
+    let mut selected: Vec<(Rank, EpisodeRecord)> = vec![];
+
+    for item in items {
+        let release = match Release::parse(&item.title) {
+            Some(release) => release,
+            None => continue,
+        };
+        for (watch_id, anime) in watched.iter() {
+            let (matched, rank) = match release.wanted_by(anime, threshold) {
+                Some(wanted) => wanted,
+                None => continue,
+            };
+            let (upgrade, replaces, decide_at) =
+                match store.get_episode(watch_id, release.episode)? {
+                    // Only releases better than the best one so far are worth
+                    // remembering.
+                    Some(record) if record.state == EpisodeState::Pending => {
+                        let pending = Release::parse(&record.release_title)
+                            .and_then(|pending| pending.rank(anime));
+                        if pending.is_some_and(|pending| pending <= rank) {
+                            continue;
+                        }
+                        (false, None, record.decide_at)
+                    }
+                    Some(record) if record.state != EpisodeState::Failed => {
+                        // An upgrade that is underway is not started again,
+                        // but one that failed is retried.
+                        let best = match record.upgrade.as_deref() {
+                            Some(upgrade) if upgrade.state != EpisodeState::Failed => upgrade,
+                            _ => &record,
+                        };
+                        if !is_upgrade(anime, best, &release, rank) {
+                            continue;
+                        }
+                        (true, record.info_hash, None)
+                    }
+                    _ if release.episode <= anime.last_seen_episode => continue,
+                    _ => (false, None, None),
+                };
+            // Feeds often list the same episode more than once, e.g. when a
+            // group re-releases it as v2, or by several groups or in several
+            // qualities.
+            let already_selected = selected.iter().position(|(_, record)| {
+                record.watch_id == *watch_id && record.episode == release.episode
+            });
+            if let Some(index) = already_selected {
+                if selected[index].0 <= rank {
+                    continue;
+                }
+                selected.remove(index);
+            }
+
+            // Releases by the preferred group are taken right away, others
+            // only once no better ranked group released the episode in time.
+            let decide_at = decide_at.unwrap_or(now + anime.delay_hours * 60 * 60);
+            let waits = !upgrade && rank.subgroup > 0 && now < decide_at;
+            let record = EpisodeRecord {
+                watch_id: watch_id.clone(),
+                episode: release.episode,
+                release_title: item.title.clone(),
+                link: item.link.clone(),
+                info_hash: None,
+                state: if waits {
+                    EpisodeState::Pending
+                } else {
+                    EpisodeState::Downloading
+                },
+                matched_title: Some(String::from(matched)),
+                replaces: replaces,
+                decide_at: if waits { Some(decide_at) } else { None },
+                updated_at: now,
+                upgrade: None,
+            };
+            selected.push((rank, record));
+        }
+    }
+    return Ok(selected.into_iter().map(|(_, record)| record).collect());
+}
+
+// Whether `offered` should replace the release the episode in `record` was
+// downloaded from. Releases by worse ranked groups never do.
+fn is_upgrade(anime: &Anime, record: &EpisodeRecord, offered: &Release, rank: Rank) -> bool {
+    let have = match Release::parse(&record.release_title) {
+        Some(have) => have,
+        None => return false,
+    };
+    let worse_group =
+        matches!(anime.subgroup_rank(&have.subgroup), Some(have) if rank.subgroup > have);
+    return match (have.quality, offered.quality) {
+        (Some(have), Some(offered)) => !worse_group && anime.is_upgrade(have, offered),
+        _ => false,
+    };
+}
+
+// Downloads the best release of every pending episode that no better ranked
+// group released in time, or whose anime no longer waits for one.
+fn start_due_downloads(store: &dyn WatchStore, backend: &dyn DownloadBackend, config: &Config) {
+    let records = match store.list_episodes() {
+        Ok(records) => records,
+        Err(e) => {
+            warn!("Failed to check on pending episodes: {}", e);
+            return;
+        }
+    };
+    let now = lock::now();
+    for mut record in records {
+        if record.state != EpisodeState::Pending {
+            continue;
+        }
+        let waiting = record.decide_at.is_some_and(|at| at > now);
+        match store.get_anime(&record.watch_id) {
+            Ok(Some(anime)) if !anime.tombstone && !anime.paused => {
+                if waiting && anime.delay_hours > 0 {
+                    continue;
+                }
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(
+                    "Failed to look up watch ID {} of a pending episode: {}",
+                    record.watch_id, e
+                );
+                continue;
+            }
+        }
+        info!(
+            "Done waiting for a better ranked group to release episode {} of watch ID {}.",
+            record.episode, record.watch_id
+        );
+        record.state = EpisodeState::Downloading;
+        record.decide_at = None;
+        start_download(store, backend, config, record);
+    }
+}
+
+fn poll_feeds(
+    store: &dyn WatchStore,
+    backend: &dyn DownloadBackend,
+    config: &Config,
+    shutdown: &ShutdownToken,
+) {
+    if config.feeds.is_empty() {
+        debug!("No feeds are configured, so there is nothing to poll.");
+        return;
+    }
+
+    for url in config.feeds.iter() {
+        if shutdown.is_triggered() {
+            return;
+        }
+        debug!("Polling feed {}", url);
+        let cached = store.get_feed(url).unwrap_or_else(|e| {
+            warn!("Failed to read the cached copy of feed {}: {}", url, e);
+            None
+        });
+        let fetched = match feed::fetch(url, cached.as_ref()) {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        if let Err(e) = store.put_feed(url, &fetched) {
+            warn!("Failed to cache feed {}: {}", url, e);
+        }
+        // Unchanged feeds are still searched, since the watch list may have
+        // changed since.
+        let selected = match select_releases(store, &fetched.items, config.matching.threshold()) {
+            Ok(selected) => selected,
+            Err(e) => {
+                warn!("Failed to pick releases from feed {}: {}", url, e);
+                continue;
+            }
+        };
+        for record in selected {
+            if shutdown.is_triggered() {
+                return;
+            }
+            grab(store, backend, config, record);
+        }
+    }
+}
+
+// Starts downloading a release picked by `select_releases`, or holds it back
+// if it waits for a better ranked group.
+fn grab(
+    store: &dyn WatchStore,
+    backend: &dyn DownloadBackend,
+    config: &Config,
+    record: EpisodeRecord,
+) {
+    if let Some(decide_at) = record.decide_at {
+        info!(
+            "Holding {} back for up to {} minutes in case a better ranked group \
+            releases episode {} of watch ID {}.",
+            record.release_title,
+            decide_at.saturating_sub(lock::now()) / 60,
+            record.episode,
+            record.watch_id
+        );
+        save_episode(store, &record);
+        return;
+    }
+    start_download(store, backend, config, record);
+}
+
+// Logs how a config change affects the scheduler. Everything else is picked
+// up on the next pass, since every pass works from a fresh config snapshot.
+fn describe_change(change: &ConfigChange) {
+    if change.touches("download_path") {
+        info!(
+            "New downloads will be saved to `{}` instead of `{}`. Running downloads \
+            stay where they are.",
+            change.new.download_path, change.old.download_path
+        );
+    }
+    if change.touches("poll_interval_secs") {
+        info!(
+            "Polling feeds every {} seconds.",
+            Duration::from_secs(change.new.poll_interval_secs)
+                .max(MIN_FEED_POLL_INTERVAL)
+                .as_secs()
+        );
+    }
+    if change.touches("feeds") {
+        info!("Feeds changed, polling them now.");
+    }
+}
+
+// Sleeps until the next pass is due, until the config changes so that the
+// change is applied right away, or until the daemon shuts down.
+fn wait(
+    changes: &Receiver<Arc<ConfigChange>>,
+    shutdown: &ShutdownToken,
+    last_feed_poll: &mut Option<Instant>,
+) {
+    let deadline = Instant::now() + DOWNLOAD_POLL_INTERVAL;
+    while !shutdown.is_triggered() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return;
+        }
+        match changes.recv_timeout(remaining.min(SHUTDOWN_CHECK_INTERVAL)) {
+            Ok(change) => {
+                describe_change(&change);
+                if change.touches("feeds") {
+                    *last_feed_poll = None;
+                }
+                return;
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            // The daemon no longer sends config changes.
+            Err(RecvTimeoutError::Disconnected) => {
+                shutdown.wait_timeout(remaining);
+                return;
+            }
+        }
+    }
+}
+
+pub fn run(
+    config_mtx: Arc<Mutex<Config>>,
+    changes: Receiver<Arc<ConfigChange>>,
+    store: Arc<dyn WatchStore>,
+    session: Arc<Session>,
+    shutdown: ShutdownToken,
+) -> Box<thread::JoinHandle<()>> {
+    info!("Watching for new anime to download.");
+
+    let handle = thread::spawn(move || {
+        let mut backend_config = config_mtx.lock().unwrap().backend.clone();
+        let mut backend = build_backend(&backend_config, &session);
+        let mut last_feed_poll: Option<Instant> = None;
+
+        while !shutdown.is_triggered() {
+            // Work from a snapshot so that a slow feed or backend does not
+            // block config reloads.
+            let config = config_mtx.lock().unwrap().clone();
+            if config.backend != backend_config {
+                backend_config = config.backend.clone();
+                backend = build_backend(&backend_config, &session);
+            }
+
+            check_downloads(&*store, &*backend, &config, true);
+            start_due_downloads(&*store, &*backend, &config);
+
+            let feed_poll_interval =
+                Duration::from_secs(config.poll_interval_secs).max(MIN_FEED_POLL_INTERVAL);
+            if last_feed_poll.map_or(true, |last| last.elapsed() >= feed_poll_interval) {
+                poll_feeds(&*store, &*backend, &config, &shutdown);
+                last_feed_poll = Some(Instant::now());
+            }
+
+            wait(&changes, &shutdown, &mut last_feed_poll);
+        }
+
+        // Downloads that finished since the last pass are recorded now, so
+        // that they are not fetched again after a restart.
+        info!("Saving the state of running downloads.");
+        let config = config_mtx.lock().unwrap().clone();
+        check_downloads(&*store, &*backend, &config, false);
+        info!("Stopped watching for new anime.");
+    });
+    return Box::new(handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::backend::BackendError;
+    use common::store::MemoryStore;
+    use common::structs::{Quality, QualityProfile};
+    use std::collections::HashMap;
+
+    // Keeps downloads in memory until a test finishes them.
+    #[derive(Default)]
+    struct FakeBackend {
+        downloads: Mutex<HashMap<String, DownloadState>>,
+        removed: Mutex<Vec<String>>,
+    }
+
+    impl FakeBackend {
+        fn finish(&self, info_hash: &str) {
+            self.downloads
+                .lock()
+                .unwrap()
+                .insert(String::from(info_hash), DownloadState::Completed);
+        }
+
+        fn added(&self) -> usize {
+            return self.downloads.lock().unwrap().len();
+        }
+    }
+
+    impl DownloadBackend for FakeBackend {
+        fn name(&self) -> &'static str {
+            return "fake";
+        }
+
+        fn add(
+            &self,
+            info_hash: &str,
+            _name: &str,
+            _payload: &TorrentPayload,
+            _download_path: &str,
+        ) -> Result<(), BackendError> {
+            self.downloads.lock().unwrap().insert(
+                String::from(info_hash),
+                DownloadState::Downloading {
+                    downloaded_bytes: 0,
+                    total_bytes: 1,
+                },
+            );
+            return Ok(());
+        }
+
+        fn poll(&self, info_hash: &str) -> Result<DownloadState, BackendError> {
+            return Ok(self
+                .downloads
+                .lock()
+                .unwrap()
+                .get(info_hash)
+                .cloned()
+                .unwrap_or(DownloadState::Missing));
+        }
+
+        fn remove(&self, info_hash: &str, _delete_files: bool) -> Result<(), BackendError> {
+            self.downloads.lock().unwrap().remove(info_hash);
+            self.removed.lock().unwrap().push(String::from(info_hash));
+            return Ok(());
+        }
+    }
+
+    fn frieren(profile: QualityProfile) -> Anime {
+        return Anime {
+            title: String::from("Sousou no Frieren"),
+            quality: Quality::Res1080,
+            subgroup: String::from("SubsPlease"),
+            last_seen_episode: 4,
+            tombstone: false,
+            paused: false,
+            tombstoned_at: None,
+            aliases: vec![],
+            matched_alias: None,
+            metadata: None,
+            profile: profile,
+            fallback_subgroups: vec![],
+            delay_hours: 0,
+        };
+    }
+
+    // A magnet link whose info hash is `hash` repeated.
+    fn item(subgroup: &str, episode: i32, quality: &str, hash: char) -> FeedItem {
+        let info_hash = hash.to_string().repeat(40);
+        return FeedItem {
+            title: format!(
+                "[{}] Sousou no Frieren - {:02} ({}) [ABCD1234].mkv",
+                subgroup, episode, quality
+            ),
+            link: format!("magnet:?xt=urn:btih:{}", info_hash),
+        };
+    }
+
+    // What `poll_feeds` does with a fetched feed.
+    fn offer(store: &MemoryStore, backend: &FakeBackend, items: &[FeedItem]) -> usize {
+        let config = Config::default();
+        let selected = select_releases(store, items, config.matching.threshold()).unwrap();
+        let count = selected.len();
+        for record in selected {
+            grab(store, backend, &config, record);
+        }
+        return count;
+    }
+
+    fn check(store: &MemoryStore, backend: &FakeBackend) {
+        check_downloads(store, backend, &Config::default(), true);
+    }
+
+    fn episode(store: &MemoryStore, watch_id: &str, episode: i32) -> EpisodeRecord {
+        return store.get_episode(watch_id, episode).unwrap().unwrap();
+    }
+
+    fn quality_of(record: &EpisodeRecord) -> Option<Quality> {
+        return Release::parse(&record.release_title).and_then(|release| release.quality);
+    }
+
+    #[test]
+    fn replaces_a_720p_release_with_a_1080p_one() {
+        let store = MemoryStore::new();
+        let backend = FakeBackend::default();
+        let watch_id = store
+            .upsert_anime(&frieren(QualityProfile {
+                fallbacks: vec![Quality::Res720],
+                minimum: None,
+                upgrade_until: Some(Quality::Res1080),
+            }))
+            .unwrap();
+
+        assert_eq!(
+            offer(&store, &backend, &[item("SubsPlease", 5, "720p", 'a')]),
+            1
+        );
+        backend.finish(&"a".repeat(40));
+        check(&store, &backend);
+        assert_eq!(episode(&store, &watch_id, 5).state, EpisodeState::Completed);
+
+        assert_eq!(
+            offer(&store, &backend, &[item("SubsPlease", 5, "1080p", 'b')]),
+            1
+        );
+        // The 720p episode stays until its upgrade is done.
+        let record = episode(&store, &watch_id, 5);
+        assert_eq!(record.state, EpisodeState::Completed);
+        assert_eq!(quality_of(&record), Some(Quality::Res720));
+        let upgrade = record.upgrade.expect("the upgrade is underway");
+        assert_eq!(upgrade.state, EpisodeState::Downloading);
+        assert_eq!(upgrade.replaces, Some("a".repeat(40)));
+
+        // Nor is the upgrade started twice.
+        assert_eq!(
+            offer(&store, &backend, &[item("SubsPlease", 5, "1080p", 'c')]),
+            0
+        );
+
+        backend.finish(&"b".repeat(40));
+        check(&store, &backend);
+        let record = episode(&store, &watch_id, 5);
+        assert_eq!(record.state, EpisodeState::Completed);
+        assert_eq!(quality_of(&record), Some(Quality::Res1080));
+        assert!(record.upgrade.is_none());
+        assert_eq!(*backend.removed.lock().unwrap(), vec!["a".repeat(40)]);
+
+        // 1080p is as far as it goes.
+        assert_eq!(
+            offer(&store, &backend, &[item("SubsPlease", 5, "1080p", 'd')]),
+            0
+        );
+    }
+
+    #[test]
+    fn stops_upgrading_at_upgrade_until() {
+        let store = MemoryStore::new();
+        let backend = FakeBackend::default();
+        let watch_id = store
+            .upsert_anime(&frieren(QualityProfile {
+                fallbacks: vec![Quality::Res720, Quality::Res480],
+                minimum: None,
+                upgrade_until: Some(Quality::Res720),
+            }))
+            .unwrap();
+        offer(
+            &store,
+            &backend,
+            &[
+                item("SubsPlease", 5, "720p", 'a'),
+                item("SubsPlease", 6, "480p", 'b'),
+            ],
+        );
+        backend.finish(&"a".repeat(40));
+        backend.finish(&"b".repeat(40));
+        check(&store, &backend);
+
+        let upgrades = [
+            item("SubsPlease", 5, "1080p", 'c'),
+            item("SubsPlease", 6, "720p", 'd'),
+        ];
+        assert_eq!(offer(&store, &backend, &upgrades), 1);
+        assert!(episode(&store, &watch_id, 5).upgrade.is_none());
+        let upgrade = episode(&store, &watch_id, 6).upgrade.unwrap();
+        assert_eq!(quality_of(&upgrade), Some(Quality::Res720));
+    }
+
+    #[test]
+    fn rejects_releases_below_the_minimum() {
+        let store = MemoryStore::new();
+        let backend = FakeBackend::default();
+        let watch_id = store
+            .upsert_anime(&frieren(QualityProfile {
+                fallbacks: vec![],
+                minimum: Some(Quality::Res720),
+                upgrade_until: None,
+            }))
+            .unwrap();
+
+        let below = [
+            item("SubsPlease", 5, "480p", 'a'),
+            item("SubsPlease", 5, "360p", 'b'),
+        ];
+        assert_eq!(offer(&store, &backend, &below), 0);
+        assert!(store.get_episode(&watch_id, 5).unwrap().is_none());
+        assert_eq!(backend.added(), 0);
+
+        assert_eq!(
+            offer(&store, &backend, &[item("SubsPlease", 5, "720p", 'c')]),
+            1
+        );
+        let record = episode(&store, &watch_id, 5);
+        assert_eq!(record.state, EpisodeState::Downloading);
+        assert_eq!(quality_of(&record), Some(Quality::Res720));
+    }
+
+    fn with_fallback_group(store: &MemoryStore, delay_hours: u64) -> String {
+        let mut anime = frieren(QualityProfile::default());
+        anime.fallback_subgroups = vec![String::from("Erai-raws")];
+        anime.delay_hours = delay_hours;
+        return store.upsert_anime(&anime).unwrap();
+    }
+
+    #[test]
+    fn holds_fallback_groups_back_for_delay_hours() {
+        let store = MemoryStore::new();
+        let backend = FakeBackend::default();
+        let watch_id = with_fallback_group(&store, 2);
+
+        let before = lock::now();
+        assert_eq!(
+            offer(&store, &backend, &[item("Erai-raws", 5, "1080p", 'a')]),
+            1
+        );
+        let record = episode(&store, &watch_id, 5);
+        assert_eq!(record.state, EpisodeState::Pending);
+        assert!(record.decide_at.unwrap() >= before + 2 * 60 * 60);
+
+        start_due_downloads(&store, &backend, &Config::default());
+        assert_eq!(episode(&store, &watch_id, 5).state, EpisodeState::Pending);
+        assert_eq!(backend.added(), 0);
+
+        // Once the delay is up.
+        store
+            .put_episode(&EpisodeRecord {
+                decide_at: Some(before - 1),
+                ..record
+            })
+            .unwrap();
+        start_due_downloads(&store, &backend, &Config::default());
+        let record = episode(&store, &watch_id, 5);
+        assert_eq!(record.state, EpisodeState::Downloading);
+        assert_eq!(record.info_hash, Some("a".repeat(40)));
+        assert_eq!(backend.added(), 1);
+    }
+
+    #[test]
+    fn the_preferred_group_wins_within_delay_hours() {
+        let store = MemoryStore::new();
+        let backend = FakeBackend::default();
+        let watch_id = with_fallback_group(&store, 2);
+
+        offer(&store, &backend, &[item("Erai-raws", 5, "1080p", 'a')]);
+        assert_eq!(
+            offer(&store, &backend, &[item("SubsPlease", 5, "1080p", 'b')]),
+            1
+        );
+        let record = episode(&store, &watch_id, 5);
+        assert_eq!(record.state, EpisodeState::Downloading);
+        assert_eq!(record.info_hash, Some("b".repeat(40)));
+        assert_eq!(backend.added(), 1);
+    }
+
+    #[test]
+    fn takes_fallback_groups_right_away_without_a_delay() {
+        let store = MemoryStore::new();
+        let backend = FakeBackend::default();
+        let watch_id = with_fallback_group(&store, 0);
+
+        assert_eq!(
+            offer(&store, &backend, &[item("Erai-raws", 5, "1080p", 'a')]),
+            1
+        );
+        assert_eq!(
+            episode(&store, &watch_id, 5).state,
+            EpisodeState::Downloading
+        );
+    }
+}
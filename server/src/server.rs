@@ -1,4 +1,13 @@
+mod backups;
+mod builtin;
+mod control;
 mod daemon;
+mod feed;
+mod gc;
+mod metadata;
+mod reload;
+mod scheduler;
+mod shutdown;
 
 extern crate bincode;
 extern crate common;
@@ -7,13 +16,16 @@ extern crate log;
 extern crate stderrlog;
 
 use clap::{load_yaml, App};
-use std::process;
+use std::{path::PathBuf, process};
 
 use common::config;
+use common::paths::{self, PathOverrides};
 
 fn main() {
     stderrlog::new()
         .module(module_path!())
+        .module("common")
+        .module("bittorrent")
         .verbosity(4)
         .color(stderrlog::ColorChoice::Always)
         .timestamp(stderrlog::Timestamp::Millisecond)
@@ -21,7 +33,17 @@ fn main() {
         .unwrap();
 
     let yaml = load_yaml!("server.yaml");
-    App::from(yaml).get_matches();
+    let matches = App::from(yaml).get_matches();
+    paths::init(&PathOverrides {
+        config: matches.value_of("config").map(PathBuf::from),
+        data_dir: matches.value_of("data_dir").map(PathBuf::from),
+    });
+    config::set_overrides(
+        matches
+            .values_of("set")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default(),
+    );
 
     let config_result = config::read();
 
@@ -30,10 +52,14 @@ fn main() {
             daemon::run(config);
         }
         Err(e) => {
+            let hint = if e.is_not_found() {
+                "You may need to create one with `animated -e`."
+            } else {
+                "You can fix it with `animated -e`."
+            };
             error!(
-                "An error occurred while reading animated config. \
-                You may need to create one with `animated -e`.\nError: {}",
-                e.to_string()
+                "An error occurred while reading animated config. {}\nError: {}",
+                hint, e
             );
             process::exit(1);
         }
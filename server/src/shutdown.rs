@@ -0,0 +1,52 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+// Shared by every thread of the daemon. Once triggered, threads finish what
+// they are doing, save their state and return so that the daemon can exit.
+#[derive(Clone)]
+pub struct ShutdownToken {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl ShutdownToken {
+    pub fn new() -> ShutdownToken {
+        return ShutdownToken {
+            state: Arc::new((Mutex::new(false), Condvar::new())),
+        };
+    }
+
+    // Returns whether shutdown had already been triggered before.
+    pub fn trigger(&self) -> bool {
+        let (triggered, condvar) = &*self.state;
+        let mut triggered = triggered.lock().unwrap();
+        let already_triggered = *triggered;
+        *triggered = true;
+        condvar.notify_all();
+        return already_triggered;
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        return *self.state.0.lock().unwrap();
+    }
+
+    pub fn wait(&self) {
+        let (triggered, condvar) = &*self.state;
+        let mut triggered = triggered.lock().unwrap();
+        while !*triggered {
+            triggered = condvar.wait(triggered).unwrap();
+        }
+    }
+
+    // Sleeps for `timeout` unless shutdown is triggered first. Returns
+    // whether it was.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (triggered, condvar) = &*self.state;
+        let triggered = triggered.lock().unwrap();
+        let (triggered, _) = condvar
+            .wait_timeout_while(triggered, timeout, |triggered| !*triggered)
+            .unwrap();
+        return *triggered;
+    }
+}